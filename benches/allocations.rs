@@ -0,0 +1,147 @@
+//! Heap allocations per request round trip through a proxy with hooks on both
+//! the request and the response, counted by a global allocator. The count
+//! includes the test client and the fake server on the other ends.
+
+use async_trait::async_trait;
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use serde_json::json;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::runtime::Runtime;
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::duplex;
+use lsp_proxy::{Hook, Message, ProxyBuilder, Response};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Measures allocations, reallocations included, instead of time.
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: u64) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, v1: &u64, v2: &u64) -> u64 {
+        v1 + v2
+    }
+
+    fn zero(&self) -> u64 {
+        0
+    }
+
+    fn to_f64(&self, value: &u64) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationsFormatter
+    }
+}
+
+struct AllocationsFormatter;
+
+impl ValueFormatter for AllocationsFormatter {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        _throughput: &Throughput,
+        _values: &mut [f64],
+    ) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+struct Noop;
+
+#[async_trait]
+impl Hook for Noop {}
+
+fn requests(c: &mut Criterion<Allocations>) {
+    let runtime = Runtime::new().unwrap();
+    let mut client = runtime.block_on(async {
+        let io = duplex();
+        let proxy = ProxyBuilder::new()
+            .with_hook("textDocument/hover", Arc::new(Noop))
+            .build();
+        tokio::spawn(proxy.forward(
+            io.proxy_server.reader,
+            io.proxy_server.writer,
+            io.proxy_client.reader,
+            io.proxy_client.writer,
+        ));
+
+        let mut server = TestClient::from_endpoint(io.server);
+        tokio::spawn(async move {
+            while let Ok(Message::Request(request)) = server.recv().await {
+                let response = Response {
+                    id: request.id,
+                    result: Some(json!({ "contents": "docs" })),
+                    error: None,
+                };
+                server.send(&Message::Response(response)).await.unwrap();
+            }
+        });
+
+        TestClient::from_endpoint(io.client)
+    });
+
+    let params = json!({
+        "textDocument": { "uri": "file:///bench.rs" },
+        "position": { "line": 0, "character": 3 }
+    });
+
+    c.bench_function("allocations/request", |b| {
+        b.iter(|| {
+            runtime
+                .block_on(client.request("textDocument/hover", Some(params.clone())))
+                .unwrap()
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().with_measurement(Allocations);
+    targets = requests
+}
+criterion_main!(benches);
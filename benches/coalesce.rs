@@ -0,0 +1,74 @@
+//! Throughput of a burst of server notifications for different
+//! `write_coalesce_max` values. The proxy writes to the client through a
+//! `BufWriter` over a Unix socket, so every flush is a syscall.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use serde_json::json;
+use tokio::io::BufWriter;
+use tokio::net::UnixStream;
+use tokio::runtime::Runtime;
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::{duplex, write_messages};
+use lsp_proxy::{Message, ProxyBuilder};
+
+const BURST: usize = 1000;
+
+fn burst() -> Vec<u8> {
+    let notification = Message::notification(
+        "textDocument/publishDiagnostics",
+        Some(json!({ "uri": "file:///bench.rs", "diagnostics": [] })),
+    )
+    .to_value();
+    let mut bytes = Vec::new();
+    let runtime = Runtime::new().unwrap();
+    runtime
+        .block_on(write_messages(&mut bytes, &vec![notification; BURST]))
+        .unwrap();
+    bytes
+}
+
+fn coalesce(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let burst = burst();
+
+    let mut group = c.benchmark_group("server_burst");
+    group.throughput(Throughput::Elements(BURST as u64));
+
+    for coalesce_max in [1, 16, 64] {
+        let (mut client, mut server) = runtime.block_on(async {
+            let io = duplex();
+            let (client_end, proxy_end) = UnixStream::pair().unwrap();
+            let (proxy_reader, proxy_writer) = proxy_end.into_split();
+            let (client_reader, client_writer) = client_end.into_split();
+
+            let proxy = ProxyBuilder::new().write_coalesce_max(coalesce_max).build();
+            tokio::spawn(proxy.forward(
+                io.proxy_server.reader,
+                io.proxy_server.writer,
+                proxy_reader,
+                BufWriter::new(proxy_writer),
+            ));
+
+            (
+                TestClient::new(client_reader, client_writer),
+                TestClient::from_endpoint(io.server),
+            )
+        });
+
+        group.bench_function(BenchmarkId::from_parameter(coalesce_max), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    server.send_bytes(&burst).await.unwrap();
+                    for _ in 0..BURST {
+                        client.recv().await.unwrap();
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, coalesce);
+criterion_main!(benches);
@@ -0,0 +1,218 @@
+//! Peak heap use while a multi-megabyte `semanticTokens/full` response goes
+//! through a proxy, with and without a hook on the method. The client end
+//! drains the frame through a small buffer, so the peak is the proxy's.
+
+use async_trait::async_trait;
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use serde_json::json;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::{DuplexReader, duplex, write_message};
+use lsp_proxy::{Hook, Message, ProxyBuilder, RequestId, Response};
+
+const METHOD: &str = "textDocument/semanticTokens/full";
+
+struct Tracking;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn grow(size: usize) {
+    let current = CURRENT.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(current, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Tracking {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        grow(layout.size());
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        grow(new_size);
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+        new
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Tracking = Tracking;
+
+/// Measures how far heap use rose above where it started, in bytes.
+struct PeakMemory;
+
+impl Measurement for PeakMemory {
+    type Intermediate = usize;
+    type Value = usize;
+
+    fn start(&self) -> usize {
+        let current = CURRENT.load(Ordering::Relaxed);
+        PEAK.store(current, Ordering::Relaxed);
+        current
+    }
+
+    fn end(&self, start: usize) -> usize {
+        PEAK.load(Ordering::Relaxed).saturating_sub(start)
+    }
+
+    fn add(&self, v1: &usize, v2: &usize) -> usize {
+        v1 + v2
+    }
+
+    fn zero(&self) -> usize {
+        0
+    }
+
+    fn to_f64(&self, value: &usize) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &BytesFormatter
+    }
+}
+
+struct BytesFormatter;
+
+impl ValueFormatter for BytesFormatter {
+    fn scale_values(&self, typical_value: f64, values: &mut [f64]) -> &'static str {
+        let (divisor, unit) = if typical_value >= 1024.0 * 1024.0 {
+            (1024.0 * 1024.0, "MiB")
+        } else if typical_value >= 1024.0 {
+            (1024.0, "KiB")
+        } else {
+            (1.0, "B")
+        };
+        for value in values {
+            *value /= divisor;
+        }
+        unit
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        _throughput: &Throughput,
+        _values: &mut [f64],
+    ) -> &'static str {
+        "B"
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "B"
+    }
+}
+
+struct Noop;
+
+#[async_trait]
+impl Hook for Noop {}
+
+/// Reads one frame and throws it away, without holding on to its body.
+async fn drain_frame(reader: &mut DuplexReader, buffer: &mut [u8]) {
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        reader.read_exact(&mut byte).await.unwrap();
+        header.extend_from_slice(&byte);
+    }
+    let header = String::from_utf8(header).unwrap();
+    let mut remaining: usize = header
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    while remaining > 0 {
+        let len = remaining.min(buffer.len());
+        reader.read_exact(&mut buffer[..len]).await.unwrap();
+        remaining -= len;
+    }
+}
+
+fn large_response(c: &mut Criterion<PeakMemory>) {
+    let runtime = Runtime::new().unwrap();
+
+    let data: Vec<u32> = (0..1_000_000).map(|i| i % 1000).collect();
+    let response = Message::Response(Response {
+        id: RequestId::Int(1),
+        result: Some(json!({ "data": data })),
+        error: None,
+    });
+    let mut frame = Vec::new();
+    runtime
+        .block_on(write_message(&mut frame, &response.to_value()))
+        .unwrap();
+    let request = Message::request(
+        1,
+        METHOD,
+        Some(json!({ "textDocument": { "uri": "file:///big.rs" } })),
+    )
+    .to_value();
+
+    let mut group = c.benchmark_group("large_response");
+    group.sample_size(10);
+    group.warm_up_time(Duration::from_secs(1));
+
+    for hooked in [false, true] {
+        let (mut client, mut client_writer, mut server) = runtime.block_on(async {
+            let io = duplex();
+            let mut builder = ProxyBuilder::new();
+            if hooked {
+                builder = builder.with_hook(METHOD, Arc::new(Noop));
+            }
+            tokio::spawn(builder.build().forward(
+                io.proxy_server.reader,
+                io.proxy_server.writer,
+                io.proxy_client.reader,
+                io.proxy_client.writer,
+            ));
+            (
+                io.client.reader,
+                io.client.writer,
+                TestClient::from_endpoint(io.server),
+            )
+        });
+        let mut buffer = vec![0; 64 * 1024];
+
+        let name = if hooked { "hooked" } else { "unhooked" };
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_custom(|iters| {
+                let mut total = 0;
+                for _ in 0..iters {
+                    let start = PeakMemory.start();
+                    runtime.block_on(async {
+                        write_message(&mut client_writer, &request).await.unwrap();
+                        let received = server.recv().await.unwrap();
+                        assert_eq!(received.get_id(), Some(&RequestId::Int(1)));
+                        server.send_bytes(&frame).await.unwrap();
+                        drain_frame(&mut client, &mut buffer).await;
+                    });
+                    total += PeakMemory.end(start);
+                }
+                total
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().with_measurement(PeakMemory);
+    targets = large_response
+}
+criterion_main!(benches);
@@ -0,0 +1,108 @@
+//! Messages per second through a proxy over in-memory connections, with a
+//! hook that returns every message unchanged.
+
+use async_trait::async_trait;
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::{duplex, write_messages};
+use lsp_proxy::{Hook, Message, ProxyBuilder, Response};
+
+const BURST: usize = 1000;
+
+struct Noop;
+
+#[async_trait]
+impl Hook for Noop {}
+
+fn start(runtime: &Runtime) -> (TestClient, TestClient) {
+    runtime.block_on(async {
+        let io = duplex();
+        let proxy = ProxyBuilder::new()
+            .with_hooks(
+                &["textDocument/didChange", "textDocument/hover"],
+                Arc::new(Noop),
+            )
+            .build();
+        tokio::spawn(proxy.forward(
+            io.proxy_server.reader,
+            io.proxy_server.writer,
+            io.proxy_client.reader,
+            io.proxy_client.writer,
+        ));
+        (
+            TestClient::from_endpoint(io.client),
+            TestClient::from_endpoint(io.server),
+        )
+    })
+}
+
+fn notifications(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let (mut client, mut server) = start(&runtime);
+
+    let notification = Message::notification(
+        "textDocument/didChange",
+        Some(json!({
+            "textDocument": { "uri": "file:///bench.rs", "version": 2 },
+            "contentChanges": [{ "text": "fn main() {}" }]
+        })),
+    )
+    .to_value();
+    let mut burst = Vec::new();
+    runtime
+        .block_on(write_messages(&mut burst, &vec![notification; BURST]))
+        .unwrap();
+
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Elements(BURST as u64));
+    group.bench_function("notifications", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                client.send_bytes(&burst).await.unwrap();
+                for _ in 0..BURST {
+                    server.recv().await.unwrap();
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+fn requests(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let (mut client, mut server) = start(&runtime);
+
+    runtime.spawn(async move {
+        while let Ok(Message::Request(request)) = server.recv().await {
+            let response = Response {
+                id: request.id,
+                result: Some(json!({ "contents": "docs" })),
+                error: None,
+            };
+            server.send(&Message::Response(response)).await.unwrap();
+        }
+    });
+
+    let params = json!({
+        "textDocument": { "uri": "file:///bench.rs" },
+        "position": { "line": 0, "character": 3 }
+    });
+
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("requests", |b| {
+        b.iter(|| {
+            runtime
+                .block_on(client.request("textDocument/hover", Some(params.clone())))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, notifications, requests);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+mod configuration;
+mod diagnostics;
+mod uri_remap;
+
+pub use configuration::ConfigurationHook;
+pub use diagnostics::DiagnosticsHook;
+pub use uri_remap::UriRemapHook;
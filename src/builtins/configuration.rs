@@ -0,0 +1,113 @@
+use async_trait::async_trait;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Hook, HookContext, HookOutput, HookResult, Message, Request, Response};
+
+type SettingsFn = Arc<dyn Fn(Option<&str>) -> Option<Value> + Send + Sync>;
+
+/// Answers the server's `workspace/configuration` requests from a settings
+/// provider, called with the `section` of each requested item. Items the
+/// provider returns `None` for are asked of the client in a single request
+/// and merged in, so the provider can override some sections and leave the
+/// rest to the editor. If it answers none of them, the request is forwarded
+/// untouched. Register it for `workspace/configuration`.
+#[derive(Clone)]
+pub struct ConfigurationHook {
+    settings: SettingsFn,
+    client_timeout: Duration,
+}
+
+impl ConfigurationHook {
+    pub fn new<F>(settings: F) -> Self
+    where
+        F: Fn(Option<&str>) -> Option<Value> + Send + Sync + 'static,
+    {
+        Self {
+            settings: Arc::new(settings),
+            client_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// How long to wait for the client to answer the items the provider left
+    /// open; they are answered with `null` if it does not. Defaults to 10s.
+    pub fn client_timeout(mut self, timeout: Duration) -> Self {
+        self.client_timeout = timeout;
+        self
+    }
+
+    /// Asks the client for `items` and returns its results, or `null` for
+    /// each item if it cannot be asked or does not answer in time.
+    async fn ask_client<S>(&self, items: Vec<Value>, context: &HookContext<S>) -> Vec<Value> {
+        let count = items.len();
+        if count == 0 {
+            return Vec::new();
+        }
+
+        let answered = match context.handle() {
+            Some(handle) => handle
+                .send_request(
+                    context.to_peer(),
+                    "workspace/configuration",
+                    Some(json!({ "items": items })),
+                    self.client_timeout,
+                )
+                .await
+                .ok()
+                .and_then(|response| response.result),
+            None => None,
+        };
+
+        match answered {
+            Some(Value::Array(results)) if results.len() == count => results,
+            _ => vec![Value::Null; count],
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync + 'static> Hook<S> for ConfigurationHook {
+    async fn on_request(&self, request: Request, context: &HookContext<S>) -> HookResult {
+        let Some(items) = request
+            .params
+            .as_ref()
+            .and_then(|params| params.get("items"))
+            .and_then(Value::as_array)
+        else {
+            return Ok(HookOutput::new(Message::Request(request)));
+        };
+
+        let provided: Vec<Option<Value>> = items
+            .iter()
+            .map(|item| (self.settings)(item.get("section").and_then(Value::as_str)))
+            .collect();
+        if provided.iter().all(Option::is_none) {
+            return Ok(HookOutput::new(Message::Request(request)));
+        }
+
+        let open: Vec<Value> = items
+            .iter()
+            .zip(&provided)
+            .filter(|(_, provided)| provided.is_none())
+            .map(|(item, _)| item.clone())
+            .collect();
+        let mut from_client = self.ask_client(open, context).await.into_iter();
+
+        let results = provided
+            .into_iter()
+            .map(|provided| {
+                provided
+                    .or_else(|| from_client.next())
+                    .unwrap_or(Value::Null)
+            })
+            .collect();
+
+        let response = Response {
+            id: request.id,
+            result: Some(Value::Array(results)),
+            error: None,
+        };
+        Ok(HookOutput::empty().with_message(context.to_origin(), Message::Response(response)))
+    }
+}
@@ -0,0 +1,50 @@
+use async_trait::async_trait;
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::{Direction, Hook, HookContext, HookOutput, HookResult, Message, Notification};
+
+type TransformFn = Arc<dyn Fn(&str, &mut Vec<Value>) + Send + Sync>;
+
+/// Rewrites the `diagnostics` of `textDocument/publishDiagnostics`
+/// notifications on their way to the client, e.g. to drop certain codes,
+/// change severities or prefix the `source`. The closure gets the document
+/// URI and the diagnostics as JSON. The notification is always forwarded, even
+/// when the closure removes every diagnostic, since an empty array is how the
+/// client learns to clear the ones it shows. Register it for
+/// `textDocument/publishDiagnostics`.
+#[derive(Clone)]
+pub struct DiagnosticsHook {
+    transform: TransformFn,
+}
+
+impl DiagnosticsHook {
+    pub fn new<F>(transform: F) -> Self
+    where
+        F: Fn(&str, &mut Vec<Value>) + Send + Sync + 'static,
+    {
+        Self {
+            transform: Arc::new(transform),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync + 'static> Hook<S> for DiagnosticsHook {
+    async fn on_notification(
+        &self,
+        mut notification: Notification,
+        context: &HookContext<S>,
+    ) -> HookResult {
+        if notification.method == "textDocument/publishDiagnostics"
+            && context.to_peer() == Direction::ToClient
+            && let Some(Value::Object(params)) = notification.params.as_mut()
+            && let Some(Value::String(uri)) = params.get("uri").cloned()
+            && let Some(Value::Array(diagnostics)) = params.get_mut("diagnostics")
+        {
+            (self.transform)(&uri, diagnostics);
+        }
+
+        Ok(HookOutput::new(Message::Notification(notification)))
+    }
+}
@@ -0,0 +1,97 @@
+use async_trait::async_trait;
+use serde_json::{Map, Value};
+
+use crate::{Hook, HookContext, HookOutput, HookResult, Message, Notification, Request, Response};
+
+/// Rewrites `file:` URIs between the client's and the server's view of the
+/// file system, e.g. when the server runs in a container. Every string and
+/// object key anywhere in the payload is checked, so nested locations such as
+/// `textDocument.uri`, `location.uri` and `WorkspaceEdit.changes` are covered.
+///
+/// A URI under a client prefix is rewritten to the matching server prefix and
+/// vice versa, which makes the hook independent of the message direction. It
+/// only does its job for methods it is registered for; see
+/// `ProxyBuilder::with_hooks`.
+#[derive(Debug, Clone, Default)]
+pub struct UriRemapHook {
+    mappings: Vec<(String, String)>,
+}
+
+impl UriRemapHook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_mapping(mut self, client_prefix: &str, server_prefix: &str) -> Self {
+        self.mappings.push((
+            client_prefix.trim_end_matches('/').to_owned(),
+            server_prefix.trim_end_matches('/').to_owned(),
+        ));
+        self
+    }
+
+    pub fn remap(&self, value: &mut Value) {
+        match value {
+            Value::String(s) => {
+                if let Some(remapped) = self.remap_uri(s) {
+                    *s = remapped;
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.remap(item)),
+            Value::Object(object) => {
+                let entries = std::mem::take(object);
+                *object = entries
+                    .into_iter()
+                    .map(|(key, mut value)| {
+                        self.remap(&mut value);
+                        (self.remap_uri(&key).unwrap_or(key), value)
+                    })
+                    .collect::<Map<String, Value>>();
+            }
+            _ => {}
+        }
+    }
+
+    fn remap_uri(&self, uri: &str) -> Option<String> {
+        if !uri.starts_with("file:") {
+            return None;
+        }
+
+        self.mappings.iter().find_map(|(client, server)| {
+            replace_prefix(uri, client, server).or_else(|| replace_prefix(uri, server, client))
+        })
+    }
+}
+
+fn replace_prefix(uri: &str, from: &str, to: &str) -> Option<String> {
+    let rest = uri.strip_prefix(from)?;
+    (rest.is_empty() || rest.starts_with('/')).then(|| format!("{}{}", to, rest))
+}
+
+#[async_trait]
+impl<S: Send + Sync + 'static> Hook<S> for UriRemapHook {
+    async fn on_request(&self, mut request: Request, _context: &HookContext<S>) -> HookResult {
+        if let Some(params) = request.params.as_mut() {
+            self.remap(params);
+        }
+        Ok(HookOutput::new(Message::Request(request)))
+    }
+
+    async fn on_response(&self, mut response: Response, _context: &HookContext<S>) -> HookResult {
+        if let Some(result) = response.result.as_mut() {
+            self.remap(result);
+        }
+        Ok(HookOutput::new(Message::Response(response)))
+    }
+
+    async fn on_notification(
+        &self,
+        mut notification: Notification,
+        _context: &HookContext<S>,
+    ) -> HookResult {
+        if let Some(params) = notification.params.as_mut() {
+            self.remap(params);
+        }
+        Ok(HookOutput::new(Message::Notification(notification)))
+    }
+}
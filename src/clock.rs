@@ -0,0 +1,96 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::watch;
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The time source behind every timeout, delay and window the proxy applies:
+/// `send_request` timeouts, `idle_timeout`, rate limits, request dedup, pair
+/// timeouts, health checks, telemetry, retries, reconnects and the drain
+/// graces. Set with `ProxyBuilder::clock`; `TestClock` makes them testable
+/// without sleeping.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Completes once `now()` has reached `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+}
+
+/// The default clock: tokio's, so it also follows `tokio::time::pause`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+/// A clock that only moves when `advance` is called. Clones share the same
+/// time, so keep one to drive the proxy built with another.
+#[derive(Debug, Clone)]
+pub struct TestClock {
+    now: Arc<watch::Sender<Instant>>,
+}
+
+impl TestClock {
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(watch::Sender::new(Instant::now())),
+        }
+    }
+
+    /// Moves the time forward by `duration`, waking everything whose deadline
+    /// it reaches.
+    pub fn advance(&self, duration: Duration) {
+        self.now.send_modify(|now| *now += duration);
+    }
+}
+
+impl Default for TestClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            while *now.borrow_and_update() < deadline {
+                if now.changed().await.is_err() {
+                    // The clock is gone, so the deadline never comes.
+                    return std::future::pending().await;
+                }
+            }
+        })
+    }
+}
+
+/// Runs `future` for at most `duration` on `clock`, returning `None` if the
+/// time ran out first.
+pub(crate) async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    select! {
+        output = future => Some(output),
+        () = clock.sleep(duration) => None,
+    }
+}
@@ -0,0 +1,89 @@
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_util::sync::CancellationToken;
+
+use crate::Message;
+use crate::outbound::{Outgoing, recv_until_drained};
+
+pub(crate) type CoalesceKeyFn = Arc<dyn Fn(&Message) -> Option<String> + Send + Sync>;
+
+/// The key `ProxyBuilder::coalesce_superseded` uses: diagnostics are keyed by
+/// document URI and `$/progress` reports by token. Progress `begin` and `end`
+/// notifications are never coalesced.
+pub fn superseded_key(message: &Message) -> Option<String> {
+    let Message::Notification(notification) = message else {
+        return None;
+    };
+    let params = notification.params.as_ref()?;
+
+    match notification.method.as_str() {
+        "textDocument/publishDiagnostics" => {
+            params.get("uri").and_then(Value::as_str).map(str::to_owned)
+        }
+        "$/progress" if params.pointer("/value/kind") == Some(&Value::from("report")) => {
+            params.get("token").map(Value::to_string)
+        }
+        _ => None,
+    }
+}
+
+/// Messages waiting to be written to one peer. Everything that queued up
+/// while the writer was busy is taken at once, so a notification superseded
+/// by a later one with the same method and key can be dropped before it is
+/// ever written to a slow peer.
+pub(crate) struct PeerQueue {
+    receiver: UnboundedReceiver<Outgoing>,
+    pending: VecDeque<(Option<(String, String)>, Outgoing)>,
+}
+
+impl PeerQueue {
+    pub(crate) fn new(receiver: UnboundedReceiver<Outgoing>) -> Self {
+        Self {
+            receiver,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Waits until something is pending, then takes whatever else is queued.
+    /// Returns `false` once the channel is closed, or `draining` cancelled,
+    /// and everything queued has been taken.
+    pub(crate) async fn fill(
+        &mut self,
+        key_fn: Option<&CoalesceKeyFn>,
+        draining: &CancellationToken,
+    ) -> bool {
+        if self.pending.is_empty() {
+            match recv_until_drained(&mut self.receiver, draining).await {
+                Some(outgoing) => self.push(outgoing, key_fn),
+                None => return false,
+            }
+        }
+
+        while let Ok(outgoing) = self.receiver.try_recv() {
+            self.push(outgoing, key_fn);
+        }
+        true
+    }
+
+    pub(crate) fn take(&mut self, max: usize) -> impl Iterator<Item = Outgoing> + '_ {
+        let count = self.pending.len().min(max);
+        self.pending.drain(..count).map(|(_, outgoing)| outgoing)
+    }
+
+    fn push(&mut self, outgoing: Outgoing, key_fn: Option<&CoalesceKeyFn>) {
+        let key = key_fn
+            .zip(outgoing.message.as_ref())
+            .filter(|(_, message)| matches!(message, Message::Notification(_)))
+            .and_then(|(key_fn, message)| {
+                key_fn(message).zip(message.get_method().map(str::to_owned))
+            })
+            .map(|(key, method)| (method, key));
+
+        if key.is_some() {
+            self.pending.retain(|(pending_key, _)| *pending_key != key);
+        }
+        self.pending.push_back((key, outgoing));
+    }
+}
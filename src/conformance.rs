@@ -0,0 +1,239 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+
+use crate::{Direction, Message, RequestId};
+
+/// How many answered ids per peer are remembered to tell a duplicate response
+/// apart from one for an id that was never requested.
+const ANSWERED_CAPACITY: usize = 1024;
+
+/// A message that breaks the LSP message ordering rules. `peer` is the side
+/// that sent it: `Direction::ToServer` for the client, whose messages travel
+/// to the server, and `Direction::ToClient` for the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolViolation {
+    /// A response for an id that was never sent to `peer` as a request.
+    UnknownResponse { peer: Direction, id: RequestId },
+    /// A second response for an id `peer` already answered.
+    DuplicateResponse { peer: Direction, id: RequestId },
+    /// A request reusing the id of one of `peer`'s requests that is still
+    /// unanswered.
+    ReusedId { peer: Direction, id: RequestId },
+    /// A request or notification sent before the `initialize` request was
+    /// answered, other than those the specification allows.
+    BeforeInitialize { peer: Direction, method: String },
+}
+
+impl Display for ProtocolViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sender = |peer: &Direction| match peer {
+            Direction::ToServer => "client",
+            Direction::ToClient => "server",
+        };
+
+        match self {
+            ProtocolViolation::UnknownResponse { peer, id } => write!(
+                f,
+                "The {} answered id {}, which it was never sent",
+                sender(peer),
+                id
+            ),
+            ProtocolViolation::DuplicateResponse { peer, id } => {
+                write!(f, "The {} answered id {} twice", sender(peer), id)
+            }
+            ProtocolViolation::ReusedId { peer, id } => write!(
+                f,
+                "The {} reused id {} while its request is pending",
+                sender(peer),
+                id
+            ),
+            ProtocolViolation::BeforeInitialize { peer, method } => write!(
+                f,
+                "The {} sent {} before initialize was answered",
+                sender(peer),
+                method
+            ),
+        }
+    }
+}
+
+type Observer = Arc<dyn Fn(&ProtocolViolation) + Send + Sync>;
+
+/// Checks the traffic in both directions against the LSP ordering rules and
+/// reports every violation, turning the proxy into a protocol linter for
+/// server or client development. Violations are written to stderr unless
+/// `on_violation` is set; offending messages are still forwarded unless
+/// `drop_violations` is enabled.
+#[derive(Clone, Default)]
+pub struct ProtocolCheck {
+    observer: Option<Observer>,
+    drop_violations: bool,
+}
+
+impl ProtocolCheck {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called with each violation instead of writing it to stderr.
+    pub fn on_violation<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(&ProtocolViolation) + Send + Sync + 'static,
+    {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Drops messages that violate the protocol instead of forwarding them.
+    /// Dropped requests are answered with `InvalidRequest`.
+    pub fn drop_violations(mut self, enabled: bool) -> Self {
+        self.drop_violations = enabled;
+        self
+    }
+
+    pub(crate) fn drops_violations(&self) -> bool {
+        self.drop_violations
+    }
+
+    /// Hands `violation` to the observer, or returns `false` if there is
+    /// none and it should be logged instead.
+    pub(crate) fn notify(&self, violation: &ProtocolViolation) -> bool {
+        match &self.observer {
+            Some(observer) => {
+                observer(violation);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Requests in flight and answered per peer, keyed by the direction of the
+/// peer's own messages.
+#[derive(Default)]
+struct Exchanges {
+    /// Requests written to the peer that it has not answered, with their
+    /// method.
+    sent: HashMap<RequestId, String>,
+    /// Requests the peer sent that have not been answered.
+    received: HashSet<RequestId>,
+    answered: VecDeque<RequestId>,
+}
+
+#[derive(Default)]
+struct CheckerState {
+    client: Exchanges,
+    server: Exchanges,
+    initialized: bool,
+}
+
+impl CheckerState {
+    fn exchanges(&mut self, peer: Direction) -> &mut Exchanges {
+        match peer {
+            Direction::ToServer => &mut self.client,
+            Direction::ToClient => &mut self.server,
+        }
+    }
+}
+
+/// The conversation state `ProtocolCheck` needs: what each peer sent is
+/// checked as it is read, what it was sent is recorded as it is written.
+#[derive(Default)]
+pub(crate) struct ProtocolChecker {
+    state: Mutex<CheckerState>,
+}
+
+impl ProtocolChecker {
+    /// Checks a message read from `peer`.
+    pub(crate) fn check(&self, peer: Direction, message: &Message) -> Option<ProtocolViolation> {
+        let mut state = self.state.lock().unwrap();
+        let initialized = state.initialized;
+        let exchanges = state.exchanges(peer);
+        let mut answers_initialize = false;
+
+        match message {
+            Message::Request(request) => {
+                if !initialized && !allowed_before_initialize(peer, &request.method) {
+                    return Some(ProtocolViolation::BeforeInitialize {
+                        peer,
+                        method: request.method.clone(),
+                    });
+                }
+                if exchanges.received.contains(&request.id) {
+                    return Some(ProtocolViolation::ReusedId {
+                        peer,
+                        id: request.id.clone(),
+                    });
+                }
+                exchanges.received.insert(request.id.clone());
+            }
+            Message::Notification(notification) => {
+                if !initialized && !allowed_before_initialize(peer, &notification.method) {
+                    return Some(ProtocolViolation::BeforeInitialize {
+                        peer,
+                        method: notification.method.clone(),
+                    });
+                }
+            }
+            Message::Response(response) => {
+                let Some(method) = exchanges.sent.remove(&response.id) else {
+                    let id = response.id.clone();
+                    return Some(if exchanges.answered.contains(&id) {
+                        ProtocolViolation::DuplicateResponse { peer, id }
+                    } else {
+                        ProtocolViolation::UnknownResponse { peer, id }
+                    });
+                };
+                if exchanges.answered.len() == ANSWERED_CAPACITY {
+                    exchanges.answered.pop_front();
+                }
+                exchanges.answered.push_back(response.id.clone());
+                answers_initialize = peer == Direction::ToClient
+                    && method == "initialize"
+                    && response.error.is_none();
+            }
+        }
+
+        // Set as the server's answer is read rather than written, so that
+        // what the server sends right after it is not flagged.
+        if answers_initialize {
+            state.initialized = true;
+        }
+        None
+    }
+
+    /// Records a message written in `direction`.
+    pub(crate) fn record_sent(&self, direction: Direction, message: &Message) {
+        let mut state = self.state.lock().unwrap();
+        let exchanges = state.exchanges(direction.opposite());
+        match message {
+            Message::Request(request) => {
+                exchanges
+                    .sent
+                    .insert(request.id.clone(), request.method.clone());
+            }
+            Message::Response(response) => {
+                exchanges.received.remove(&response.id);
+            }
+            Message::Notification(_) => {}
+        }
+    }
+}
+
+/// The client may only send `initialize` and `exit` before `initialize` is
+/// answered; the server may show and log messages and report telemetry and
+/// progress.
+fn allowed_before_initialize(peer: Direction, method: &str) -> bool {
+    match peer {
+        Direction::ToServer => matches!(method, "initialize" | "exit"),
+        Direction::ToClient => matches!(
+            method,
+            "window/showMessage"
+                | "window/logMessage"
+                | "window/showMessageRequest"
+                | "telemetry/event"
+                | "$/progress"
+        ),
+    }
+}
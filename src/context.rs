@@ -0,0 +1,269 @@
+use serde_json::Value;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+use crate::Direction;
+use crate::handle::{ConnectionId, LifecycleState, ProxyHandle};
+use crate::message::TraceValue;
+use crate::params::{Params, ParamsCache};
+use crate::position::PositionEncoding;
+
+/// What a hook knows about the message it is handling besides the message
+/// itself. `S` is the application state the proxy was built with, see
+/// `ProxyBuilder::with_state`.
+pub struct HookContext<S = ()> {
+    origin: Direction,
+    raw_bytes: Option<Arc<[u8]>>,
+    headers: Vec<(String, String)>,
+    cancellation: CancellationToken,
+    trace: TraceValue,
+    lifecycle_state: LifecycleState,
+    position_encoding: PositionEncoding,
+    workspace_roots: Arc<[String]>,
+    handle: Option<ProxyHandle>,
+    params: Arc<ParamsCache>,
+    state: Arc<S>,
+}
+
+impl<S> Clone for HookContext<S> {
+    fn clone(&self) -> Self {
+        Self {
+            origin: self.origin,
+            raw_bytes: self.raw_bytes.clone(),
+            headers: self.headers.clone(),
+            cancellation: self.cancellation.clone(),
+            trace: self.trace,
+            lifecycle_state: self.lifecycle_state,
+            position_encoding: self.position_encoding,
+            workspace_roots: Arc::clone(&self.workspace_roots),
+            handle: self.handle.clone(),
+            params: Arc::clone(&self.params),
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl<S> std::fmt::Debug for HookContext<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookContext")
+            .field("origin", &self.origin)
+            .field("raw_bytes", &self.raw_bytes)
+            .field("headers", &self.headers)
+            .field("cancellation", &self.cancellation)
+            .field("trace", &self.trace)
+            .field("lifecycle_state", &self.lifecycle_state)
+            .field("position_encoding", &self.position_encoding)
+            .field("workspace_roots", &self.workspace_roots)
+            .field("handle", &self.handle)
+            .field("params", &self.params)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for HookContext {
+    fn default() -> Self {
+        Self {
+            origin: Direction::ToClient,
+            raw_bytes: None,
+            headers: Vec::new(),
+            cancellation: CancellationToken::new(),
+            trace: TraceValue::Off,
+            lifecycle_state: LifecycleState::Uninitialized,
+            position_encoding: PositionEncoding::default(),
+            workspace_roots: Arc::new([]),
+            handle: None,
+            params: Arc::default(),
+            state: Arc::new(()),
+        }
+    }
+}
+
+impl<S> HookContext<S> {
+    /// The application state given to `ProxyBuilder::with_state`, shared by
+    /// every hook of the proxy, e.g. configuration, caches or clients for
+    /// external services. Wrap what hooks change in a `Mutex` or an atomic.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub(crate) fn shared_raw_bytes(&self) -> Option<Arc<[u8]>> {
+        self.raw_bytes.clone()
+    }
+
+    /// The trace level the client last asked the server for, via `initialize`
+    /// or `$/setTrace`, as of when this message was read.
+    pub fn trace(&self) -> TraceValue {
+        self.trace
+    }
+
+    /// Where the session was in the LSP lifecycle when this message was
+    /// read, e.g. so a hook can hold back custom notifications until the
+    /// client has sent `initialized`. The `initialized` notification itself
+    /// is handled in `Initializing`.
+    pub fn lifecycle_state(&self) -> LifecycleState {
+        self.lifecycle_state
+    }
+
+    /// The `positionEncoding` the server announced in its `initialize` result,
+    /// for hooks converting positions with the `position` module. UTF-16 until
+    /// then, or if the server announced none.
+    pub fn position_encoding(&self) -> PositionEncoding {
+        self.position_encoding
+    }
+
+    /// URIs of the workspace folders the client opened, taken from
+    /// `initialize` and kept current through `didChangeWorkspaceFolders`.
+    /// Falls back to `rootUri` or `rootPath` for clients that do not send
+    /// `workspaceFolders`. Empty before `initialize`.
+    pub fn workspace_roots(&self) -> &[String] {
+        &self.workspace_roots
+    }
+
+    /// A handle to the running proxy, so a hook can await a round-trip of its
+    /// own before deciding what to return, e.g. ask the server something and
+    /// then rewrite or answer the request. The hook holds up the messages
+    /// behind it while it waits, and only requests towards `to_peer()` can be
+    /// answered: the reply to a request sent to `to_origin()` would arrive
+    /// behind the message being processed and time out. `None` outside the
+    /// proxy.
+    pub fn handle(&self) -> Option<&ProxyHandle> {
+        self.handle.as_ref()
+    }
+
+    /// The session the message belongs to, so a hook can tag its own logs the
+    /// way the proxy does. `None` outside the proxy.
+    pub fn connection_id(&self) -> Option<ConnectionId> {
+        self.handle.as_ref().map(ProxyHandle::connection_id)
+    }
+
+    /// Cancelled when the proxy stops forwarding, e.g. through
+    /// `Proxy::forward_with_shutdown`. Hooks doing slow external I/O can select
+    /// on `cancelled()` to abandon it and clean up instead of being dropped
+    /// mid-operation.
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// The direction that leads back to whoever sent the message. Use it for
+    /// generated replies instead of hardcoding a side, so the same hook works
+    /// for messages from the client and from the server.
+    pub fn to_origin(&self) -> Direction {
+        self.origin
+    }
+
+    /// The direction the message was travelling in, towards the other peer.
+    pub fn to_peer(&self) -> Direction {
+        self.origin.opposite()
+    }
+
+    /// The JSON body of the incoming message exactly as it was read from the
+    /// wire, useful for logging or hashing without serializing the message
+    /// again. It always reflects the received message, not the one a hook
+    /// returns.
+    pub fn raw_bytes(&self) -> Option<&[u8]> {
+        self.raw_bytes.as_deref()
+    }
+
+    /// Transport headers of the incoming message in the order they were
+    /// received, including `Content-Length`.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Typed access to `params`, the params of the message being handled.
+    /// What `Params::as_typed` deserializes is kept for the other hooks that
+    /// see the same message, e.g. one wrapped by `map_request`, so the chain
+    /// parses it once; it is rebuilt if a hook has changed the params since.
+    pub fn params<'a>(&'a self, params: &'a Option<Value>) -> Params<'a> {
+        Params::new(params.as_ref(), &self.params)
+    }
+
+    /// Looks up a transport header by name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The setters the proxy fills in each message's context with. With the
+/// `test-util` feature they are public, so a hook can be called directly in a
+/// test with a context built from `HookContext::default()`; otherwise they are
+/// internal to the crate.
+macro_rules! setters {
+    ($vis:vis) => {
+        impl<S> HookContext<S> {
+            /// Sets the direction leading back to the sender of the message.
+            /// Defaults to `Direction::ToClient`.
+            $vis fn with_origin(mut self, origin: Direction) -> Self {
+                self.origin = origin;
+                self
+            }
+
+            /// Sets the application state `state()` returns.
+            $vis fn with_state<T>(self, state: Arc<T>) -> HookContext<T> {
+                HookContext {
+                    origin: self.origin,
+                    raw_bytes: self.raw_bytes,
+                    headers: self.headers,
+                    cancellation: self.cancellation,
+                    trace: self.trace,
+                    lifecycle_state: self.lifecycle_state,
+                    position_encoding: self.position_encoding,
+                    workspace_roots: self.workspace_roots,
+                    handle: self.handle,
+                    params: self.params,
+                    state,
+                }
+            }
+
+            $vis fn with_raw_bytes(mut self, raw_bytes: Arc<[u8]>) -> Self {
+                self.raw_bytes = Some(raw_bytes);
+                self
+            }
+
+            $vis fn with_headers(mut self, headers: Vec<(String, String)>) -> Self {
+                self.headers = headers;
+                self
+            }
+
+            $vis fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+                self.cancellation = cancellation;
+                self
+            }
+
+            $vis fn with_trace(mut self, trace: TraceValue) -> Self {
+                self.trace = trace;
+                self
+            }
+
+            /// Sets the lifecycle state the message was read in. Defaults to
+            /// `LifecycleState::Uninitialized`.
+            $vis fn with_lifecycle_state(mut self, state: LifecycleState) -> Self {
+                self.lifecycle_state = state;
+                self
+            }
+
+            $vis fn with_position_encoding(mut self, encoding: PositionEncoding) -> Self {
+                self.position_encoding = encoding;
+                self
+            }
+
+            $vis fn with_workspace_roots(mut self, workspace_roots: Arc<[String]>) -> Self {
+                self.workspace_roots = workspace_roots;
+                self
+            }
+
+            $vis fn with_handle(mut self, handle: ProxyHandle) -> Self {
+                self.handle = Some(handle);
+                self
+            }
+        }
+    };
+}
+
+#[cfg(feature = "test-util")]
+setters!(pub);
+#[cfg(not(feature = "test-util"))]
+setters!(pub(crate));
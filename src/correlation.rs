@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::{Direction, RequestId};
+
+/// Matches responses to the requests the proxy forwarded. A request is
+/// registered under the direction it travelled and its id when it is
+/// forwarded, and resolved to its method when the response comes back the
+/// other way; hooks for the response are looked up by that method. Implement
+/// it to key requests by something richer than the raw id, e.g. when several
+/// clients' ids are namespaced or remapped onto one server.
+pub trait Correlator: Send + Sync {
+    /// Records that request `id` was forwarded to `destination` as `method`.
+    fn register(&self, destination: Direction, id: RequestId, method: String);
+
+    /// Forgets the request `id` forwarded to `destination`, returning its
+    /// method, or `None` if it is not pending.
+    fn resolve(&self, destination: Direction, id: &RequestId) -> Option<String>;
+
+    /// The method of the pending request `id` forwarded to `destination`,
+    /// without resolving it.
+    fn method(&self, destination: Direction, id: &RequestId) -> Option<String>;
+
+    /// Forgets every request pending at `destination`, e.g. after the server
+    /// it was forwarded to is replaced.
+    fn forget(&self, destination: Direction);
+
+    /// Number of requests pending in either direction.
+    fn pending(&self) -> usize;
+
+    /// Ids of the requests pending at `destination`, for cancelling them with
+    /// `ProxyHandle::cancel_pending_requests`. Defaults to none, so nothing is
+    /// cancelled for a correlator that does not list them.
+    fn pending_ids(&self, _destination: Direction) -> Vec<RequestId> {
+        Vec::new()
+    }
+}
+
+/// The default `Correlator`: a map keyed by direction and raw id, which
+/// assumes ids are unique per direction, as the spec requires of each peer.
+#[derive(Default)]
+pub struct HashMapCorrelator {
+    pending: Mutex<HashMap<(Direction, RequestId), String>>,
+}
+
+impl HashMapCorrelator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Correlator for HashMapCorrelator {
+    fn register(&self, destination: Direction, id: RequestId, method: String) {
+        self.pending
+            .lock()
+            .unwrap()
+            .insert((destination, id), method);
+    }
+
+    fn resolve(&self, destination: Direction, id: &RequestId) -> Option<String> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(&(destination, id.clone()))
+    }
+
+    fn method(&self, destination: Direction, id: &RequestId) -> Option<String> {
+        self.pending
+            .lock()
+            .unwrap()
+            .get(&(destination, id.clone()))
+            .cloned()
+    }
+
+    fn forget(&self, destination: Direction) {
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|(pending_at, _), _| *pending_at != destination);
+    }
+
+    fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    fn pending_ids(&self, destination: Direction) -> Vec<RequestId> {
+        self.pending
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(pending_at, _)| *pending_at == destination)
+            .map(|(_, id)| id.clone())
+            .collect()
+    }
+}
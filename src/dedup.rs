@@ -0,0 +1,85 @@
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{Message, Request, RequestId, Response};
+
+/// What to do with a request seen before within the window.
+pub(crate) enum Duplicate {
+    /// The first copy is still waiting for its response, which will answer
+    /// both.
+    Pending,
+    /// The first copy was answered with this response.
+    Answered(Response),
+}
+
+struct Seen {
+    method: String,
+    params: Option<Value>,
+    at: Instant,
+    response: Option<Response>,
+}
+
+/// Recently forwarded client requests for idempotent methods, by id, so an
+/// identical resend within `window` is not forwarded again.
+pub(crate) struct RequestDedup {
+    methods: HashSet<String>,
+    window: Duration,
+    seen: Mutex<HashMap<RequestId, Seen>>,
+}
+
+impl RequestDedup {
+    pub(crate) fn new(methods: HashSet<String>, window: Duration) -> Self {
+        Self {
+            methods,
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn covers(&self, method: &str) -> bool {
+        self.methods.contains(method)
+    }
+
+    /// Returns how to handle `request`, read at `now`, if it repeats one seen
+    /// within the window, and otherwise remembers it.
+    pub(crate) fn check(&self, request: &Request, now: Instant) -> Option<Duplicate> {
+        if !self.methods.contains(&request.method) {
+            return None;
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, entry| now.saturating_duration_since(entry.at) < self.window);
+
+        if let Some(entry) = seen.get(&request.id)
+            && entry.method == request.method
+            && entry.params == request.params
+        {
+            return Some(match &entry.response {
+                Some(response) => Duplicate::Answered(response.clone()),
+                None => Duplicate::Pending,
+            });
+        }
+
+        seen.insert(
+            request.id.clone(),
+            Seen {
+                method: request.method.clone(),
+                params: request.params.clone(),
+                at: now,
+                response: None,
+            },
+        );
+        None
+    }
+
+    /// Keeps the response sent to the client for a remembered request.
+    pub(crate) fn observe_response(&self, message: Option<&Message>) {
+        if let Some(Message::Response(response)) = message
+            && let Some(entry) = self.seen.lock().unwrap().get_mut(&response.id)
+        {
+            entry.response = Some(response.clone());
+        }
+    }
+}
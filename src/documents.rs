@@ -0,0 +1,181 @@
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::Notification;
+use crate::handle::ConnectionId;
+use crate::position::{self, PositionEncoding};
+
+/// `TextDocumentSyncKind.Full`.
+const SYNC_FULL: u64 = 1;
+
+#[derive(Clone, Copy, Default)]
+struct Negotiated {
+    full_sync: bool,
+    encoding: PositionEncoding,
+}
+
+struct Document {
+    version: Option<i64>,
+    text: String,
+}
+
+/// The text of the documents the client has open, so incremental `didChange`
+/// notifications can be rewritten as full-text ones for servers that only
+/// accept full sync.
+pub(crate) struct DocumentStore {
+    connection_id: ConnectionId,
+    negotiated: Mutex<Negotiated>,
+    documents: Mutex<HashMap<String, Document>>,
+}
+
+impl DocumentStore {
+    pub(crate) fn new(connection_id: ConnectionId) -> Self {
+        Self {
+            connection_id,
+            negotiated: Mutex::default(),
+            documents: Mutex::default(),
+        }
+    }
+
+    /// Reads `textDocumentSync` and `positionEncoding` from the server's
+    /// `initialize` result.
+    pub(crate) fn observe_capabilities(&self, result: Option<&Value>) {
+        let capabilities = result.and_then(|result| result.get("capabilities"));
+        let change =
+            match capabilities.and_then(|capabilities| capabilities.get("textDocumentSync")) {
+                Some(Value::Object(options)) => options.get("change"),
+                kind => kind,
+            };
+
+        *self.negotiated.lock().unwrap() = Negotiated {
+            full_sync: change.and_then(Value::as_u64) == Some(SYNC_FULL),
+            encoding: PositionEncoding::from_initialize_result(result),
+        };
+    }
+
+    /// Tracks the document `notification` is about. A `didChange` with ranged
+    /// changes for a full-sync server has its changes replaced by the whole
+    /// new text; returns whether that happened.
+    pub(crate) fn normalize(&self, notification: &mut Notification) -> bool {
+        let Some(params) = notification.params.as_mut() else {
+            return false;
+        };
+        let Some(uri) = params
+            .pointer("/textDocument/uri")
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+        else {
+            return false;
+        };
+        let version = params
+            .pointer("/textDocument/version")
+            .and_then(Value::as_i64);
+        let mut documents = self.documents.lock().unwrap();
+
+        match notification.method.as_str() {
+            "textDocument/didOpen" => {
+                if let Some(text) = params.pointer("/textDocument/text").and_then(Value::as_str) {
+                    let text = text.to_owned();
+                    documents.insert(uri, Document { version, text });
+                }
+                false
+            }
+            "textDocument/didClose" => {
+                documents.remove(&uri);
+                false
+            }
+            "textDocument/didChange" => {
+                let Some(document) = documents.get_mut(&uri) else {
+                    return false;
+                };
+                let changes = params
+                    .get("contentChanges")
+                    .and_then(Value::as_array)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                let Negotiated {
+                    full_sync,
+                    encoding,
+                } = *self.negotiated.lock().unwrap();
+
+                if let Some(stale) = document
+                    .version
+                    .filter(|current| version.is_some_and(|version| version <= *current))
+                {
+                    eprintln!(
+                        "[{}] Ignoring change to {} at version {:?}, already at {}",
+                        self.connection_id, uri, version, stale
+                    );
+                    return false;
+                }
+                if !changes
+                    .iter()
+                    .all(|change| apply_change(&mut document.text, change, encoding))
+                {
+                    // The copy no longer matches what the client has, so the
+                    // document is no longer rewritten.
+                    eprintln!(
+                        "[{}] Could not apply change to {}, no longer tracking it",
+                        self.connection_id, uri
+                    );
+                    documents.remove(&uri);
+                    return false;
+                }
+                document.version = version;
+
+                let ranged = changes.iter().any(|change| change.get("range").is_some());
+                if full_sync && ranged {
+                    params["contentChanges"] = json!([{ "text": document.text }]);
+                }
+                full_sync && ranged
+            }
+            _ => false,
+        }
+    }
+
+    /// The tracked text of `uri`, if the client has it open.
+    pub(crate) fn text(&self, uri: &str) -> Option<String> {
+        self.documents
+            .lock()
+            .unwrap()
+            .get(uri)
+            .map(|document| document.text.clone())
+    }
+
+    /// The URIs of the documents the client has open.
+    pub(crate) fn uris(&self) -> Vec<String> {
+        self.documents.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+/// Applies one `TextDocumentContentChangeEvent`: a ranged change replaces the
+/// range, a change without one replaces the whole text.
+fn apply_change(text: &mut String, change: &Value, encoding: PositionEncoding) -> bool {
+    let Some(new_text) = change.get("text").and_then(Value::as_str) else {
+        return false;
+    };
+    let Some(range) = change.get("range") else {
+        *text = new_text.to_owned();
+        return true;
+    };
+
+    let start = range
+        .get("start")
+        .and_then(|start| offset(text, start, encoding));
+    let end = range.get("end").and_then(|end| offset(text, end, encoding));
+    match start.zip(end) {
+        Some((start, end)) if start <= end => {
+            text.replace_range(start..end, new_text);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The byte offset of an LSP position given as JSON.
+fn offset(text: &str, position: &Value, encoding: PositionEncoding) -> Option<usize> {
+    let line = position.get("line").and_then(Value::as_u64)? as usize;
+    let character = position.get("character").and_then(Value::as_u64)? as usize;
+    Some(position::offset(text, line, character, encoding))
+}
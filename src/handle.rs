@@ -0,0 +1,500 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::{Mutex, Notify, oneshot};
+
+use crate::clock::{self, Clock};
+use crate::correlation::Correlator;
+use crate::health::Liveness;
+use crate::outbound::Outbound;
+use crate::recent::RecentMessages;
+use crate::{Message, MessageType, RequestId, Response, message::Direction};
+
+/// Longest stderr line sent as one `window/logMessage`; longer lines are
+/// split so a server writing without newlines cannot grow the buffer.
+const MAX_LOG_LINE: u64 = 64 * 1024;
+
+/// The requests the proxy sent itself, keyed by the peer they went to and
+/// their id, so a response from one peer never resolves a request sent to
+/// the other.
+pub(crate) type ResponseWaiters =
+    Arc<Mutex<HashMap<(Direction, RequestId), oneshot::Sender<Response>>>>;
+
+type ForwardedAt = Arc<std::sync::Mutex<HashMap<(Direction, RequestId), Instant>>>;
+
+/// Requests forwarded to a peer and not yet answered. The `Correlator` keeps
+/// their methods; when telemetry measures latency, when each was forwarded is
+/// kept alongside under the same key, as are the requests a hook already
+/// answered while forwarding them, whose responses are dropped.
+#[derive(Clone)]
+pub(crate) struct PendingRequests {
+    correlator: Arc<dyn Correlator>,
+    clock: Arc<dyn Clock>,
+    forwarded_at: Option<ForwardedAt>,
+    answered_locally: Arc<std::sync::Mutex<HashSet<(Direction, RequestId)>>>,
+}
+
+/// What the response to a forwarded request needs from it.
+pub(crate) struct PendingRequest {
+    /// The method the request was forwarded under. The response's hook is
+    /// looked up by it when the response arrives.
+    pub(crate) method: String,
+    /// When the request was forwarded, if telemetry measures latency.
+    pub(crate) forwarded_at: Option<Instant>,
+    /// Whether a hook answered the request when forwarding it, so the
+    /// response is not delivered a second time.
+    pub(crate) answered_locally: bool,
+}
+
+impl PendingRequests {
+    pub(crate) fn new(correlator: Arc<dyn Correlator>, clock: Arc<dyn Clock>, timed: bool) -> Self {
+        Self {
+            correlator,
+            clock,
+            forwarded_at: timed.then(Arc::default),
+            answered_locally: Arc::default(),
+        }
+    }
+
+    pub(crate) fn insert(&self, destination: Direction, id: RequestId, method: String) {
+        if let Some(forwarded_at) = &self.forwarded_at {
+            forwarded_at
+                .lock()
+                .unwrap()
+                .insert((destination, id.clone()), self.clock.now());
+        }
+        self.correlator.register(destination, id, method);
+    }
+
+    pub(crate) fn remove(&self, destination: Direction, id: &RequestId) -> Option<PendingRequest> {
+        let forwarded_at = self.forwarded_at.as_ref().and_then(|forwarded_at| {
+            forwarded_at
+                .lock()
+                .unwrap()
+                .remove(&(destination, id.clone()))
+        });
+        let answered_locally = self
+            .answered_locally
+            .lock()
+            .unwrap()
+            .remove(&(destination, id.clone()));
+        let method = self.correlator.resolve(destination, id)?;
+        Some(PendingRequest {
+            method,
+            forwarded_at,
+            answered_locally,
+        })
+    }
+
+    pub(crate) fn answer_locally(&self, destination: Direction, id: RequestId) {
+        self.answered_locally
+            .lock()
+            .unwrap()
+            .insert((destination, id));
+    }
+
+    pub(crate) fn method(&self, destination: Direction, id: &RequestId) -> Option<String> {
+        self.correlator.method(destination, id)
+    }
+
+    pub(crate) fn ids(&self, destination: Direction) -> Vec<RequestId> {
+        self.correlator.pending_ids(destination)
+    }
+
+    pub(crate) fn forget(&self, destination: Direction) {
+        if let Some(forwarded_at) = &self.forwarded_at {
+            forwarded_at
+                .lock()
+                .unwrap()
+                .retain(|(pending_at, _), _| *pending_at != destination);
+        }
+        self.answered_locally
+            .lock()
+            .unwrap()
+            .retain(|(pending_at, _)| *pending_at != destination);
+        self.correlator.forget(destination);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.correlator.pending()
+    }
+}
+
+pub(crate) fn next_injected_id(next_request_id: &AtomicI64) -> RequestId {
+    RequestId::Int(next_request_id.fetch_sub(1, Ordering::Relaxed))
+}
+
+fn cancel_notification(id: &RequestId) -> Message {
+    Message::notification("$/cancelRequest", Some(serde_json::json!({ "id": id })))
+}
+
+/// Where the session is in the LSP lifecycle, as seen from the messages the
+/// client sent to the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LifecycleState {
+    /// `initialize` has not been sent yet.
+    #[default]
+    Uninitialized,
+    /// `initialize` was sent, but not yet the `initialized` notification.
+    Initializing,
+    /// The client sent `initialized` and the session is in normal operation.
+    Initialized,
+    /// The client sent `shutdown` and may only send `exit` now.
+    ShuttingDown,
+    /// The client sent `exit`.
+    Exited,
+}
+
+/// How far the client got through the LSP lifecycle. Each transition returns
+/// the state it left, as an error if the client skipped or repeated a step.
+/// The state only moves forward: a skipped step is caught up on, so a proxy
+/// attached to a session already in progress follows along, and a repeated
+/// one is ignored.
+#[derive(Default)]
+pub(crate) struct Lifecycle {
+    state: std::sync::Mutex<LifecycleState>,
+    exit_code: OnceLock<i32>,
+}
+
+impl Lifecycle {
+    pub(crate) fn state(&self) -> LifecycleState {
+        *self.state.lock().unwrap()
+    }
+
+    fn advance(
+        &self,
+        expected: LifecycleState,
+        next: LifecycleState,
+    ) -> Result<(), LifecycleState> {
+        let mut state = self.state.lock().unwrap();
+        let previous = *state;
+        *state = previous.max(next);
+        if previous == expected {
+            Ok(())
+        } else {
+            Err(previous)
+        }
+    }
+
+    pub(crate) fn initialize(&self) -> Result<(), LifecycleState> {
+        self.advance(LifecycleState::Uninitialized, LifecycleState::Initializing)
+    }
+
+    pub(crate) fn initialized(&self) -> Result<(), LifecycleState> {
+        self.advance(LifecycleState::Initializing, LifecycleState::Initialized)
+    }
+
+    pub(crate) fn request_shutdown(&self) -> Result<(), LifecycleState> {
+        self.advance(LifecycleState::Initialized, LifecycleState::ShuttingDown)
+    }
+
+    /// Records the first `exit`: code 0 after a `shutdown` request, 1 without
+    /// one, as the spec requires of servers.
+    pub(crate) fn exit(&self) -> Result<(), LifecycleState> {
+        let result = self.advance(LifecycleState::ShuttingDown, LifecycleState::Exited);
+        let _ = self.exit_code.set(if result.is_ok() { 0 } else { 1 });
+        result
+    }
+
+    pub(crate) fn has_exited(&self) -> bool {
+        self.exit_code.get().is_some()
+    }
+
+    pub(crate) fn exit_code(&self) -> Option<i32> {
+        self.exit_code.get().copied()
+    }
+}
+
+/// Identifies one proxy session in logs. Ids are unique within the process
+/// and count up from 1 in the order proxies are built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    pub(crate) fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connection {}", self.0)
+    }
+}
+
+/// Holds both readers between messages while a handle has paused the proxy.
+#[derive(Default)]
+pub(crate) struct Pause {
+    paused: AtomicBool,
+    resumed: Notify,
+}
+
+impl Pause {
+    pub(crate) async fn wait_while_paused(&self) {
+        loop {
+            let resumed = self.resumed.notified();
+            if !self.paused.load(Ordering::Acquire) {
+                return;
+            }
+            resumed.await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    Timeout,
+    ChannelClosed,
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "Request timed out"),
+            RequestError::ChannelClosed => write!(f, "Message channel closed"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+#[derive(Clone)]
+pub struct ProxyHandle {
+    pub(crate) connection_id: ConnectionId,
+    pub(crate) outbound: Outbound,
+    pub(crate) response_waiters: ResponseWaiters,
+    pub(crate) pending_requests: PendingRequests,
+    pub(crate) next_request_id: Arc<AtomicI64>,
+    pub(crate) liveness: Arc<Liveness>,
+    pub(crate) lifecycle: Arc<Lifecycle>,
+    pub(crate) recent: Option<Arc<RecentMessages>>,
+    pub(crate) pause: Arc<Pause>,
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for ProxyHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyHandle")
+            .field("connection_id", &self.connection_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProxyHandle {
+    /// The session this handle belongs to, as it appears in the proxy's logs.
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    /// Stops processing messages from both peers until `resume`. Each reader
+    /// holds the message it has just read; anything the peers send after that
+    /// waits in the connection, so a peer that keeps writing is eventually
+    /// blocked by the transport rather than buffered without bound. Messages
+    /// injected through a handle are still written.
+    pub fn pause(&self) {
+        self.pause.paused.store(true, Ordering::Release);
+    }
+
+    /// Continues after `pause`, processing the held messages in the order
+    /// they arrived.
+    pub fn resume(&self) {
+        self.pause.paused.store(false, Ordering::Release);
+        self.pause.resumed.notify_waiters();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause.paused.load(Ordering::Acquire)
+    }
+
+    /// The exit code the client asked for once it has sent `exit`: 0 if it
+    /// requested `shutdown` first, 1 if it skipped it, which the spec treats
+    /// as a protocol violation. `None` before `exit`. A proxy that stands in
+    /// for the server process should exit with this code.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.lifecycle.exit_code()
+    }
+
+    /// Where the session is in the LSP lifecycle, driven by the `initialize`,
+    /// `initialized`, `shutdown` and `exit` messages the client sent.
+    pub fn lifecycle_state(&self) -> LifecycleState {
+        self.lifecycle.state()
+    }
+
+    /// Time since the last message was read from the server, or `None` if
+    /// nothing has been read yet. A passive liveness signal that needs no
+    /// cooperation from the server.
+    pub fn since_last_server_message(&self) -> Option<Duration> {
+        self.liveness.since_last_server_message(self.clock.now())
+    }
+
+    /// Whether the server answered the most recent `HealthCheck` probe. Always
+    /// `true` when no health check is configured or none has completed yet.
+    pub fn is_healthy(&self) -> bool {
+        self.liveness.is_healthy()
+    }
+
+    /// The last messages read that were travelling in `direction`, oldest
+    /// first, as kept by `ProxyBuilder::keep_recent`. Empty if it is not set.
+    pub fn recent(&self, direction: Direction) -> Vec<Message> {
+        self.recent
+            .as_ref()
+            .map_or_else(Vec::new, |recent| recent.snapshot(direction))
+    }
+
+    /// Number of forwarded requests, in either direction, that are still
+    /// waiting for a response. Requests sent through `send_request` are not
+    /// included.
+    pub fn pending_count(&self) -> usize {
+        self.pending_requests.len()
+    }
+
+    /// Sends the server `$/cancelRequest` for the client's request `id`, e.g.
+    /// from a hook that knows its result is no longer wanted. Returns whether
+    /// the request was still pending; nothing is sent otherwise. The server
+    /// still answers it, typically with a `RequestCancelled` error, and the
+    /// answer is forwarded to the client as usual.
+    pub fn cancel_request(&self, id: &RequestId) -> Result<bool, RequestError> {
+        if self
+            .pending_requests
+            .method(Direction::ToServer, id)
+            .is_none()
+        {
+            return Ok(false);
+        }
+        self.inject(Direction::ToServer, cancel_notification(id))?;
+        Ok(true)
+    }
+
+    /// Sends the server `$/cancelRequest` for every request from the client
+    /// it has not answered yet, returning how many. See
+    /// `ProxyBuilder::cancel_pending_on_eof` to do so when the client
+    /// disconnects.
+    pub fn cancel_pending_requests(&self) -> Result<usize, RequestError> {
+        let ids = self.pending_requests.ids(Direction::ToServer);
+        for id in &ids {
+            self.inject(Direction::ToServer, cancel_notification(id))?;
+        }
+        Ok(ids.len())
+    }
+
+    /// Queues `message` for the peer in `direction`, as if a hook had
+    /// generated it, e.g. a `window/showMessage` for the client prompted by an
+    /// external event. It bypasses hooks and is not tracked, so use
+    /// `send_request` for requests whose response the caller needs. Can be
+    /// called from any task; fails with `ChannelClosed` once that peer's
+    /// writer has stopped.
+    pub fn inject(&self, direction: Direction, message: Message) -> Result<(), RequestError> {
+        self.outbound
+            .send(direction, message)
+            .map_err(|_| RequestError::ChannelClosed)
+    }
+
+    /// Sends a request originated by the proxy itself and waits for the peer's
+    /// response, which is consumed by the proxy instead of being forwarded.
+    /// Injected requests count down from -1, skipping any id that is still
+    /// pending towards the same peer. The ids are not reserved, though: if the
+    /// other peer sends a request with the same id while this one is in
+    /// flight, the first response with that id is taken as this one's.
+    pub async fn send_request(
+        &self,
+        direction: Direction,
+        method: &str,
+        params: Option<Value>,
+        timeout: Duration,
+    ) -> Result<Response, RequestError> {
+        let mut id = next_injected_id(&self.next_request_id);
+        while self.pending_requests.method(direction, &id).is_some() {
+            id = next_injected_id(&self.next_request_id);
+        }
+        let key = (direction, id.clone());
+        let (sender, receiver) = oneshot::channel();
+        self.response_waiters
+            .lock()
+            .await
+            .insert(key.clone(), sender);
+
+        if self
+            .outbound
+            .send(direction, Message::request(id.clone(), method, params))
+            .is_err()
+        {
+            self.response_waiters.lock().await.remove(&key);
+            return Err(RequestError::ChannelClosed);
+        }
+
+        match clock::timeout(&*self.clock, timeout, receiver).await {
+            Some(Ok(response)) => Ok(response),
+            Some(Err(_)) => Err(RequestError::ChannelClosed),
+            None => {
+                self.response_waiters.lock().await.remove(&key);
+                Err(RequestError::Timeout)
+            }
+        }
+    }
+
+    /// Sends each line read from `stderr` to the client as a
+    /// `window/logMessage` of `message_type`, so crash traces from a server
+    /// the caller spawned show up in the editor, e.g. with the `stderr` of a
+    /// `tokio::process::Child`. Invalid UTF-8 is replaced, a final line
+    /// without a newline is still sent, and blank lines are skipped. Returns
+    /// at EOF or once the client's writer has stopped.
+    pub async fn forward_stderr<R>(&self, stderr: R, message_type: MessageType) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut stderr = BufReader::new(stderr);
+        let mut line = Vec::new();
+        // Bytes of a character cut off when a long line was split.
+        let mut carry = Vec::new();
+
+        loop {
+            line.clear();
+            line.append(&mut carry);
+            let read = (&mut stderr)
+                .take(MAX_LOG_LINE)
+                .read_until(b'\n', &mut line)
+                .await?;
+            if read == 0 && line.is_empty() {
+                return Ok(());
+            }
+
+            if line.last() == Some(&b'\n') {
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+            } else if read > 0
+                && let Err(e) = std::str::from_utf8(&line)
+                && e.error_len().is_none()
+            {
+                carry = line.split_off(e.valid_up_to());
+            }
+
+            if line.is_empty() {
+                continue;
+            }
+            let text = String::from_utf8_lossy(&line);
+            if self
+                .outbound
+                .send(
+                    Direction::ToClient,
+                    Message::log_message(message_type, &text),
+                )
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+    }
+}
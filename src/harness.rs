@@ -0,0 +1,166 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::message::{Direction, LogFormat};
+use crate::transport::{self, ReadOptions, TransportError, read_frame, write_message};
+use crate::{Message, Proxy, Response};
+
+/// Runs a proxy against a scripted client and server over in-memory
+/// connections and records everything it emits, for snapshot tests of hooks.
+///
+/// Each scripted message is sent once the proxy has gone quiet after the
+/// previous one, i.e. nothing was emitted for `settle`, so the transcript does
+/// not depend on scheduling. Requests reaching the server can be answered
+/// automatically with `server_replies`, which keeps the id the proxy chose, so
+/// requests injected by hooks are correlated too.
+pub struct TestHarness {
+    proxy: Proxy,
+    script: Vec<(Direction, Message)>,
+    replies: HashMap<String, Value>,
+    settle: Duration,
+}
+
+/// The messages the proxy wrote to each side, in the order they were written.
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    pub to_client: Vec<Message>,
+    pub to_server: Vec<Message>,
+}
+
+impl Transcript {
+    /// One line per message with sorted keys, the client side first, for
+    /// comparing against a golden file.
+    pub fn snapshot(&self) -> String {
+        let mut snapshot = String::new();
+        for (side, messages) in [("client", &self.to_client), ("server", &self.to_server)] {
+            for message in messages {
+                snapshot.push_str(side);
+                snapshot.push_str(" <- ");
+                snapshot.push_str(&message.to_log_string(LogFormat::Compact));
+                snapshot.push('\n');
+            }
+        }
+        snapshot
+    }
+}
+
+impl TestHarness {
+    pub fn new(proxy: Proxy) -> Self {
+        Self {
+            proxy,
+            script: Vec::new(),
+            replies: HashMap::new(),
+            settle: Duration::from_millis(50),
+        }
+    }
+
+    /// Queues a message from the client.
+    pub fn client_sends(mut self, message: Message) -> Self {
+        self.script.push((Direction::ToServer, message));
+        self
+    }
+
+    /// Queues a message from the server.
+    pub fn server_sends(mut self, message: Message) -> Self {
+        self.script.push((Direction::ToClient, message));
+        self
+    }
+
+    /// Makes the server answer every `method` request it receives with
+    /// `result`. Other requests are left unanswered.
+    pub fn server_replies(mut self, method: &str, result: Value) -> Self {
+        self.replies.insert(method.to_owned(), result);
+        self
+    }
+
+    /// How long the proxy must stay quiet after a scripted message before the
+    /// next one is sent, or the run ends (default 50ms).
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// Plays the script and stops the proxy. Fails if the proxy writes
+    /// something that is not a valid message or stops with an error.
+    pub async fn run(self) -> io::Result<Transcript> {
+        let io = transport::duplex();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let proxy = tokio::spawn(self.proxy.forward_with_shutdown(
+            io.proxy_server.reader,
+            io.proxy_server.writer,
+            io.proxy_client.reader,
+            io.proxy_client.writer,
+            async {
+                let _ = stopped.await;
+            },
+        ));
+
+        let (sender, mut received) = mpsc::unbounded_channel();
+        tokio::spawn(read_side(
+            Direction::ToClient,
+            io.client.reader,
+            sender.clone(),
+        ));
+        tokio::spawn(read_side(Direction::ToServer, io.server.reader, sender));
+        let mut client = io.client.writer;
+        let mut server = io.server.writer;
+
+        let mut transcript = Transcript::default();
+        for (direction, message) in &self.script {
+            match direction {
+                Direction::ToServer => write_message(&mut client, &message.to_value()).await?,
+                Direction::ToClient => write_message(&mut server, &message.to_value()).await?,
+            }
+
+            while let Ok(Some(output)) = tokio::time::timeout(self.settle, received.recv()).await {
+                let (side, message) = output?;
+                if side == Direction::ToServer
+                    && let Message::Request(request) = &message
+                    && let Some(result) = self.replies.get(&request.method)
+                {
+                    let response = Message::Response(Response {
+                        id: request.id.clone(),
+                        result: Some(result.clone()),
+                        error: None,
+                    });
+                    write_message(&mut server, &response.to_value()).await?;
+                }
+                match side {
+                    Direction::ToClient => transcript.to_client.push(message),
+                    Direction::ToServer => transcript.to_server.push(message),
+                }
+            }
+        }
+
+        let _ = stop.send(());
+        proxy.await.map_err(io::Error::other)??;
+        Ok(transcript)
+    }
+}
+
+async fn read_side<R>(
+    side: Direction,
+    reader: R,
+    sender: mpsc::UnboundedSender<io::Result<(Direction, Message)>>,
+) where
+    R: AsyncReadExt + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let message = match read_frame(&mut reader, &ReadOptions::default()).await {
+            Ok(frame) => Message::from_value(frame.content)
+                .map(|message| (side, message))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(TransportError::Eof) => return,
+            Err(e) => Err(e.into()),
+        };
+        if sender.send(message).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthEvent {
+    /// The server stopped answering the health check.
+    Unhealthy,
+    /// The server answered again after being reported unhealthy.
+    Healthy,
+}
+
+type Observer = Arc<dyn Fn(&HealthEvent) + Send + Sync>;
+
+/// Actively probes the server by sending `method` as a request every
+/// `interval`. Any response, including an error response, counts as alive;
+/// no response within `timeout` marks the server unhealthy. Servers rarely
+/// implement a custom ping, so pick a method the server at least answers with
+/// `MethodNotFound`.
+#[derive(Clone)]
+pub struct HealthCheck {
+    method: String,
+    interval: Duration,
+    timeout: Duration,
+    observer: Option<Observer>,
+}
+
+impl HealthCheck {
+    pub fn new(method: &str) -> Self {
+        Self {
+            method: method.to_owned(),
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+            observer: None,
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Called whenever the health state changes.
+    pub fn on_event<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(&HealthEvent) + Send + Sync + 'static,
+    {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    pub(crate) fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub(crate) fn period(&self) -> Duration {
+        self.interval
+    }
+
+    pub(crate) fn deadline(&self) -> Duration {
+        self.timeout
+    }
+
+    pub(crate) fn notify(&self, event: HealthEvent) {
+        if let Some(observer) = &self.observer {
+            observer(&event);
+        }
+    }
+}
+
+/// Liveness signals shared between the proxy and its handles.
+pub(crate) struct Liveness {
+    last_server_message: Mutex<Option<Instant>>,
+    healthy: AtomicBool,
+}
+
+impl Liveness {
+    pub(crate) fn touch_server(&self, now: Instant) {
+        *self.last_server_message.lock().unwrap() = Some(now);
+    }
+
+    pub(crate) fn since_last_server_message(&self, now: Instant) -> Option<Duration> {
+        self.last_server_message
+            .lock()
+            .unwrap()
+            .map(|last| now.saturating_duration_since(last))
+    }
+
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Records the outcome of a health check and returns whether it changed
+    /// the health state.
+    pub(crate) fn set_healthy(&self, healthy: bool) -> bool {
+        self.healthy.swap(healthy, Ordering::Relaxed) != healthy
+    }
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self {
+            last_server_message: Mutex::new(None),
+            healthy: AtomicBool::new(true),
+        }
+    }
+}
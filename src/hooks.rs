@@ -1,30 +1,112 @@
 use async_trait::async_trait;
+use futures_core::Stream;
+use std::any::Any;
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 
 use crate::{
-    Message, Notification, Request, Response, message::Direction,
-    processed_message::ProcessedMessage,
+    HookContext, Message, Notification, Request, Response,
+    message::Direction,
+    processed_message::{GeneratedOrder, ProcessedMessage},
 };
 
 #[derive(Debug)]
 pub enum HookError {
     ProcessingFailed(String),
+    /// The hook panicked; holds the panic message.
+    Panicked(String),
 }
 
 impl Display for HookError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             HookError::ProcessingFailed(msg) => write!(f, "Hook processing failed: {}", msg),
+            HookError::Panicked(msg) => write!(f, "Hook panicked: {}", msg),
         }
     }
 }
 
 impl std::error::Error for HookError {}
 
-#[derive(Debug)]
+/// What happens to a message when a hook returns an error for it. Panicking
+/// hooks are not covered: their message is always forwarded unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HookErrorPolicy {
+    /// Forward the original message as if no hook had run.
+    FailOpen,
+    /// Drop the message.
+    #[default]
+    FailClosed,
+    /// Answer a request with an `InternalError` response carrying the hook's
+    /// error, and replace a response with one, so the requester is not left
+    /// waiting. Notifications cannot be answered and are dropped.
+    Error,
+}
+
+impl From<serde_json::Error> for HookError {
+    fn from(e: serde_json::Error) -> Self {
+        HookError::ProcessingFailed(e.to_string())
+    }
+}
+
+/// Polls a hook future, turning a panic inside it into `HookError::Panicked`
+/// so a buggy hook cannot take the forwarding task down with it.
+pub(crate) struct CatchPanic<F>(pub(crate) F);
+
+impl<F, T> Future for CatchPanic<F>
+where
+    F: Future<Output = T> + Unpin,
+{
+    type Output = Result<T, HookError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| Pin::new(&mut self.0).poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(payload) => Poll::Ready(Err(HookError::Panicked(panic_message(payload)))),
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>().map_or_else(
+            || "unknown panic".to_owned(),
+            |message| (*message).to_owned(),
+        ),
+    }
+}
+
+/// Messages a hook produces over time rather than all at once.
+pub type MessageFeed = Pin<Box<dyn Stream<Item = (Direction, Message)> + Send>>;
+
 pub struct HookOutput {
     pub message: Option<Message>,
     pub generated_messages: Vec<(Direction, Message)>,
+    pub order: GeneratedOrder,
+    /// Forwarded item by item as it yields, after `message` and
+    /// `generated_messages` have been queued. The proxy stops polling it when
+    /// the session shuts down.
+    pub feed: Option<MessageFeed>,
+    /// Set by `with_ordered_feed`: the key `feed` is sequenced under.
+    pub feed_key: Option<String>,
+}
+
+impl std::fmt::Debug for HookOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookOutput")
+            .field("message", &self.message)
+            .field("generated_messages", &self.generated_messages)
+            .field("order", &self.order)
+            .field("feed", &self.feed.as_ref().map(|_| ".."))
+            .field("feed_key", &self.feed_key)
+            .finish()
+    }
 }
 
 impl HookOutput {
@@ -32,6 +114,9 @@ impl HookOutput {
         Self {
             message: Some(message),
             generated_messages: Vec::new(),
+            order: GeneratedOrder::default(),
+            feed: None,
+            feed_key: None,
         }
     }
 
@@ -39,9 +124,25 @@ impl HookOutput {
         Self {
             message: None,
             generated_messages: Vec::new(),
+            order: GeneratedOrder::default(),
+            feed: None,
+            feed_key: None,
         }
     }
 
+    pub fn replace_with(messages: Vec<Message>, direction: Direction) -> Self {
+        Self::empty().with_messages(
+            messages
+                .into_iter()
+                .map(|message| (direction, message))
+                .collect(),
+        )
+    }
+
+    /// Adds a message to send to the peer in `direction`. A response to the
+    /// request being handled, sent back to its sender while the request is
+    /// still forwarded, answers it for good: the peer's own response to it is
+    /// dropped, so the sender never sees two.
     pub fn with_message(mut self, direction: Direction, message: Message) -> Self {
         self.generated_messages.push((direction, message));
         self
@@ -52,6 +153,36 @@ impl HookOutput {
         self
     }
 
+    pub fn with_order(mut self, order: GeneratedOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Forwards each message `feed` yields as it arrives, e.g. progress
+    /// notifications for a long-running request.
+    pub fn with_feed<S>(mut self, feed: S) -> Self
+    where
+        S: Stream<Item = (Direction, Message)> + Send + 'static,
+    {
+        self.feed = Some(Box::pin(feed));
+        self
+    }
+
+    /// Like `with_feed`, but sequenced under `key`, typically the URI of the
+    /// document the message is about: what the feed yields is written after
+    /// everything yielded by feeds returned earlier under the same key, even
+    /// if it is ready sooner. Requests derived from successive `didChange`s
+    /// thereby reach the server in the order of the changes. A feed that never
+    /// ends holds back the ones behind it until the session stops.
+    pub fn with_ordered_feed<S>(mut self, key: impl Into<String>, feed: S) -> Self
+    where
+        S: Stream<Item = (Direction, Message)> + Send + 'static,
+    {
+        self.feed_key = Some(key.into());
+        self.with_feed(feed)
+    }
+
+    /// Drops `feed`, which has no `ProcessedMessage` equivalent.
     pub fn as_processed(self) -> ProcessedMessage {
         match self.message {
             Some(message) => {
@@ -62,6 +193,7 @@ impl HookOutput {
                 ProcessedMessage::WithMessages {
                     message,
                     generated_messages: self.generated_messages,
+                    order: self.order,
                 }
             }
             None => ProcessedMessage::Ignore {
@@ -73,17 +205,345 @@ impl HookOutput {
 
 pub type HookResult = Result<HookOutput, HookError>;
 
+/// Handles the messages of the methods it is registered for. `S` is the
+/// application state of the proxy, reachable through `HookContext::state`;
+/// hooks that need none implement `Hook`, i.e. `Hook<()>`.
 #[async_trait]
-pub trait Hook: Send + Sync {
-    async fn on_request(&self, request: Request) -> HookResult {
+pub trait Hook<S = ()>: Send + Sync
+where
+    S: Send + Sync + 'static,
+{
+    /// Called once before the proxy starts forwarding, e.g. to open
+    /// connections the hook needs. A hook registered for several methods is
+    /// still started only once.
+    async fn on_start(&self) {}
+
+    /// A name for the hook in `Proxy::describe_dispatch`. Defaults to the
+    /// type name.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Called once after the proxy stops forwarding, for whatever reason, to
+    /// release what `on_start` acquired.
+    async fn on_shutdown(&self) {}
+
+    async fn on_request(&self, request: Request, _context: &HookContext<S>) -> HookResult {
         Ok(HookOutput::new(Message::Request(request)))
     }
 
-    async fn on_response(&self, response: Response) -> HookResult {
+    async fn on_response(&self, response: Response, _context: &HookContext<S>) -> HookResult {
         Ok(HookOutput::new(Message::Response(response)))
     }
 
-    async fn on_notification(&self, notification: Notification) -> HookResult {
+    async fn on_notification(
+        &self,
+        notification: Notification,
+        _context: &HookContext<S>,
+    ) -> HookResult {
         Ok(HookOutput::new(Message::Notification(notification)))
     }
 }
+
+type RequestMap = Box<dyn Fn(Request) -> Request + Send + Sync>;
+type ResponseMap = Box<dyn Fn(Response) -> Response + Send + Sync>;
+
+/// One step a message for a method goes through before it is forwarded, as
+/// listed by `Proxy::describe_dispatch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookDescriptor {
+    /// `Hook::name` for hooks, the builder method that configured it for
+    /// built-in checks.
+    pub name: String,
+    /// The direction the step applies to, or `None` for both.
+    pub direction: Option<Direction>,
+    pub kind: HookKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookKind {
+    /// A check configured on the builder that may answer or drop the message
+    /// before any hook sees it.
+    Builtin,
+    /// A hook registered for the method.
+    Method,
+    /// The hook from `with_default_hook`.
+    Default,
+}
+
+/// Hooks by method, optionally scoped to one direction. The direction of a
+/// request or notification is the one it travels in; a response counts as
+/// travelling in the direction of the request it answers. A scoped hook takes
+/// precedence over one registered for both directions, and `default` only
+/// runs for methods with no hook at all. The registry is fixed once the proxy
+/// is built; a response hook is looked up when the response arrives, but can
+/// only be one that was registered before forwarding started.
+pub(crate) struct HookRegistry<S> {
+    by_method: HashMap<String, MethodHooks<S>>,
+    default: Option<Registered<S>>,
+}
+
+impl<S> Default for HookRegistry<S> {
+    fn default() -> Self {
+        Self {
+            by_method: HashMap::new(),
+            default: None,
+        }
+    }
+}
+
+/// A registered hook and the names of the hooks it runs, in order: more than
+/// one once `map_request` or `map_response` wrapped an earlier hook.
+struct Registered<S> {
+    hook: Arc<dyn Hook<S>>,
+    names: Vec<String>,
+}
+
+impl<S: Send + Sync + 'static> Registered<S> {
+    fn new(hook: Arc<dyn Hook<S>>) -> Self {
+        let names = vec![hook.name().to_owned()];
+        Self { hook, names }
+    }
+
+    fn describe(
+        &self,
+        direction: Option<Direction>,
+        kind: HookKind,
+    ) -> impl Iterator<Item = HookDescriptor> {
+        self.names.iter().map(move |name| HookDescriptor {
+            name: name.clone(),
+            direction,
+            kind,
+        })
+    }
+}
+
+struct MethodHooks<S> {
+    both: Option<Registered<S>>,
+    to_server: Option<Registered<S>>,
+    to_client: Option<Registered<S>>,
+}
+
+impl<S> Default for MethodHooks<S> {
+    fn default() -> Self {
+        Self {
+            both: None,
+            to_server: None,
+            to_client: None,
+        }
+    }
+}
+
+impl<S: Send + Sync + 'static> MethodHooks<S> {
+    fn slot(&mut self, direction: Option<Direction>) -> &mut Option<Registered<S>> {
+        match direction {
+            None => &mut self.both,
+            Some(Direction::ToServer) => &mut self.to_server,
+            Some(Direction::ToClient) => &mut self.to_client,
+        }
+    }
+
+    fn resolve(&self, direction: Direction) -> Option<&Registered<S>> {
+        let scoped = match direction {
+            Direction::ToServer => &self.to_server,
+            Direction::ToClient => &self.to_client,
+        };
+        scoped.as_ref().or(self.both.as_ref())
+    }
+
+    fn hooks(&self) -> impl Iterator<Item = &Arc<dyn Hook<S>>> {
+        [&self.both, &self.to_server, &self.to_client]
+            .into_iter()
+            .flatten()
+            .map(|registered| &registered.hook)
+    }
+}
+
+impl<S: Send + Sync + 'static> HookRegistry<S> {
+    pub(crate) fn insert(
+        &mut self,
+        method: &str,
+        direction: Option<Direction>,
+        hook: Arc<dyn Hook<S>>,
+    ) {
+        *self
+            .by_method
+            .entry(method.to_owned())
+            .or_default()
+            .slot(direction) = Some(Registered::new(hook));
+    }
+
+    /// Replaces the hook for `method` and `direction` with `wrap` applied to
+    /// it, keeping the names of the hooks it wraps.
+    pub(crate) fn wrap<F>(&mut self, method: &str, direction: Option<Direction>, wrap: F)
+    where
+        F: FnOnce(Option<Arc<dyn Hook<S>>>) -> Arc<dyn Hook<S>>,
+    {
+        let slot = self
+            .by_method
+            .entry(method.to_owned())
+            .or_default()
+            .slot(direction);
+        let (inner, mut names) = match slot.take() {
+            Some(Registered { hook, names }) => (Some(hook), names),
+            None => (None, Vec::new()),
+        };
+        let hook = wrap(inner);
+        names.push(hook.name().to_owned());
+        *slot = Some(Registered { hook, names });
+    }
+
+    pub(crate) fn get(&self, method: &str, direction: Direction) -> Option<&Arc<dyn Hook<S>>> {
+        match self.by_method.get(method) {
+            Some(hooks) => hooks.resolve(direction),
+            None => self.default.as_ref(),
+        }
+        .map(|registered| &registered.hook)
+    }
+
+    pub(crate) fn set_default(&mut self, hook: Arc<dyn Hook<S>>) {
+        self.default = Some(Registered::new(hook));
+    }
+
+    pub(crate) fn contains(&self, method: &str) -> bool {
+        self.by_method.contains_key(method)
+    }
+
+    pub(crate) fn methods(&self) -> impl Iterator<Item = &String> {
+        self.by_method.keys()
+    }
+
+    pub(crate) fn hooks(&self) -> impl Iterator<Item = &Arc<dyn Hook<S>>> {
+        self.by_method
+            .values()
+            .flat_map(MethodHooks::hooks)
+            .chain(self.default.as_ref().map(|registered| &registered.hook))
+    }
+
+    /// The hooks that run for `method`, with the direction each applies to.
+    pub(crate) fn describe(&self, method: &str) -> Vec<HookDescriptor> {
+        let Some(hooks) = self.by_method.get(method) else {
+            return self
+                .default
+                .iter()
+                .flat_map(|registered| registered.describe(None, HookKind::Default))
+                .collect();
+        };
+
+        if hooks.to_server.is_none() && hooks.to_client.is_none() {
+            return hooks
+                .both
+                .iter()
+                .flat_map(|registered| registered.describe(None, HookKind::Method))
+                .collect();
+        }
+        [Direction::ToServer, Direction::ToClient]
+            .into_iter()
+            .flat_map(|direction| {
+                hooks
+                    .resolve(direction)
+                    .into_iter()
+                    .flat_map(move |registered| {
+                        registered.describe(Some(direction), HookKind::Method)
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Backs `ProxyBuilder::map_request` and `map_response`: runs the hook that was
+/// registered for the method before it, then applies the closure to whatever
+/// message that hook let through.
+pub(crate) struct MapHook<S> {
+    inner: Option<Arc<dyn Hook<S>>>,
+    map_request: Option<RequestMap>,
+    map_response: Option<ResponseMap>,
+}
+
+impl<S> MapHook<S> {
+    pub(crate) fn requests<F>(inner: Option<Arc<dyn Hook<S>>>, map: F) -> Self
+    where
+        F: Fn(Request) -> Request + Send + Sync + 'static,
+    {
+        Self {
+            inner,
+            map_request: Some(Box::new(map)),
+            map_response: None,
+        }
+    }
+
+    pub(crate) fn responses<F>(inner: Option<Arc<dyn Hook<S>>>, map: F) -> Self
+    where
+        F: Fn(Response) -> Response + Send + Sync + 'static,
+    {
+        Self {
+            inner,
+            map_request: None,
+            map_response: Some(Box::new(map)),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync + 'static> Hook<S> for MapHook<S> {
+    fn name(&self) -> &str {
+        if self.map_request.is_some() {
+            "map_request"
+        } else {
+            "map_response"
+        }
+    }
+
+    async fn on_start(&self) {
+        if let Some(inner) = &self.inner {
+            inner.on_start().await;
+        }
+    }
+
+    async fn on_shutdown(&self) {
+        if let Some(inner) = &self.inner {
+            inner.on_shutdown().await;
+        }
+    }
+
+    async fn on_request(&self, request: Request, context: &HookContext<S>) -> HookResult {
+        let mut output = match &self.inner {
+            Some(inner) => inner.on_request(request, context).await?,
+            None => HookOutput::new(Message::Request(request)),
+        };
+
+        if let Some(map) = &self.map_request {
+            output.message = output.message.map(|message| match message {
+                Message::Request(request) => Message::Request(map(request)),
+                other => other,
+            });
+        }
+        Ok(output)
+    }
+
+    async fn on_response(&self, response: Response, context: &HookContext<S>) -> HookResult {
+        let mut output = match &self.inner {
+            Some(inner) => inner.on_response(response, context).await?,
+            None => HookOutput::new(Message::Response(response)),
+        };
+
+        if let Some(map) = &self.map_response {
+            output.message = output.message.map(|message| match message {
+                Message::Response(response) => Message::Response(map(response)),
+                other => other,
+            });
+        }
+        Ok(output)
+    }
+
+    async fn on_notification(
+        &self,
+        notification: Notification,
+        context: &HookContext<S>,
+    ) -> HookResult {
+        match &self.inner {
+            Some(inner) => inner.on_notification(notification, context).await,
+            None => Ok(HookOutput::new(Message::Notification(notification))),
+        }
+    }
+}
@@ -1,9 +1,72 @@
+pub mod builtins;
+pub mod clock;
+pub mod coalesce;
+pub mod conformance;
+pub mod context;
+pub mod correlation;
+mod dedup;
+mod documents;
+pub mod handle;
+#[cfg(feature = "test-util")]
+pub mod harness;
+pub mod health;
 pub mod hooks;
 pub mod message;
+pub mod methods;
+pub mod mirror;
+pub mod multiplex;
+mod outbound;
+pub mod pairs;
+pub mod params;
+pub mod params_limit;
+pub mod position;
 pub mod processed_message;
 pub mod proxy;
+pub mod rate_limit;
+mod recent;
+pub mod reconnect;
+pub mod redact;
+pub mod stream;
+pub mod telemetry;
+pub mod testing;
 pub mod transport;
+#[cfg(feature = "lsp-types")]
+pub mod typed;
+pub mod util;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub mod writes;
 
-pub use hooks::{Hook, HookError, HookOutput, HookResult};
-pub use message::{Message, Notification, Request, Response};
-pub use proxy::{Proxy, ProxyBuilder};
+pub use clock::{Clock, TestClock, TokioClock};
+pub use conformance::{ProtocolCheck, ProtocolViolation};
+pub use context::HookContext;
+pub use correlation::{Correlator, HashMapCorrelator};
+pub use handle::{ConnectionId, LifecycleState, ProxyHandle, RequestError};
+#[cfg(feature = "test-util")]
+pub use harness::{TestHarness, Transcript};
+pub use health::{HealthCheck, HealthEvent};
+pub use hooks::{
+    Hook, HookDescriptor, HookError, HookErrorPolicy, HookKind, HookOutput, HookResult, MessageFeed,
+};
+pub use message::{
+    Direction, ErrorCode, JsonRpcField, LogFormat, Message, MessageParseError, MessageType,
+    Notification, Request, RequestId, Response, ResponseError, TraceValue,
+};
+pub use mirror::Mirror;
+pub use multiplex::Multiplexer;
+pub use pairs::RequestResponsePair;
+pub use params::Params;
+pub use params_limit::ParamsLimit;
+pub use position::PositionEncoding;
+pub use processed_message::GeneratedOrder;
+#[cfg(feature = "tower")]
+pub use proxy::ProxyService;
+pub use proxy::{BuildError, Proxy, ProxyBuilder};
+pub use rate_limit::RateLimit;
+pub use reconnect::{ReconnectEvent, ReconnectPolicy};
+pub use redact::Redactor;
+pub use stream::{MessageSink, MessageStream};
+pub use telemetry::{ProxyStats, Telemetry};
+#[cfg(feature = "lsp-types")]
+pub use typed::TypedRequest;
+pub use writes::WrittenMessage;
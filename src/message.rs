@@ -1,45 +1,444 @@
-use serde::Serialize;
+use serde::de::{Deserializer, IgnoredAny};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::fmt::Display;
 
-#[derive(Debug, Clone)]
+use crate::util::{ArrayMerge, fnv1a, merge};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Direction {
     ToClient,
     ToServer,
 }
 
-#[derive(Debug, Clone, Serialize)]
+impl Direction {
+    pub fn opposite(self) -> Self {
+        match self {
+            Direction::ToClient => Direction::ToServer,
+            Direction::ToServer => Direction::ToClient,
+        }
+    }
+}
+
+pub const METHOD_NOT_FOUND: i64 = ErrorCode::MethodNotFound.code();
+pub const INVALID_PARAMS: i64 = ErrorCode::InvalidParams.code();
+pub const INTERNAL_ERROR: i64 = ErrorCode::InternalError.code();
+pub const REQUEST_FAILED: i64 = ErrorCode::RequestFailed.code();
+
+/// The `code` of a response error: the JSON-RPC codes, the ones LSP adds, or
+/// any other number as `Custom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    ParseError,
+    InvalidRequest,
+    MethodNotFound,
+    InvalidParams,
+    InternalError,
+    /// A request other than `initialize` arrived before it.
+    ServerNotInitialized,
+    UnknownErrorCode,
+    /// The request was syntactically correct but could not be served.
+    RequestFailed,
+    /// The server cancelled the request itself.
+    ServerCancelled,
+    /// The document changed while the request was being served, so the result
+    /// would be stale.
+    ContentModified,
+    /// The client cancelled the request with `$/cancelRequest`.
+    RequestCancelled,
+    Custom(i64),
+}
+
+impl ErrorCode {
+    pub const fn code(self) -> i64 {
+        match self {
+            ErrorCode::ParseError => -32700,
+            ErrorCode::InvalidRequest => -32600,
+            ErrorCode::MethodNotFound => -32601,
+            ErrorCode::InvalidParams => -32602,
+            ErrorCode::InternalError => -32603,
+            ErrorCode::ServerNotInitialized => -32002,
+            ErrorCode::UnknownErrorCode => -32001,
+            ErrorCode::RequestFailed => -32803,
+            ErrorCode::ServerCancelled => -32802,
+            ErrorCode::ContentModified => -32801,
+            ErrorCode::RequestCancelled => -32800,
+            ErrorCode::Custom(code) => code,
+        }
+    }
+}
+
+impl From<i64> for ErrorCode {
+    fn from(code: i64) -> Self {
+        match code {
+            -32700 => ErrorCode::ParseError,
+            -32600 => ErrorCode::InvalidRequest,
+            -32601 => ErrorCode::MethodNotFound,
+            -32602 => ErrorCode::InvalidParams,
+            -32603 => ErrorCode::InternalError,
+            -32002 => ErrorCode::ServerNotInitialized,
+            -32001 => ErrorCode::UnknownErrorCode,
+            -32803 => ErrorCode::RequestFailed,
+            -32802 => ErrorCode::ServerCancelled,
+            -32801 => ErrorCode::ContentModified,
+            -32800 => ErrorCode::RequestCancelled,
+            code => ErrorCode::Custom(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for i64 {
+    fn from(code: ErrorCode) -> Self {
+        code.code()
+    }
+}
+
+/// The `error` of a response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+impl ResponseError {
+    pub fn new(code: impl Into<ErrorCode>, message: &str, data: Option<Value>) -> Self {
+        Self {
+            code: code.into(),
+            message: message.to_owned(),
+            data,
+        }
+    }
+
+    /// Reads an `error` object, or returns `None` if it lacks a numeric `code`
+    /// or a string `message`.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        Some(Self {
+            code: value.get("code")?.as_i64()?.into(),
+            message: value.get("message")?.as_str()?.to_owned(),
+            data: value.get("data").cloned(),
+        })
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut error = serde_json::json!({
+            "code": self.code.code(),
+            "message": self.message,
+        });
+        if let Some(data) = &self.data {
+            error["data"] = data.clone();
+        }
+        error
+    }
+}
+
+/// Why a JSON value is not a valid request, response or notification.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageParseError {
+    NotAnObject,
+    /// The `id` is not a number, a string or null.
+    InvalidId,
+    InvalidMethod,
+    /// The `method` is empty or contains whitespace or control characters,
+    /// which no LSP method does and which could forge lines in logs.
+    MalformedMethod(String),
+    MethodWithResult,
+    ResponseWithoutId,
+    IdWithoutMethod,
+    Empty,
+}
+
+impl Display for MessageParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageParseError::NotAnObject => write!(f, "Message must be an object"),
+            MessageParseError::InvalidId => {
+                write!(f, "Message `id` is not a number, string or null")
+            }
+            MessageParseError::InvalidMethod => write!(f, "Message `method` is not a string"),
+            MessageParseError::MalformedMethod(method) => {
+                write!(f, "Malformed message `method`: {:?}", method)
+            }
+            MessageParseError::MethodWithResult => {
+                write!(f, "Message has both `method` and `result` or `error`")
+            }
+            MessageParseError::ResponseWithoutId => {
+                write!(f, "Response has `result` or `error` but no `id`")
+            }
+            MessageParseError::IdWithoutMethod => {
+                write!(
+                    f,
+                    "Message has an `id` but no `method`, `result` or `error`"
+                )
+            }
+            MessageParseError::Empty => {
+                write!(f, "Message has neither `method` nor `result` or `error`")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MessageParseError {}
+
+/// Whether the proxy writes `"jsonrpc": "2.0"` into the messages it sends.
+/// Except under `Never`, frames forwarded unchanged are written as the bytes
+/// they were read as, with or without the field.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JsonRpcField {
+    /// Every message the proxy serializes carries it, as `to_value` writes
+    /// it.
+    #[default]
+    Always,
+    /// A message a hook changed carries it only if the message it was read
+    /// as did. Messages forwarded unchanged keep their bytes anyway, and
+    /// messages the proxy or hooks create carry it.
+    PreserveOriginal,
+    /// No message carries it, for downstreams that are not JSON-RPC.
+    Never,
+}
+
+impl JsonRpcField {
+    /// Removes `jsonrpc` from `value`, a message from `to_value`.
+    pub(crate) fn strip(mut value: Value) -> Value {
+        if let Some(object) = value.as_object_mut() {
+            object.remove("jsonrpc");
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    Pretty,
+}
+
+/// The trace level negotiated between client and server, from the `trace`
+/// field of `initialize` and later `$/setTrace` notifications.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TraceValue {
+    #[default]
+    Off,
+    Messages,
+    Verbose,
+}
+
+impl TraceValue {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "off" => Some(TraceValue::Off),
+            "messages" => Some(TraceValue::Messages),
+            "verbose" => Some(TraceValue::Verbose),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Error = 1,
+    Warning = 2,
+    Info = 3,
+    Log = 4,
+}
+
+/// The id of a request and of its response. Ids that fit in an `i64` are
+/// `Int`; any other JSON number, such as a large unsigned id or a float from a
+/// noncompliant client, is kept as its exact `serde_json::Number`, so it is
+/// written back unchanged and responses are matched by JSON equality. Numbers
+/// beyond `u64` are only exact if serde_json's `arbitrary_precision` feature
+/// is enabled. String ids are `String` and never equal a numeric id, so `"1"`
+/// and `1` are different requests.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(untagged)]
+pub enum RequestId {
+    Int(i64),
+    Number(serde_json::Number),
+    String(String),
+}
+
+impl RequestId {
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(number) => Some(
+                number
+                    .as_i64()
+                    .map_or_else(|| RequestId::Number(number.clone()), RequestId::Int),
+            ),
+            Value::String(id) => Some(RequestId::String(id.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            RequestId::Int(id) => Some(*id),
+            RequestId::Number(_) | RequestId::String(_) => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            RequestId::String(id) => Some(id),
+            RequestId::Int(_) | RequestId::Number(_) => None,
+        }
+    }
+}
+
+impl From<i64> for RequestId {
+    fn from(id: i64) -> Self {
+        RequestId::Int(id)
+    }
+}
+
+impl From<String> for RequestId {
+    fn from(id: String) -> Self {
+        RequestId::String(id)
+    }
+}
+
+impl From<&str> for RequestId {
+    fn from(id: &str) -> Self {
+        RequestId::String(id.to_owned())
+    }
+}
+
+impl<'de> Deserialize<'de> for RequestId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        RequestId::from_value(&value)
+            .ok_or_else(|| serde::de::Error::custom(MessageParseError::InvalidId))
+    }
+}
+
+impl PartialEq<i64> for RequestId {
+    fn eq(&self, other: &i64) -> bool {
+        self.as_i64() == Some(*other)
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestId::Int(id) => write!(f, "{}", id),
+            RequestId::Number(id) => write!(f, "{}", id),
+            // Quoted, so a string id cannot be mistaken for a numeric one.
+            RequestId::String(id) => write!(f, "{:?}", id),
+        }
+    }
+}
+
+/// In `Request`, `Response` and `Notification`, `None` is a field that is
+/// absent from the message and `Some(Value::Null)` one that is present with a
+/// `null` value; `from_value` and `to_value` keep the two apart.
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Request {
-    pub id: i64,
+    pub id: RequestId,
     pub method: String,
     pub params: Option<Value>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Response {
-    pub id: i64,
+    pub id: RequestId,
+    /// `Some(Value::Null)` for methods such as `shutdown` that succeed with a
+    /// `null` result. A response with neither `result` nor `error` is still
+    /// written with `"result": null`, since JSON-RPC requires one of them.
     pub result: Option<Value>,
     pub error: Option<Value>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+impl Request {
+    /// Deep-merges `patch` into `params`, e.g. to add a `workDoneToken`
+    /// without touching the rest; arrays in `patch` replace those in `params`.
+    /// See `util::merge`.
+    pub fn merge_params(&mut self, patch: Value) {
+        match &mut self.params {
+            Some(params) => merge(params, patch, ArrayMerge::Replace),
+            None => self.params = Some(patch),
+        }
+    }
+}
+
+impl Response {
+    /// The `result` written for this response.
+    fn wire_result(&self) -> Option<&Value> {
+        const NULL: &Value = &Value::Null;
+
+        match (&self.result, &self.error) {
+            (None, None) => Some(NULL),
+            (result, _) => result.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Notification {
     pub method: String,
     pub params: Option<Value>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Message {
     Request(Request),
     Response(Response),
     Notification(Notification),
 }
 
+/// The fields of a message that tell a response apart, with the others
+/// skipped. `Present` rather than `Option` so that a `null` result counts.
+#[derive(Deserialize)]
+struct ResponseEnvelope {
+    id: Option<Value>,
+    #[serde(default)]
+    method: Present,
+    #[serde(default)]
+    result: Present,
+    #[serde(default)]
+    error: Present,
+}
+
+#[derive(Deserialize)]
+struct JsonRpcEnvelope {
+    #[serde(default)]
+    jsonrpc: Present,
+}
+
+#[derive(Default)]
+struct Present(bool);
+
+impl<'de> Deserialize<'de> for Present {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        IgnoredAny::deserialize(deserializer).map(|_| Present(true))
+    }
+}
+
 impl Message {
-    pub fn from_value(value: Value) -> Result<Self, String> {
-        let obj = value.as_object().ok_or("Message must be an object")?;
+    pub fn from_value(value: Value) -> Result<Self, MessageParseError> {
+        let obj = value.as_object().ok_or(MessageParseError::NotAnObject)?;
 
-        let id = obj.get("id").and_then(|id| id.as_i64());
-        let method = obj.get("method").and_then(|m| m.as_str()).map(String::from);
+        // A null id is only valid on error responses to unparseable requests,
+        // which cannot be routed anyway, so it is treated as absent.
+        let id = match obj.get("id") {
+            None | Some(Value::Null) => None,
+            Some(id) => Some(RequestId::from_value(id).ok_or(MessageParseError::InvalidId)?),
+        };
+        let method = match obj.get("method") {
+            None => None,
+            Some(method) => {
+                let method = method.as_str().ok_or(MessageParseError::InvalidMethod)?;
+                if method.is_empty()
+                    || method
+                        .chars()
+                        .any(|ch| ch.is_control() || ch.is_whitespace())
+                {
+                    return Err(MessageParseError::MalformedMethod(method.to_owned()));
+                }
+                Some(method.to_owned())
+            }
+        };
         let params = obj.get("params").cloned();
         let result = obj.get("result").cloned();
         let error = obj.get("error").cloned();
@@ -50,10 +449,41 @@ impl Message {
             (None, Some(method), false) => {
                 Ok(Message::Notification(Notification { method, params }))
             }
-            _ => Err("Invalid message format".to_string()),
+            (_, Some(_), true) => Err(MessageParseError::MethodWithResult),
+            (None, None, true) => Err(MessageParseError::ResponseWithoutId),
+            (Some(_), None, false) => Err(MessageParseError::IdWithoutMethod),
+            (None, None, false) => Err(MessageParseError::Empty),
+        }
+    }
+
+    /// The id of `body` if it is a response `from_value` would accept, read
+    /// without building the result or error.
+    pub(crate) fn peek_response_id(body: &[u8]) -> Option<RequestId> {
+        let envelope: ResponseEnvelope = serde_json::from_slice(body).ok()?;
+        match envelope {
+            ResponseEnvelope {
+                id: Some(id),
+                method: Present(false),
+                result,
+                error,
+            } if result.0 || error.0 => RequestId::from_value(&id),
+            _ => None,
         }
     }
 
+    /// A hash of the compact `to_log_string` form, so messages that differ
+    /// only in key order hash the same. Stable across processes, for log
+    /// correlation, cache keys or integrity checks, but not a cryptographic
+    /// digest.
+    pub fn content_hash(&self) -> u64 {
+        fnv1a(self.to_log_string(LogFormat::Compact).as_bytes())
+    }
+
+    /// Whether `body` has a `jsonrpc` field.
+    pub(crate) fn body_has_jsonrpc(body: &[u8]) -> bool {
+        serde_json::from_slice::<JsonRpcEnvelope>(body).is_ok_and(|envelope| envelope.jsonrpc.0)
+    }
+
     pub fn to_value(&self) -> Value {
         match self {
             Message::Request(Request { id, method, params }) => {
@@ -67,15 +497,15 @@ impl Message {
                 }
                 obj
             }
-            Message::Response(Response { id, result, error }) => {
+            Message::Response(response) => {
                 let mut obj = serde_json::json!({
                     "jsonrpc": "2.0",
-                    "id": id,
+                    "id": response.id,
                 });
-                if let Some(result) = result {
+                if let Some(result) = response.wire_result() {
                     obj["result"] = result.clone();
                 }
-                if let Some(error) = error {
+                if let Some(error) = &response.error {
                     obj["error"] = error.clone();
                 }
                 obj
@@ -101,7 +531,7 @@ impl Message {
         }
     }
 
-    pub fn get_id(&self) -> Option<&i64> {
+    pub fn get_id(&self) -> Option<&RequestId> {
         match self {
             Message::Request(Request { id, .. }) => Some(id),
             Message::Response(Response { id, .. }) => Some(id),
@@ -109,10 +539,144 @@ impl Message {
         }
     }
 
+    /// Serializes the message for logs and recordings. Object keys are sorted
+    /// so the same message always produces the same text, whatever order its
+    /// fields arrived in. The wire format written by `transport` is unaffected
+    /// and stays compact.
+    pub fn to_log_string(&self, format: LogFormat) -> String {
+        let value = sort_keys(self.to_value());
+        match format {
+            LogFormat::Compact => value.to_string(),
+            LogFormat::Pretty => {
+                serde_json::to_string_pretty(&value).unwrap_or_else(|_| value.to_string())
+            }
+        }
+    }
+
+    /// The length in bytes of the JSON body `write_message` emits for this
+    /// message, i.e. its `Content-Length` before any compression. Computed by
+    /// serializing into a byte counter, without building `to_value` or the
+    /// body itself.
+    pub fn byte_len(&self) -> usize {
+        let mut counter = ByteCounter(0);
+        // Serializing plain JSON values into a writer that never fails cannot
+        // fail either.
+        let _ = serde_json::to_writer(&mut counter, &WireMessage(self));
+        counter.0
+    }
+
     pub fn notification(method: &str, params: Option<Value>) -> Self {
         Message::Notification(Notification {
             method: method.to_owned(),
             params,
         })
     }
+
+    pub fn request(id: impl Into<RequestId>, method: &str, params: Option<Value>) -> Self {
+        Message::Request(Request {
+            id: id.into(),
+            method: method.to_owned(),
+            params,
+        })
+    }
+
+    pub fn error_response(
+        id: impl Into<RequestId>,
+        code: impl Into<ErrorCode>,
+        message: &str,
+    ) -> Self {
+        Self::response_error(id, ResponseError::new(code, message, None))
+    }
+
+    pub fn response_error(id: impl Into<RequestId>, error: ResponseError) -> Self {
+        Message::Response(Response {
+            id: id.into(),
+            result: None,
+            error: Some(error.to_value()),
+        })
+    }
+
+    pub fn log_message(message_type: MessageType, message: &str) -> Self {
+        Self::window_message("window/logMessage", message_type, message)
+    }
+
+    pub fn show_message(message_type: MessageType, message: &str) -> Self {
+        Self::window_message("window/showMessage", message_type, message)
+    }
+
+    fn window_message(method: &str, message_type: MessageType, message: &str) -> Self {
+        Message::notification(
+            method,
+            Some(serde_json::json!({
+                "type": message_type as u8,
+                "message": message,
+            })),
+        )
+    }
+}
+
+/// Serializes a message with the same fields as `to_value`, borrowing instead
+/// of cloning the params.
+struct WireMessage<'a>(&'a Message);
+
+impl Serialize for WireMessage<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("jsonrpc", "2.0")?;
+        match self.0 {
+            Message::Request(Request { id, method, params }) => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("method", method)?;
+                if let Some(params) = params {
+                    map.serialize_entry("params", params)?;
+                }
+            }
+            Message::Response(response) => {
+                map.serialize_entry("id", &response.id)?;
+                if let Some(result) = response.wire_result() {
+                    map.serialize_entry("result", result)?;
+                }
+                if let Some(error) = &response.error {
+                    map.serialize_entry("error", error)?;
+                }
+            }
+            Message::Notification(Notification { method, params }) => {
+                map.serialize_entry("method", method)?;
+                if let Some(params) = params {
+                    map.serialize_entry("params", params)?;
+                }
+            }
+        }
+        map.end()
+    }
+}
+
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, sort_keys(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+        other => other,
+    }
 }
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::select;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::message::REQUEST_FAILED;
+use crate::transport::{ReadOptions, TransportError, read_frame, write_message};
+use crate::{Message, Request, RequestId, Response};
+
+type CompareFn = Arc<dyn Fn(&Request, &Response, &Response) + Send + Sync>;
+
+/// A second server that receives a copy of every request and notification the
+/// client sends, for trying a new server version against real traffic. Its
+/// responses never reach the client; `compare` sees them next to the primary
+/// server's. Requests the mirror sends are answered with an error. Used with
+/// `Proxy::forward_mirrored`.
+pub struct Mirror<R, W> {
+    reader: R,
+    writer: W,
+    compare: Option<CompareFn>,
+}
+
+impl<R, W> Mirror<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader,
+            writer,
+            compare: None,
+        }
+    }
+
+    /// Called with each mirrored request and the responses of the primary
+    /// server and the mirror, once both have answered.
+    pub fn compare<F>(mut self, compare: F) -> Self
+    where
+        F: Fn(&Request, &Response, &Response) + Send + Sync + 'static,
+    {
+        self.compare = Some(Arc::new(compare));
+        self
+    }
+
+    pub(crate) fn into_parts(self) -> (R, W, MirrorTap, UnboundedReceiver<Message>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let tap = MirrorTap {
+            sender,
+            compare: self.compare,
+            exchanges: Mutex::new(HashMap::new()),
+        };
+        (self.reader, self.writer, tap, receiver)
+    }
+}
+
+/// A mirrored request waiting for the other server's answer.
+struct Exchange {
+    request: Request,
+    primary: Option<Response>,
+    mirror: Option<Response>,
+}
+
+/// Copies client traffic to the mirror and pairs up the two servers'
+/// responses. Exchanges are only tracked when there is a `compare` callback.
+pub(crate) struct MirrorTap {
+    sender: UnboundedSender<Message>,
+    compare: Option<CompareFn>,
+    exchanges: Mutex<HashMap<RequestId, Exchange>>,
+}
+
+impl MirrorTap {
+    /// Queues a copy of a message on its way from the client to the primary
+    /// server. Responses are not copied: they answer the primary's requests,
+    /// not the mirror's.
+    pub(crate) fn tee(&self, message: &Message) {
+        if self.sender.is_closed() {
+            return;
+        }
+
+        match message {
+            Message::Request(request) => {
+                if self.compare.is_some() {
+                    self.exchanges.lock().unwrap().insert(
+                        request.id.clone(),
+                        Exchange {
+                            request: request.clone(),
+                            primary: None,
+                            mirror: None,
+                        },
+                    );
+                }
+            }
+            Message::Notification(_) => {}
+            Message::Response(_) => return,
+        }
+
+        let _ = self.sender.send(message.clone());
+    }
+
+    pub(crate) fn observe_primary(&self, response: &Response) {
+        self.record(&response.id, |exchange| {
+            exchange.primary = Some(response.clone())
+        });
+    }
+
+    fn observe_mirror(&self, response: Response) {
+        let id = response.id.clone();
+        self.record(&id, |exchange| exchange.mirror = Some(response));
+    }
+
+    fn record(&self, id: &RequestId, update: impl FnOnce(&mut Exchange)) {
+        let Some(compare) = &self.compare else {
+            return;
+        };
+
+        let exchange = {
+            let mut exchanges = self.exchanges.lock().unwrap();
+            let Some(exchange) = exchanges.get_mut(id) else {
+                return;
+            };
+            update(exchange);
+            if exchange.primary.is_none() || exchange.mirror.is_none() {
+                return;
+            }
+            exchanges.remove(id)
+        };
+
+        if let Some(Exchange {
+            request,
+            primary: Some(primary),
+            mirror: Some(mirror),
+        }) = exchange
+        {
+            compare(&request, &primary, &mirror);
+        }
+    }
+}
+
+/// Writes the copied traffic to the mirror and reads back what it sends until
+/// either direction of the connection fails or closes.
+pub(crate) async fn run_mirror<R, W>(
+    tap: Arc<MirrorTap>,
+    reader: R,
+    mut writer: W,
+    mut receiver: UnboundedReceiver<Message>,
+    options: ReadOptions,
+) -> std::io::Result<()>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let write = async {
+        while let Some(message) = receiver.recv().await {
+            write_message(&mut writer, &message.to_value()).await?;
+        }
+        Ok(())
+    };
+
+    let read = async {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let frame = match read_frame(&mut reader, &options).await {
+                Ok(frame) => frame,
+                Err(TransportError::Eof) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+
+            match Message::from_value(frame.content) {
+                Ok(Message::Response(response)) => tap.observe_mirror(response),
+                Ok(Message::Request(request)) => {
+                    let _ = tap.sender.send(Message::error_response(
+                        request.id,
+                        REQUEST_FAILED,
+                        "Requests from a mirror server are not answered",
+                    ));
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let result = select! {
+        result = write => result,
+        result = read => result,
+    };
+    drop(receiver);
+    tap.exchanges.lock().unwrap().clear();
+    result
+}
@@ -0,0 +1,536 @@
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::clock::TokioClock;
+use crate::correlation::{Correlator, HashMapCorrelator};
+use crate::documents::DocumentStore;
+use crate::handle::{ConnectionId, PendingRequests};
+use crate::message::INTERNAL_ERROR;
+use crate::transport::{ReadOptions, TransportError, read_frame, write_messages};
+use crate::{Direction, Message, Notification, Request, RequestId, Response};
+
+/// Lets several clients share one language server. Request ids from each
+/// client are remapped into a single server-side namespace and responses are
+/// routed back to the client that asked. Server notifications go to every
+/// client; server requests go to the primary client, the longest attached one.
+///
+/// The lifecycle is shared as well: only the first `initialize` reaches the
+/// server and later clients get the same result, while `shutdown` and `exit`
+/// are only forwarded for the last attached client. Hooks are not applied;
+/// put a `Proxy` in front of a client to transform its traffic.
+///
+/// Each client's open documents are tracked separately. The server sees a
+/// document opened once and closed when the last client closes it, and holds
+/// the text of the client that changed it last: a change from another client
+/// than the previous one is sent as that client's full text.
+pub struct Multiplexer {
+    state: Arc<MuxState>,
+    server_tasks: [JoinHandle<std::io::Result<()>>; 2],
+}
+
+struct MuxState {
+    server: UnboundedSender<Message>,
+    clients: Mutex<BTreeMap<usize, MuxClient>>,
+    next_client: AtomicUsize,
+    next_request_id: AtomicI64,
+    /// Requests forwarded to the server under their server-side ids.
+    pending_requests: PendingRequests,
+    /// The documents open on the server.
+    documents: Mutex<HashMap<String, SharedDocument>>,
+    initialize: Mutex<Initialize>,
+    initialized_sent: AtomicBool,
+}
+
+struct MuxClient {
+    sender: UnboundedSender<Message>,
+    /// Server-side id -> the id the client used, for its requests in flight.
+    requests: HashMap<RequestId, RequestId>,
+    documents: DocumentStore,
+}
+
+/// A document as the server has it: whose text it is and its version.
+struct SharedDocument {
+    owner: usize,
+    version: i64,
+}
+
+enum Initialize {
+    NotSent,
+    Pending {
+        id: RequestId,
+        waiting: Vec<(usize, RequestId)>,
+    },
+    Done(Option<Value>),
+}
+
+impl Multiplexer {
+    /// Starts serving the server connection. Must be called from within a
+    /// Tokio runtime.
+    pub fn new<SR, SW>(server_reader: SR, server_writer: SW) -> Self
+    where
+        SR: AsyncReadExt + Unpin + Send + 'static,
+        SW: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        Self::with_correlator(
+            server_reader,
+            server_writer,
+            Arc::new(HashMapCorrelator::new()),
+        )
+    }
+
+    /// Like `new`, with the requests forwarded to the server tracked by
+    /// `correlator`, keyed by their server-side ids.
+    pub fn with_correlator<SR, SW>(
+        server_reader: SR,
+        server_writer: SW,
+        correlator: Arc<dyn Correlator>,
+    ) -> Self
+    where
+        SR: AsyncReadExt + Unpin + Send + 'static,
+        SW: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        let (server, server_receiver) = mpsc::unbounded_channel();
+        let state = Arc::new(MuxState {
+            server,
+            clients: Mutex::new(BTreeMap::new()),
+            next_client: AtomicUsize::new(0),
+            next_request_id: AtomicI64::new(1),
+            pending_requests: PendingRequests::new(correlator, Arc::new(TokioClock), false),
+            documents: Mutex::new(HashMap::new()),
+            initialize: Mutex::new(Initialize::NotSent),
+            initialized_sent: AtomicBool::new(false),
+        });
+
+        let server_tasks = [
+            tokio::spawn(read_server(Arc::clone(&state), server_reader)),
+            tokio::spawn(write_peer(server_writer, server_receiver)),
+        ];
+
+        Self {
+            state,
+            server_tasks,
+        }
+    }
+
+    /// Attaches a client. The returned task completes when the client
+    /// disconnects; its in-flight requests are abandoned and the documents
+    /// it had open are closed for it.
+    pub fn attach<CR, CW>(
+        &self,
+        client_reader: CR,
+        client_writer: CW,
+    ) -> JoinHandle<std::io::Result<()>>
+    where
+        CR: AsyncReadExt + Unpin + Send + 'static,
+        CW: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        let state = Arc::clone(&self.state);
+
+        tokio::spawn(async move {
+            let client = state.next_client.fetch_add(1, Ordering::Relaxed);
+            let (sender, receiver) = mpsc::unbounded_channel();
+            state.clients.lock().await.insert(
+                client,
+                MuxClient {
+                    sender,
+                    requests: HashMap::new(),
+                    documents: DocumentStore::new(ConnectionId::next()),
+                },
+            );
+
+            let writer = tokio::spawn(write_peer(client_writer, receiver));
+            let result = read_client(&state, client, client_reader).await;
+
+            state.detach(client).await;
+            writer.abort();
+            result
+        })
+    }
+
+    /// Number of requests forwarded to the server, from any client, that are
+    /// still waiting for a response.
+    pub fn pending_count(&self) -> usize {
+        self.state.pending_requests.len()
+    }
+}
+
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        for task in &self.server_tasks {
+            task.abort();
+        }
+    }
+}
+
+impl MuxState {
+    async fn send_to_client(&self, client: usize, message: Message) {
+        if let Some(client) = self.clients.lock().await.get(&client) {
+            let _ = client.sender.send(message);
+        }
+    }
+
+    async fn has_other_clients(&self, client: usize) -> bool {
+        self.clients
+            .lock()
+            .await
+            .keys()
+            .any(|other| *other != client)
+    }
+
+    async fn forward_request(&self, client: usize, mut request: Request) -> RequestId {
+        let id = RequestId::Int(self.next_request_id.fetch_add(1, Ordering::Relaxed));
+        let client_id = std::mem::replace(&mut request.id, id.clone());
+        if let Some(owner) = self.clients.lock().await.get_mut(&client) {
+            owner.requests.insert(id.clone(), client_id);
+        }
+        self.pending_requests
+            .insert(Direction::ToServer, id.clone(), request.method.clone());
+        let _ = self.server.send(Message::Request(request));
+        id
+    }
+
+    /// Resolves the server-side id of a response to the client that sent the
+    /// request and the id it used.
+    async fn resolve_response(&self, id: &RequestId) -> Option<(usize, RequestId)> {
+        self.pending_requests.remove(Direction::ToServer, id)?;
+        self.clients
+            .lock()
+            .await
+            .iter_mut()
+            .find_map(|(client, owner)| Some((*client, owner.requests.remove(id)?)))
+    }
+
+    async fn initialize(&self, client: usize, request: Request) {
+        let mut initialize = self.initialize.lock().await;
+
+        match &mut *initialize {
+            Initialize::NotSent => {
+                let id = self.forward_request(client, request).await;
+                *initialize = Initialize::Pending {
+                    id,
+                    waiting: Vec::new(),
+                };
+            }
+            Initialize::Pending { waiting, .. } => waiting.push((client, request.id)),
+            Initialize::Done(result) => {
+                if let Some(owner) = self.clients.lock().await.get(&client) {
+                    owner.documents.observe_capabilities(result.as_ref());
+                }
+                let response = Message::Response(Response {
+                    id: request.id,
+                    result: result.clone(),
+                    error: None,
+                });
+                drop(initialize);
+                self.send_to_client(client, response).await;
+            }
+        }
+    }
+
+    /// Records the server's answer to `initialize` and passes it to the clients
+    /// that sent their own `initialize` while it was in flight.
+    async fn finish_initialize(&self, response: &Response) {
+        let mut initialize = self.initialize.lock().await;
+        let Initialize::Pending { id, waiting } = &mut *initialize else {
+            return;
+        };
+        if *id != response.id {
+            return;
+        }
+
+        for owner in self.clients.lock().await.values() {
+            owner
+                .documents
+                .observe_capabilities(response.result.as_ref());
+        }
+        for (waiting_client, waiting_id) in std::mem::take(waiting) {
+            let response = Message::Response(Response {
+                id: waiting_id,
+                ..response.clone()
+            });
+            self.send_to_client(waiting_client, response).await;
+        }
+        *initialize = Initialize::Done(response.result.clone());
+    }
+
+    /// Rewrites the id in `$/cancelRequest` from the client's namespace to the
+    /// server's.
+    async fn remap_cancel(&self, client: usize, params: &mut Option<Value>) {
+        let Some(id) = params
+            .as_ref()
+            .and_then(|params| params.get("id"))
+            .and_then(RequestId::from_value)
+        else {
+            return;
+        };
+
+        let clients = self.clients.lock().await;
+        if let Some(owner) = clients.get(&client)
+            && let Some((server_id, _)) = owner
+                .requests
+                .iter()
+                .find(|(_, client_id)| **client_id == id)
+            && let Some(params) = params
+        {
+            params["id"] = json!(server_id);
+        }
+    }
+
+    /// Tracks a document notification in the client's own store and forwards
+    /// what the server needs to keep one consistent copy of the document.
+    async fn sync_document(&self, client: usize, mut notification: Notification) {
+        let Some(uri) = notification
+            .params
+            .as_ref()
+            .and_then(|params| params.pointer("/textDocument/uri"))
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+        else {
+            let _ = self.server.send(Message::Notification(notification));
+            return;
+        };
+
+        let version = notification
+            .params
+            .as_ref()
+            .and_then(|params| params.pointer("/textDocument/version"))
+            .and_then(Value::as_i64)
+            .unwrap_or_default();
+
+        let clients = self.clients.lock().await;
+        let mut documents = self.documents.lock().await;
+        let Some(from) = clients.get(&client) else {
+            return;
+        };
+        from.documents.normalize(&mut notification);
+
+        match (notification.method.as_str(), documents.get_mut(&uri)) {
+            ("textDocument/didOpen", None) => {
+                documents.insert(
+                    uri,
+                    SharedDocument {
+                        owner: client,
+                        version,
+                    },
+                );
+            }
+            // Already open on the server for another client: only its text
+            // may differ.
+            ("textDocument/didOpen", Some(shared)) => {
+                if let Some(text) = from.documents.text(&uri) {
+                    shared.owner = client;
+                    shared.version = next_version(shared.version, version);
+                    let _ = self.server.send(full_change(&uri, shared.version, text));
+                }
+                return;
+            }
+            ("textDocument/didChange", Some(shared)) => {
+                shared.version = next_version(shared.version, version);
+                let params = notification.params.get_or_insert_with(|| json!({}));
+                params["textDocument"]["version"] = json!(shared.version);
+                if shared.owner != client
+                    && let Some(text) = from.documents.text(&uri)
+                {
+                    shared.owner = client;
+                    params["contentChanges"] = json!([{ "text": text }]);
+                }
+            }
+            ("textDocument/didClose", Some(shared)) => {
+                let other = clients
+                    .iter()
+                    .filter(|(other, _)| **other != client)
+                    .find_map(|(other, open)| Some((*other, open.documents.text(&uri)?)));
+                match other {
+                    None => {
+                        documents.remove(&uri);
+                    }
+                    Some((other, text)) => {
+                        if shared.owner == client {
+                            shared.owner = other;
+                            shared.version += 1;
+                            let _ = self.server.send(full_change(&uri, shared.version, text));
+                        }
+                        return;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        let _ = self.server.send(Message::Notification(notification));
+    }
+
+    /// Forgets a client that disconnected: its requests are dropped and the
+    /// documents it had open are closed as if it had closed them.
+    async fn detach(&self, client: usize) {
+        let uris = match self.clients.lock().await.get(&client) {
+            Some(owner) => owner.documents.uris(),
+            None => return,
+        };
+        for uri in uris {
+            let close = Notification {
+                method: "textDocument/didClose".to_owned(),
+                params: Some(json!({ "textDocument": { "uri": uri } })),
+            };
+            self.sync_document(client, close).await;
+        }
+
+        if let Some(owner) = self.clients.lock().await.remove(&client) {
+            for id in owner.requests.keys() {
+                self.pending_requests.remove(Direction::ToServer, id);
+            }
+        }
+    }
+}
+
+/// The server-side version after `current` for a change the client numbered
+/// `version`, which never goes backwards when several clients edit.
+fn next_version(current: i64, version: i64) -> i64 {
+    version.max(current + 1)
+}
+
+fn full_change(uri: &str, version: i64, text: String) -> Message {
+    Message::notification(
+        "textDocument/didChange",
+        Some(json!({
+            "textDocument": { "uri": uri, "version": version },
+            "contentChanges": [{ "text": text }],
+        })),
+    )
+}
+
+async fn read_client<R>(state: &MuxState, client: usize, reader: R) -> std::io::Result<()>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let message = match read_frame(&mut reader, &ReadOptions::default()).await {
+            Ok(frame) => Message::from_value(frame.content),
+            Err(TransportError::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        match message {
+            Ok(Message::Request(request)) if request.method == "initialize" => {
+                state.initialize(client, request).await;
+            }
+            Ok(Message::Request(request))
+                if request.method == "shutdown" && state.has_other_clients(client).await =>
+            {
+                let response = Message::Response(Response {
+                    id: request.id,
+                    result: Some(Value::Null),
+                    error: None,
+                });
+                state.send_to_client(client, response).await;
+            }
+            Ok(Message::Request(request)) => {
+                state.forward_request(client, request).await;
+            }
+            Ok(Message::Notification(notification))
+                if notification.method == "initialized"
+                    && state.initialized_sent.swap(true, Ordering::Relaxed) => {}
+            Ok(Message::Notification(notification))
+                if notification.method == "exit" && state.has_other_clients(client).await => {}
+            Ok(Message::Notification(notification))
+                if matches!(
+                    notification.method.as_str(),
+                    "textDocument/didOpen" | "textDocument/didChange" | "textDocument/didClose"
+                ) =>
+            {
+                state.sync_document(client, notification).await;
+            }
+            Ok(Message::Notification(mut notification)) => {
+                if notification.method == "$/cancelRequest" {
+                    state.remap_cancel(client, &mut notification.params).await;
+                }
+                let _ = state.server.send(Message::Notification(notification));
+            }
+            // Answers to server requests, which only the primary client gets.
+            Ok(response @ Message::Response(_)) => {
+                let _ = state.server.send(response);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+async fn read_server<R>(state: Arc<MuxState>, reader: R) -> std::io::Result<()>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let message = match read_frame(&mut reader, &ReadOptions::default()).await {
+            Ok(frame) => Message::from_value(frame.content),
+            Err(TransportError::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        match message {
+            Ok(Message::Response(mut response)) => {
+                // Before resolving its owner, which may have disconnected
+                // while the clients that joined later are still waiting.
+                state.finish_initialize(&response).await;
+                let Some((client, client_id)) = state.resolve_response(&response.id).await else {
+                    continue;
+                };
+
+                response.id = client_id;
+                state
+                    .send_to_client(client, Message::Response(response))
+                    .await;
+            }
+            Ok(Message::Request(request)) => {
+                let clients = state.clients.lock().await;
+                match clients.values().next() {
+                    Some(primary) => {
+                        let _ = primary.sender.send(Message::Request(request));
+                    }
+                    None => {
+                        let _ = state.server.send(Message::error_response(
+                            request.id,
+                            INTERNAL_ERROR,
+                            "No client attached",
+                        ));
+                    }
+                }
+            }
+            Ok(notification @ Message::Notification(_)) => {
+                for client in state.clients.lock().await.values() {
+                    let _ = client.sender.send(notification.clone());
+                }
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+async fn write_peer<W>(
+    mut writer: W,
+    mut receiver: UnboundedReceiver<Message>,
+) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let mut batch = Vec::new();
+    while let Some(message) = receiver.recv().await {
+        batch.push(message.to_value());
+        while let Ok(message) = receiver.try_recv() {
+            batch.push(message.to_value());
+        }
+
+        write_messages(&mut writer, &batch).await?;
+        batch.clear();
+    }
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::select;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+use crate::{Message, message::Direction};
+
+/// Waits for the next queued message. Once `draining` is cancelled, returns
+/// what is still queued and then `None` instead of waiting for more.
+pub(crate) async fn recv_until_drained<T>(
+    receiver: &mut UnboundedReceiver<T>,
+    draining: &CancellationToken,
+) -> Option<T> {
+    select! {
+        biased;
+        next = receiver.recv() => next,
+        _ = draining.cancelled() => receiver.try_recv().ok(),
+    }
+}
+
+/// The writer for the peer in this direction has stopped.
+#[derive(Debug)]
+pub(crate) struct ChannelClosed(pub(crate) Direction);
+
+/// A message queued for a peer. `raw` holds the body exactly as it was
+/// received when the message is forwarded unchanged, so the writer can send
+/// those bytes instead of serializing the message again. `message` is `None`
+/// for bodies passed through without being understood, which always have
+/// `raw`.
+pub(crate) struct Outgoing {
+    pub(crate) message: Option<Message>,
+    pub(crate) raw: Option<Arc<[u8]>>,
+}
+
+/// Queues messages for the writer tasks. By default each peer has its own
+/// channel and writer; in serialized mode both directions share one channel,
+/// so everything the proxy emits is written in a single total order.
+#[derive(Clone)]
+pub(crate) enum Outbound {
+    Split {
+        client: UnboundedSender<Outgoing>,
+        server: UnboundedSender<Outgoing>,
+    },
+    Serialized(UnboundedSender<(Direction, Outgoing)>),
+}
+
+pub(crate) enum OutboundReceivers {
+    Split {
+        client: UnboundedReceiver<Outgoing>,
+        server: UnboundedReceiver<Outgoing>,
+    },
+    Serialized(UnboundedReceiver<(Direction, Outgoing)>),
+}
+
+pub(crate) fn channel(serialized: bool) -> (Outbound, OutboundReceivers) {
+    if serialized {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        return (
+            Outbound::Serialized(sender),
+            OutboundReceivers::Serialized(receiver),
+        );
+    }
+
+    let (client, client_receiver) = mpsc::unbounded_channel();
+    let (server, server_receiver) = mpsc::unbounded_channel();
+    (
+        Outbound::Split { client, server },
+        OutboundReceivers::Split {
+            client: client_receiver,
+            server: server_receiver,
+        },
+    )
+}
+
+impl Outbound {
+    pub(crate) fn send(&self, direction: Direction, message: Message) -> Result<(), ChannelClosed> {
+        self.send_outgoing(
+            direction,
+            Outgoing {
+                message: Some(message),
+                raw: None,
+            },
+        )
+    }
+
+    /// Queues a message received as `raw` and forwarded unchanged.
+    pub(crate) fn send_raw(
+        &self,
+        direction: Direction,
+        message: Message,
+        raw: Arc<[u8]>,
+    ) -> Result<(), ChannelClosed> {
+        self.send_outgoing(
+            direction,
+            Outgoing {
+                message: Some(message),
+                raw: Some(raw),
+            },
+        )
+    }
+
+    /// Queues a body to be written exactly as received without a parsed
+    /// message: one the proxy does not understand, or a response it forwards
+    /// without parsing.
+    pub(crate) fn send_unparsed(
+        &self,
+        direction: Direction,
+        raw: Arc<[u8]>,
+    ) -> Result<(), ChannelClosed> {
+        self.send_outgoing(
+            direction,
+            Outgoing {
+                message: None,
+                raw: Some(raw),
+            },
+        )
+    }
+
+    fn send_outgoing(&self, direction: Direction, outgoing: Outgoing) -> Result<(), ChannelClosed> {
+        let result = match (self, direction) {
+            (Outbound::Split { client, .. }, Direction::ToClient) => client.send(outgoing).is_ok(),
+            (Outbound::Split { server, .. }, Direction::ToServer) => server.send(outgoing).is_ok(),
+            (Outbound::Serialized(sender), direction) => sender.send((direction, outgoing)).is_ok(),
+        };
+
+        result.then_some(()).ok_or(ChannelClosed(direction))
+    }
+}
+
+/// Keeps what is sent under the same key, e.g. a document URI, in the order
+/// places were reserved for it, whatever order it is produced in. Each `Slot`
+/// is a place in its key's queue: while every earlier slot of the key is
+/// done, what is sent through it goes straight to the writer; until then it
+/// is held, and released in order as those slots finish.
+#[derive(Clone, Default)]
+pub(crate) struct Sequencer {
+    queues: Arc<Mutex<HashMap<String, SlotQueue>>>,
+}
+
+#[derive(Default)]
+struct SlotQueue {
+    next_id: u64,
+    /// Slots not yet released, oldest first.
+    slots: VecDeque<HeldSlot>,
+}
+
+struct HeldSlot {
+    id: u64,
+    held: Vec<(Direction, Message)>,
+    done: bool,
+}
+
+/// A place in a `Sequencer` queue. Dropping it marks it done, which releases
+/// the slots behind it.
+pub(crate) struct Slot {
+    sequencer: Sequencer,
+    outbound: Outbound,
+    key: String,
+    id: u64,
+}
+
+impl Sequencer {
+    pub(crate) fn reserve(&self, key: String, outbound: Outbound) -> Slot {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(key.clone()).or_default();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.slots.push_back(HeldSlot {
+            id,
+            held: Vec::new(),
+            done: false,
+        });
+
+        Slot {
+            sequencer: self.clone(),
+            outbound,
+            key,
+            id,
+        }
+    }
+}
+
+impl Slot {
+    pub(crate) fn send(&self, direction: Direction, message: Message) -> Result<(), ChannelClosed> {
+        let mut queues = self.sequencer.queues.lock().unwrap();
+        let Some(queue) = queues.get_mut(&self.key) else {
+            return self.outbound.send(direction, message);
+        };
+
+        match queue.slots.iter_mut().position(|slot| slot.id == self.id) {
+            Some(0) | None => self.outbound.send(direction, message),
+            Some(position) => {
+                queue.slots[position].held.push((direction, message));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut queues = self.sequencer.queues.lock().unwrap();
+        let Some(queue) = queues.get_mut(&self.key) else {
+            return;
+        };
+        if let Some(slot) = queue.slots.iter_mut().find(|slot| slot.id == self.id) {
+            slot.done = true;
+        }
+
+        // Whatever the finished slots at the front kept back can go now, and
+        // so can the messages of the first slot still running.
+        while let Some(front) = queue.slots.front_mut() {
+            for (direction, message) in front.held.drain(..) {
+                let _ = self.outbound.send(direction, message);
+            }
+            if !front.done {
+                break;
+            }
+            queue.slots.pop_front();
+        }
+
+        if queue.slots.is_empty() {
+            queues.remove(&self.key);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::clock::Clock;
+use crate::redact::Redactor;
+use crate::{Direction, Request, RequestId, Response};
+
+const PAIR_CHANNEL_CAPACITY: usize = 1024;
+
+/// A request together with the response that closed it.
+#[derive(Debug, Clone)]
+pub struct RequestResponsePair {
+    pub request: Request,
+    /// `None` if no response arrived within the pair timeout.
+    pub response: Option<Response>,
+    /// The direction the request travelled; the response travels the other way.
+    pub direction: Direction,
+    pub latency: Duration,
+}
+
+pub(crate) struct PairTracker {
+    subscribers: std::sync::Mutex<Vec<Sender<RequestResponsePair>>>,
+    in_flight: Mutex<HashMap<(Direction, RequestId), (Request, Instant)>>,
+    timeout: Duration,
+    redactor: Option<Redactor>,
+    clock: Arc<dyn Clock>,
+}
+
+impl PairTracker {
+    pub(crate) fn new(
+        timeout: Duration,
+        redactor: Option<Redactor>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            subscribers: std::sync::Mutex::new(Vec::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            timeout,
+            redactor,
+            clock,
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> Receiver<RequestResponsePair> {
+        let (sender, receiver) = mpsc::channel(PAIR_CHANNEL_CAPACITY);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        !self.subscribers.lock().unwrap().is_empty()
+    }
+
+    pub(crate) async fn record_request(&self, direction: Direction, request: &Request) {
+        if self.is_active() {
+            let request = match &self.redactor {
+                Some(redactor) => redactor.redact_request(request),
+                None => request.clone(),
+            };
+            self.in_flight
+                .lock()
+                .await
+                .insert((direction, request.id.clone()), (request, self.clock.now()));
+        }
+    }
+
+    /// `direction` is the one the resolved request travelled in.
+    pub(crate) async fn resolve(&self, direction: Direction, response: &Response) {
+        let in_flight = self
+            .in_flight
+            .lock()
+            .await
+            .remove(&(direction, response.id.clone()));
+
+        if let Some((request, started)) = in_flight {
+            let response = match &self.redactor {
+                Some(redactor) => redactor.redact_response(response),
+                None => response.clone(),
+            };
+            self.publish(RequestResponsePair {
+                request,
+                response: Some(response),
+                direction,
+                latency: self.clock.now().saturating_duration_since(started),
+            });
+        }
+    }
+
+    /// Periodically reports requests that were never answered.
+    pub(crate) async fn expire_forever(&self) -> std::io::Result<()> {
+        let period = self.timeout.min(Duration::from_secs(1));
+
+        loop {
+            self.clock.sleep(period).await;
+
+            let now = self.clock.now();
+            let expired: Vec<_> = {
+                let mut in_flight = self.in_flight.lock().await;
+                let keys: Vec<_> = in_flight
+                    .iter()
+                    .filter(|(_, (_, started))| {
+                        now.saturating_duration_since(*started) >= self.timeout
+                    })
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                keys.into_iter()
+                    .filter_map(|key| in_flight.remove(&key).map(|entry| (key.0, entry)))
+                    .collect()
+            };
+
+            for (direction, (request, started)) in expired {
+                self.publish(RequestResponsePair {
+                    request,
+                    response: None,
+                    direction,
+                    latency: now.saturating_duration_since(started),
+                });
+            }
+        }
+    }
+
+    // Pairs are dropped for subscribers that fall behind rather than slowing
+    // down forwarding.
+    fn publish(&self, pair: RequestResponsePair) {
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            !matches!(
+                subscriber.try_send(pair.clone()),
+                Err(mpsc::error::TrySendError::Closed(_))
+            )
+        });
+    }
+}
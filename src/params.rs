@@ -0,0 +1,86 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::any::{Any, TypeId};
+use std::sync::{Arc, Mutex};
+
+use crate::HookError;
+
+/// The typed params last built for a message, shared by every hook that
+/// handles it through the same `HookContext`.
+#[derive(Default)]
+pub(crate) struct ParamsCache {
+    typed: Mutex<Option<Typed>>,
+}
+
+struct Typed {
+    type_id: TypeId,
+    /// The params `value` was deserialized from, to tell whether a hook has
+    /// changed them since.
+    source: Option<Value>,
+    value: Arc<dyn Any + Send + Sync>,
+}
+
+impl std::fmt::Debug for ParamsCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ParamsCache").finish_non_exhaustive()
+    }
+}
+
+/// Read access to the `params` of a message, from `HookContext::params`.
+/// Fields are looked up by JSON pointer, e.g. `/textDocument/uri`.
+#[derive(Clone, Copy)]
+pub struct Params<'a> {
+    value: Option<&'a Value>,
+    cache: &'a ParamsCache,
+}
+
+impl<'a> Params<'a> {
+    pub(crate) fn new(value: Option<&'a Value>, cache: &'a ParamsCache) -> Self {
+        Self { value, cache }
+    }
+
+    pub fn get(&self, pointer: &str) -> Option<&'a Value> {
+        self.value?.pointer(pointer)
+    }
+
+    pub fn get_str(&self, pointer: &str) -> Option<&'a str> {
+        self.get(pointer).and_then(Value::as_str)
+    }
+
+    pub fn get_i64(&self, pointer: &str) -> Option<i64> {
+        self.get(pointer).and_then(Value::as_i64)
+    }
+
+    pub fn get_bool(&self, pointer: &str) -> Option<bool> {
+        self.get(pointer).and_then(Value::as_bool)
+    }
+
+    /// Deserializes the params as `T`, or reuses the `T` an earlier hook
+    /// handling the same message built, as long as the params still equal
+    /// the ones it was built from. Missing params deserialize from `null`.
+    /// Only the last type asked for is kept.
+    pub fn as_typed<T>(&self) -> Result<Arc<T>, HookError>
+    where
+        T: DeserializeOwned + Send + Sync + 'static,
+    {
+        let mut typed = self.cache.typed.lock().unwrap();
+        if let Some(cached) = typed.as_ref()
+            && cached.type_id == TypeId::of::<T>()
+            && cached.source.as_ref() == self.value
+            && let Ok(value) = Arc::clone(&cached.value).downcast::<T>()
+        {
+            return Ok(value);
+        }
+
+        let value = Arc::new(match self.value {
+            Some(value) => T::deserialize(value)?,
+            None => T::deserialize(&Value::Null)?,
+        });
+        *typed = Some(Typed {
+            type_id: TypeId::of::<T>(),
+            source: self.value.cloned(),
+            value: Arc::clone(&value) as Arc<dyn Any + Send + Sync>,
+        });
+        Ok(value)
+    }
+}
@@ -0,0 +1,97 @@
+use serde_json::Value;
+
+use crate::Message;
+
+/// A cap on the size of one method's messages, measured as `Message::byte_len`
+/// (their `Content-Length`). Requests over it are answered with a
+/// `RequestFailed` error and notifications are dropped, unless trimming the
+/// string fields given to `truncate` brings them under it first.
+#[derive(Debug, Clone)]
+pub struct ParamsLimit {
+    max_bytes: usize,
+    truncate: Vec<String>,
+}
+
+impl ParamsLimit {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            truncate: Vec::new(),
+        }
+    }
+
+    /// A JSON pointer into the params, e.g. `/textDocument/text`, to a string
+    /// that is cut short instead of rejecting the message. Fields are trimmed
+    /// from the end, in the order they were added, until the message fits.
+    /// A truncated document is what the server sees from then on, so edits
+    /// past the cut will not apply.
+    pub fn truncate(mut self, pointer: &str) -> Self {
+        self.truncate.push(pointer.to_owned());
+        self
+    }
+
+    /// Trims `message` to the limit where the configured fields allow it,
+    /// returning whether it had to. Fails with the size it arrived with if it
+    /// is still over the limit.
+    pub(crate) fn enforce(&self, message: &mut Message) -> Result<bool, usize> {
+        let received = message.byte_len();
+        let mut len = received;
+        if len <= self.max_bytes {
+            return Ok(false);
+        }
+
+        for pointer in &self.truncate {
+            while len > self.max_bytes {
+                let Some(Value::String(text)) =
+                    params_mut(message).and_then(|params| params.pointer_mut(pointer))
+                else {
+                    break;
+                };
+                if text.is_empty() {
+                    break;
+                }
+                // Cut as many characters from the end as the excess takes on
+                // the wire, where escaped ones count for more than one byte.
+                let excess = len - self.max_bytes;
+                let mut cut = 0;
+                let keep = text
+                    .char_indices()
+                    .rev()
+                    .find(|(_, ch)| {
+                        cut += escaped_len(*ch);
+                        cut >= excess
+                    })
+                    .map_or(0, |(index, _)| index);
+                text.truncate(keep);
+                len = message.byte_len();
+            }
+        }
+
+        if len > self.max_bytes {
+            Err(received)
+        } else {
+            Ok(true)
+        }
+    }
+
+    pub(crate) fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+}
+
+/// The bytes `ch` takes in a JSON string as `serde_json` writes it.
+fn escaped_len(ch: char) -> usize {
+    match ch {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{8}' | '\u{c}' => 2,
+        '\0'..='\u{1f}' => 6,
+        _ => ch.len_utf8(),
+    }
+}
+
+fn params_mut(message: &mut Message) -> Option<&mut Value> {
+    match message {
+        Message::Request(request) => request.params.as_mut(),
+        Message::Notification(notification) => notification.params.as_mut(),
+        Message::Response(_) => None,
+    }
+}
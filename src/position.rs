@@ -0,0 +1,122 @@
+use serde_json::Value;
+
+/// How the `character` of an LSP position is counted, as negotiated through
+/// the server's `positionEncoding` capability. Servers that do not announce
+/// one use UTF-16, as the spec requires.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PositionEncoding {
+    /// `character` counts bytes.
+    Utf8,
+    #[default]
+    Utf16,
+    /// `character` counts Unicode scalar values.
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// Parses a `PositionEncodingKind` such as `"utf-8"`.
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "utf-8" => Some(PositionEncoding::Utf8),
+            "utf-16" => Some(PositionEncoding::Utf16),
+            "utf-32" => Some(PositionEncoding::Utf32),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PositionEncoding::Utf8 => "utf-8",
+            PositionEncoding::Utf16 => "utf-16",
+            PositionEncoding::Utf32 => "utf-32",
+        }
+    }
+
+    /// Reads `capabilities.positionEncoding` from the result of `initialize`,
+    /// falling back to UTF-16 when it is missing or unknown.
+    pub fn from_initialize_result(result: Option<&Value>) -> Self {
+        result
+            .and_then(|result| result.pointer("/capabilities/positionEncoding"))
+            .and_then(Value::as_str)
+            .and_then(Self::parse)
+            .unwrap_or_default()
+    }
+
+    fn units(self, ch: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => ch.len_utf8(),
+            PositionEncoding::Utf16 => ch.len_utf16(),
+            PositionEncoding::Utf32 => 1,
+        }
+    }
+}
+
+/// The byte offset in `text` of the position `line`:`character`. A
+/// `character` past the end of its line means the end of the line, and a line
+/// past the end of the text means the end of the text. Lines end at `\n`,
+/// `\r\n` or `\r`.
+pub fn offset(text: &str, line: usize, character: usize, encoding: PositionEncoding) -> usize {
+    let Some(start) = line_start(text, line) else {
+        return text.len();
+    };
+
+    let mut units = 0;
+    for (index, ch) in text[start..].char_indices() {
+        if units >= character || ch == '\n' || ch == '\r' {
+            return start + index;
+        }
+        units += encoding.units(ch);
+    }
+    text.len()
+}
+
+/// The position of byte `offset` in `text` as `(line, character)`. An offset
+/// inside a character is moved back to its start, and one past the end of the
+/// text means the end of the text.
+pub fn position(text: &str, offset: usize, encoding: PositionEncoding) -> (usize, usize) {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+
+    let before = &text[..offset];
+    let bytes = before.as_bytes();
+    let mut line = 0;
+    let mut start = 0;
+    for (index, byte) in bytes.iter().enumerate() {
+        let ends_line = match byte {
+            b'\n' => true,
+            // The `\n` of a `\r\n` ends the line instead.
+            b'\r' => text.as_bytes().get(index + 1) != Some(&b'\n'),
+            _ => false,
+        };
+        if ends_line {
+            line += 1;
+            start = index + 1;
+        }
+    }
+
+    let character = before[start..]
+        .chars()
+        .filter(|ch| *ch != '\r')
+        .map(|ch| encoding.units(ch))
+        .sum();
+    (line, character)
+}
+
+/// The byte offset at which `line` starts, or `None` if the text has fewer
+/// lines.
+fn line_start(text: &str, line: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut start = 0;
+    for _ in 0..line {
+        let line_break = bytes[start..]
+            .iter()
+            .position(|byte| matches!(byte, b'\n' | b'\r'))?;
+        start += line_break + 1;
+        if bytes[start - 1] == b'\r' && bytes.get(start) == Some(&b'\n') {
+            start += 1;
+        }
+    }
+    Some(start)
+}
@@ -1,11 +1,32 @@
 use crate::{Message, message::Direction};
 
+/// Where generated messages are queued relative to the main message. Messages
+/// going to the same peer are written in queue order; messages going to
+/// different peers travel on independent streams, so the order only says which
+/// one is handed to its writer first.
+///
+/// Hooks run for one message at a time per direction, and everything they
+/// return is queued before the next message is read. Requests a hook derives
+/// from a `didChange` therefore reach the server after that change and before
+/// the next one, which keeps them ordered per document as well. Requests that
+/// take time to prepare can be returned with `HookOutput::with_ordered_feed`
+/// keyed by the document URI, which keeps them in order among themselves.
+/// Messages sent through `ProxyHandle` from other tasks carry no such
+/// guarantee.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GeneratedOrder {
+    #[default]
+    AfterMessage,
+    BeforeMessage,
+}
+
 #[derive(Debug)]
 pub enum ProcessedMessage {
     Forward(Message),
     WithMessages {
         message: Message,
         generated_messages: Vec<(Direction, Message)>,
+        order: GeneratedOrder,
     },
     Ignore {
         generated_messages: Vec<(Direction, Message)>,
@@ -21,6 +42,13 @@ impl ProcessedMessage {
         }
     }
 
+    pub fn get_order(&self) -> GeneratedOrder {
+        match self {
+            ProcessedMessage::WithMessages { order, .. } => *order,
+            _ => GeneratedOrder::default(),
+        }
+    }
+
     pub fn get_generated_messages(&self) -> &[(Direction, Message)] {
         match self {
             ProcessedMessage::Forward(_) => &[],
@@ -37,6 +65,7 @@ impl ProcessedMessage {
             ProcessedMessage::WithMessages {
                 message,
                 generated_messages,
+                ..
             } => (Some(message), generated_messages),
             ProcessedMessage::Ignore { generated_messages } => (None, generated_messages),
         }
@@ -1,25 +1,797 @@
-use crate::Message;
-use crate::hooks::{Hook, HookError};
-use crate::message::Direction;
-use crate::processed_message::ProcessedMessage;
-use crate::transport::{read_message, write_message};
+use crate::clock::{self, Clock, TokioClock};
+use crate::coalesce::{CoalesceKeyFn, PeerQueue, superseded_key};
+use crate::conformance::{ProtocolCheck, ProtocolChecker, ProtocolViolation};
+use crate::correlation::{Correlator, HashMapCorrelator};
+use crate::dedup::{Duplicate, RequestDedup};
+use crate::documents::DocumentStore;
+use crate::handle::{
+    ConnectionId, Lifecycle, LifecycleState, Pause, PendingRequests, ProxyHandle, ResponseWaiters,
+    next_injected_id,
+};
+use crate::health::{HealthCheck, HealthEvent, Liveness};
+use crate::hooks::{
+    CatchPanic, Hook, HookDescriptor, HookError, HookErrorPolicy, HookKind, HookRegistry, MapHook,
+    MessageFeed,
+};
+use crate::message::{
+    Direction, ErrorCode, INTERNAL_ERROR, INVALID_PARAMS, JsonRpcField, LogFormat,
+    METHOD_NOT_FOUND, MessageParseError, MessageType, REQUEST_FAILED, TraceValue,
+};
+use crate::methods::is_standard_method;
+use crate::mirror::{Mirror, MirrorTap, run_mirror};
+use crate::outbound::{
+    self, ChannelClosed, Outbound, OutboundReceivers, Outgoing, Sequencer, recv_until_drained,
+};
+use crate::pairs::{PairTracker, RequestResponsePair};
+use crate::params_limit::ParamsLimit;
+use crate::position::PositionEncoding;
+use crate::processed_message::{GeneratedOrder, ProcessedMessage};
+use crate::rate_limit::{RateLimit, TokenBucket};
+use crate::recent::{RecentMessages, RecordFilter};
+use crate::reconnect::{ReconnectEvent, ReconnectPolicy};
+use crate::redact::Redactor;
+use crate::telemetry::{StatsCollector, Telemetry};
+use crate::transport::{
+    RawFrame, ReadOptions, TransportError, WriteOptions, is_valid_extra_header, read_frame,
+    read_raw_frame, serialize, write_bodies, write_bodies_counted, write_message,
+};
+#[cfg(unix)]
+use crate::transport::{bind_unix, connect_unix};
+use crate::writes::{WriteLog, WrittenMessage};
+use crate::{HookContext, Message, Request, RequestId, Response};
+use serde_json::Value;
+use std::borrow::BorrowMut;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::future::Future;
+#[cfg(unix)]
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::AtomicI64;
+#[cfg(feature = "compression")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::select;
 use tokio::sync::Mutex;
-use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{Receiver, UnboundedReceiver};
+use tokio::sync::watch;
+use tokio::task::{self, JoinError, JoinSet};
+use tokio_util::sync::CancellationToken;
 
-pub struct Proxy {
-    hooks: HashMap<String, Arc<dyn Hook>>,
-    pending_requests: HashMap<i64, String>,
+const DEFAULT_WRITE_COALESCE_MAX: usize = 16;
+const DEFAULT_HALF_CLOSE_GRACE: Duration = Duration::from_secs(2);
+const DEFAULT_EOF_GRACE: Duration = Duration::from_secs(1);
+const DEFAULT_PAIR_TIMEOUT: Duration = Duration::from_secs(30);
+
+enum Body {
+    Raw(Arc<[u8]>),
+    Serialized(Vec<u8>),
+}
+
+impl AsRef<[u8]> for Body {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Body::Raw(raw) => raw,
+            Body::Serialized(serialized) => serialized,
+        }
+    }
+}
+
+type HeaderFn = Arc<dyn Fn(&Message) -> Vec<(String, String)> + Send + Sync>;
+type StopFn = Box<dyn Fn(&Message, Direction) -> bool + Send + Sync>;
+
+struct WriteRetry {
+    methods: HashSet<String>,
+    max_retries: u32,
+    delay: Duration,
+}
+
+/// Holds messages from the client between its `initialize` request and the
+/// response, which the spec forbids apart from `exit`.
+struct InitGate {
+    /// The id of the `initialize` request while its response is outstanding.
+    awaiting: std::sync::Mutex<Option<RequestId>>,
+    open: watch::Sender<bool>,
+}
+
+impl InitGate {
+    fn new() -> Self {
+        Self {
+            awaiting: std::sync::Mutex::new(None),
+            open: watch::Sender::new(true),
+        }
+    }
+
+    /// Waits until a message read from the client may be processed. Closes
+    /// the gate behind an `initialize` request.
+    async fn admit(&self, message: &Result<Message, MessageParseError>) {
+        match message {
+            Ok(Message::Request(request)) if request.method == "initialize" => {
+                *self.awaiting.lock().unwrap() = Some(request.id.clone());
+                self.open.send_replace(false);
+                return;
+            }
+            Ok(Message::Notification(notification)) if notification.method == "exit" => return,
+            _ => {}
+        }
+
+        let _ = self.open.subscribe().wait_for(|open| *open).await;
+    }
+
+    /// Whether `dispatch`, sent toward `destination`, gives the client the
+    /// response to `initialize`, whether from the server or from a hook.
+    fn is_answered_by(&self, dispatch: &Dispatch, destination: Direction) -> bool {
+        let awaiting = self.awaiting.lock().unwrap();
+        let Some(id) = awaiting.as_ref() else {
+            return false;
+        };
+
+        let main = dispatch
+            .get_message()
+            .filter(|_| destination == Direction::ToClient);
+        let generated = dispatch
+            .get_generated_messages()
+            .iter()
+            .filter(|(direction, _)| *direction == Direction::ToClient)
+            .map(|(_, message)| message);
+
+        main.into_iter()
+            .chain(generated)
+            .any(|message| matches!(message, Message::Response(response) if response.id == *id))
+    }
+
+    fn open(&self) {
+        *self.awaiting.lock().unwrap() = None;
+        self.open.send_replace(true);
+    }
+}
+
+pub struct Proxy<S = ()> {
+    state: Arc<ProxyState<S>>,
+    write_coalesce_max: usize,
+    idle_timeout: Option<Duration>,
+    half_close_grace: Duration,
+    eof_grace: Duration,
+    outbound: Outbound,
+    receivers: OutboundReceivers,
+}
+
+struct ProxyState<S> {
+    connection_id: ConnectionId,
+    app_state: Arc<S>,
+    hooks: HookRegistry<S>,
+    pending_requests: PendingRequests,
+    max_pending_requests: Option<usize>,
+    rate_limits: HashMap<String, TokenBucket>,
+    clock: Arc<dyn Clock>,
+    params_limits: HashMap<String, ParamsLimit>,
+    pairs: PairTracker,
+    /// Publishes what the serialized writer writes; see `subscribe_writes`.
+    writes: WriteLog,
+    serialized_writes: bool,
+    response_waiters: ResponseWaiters,
+    next_request_id: Arc<AtomicI64>,
+    liveness: Arc<Liveness>,
+    health_check: Option<HealthCheck>,
+    telemetry: Option<Telemetry>,
+    stats: Option<StatsCollector>,
+    initialize_params: Mutex<Option<Value>>,
+    lifecycle: Arc<Lifecycle>,
+    trace: std::sync::Mutex<TraceValue>,
+    /// From the server's `initialize` result.
+    position_encoding: std::sync::Mutex<PositionEncoding>,
+    trace_to_stderr: bool,
+    redactor: Option<Redactor>,
+    recent: Option<Arc<RecentMessages>>,
+    dump_recent_on_error: bool,
+    protocol_check: Option<ProtocolCheck>,
+    protocol: ProtocolChecker,
+    pause: Arc<Pause>,
+    /// Orders the feeds hooks return with `HookOutput::with_ordered_feed`.
+    sequencer: Sequencer,
+    workspace_roots: std::sync::Mutex<Arc<[String]>>,
+    shutdown: CancellationToken,
+    /// Cancelled once forwarding winds down after an EOF, so the writers exit
+    /// as soon as their queues are empty.
+    draining: CancellationToken,
+    activity: Notify,
+    allowlist: Option<HashSet<String>>,
+    /// Set when unknown `$/` methods are filtered; holds the custom methods
+    /// whitelisted with `with_known_methods`.
+    dollar_filter: Option<HashSet<String>>,
+    observe_only: bool,
+    pass_through_unparsed: bool,
+    preserve_unmodified_bytes: bool,
+    cancel_pending_on_eof: bool,
+    documents: Option<DocumentStore>,
+    hook_error_report: Option<MessageType>,
+    hook_error_policy: HookErrorPolicy,
+    read_options: ReadOptions,
+    outgoing_headers: OutgoingHeaders,
+    jsonrpc_field: JsonRpcField,
+    coalesce_key: Option<CoalesceKeyFn>,
+    stop_after: Option<StopFn>,
+    write_retry: Option<WriteRetry>,
+    dedup: Option<RequestDedup>,
+    init_gate: Option<InitGate>,
+    /// Set by `forward_mirrored` before forwarding starts.
+    mirror: OnceLock<Arc<MirrorTap>>,
+    /// Set by `forward_stdio_to_child`: how long the server it spawned gets
+    /// to answer the `shutdown` sent for a client that disconnected early.
+    child_shutdown_timeout: OnceLock<Duration>,
+    #[cfg(feature = "schema")]
+    schemas: HashMap<String, jsonschema::Validator>,
+    #[cfg(feature = "compression")]
+    compression: Compression,
+}
+
+#[derive(Default)]
+struct OutgoingHeaders {
+    client: Option<HeaderFn>,
+    server: Option<HeaderFn>,
+}
+
+#[cfg(feature = "compression")]
+#[derive(Default)]
+struct Compression {
+    client_min_size: Option<usize>,
+    server_min_size: Option<usize>,
+    client_accepts_gzip: AtomicBool,
+    server_accepts_gzip: AtomicBool,
+}
+
+impl<S: Send + Sync + 'static> ProxyState<S> {
+    fn stops_after(&self, outgoing: &Outgoing, peer: Direction) -> bool {
+        self.stop_after
+            .as_ref()
+            .zip(outgoing.message.as_ref())
+            .is_some_and(|(stop_after, message)| stop_after(message, peer))
+    }
+
+    /// Writes a line to stderr tagged with the session it belongs to.
+    fn log(&self, message: std::fmt::Arguments) {
+        eprintln!("[{}] {}", self.connection_id, message);
+    }
+
+    fn handle(&self, outbound: Outbound) -> ProxyHandle {
+        ProxyHandle {
+            connection_id: self.connection_id,
+            outbound,
+            response_waiters: Arc::clone(&self.response_waiters),
+            pending_requests: self.pending_requests.clone(),
+            next_request_id: Arc::clone(&self.next_request_id),
+            liveness: Arc::clone(&self.liveness),
+            lifecycle: Arc::clone(&self.lifecycle),
+            recent: self.recent.clone(),
+            pause: Arc::clone(&self.pause),
+            clock: Arc::clone(&self.clock),
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    fn observe_frame(&self, peer: Direction, headers: &[(String, String)]) {
+        let accepts_gzip =
+            crate::transport::find_header(headers, "Accept-Encoding").is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|e| e.trim().eq_ignore_ascii_case("gzip"))
+            });
+
+        if accepts_gzip {
+            match peer {
+                Direction::ToClient => &self.compression.client_accepts_gzip,
+                Direction::ToServer => &self.compression.server_accepts_gzip,
+            }
+            .store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn observe_frame(&self, _peer: Direction, _headers: &[(String, String)]) {}
+
+    #[cfg(feature = "compression")]
+    fn write_options(&self, peer: Direction) -> WriteOptions {
+        let (min_size, peer_accepts_gzip) = match peer {
+            Direction::ToClient => (
+                self.compression.client_min_size,
+                &self.compression.client_accepts_gzip,
+            ),
+            Direction::ToServer => (
+                self.compression.server_min_size,
+                &self.compression.server_accepts_gzip,
+            ),
+        };
+
+        WriteOptions {
+            accept_gzip: min_size.is_some(),
+            gzip_min_size: min_size.filter(|_| peer_accepts_gzip.load(Ordering::Relaxed)),
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn write_options(&self, _peer: Direction) -> WriteOptions {
+        WriteOptions::default()
+    }
+
+    /// How frames from `peer` are read: gzip bodies are only accepted on a
+    /// link where the proxy advertises that it accepts them.
+    #[cfg(feature = "compression")]
+    fn read_options_for(&self, peer: Direction) -> ReadOptions {
+        let min_size = match peer {
+            Direction::ToClient => self.compression.client_min_size,
+            Direction::ToServer => self.compression.server_min_size,
+        };
+
+        ReadOptions {
+            accept_gzip: min_size.is_some(),
+            ..self.read_options.clone()
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn read_options_for(&self, _peer: Direction) -> ReadOptions {
+        self.read_options.clone()
+    }
+
+    /// Serializes a queued message for `peer` together with the extra headers
+    /// the builder asked for, dropping any that would break the framing.
+    /// Messages forwarded unchanged reuse the bytes they were received as.
+    fn outgoing_frame(
+        &self,
+        peer: Direction,
+        outgoing: Outgoing,
+    ) -> std::io::Result<(Body, Vec<(String, String)>)> {
+        let Outgoing { message, raw } = outgoing;
+        if self.protocol_check.is_some()
+            && let Some(message) = &message
+        {
+            self.protocol.record_sent(peer, message);
+        }
+        let header_fn = match peer {
+            Direction::ToClient => &self.outgoing_headers.client,
+            Direction::ToServer => &self.outgoing_headers.server,
+        };
+
+        let headers = match header_fn.as_ref().zip(message.as_ref()) {
+            Some((header_fn, message)) => header_fn(message)
+                .into_iter()
+                .filter(|(name, value)| {
+                    let valid = is_valid_extra_header(name, value);
+                    if !valid {
+                        self.log(format_args!("Dropping invalid header: {:?}", name));
+                    }
+                    valid
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let body = match (raw, message) {
+            (Some(_), Some(message)) if self.jsonrpc_field == JsonRpcField::Never => {
+                Body::Serialized(serialize(&JsonRpcField::strip(message.to_value()))?)
+            }
+            (Some(raw), _) => Body::Raw(raw),
+            (None, Some(message)) if self.jsonrpc_field == JsonRpcField::Never => {
+                Body::Serialized(serialize(&JsonRpcField::strip(message.to_value()))?)
+            }
+            (None, Some(message)) => Body::Serialized(serialize(&message.to_value())?),
+            (None, None) => unreachable!("unparsed bodies are always queued raw"),
+        };
+        Ok((body, headers))
+    }
+
+    /// The built-in checks and hooks a message for `method` goes through, in
+    /// the order `process_message` applies them.
+    fn describe_dispatch(&self, method: &str) -> Vec<HookDescriptor> {
+        let builtin = |name: &str, direction: Option<Direction>| HookDescriptor {
+            name: name.to_owned(),
+            direction,
+            kind: HookKind::Builtin,
+        };
+        let mut steps = Vec::new();
+
+        if self.protocol_check.is_some() {
+            steps.push(builtin("check_protocol", None));
+        }
+        if self.params_limits.contains_key(method) {
+            steps.push(builtin("limit_params", None));
+        }
+        if self.allowlist.is_some() {
+            steps.push(builtin("allowlist", None));
+        }
+        if self.is_unknown_dollar_method(method) {
+            steps.push(builtin("filter_unknown_dollar_methods", None));
+        }
+        #[cfg(feature = "schema")]
+        if self.schemas.contains_key(method) {
+            steps.push(builtin("with_schema", None));
+        }
+        if self
+            .dedup
+            .as_ref()
+            .is_some_and(|dedup| dedup.covers(method))
+        {
+            steps.push(builtin("dedup_requests", Some(Direction::ToServer)));
+        }
+        if self.rate_limits.contains_key(method) {
+            steps.push(builtin("rate_limit", Some(Direction::ToServer)));
+        }
+        if self.documents.is_some()
+            && matches!(
+                method,
+                "textDocument/didOpen" | "textDocument/didChange" | "textDocument/didClose"
+            )
+        {
+            steps.push(builtin(
+                "normalize_document_sync",
+                Some(Direction::ToServer),
+            ));
+        }
+
+        steps.extend(self.hooks.describe(method));
+        steps
+    }
+
+    /// Validates `params` against the schema registered for `method`, returning
+    /// a description of the first violation.
+    #[cfg(feature = "schema")]
+    fn check_params(&self, method: &str, params: Option<&Value>) -> Option<String> {
+        let validator = self.schemas.get(method)?;
+        let params = params.unwrap_or(&Value::Null);
+
+        validator.validate(params).err().map(|error| {
+            format!(
+                "Invalid params for {} at '{}': {}",
+                method,
+                error.instance_path(),
+                error
+            )
+        })
+    }
+
+    #[cfg(not(feature = "schema"))]
+    fn check_params(&self, _method: &str, _params: Option<&Value>) -> Option<String> {
+        None
+    }
+
+    /// Whether `method` is an implementation-defined `$/` method that nothing
+    /// along the way is known to handle, when that filtering is enabled.
+    fn is_unknown_dollar_method(&self, method: &str) -> bool {
+        let Some(known_methods) = &self.dollar_filter else {
+            return false;
+        };
+
+        method.starts_with("$/")
+            && !is_standard_method(method)
+            && !known_methods.contains(method)
+            && !self.hooks.contains(method)
+    }
+
+    /// Remembers what is needed to bring a reconnected server back to the
+    /// state the client believes it is in.
+    async fn observe_outgoing(&self, message: Option<&Message>) {
+        match message {
+            Some(Message::Request(request)) if request.method == "initialize" => {
+                self.observe_lifecycle("initialize", self.lifecycle.initialize());
+                self.observe_trace(request.params.as_ref(), "/trace");
+                if let Some(params) = &request.params {
+                    *self.workspace_roots.lock().unwrap() = initial_workspace_roots(params).into();
+                }
+                *self.initialize_params.lock().await = request.params.clone();
+            }
+            Some(Message::Notification(notification)) if notification.method == "$/setTrace" => {
+                self.observe_trace(notification.params.as_ref(), "/value");
+            }
+            Some(Message::Notification(notification))
+                if notification.method == "workspace/didChangeWorkspaceFolders" =>
+            {
+                if let Some(event) = notification
+                    .params
+                    .as_ref()
+                    .and_then(|params| params.get("event"))
+                {
+                    self.change_workspace_roots(event);
+                }
+            }
+            Some(Message::Notification(notification)) if notification.method == "initialized" => {
+                self.observe_lifecycle("initialized", self.lifecycle.initialized());
+            }
+            Some(Message::Request(request)) if request.method == "shutdown" => {
+                self.observe_lifecycle("shutdown", self.lifecycle.request_shutdown());
+            }
+            Some(Message::Notification(notification)) if notification.method == "exit" => {
+                self.observe_lifecycle("exit", self.lifecycle.exit());
+            }
+            _ => {}
+        }
+    }
+
+    fn observe_lifecycle(&self, method: &str, transition: Result<(), LifecycleState>) {
+        if let Err(previous) = transition {
+            self.log(format_args!(
+                "The client sent {} while {:?}",
+                method, previous
+            ));
+        }
+    }
+
+    fn observe_trace(&self, params: Option<&Value>, pointer: &str) {
+        if let Some(trace) = params
+            .and_then(|params| params.pointer(pointer))
+            .and_then(Value::as_str)
+            .and_then(TraceValue::parse)
+        {
+            *self.trace.lock().unwrap() = trace;
+        }
+    }
+
+    fn change_workspace_roots(&self, event: &Value) {
+        let removed = folder_uris(event.get("removed"));
+        let added = folder_uris(event.get("added"));
+
+        let mut roots = self.workspace_roots.lock().unwrap();
+        let mut updated: Vec<String> = roots
+            .iter()
+            .filter(|root| !removed.contains(root))
+            .cloned()
+            .collect();
+        for uri in added {
+            if !updated.contains(&uri) {
+                updated.push(uri);
+            }
+        }
+        *roots = updated.into();
+    }
+
+    fn workspace_roots(&self) -> Arc<[String]> {
+        Arc::clone(&self.workspace_roots.lock().unwrap())
+    }
+
+    /// Reports any protocol violation of a message read from `peer`, and
+    /// returns it if the message should be dropped for it.
+    fn check_protocol(&self, peer: Direction, message: &Message) -> Option<ProtocolViolation> {
+        let check = self.protocol_check.as_ref()?;
+        let violation = self.protocol.check(peer, message)?;
+        if !check.notify(&violation) {
+            self.log(format_args!("Protocol violation: {}", violation));
+        }
+        check.drops_violations().then_some(violation)
+    }
+
+    /// Logs the messages kept by `keep_recent`, if `dump_recent_on_error`
+    /// asked for it.
+    fn dump_recent(&self) {
+        let Some(recent) = self.recent.as_ref().filter(|_| self.dump_recent_on_error) else {
+            return;
+        };
+
+        for direction in [Direction::ToServer, Direction::ToClient] {
+            for message in recent.snapshot(direction) {
+                let message = match &self.redactor {
+                    Some(redactor) => redactor.redact(&message).to_log_string(LogFormat::Compact),
+                    None => message.to_log_string(LogFormat::Compact),
+                };
+                self.log(format_args!("[recent] {:?}: {}", direction, message));
+            }
+        }
+    }
+
+    fn trace(&self) -> TraceValue {
+        *self.trace.lock().unwrap()
+    }
+
+    fn position_encoding(&self) -> PositionEncoding {
+        *self.position_encoding.lock().unwrap()
+    }
+
+    /// Whether responses from the server whose request has no hook can be
+    /// forwarded without being parsed: none of the enabled features look at
+    /// them.
+    fn streams_unhooked_responses(&self) -> bool {
+        self.stats.is_none()
+            && self.recent.is_none()
+            && self.dedup.is_none()
+            && self.stop_after.is_none()
+            && self.coalesce_key.is_none()
+            && self.outgoing_headers.client.is_none()
+            && self.mirror.get().is_none()
+            && !self.pairs.is_active()
+            && !self.writes.is_active()
+            && self.protocol_check.is_none()
+            && self.jsonrpc_field != JsonRpcField::Never
+            && !self.traces_verbosely()
+    }
+
+    /// Logs a forwarded message to stderr while the client has verbose
+    /// tracing enabled, if `trace_to_stderr` asked for it. With
+    /// `serialized_writes` the writer logs messages instead, as it writes
+    /// them, tagged with their sequence number.
+    fn trace_message(&self, destination: Direction, dispatch: &Dispatch) {
+        if !self.serialized_writes
+            && let Some(message) = dispatch.get_message()
+        {
+            self.log_trace(None, destination, message);
+        }
+    }
+
+    fn traces_verbosely(&self) -> bool {
+        self.trace_to_stderr && self.trace() == TraceValue::Verbose
+    }
+
+    /// Logs `message` for `trace_to_stderr`, with the sequence number the
+    /// serialized writer gave it if any.
+    fn log_trace(&self, sequence: Option<u64>, destination: Direction, message: &Message) {
+        if self.traces_verbosely() {
+            let message = match &self.redactor {
+                Some(redactor) => redactor.redact(message).to_log_string(LogFormat::Compact),
+                None => message.to_log_string(LogFormat::Compact),
+            };
+            match sequence {
+                Some(sequence) => self.log(format_args!(
+                    "[trace #{}] {:?}: {}",
+                    sequence, destination, message
+                )),
+                None => self.log(format_args!("[trace] {:?}: {}", destination, message)),
+            }
+        }
+    }
 }
 
-impl Proxy {
-    fn new(hooks: HashMap<String, Arc<dyn Hook>>) -> Self {
+impl<S: Send + Sync + 'static> Proxy<S> {
+    fn new(builder: ProxyBuilder<S>) -> Self {
+        let (outbound, receivers) = outbound::channel(builder.serialized_writes);
+        let connection_id = ConnectionId::next();
+
         Self {
-            hooks,
-            pending_requests: HashMap::new(),
+            state: Arc::new(ProxyState {
+                connection_id,
+                app_state: Arc::new(builder.state),
+                hooks: builder.hooks,
+                pending_requests: PendingRequests::new(
+                    builder.correlator,
+                    Arc::clone(&builder.clock),
+                    builder.telemetry.is_some(),
+                ),
+                max_pending_requests: builder.max_pending_requests,
+                rate_limits: builder
+                    .rate_limits
+                    .into_iter()
+                    .map(|(method, limit)| (method, TokenBucket::new(limit, builder.clock.now())))
+                    .collect(),
+                params_limits: builder.params_limits,
+                clock: Arc::clone(&builder.clock),
+                pairs: PairTracker::new(
+                    builder.pair_timeout,
+                    builder.redactor.clone(),
+                    Arc::clone(&builder.clock),
+                ),
+                writes: WriteLog::new(builder.redactor.clone()),
+                serialized_writes: builder.serialized_writes,
+                response_waiters: ResponseWaiters::default(),
+                next_request_id: Arc::new(AtomicI64::new(-1)),
+                liveness: Arc::default(),
+                health_check: builder.health_check,
+                stats: builder
+                    .telemetry
+                    .as_ref()
+                    .map(|_| StatsCollector::default()),
+                telemetry: builder.telemetry,
+                initialize_params: Mutex::new(None),
+                lifecycle: Arc::default(),
+                trace: std::sync::Mutex::new(TraceValue::Off),
+                position_encoding: std::sync::Mutex::default(),
+                trace_to_stderr: builder.trace_to_stderr,
+                redactor: builder.redactor,
+                recent: builder
+                    .keep_recent
+                    .map(|capacity| Arc::new(RecentMessages::new(capacity, builder.record_filter))),
+                dump_recent_on_error: builder.dump_recent_on_error,
+                protocol_check: builder.protocol_check,
+                protocol: ProtocolChecker::default(),
+                pause: Arc::default(),
+                sequencer: Sequencer::default(),
+                workspace_roots: std::sync::Mutex::new(Arc::new([])),
+                shutdown: CancellationToken::new(),
+                draining: CancellationToken::new(),
+                activity: Notify::new(),
+                allowlist: builder.allowlist,
+                dollar_filter: builder
+                    .filter_unknown_dollar_methods
+                    .then_some(builder.known_methods),
+                observe_only: builder.observe_only,
+                pass_through_unparsed: builder.pass_through_unparsed,
+                preserve_unmodified_bytes: builder.preserve_unmodified_bytes,
+                cancel_pending_on_eof: builder.cancel_pending_on_eof,
+                documents: builder
+                    .normalize_document_sync
+                    .then(|| DocumentStore::new(connection_id)),
+                hook_error_report: builder.hook_error_report,
+                hook_error_policy: builder.hook_error_policy,
+                read_options: builder.read_options,
+                outgoing_headers: builder.outgoing_headers,
+                jsonrpc_field: builder.jsonrpc_field,
+                coalesce_key: builder.coalesce_key,
+                stop_after: builder.stop_after,
+                write_retry: builder.write_retry,
+                dedup: builder.dedup,
+                init_gate: builder.hold_until_initialized.then(InitGate::new),
+                mirror: OnceLock::new(),
+                child_shutdown_timeout: OnceLock::new(),
+                #[cfg(feature = "schema")]
+                schemas: builder.schemas,
+                #[cfg(feature = "compression")]
+                compression: builder.compression,
+            }),
+            write_coalesce_max: builder.write_coalesce_max,
+            idle_timeout: builder.idle_timeout,
+            half_close_grace: builder.half_close_grace,
+            eof_grace: builder.eof_grace,
+            outbound,
+            receivers,
+        }
+    }
+
+    pub fn handle(&self) -> ProxyHandle {
+        self.state.handle(self.outbound.clone())
+    }
+
+    /// Identifies this proxy's session. Every line the proxy logs is prefixed
+    /// with it, and hooks can read it from `HookContext::connection_id`.
+    pub fn connection_id(&self) -> ConnectionId {
+        self.state.connection_id
+    }
+
+    /// The application state hooks see through `HookContext::state`, e.g. to
+    /// keep changing it from outside once the proxy is forwarding.
+    pub fn state(&self) -> Arc<S> {
+        Arc::clone(&self.state.app_state)
+    }
+
+    /// What a message for `method` goes through before it is forwarded: the
+    /// built-in checks configured for it, then the hooks that run for it in
+    /// the order they run, e.g. a hook followed by the `map_request` closures
+    /// added after it. Hooks that apply to one direction only are listed per
+    /// direction. Useful for finding out why a hook did or did not fire.
+    pub fn describe_dispatch(&self, method: &str) -> Vec<HookDescriptor> {
+        self.state.describe_dispatch(method)
+    }
+
+    /// Returns a stream of completed requests, each paired with its response
+    /// and latency, in both directions. Requests left unanswered for the pair
+    /// timeout are reported with `response: None`; notifications are not
+    /// reported. Pairs are dropped if the receiver falls behind. Subscribe
+    /// before calling `forward`; requests are only tracked while someone is
+    /// listening.
+    pub fn subscribe_pairs(&self) -> Receiver<RequestResponsePair> {
+        self.state.pairs.subscribe()
+    }
+
+    /// Returns a stream of the messages written to either peer, each with
+    /// the sequence number that gives its place in the total order. Only
+    /// published with `serialized_writes`, whose single writer assigns the
+    /// numbers. Messages are dropped if the receiver falls behind, so a gap
+    /// in the numbers means a missed message. Subscribe before calling
+    /// `forward`.
+    pub fn subscribe_writes(&self) -> Receiver<WrittenMessage> {
+        self.state.writes.subscribe()
+    }
+
+    /// Returns the per-message processing for messages travelling in
+    /// `direction` as a `tower::Service`, so tower middleware can be layered
+    /// around it. Use one service per direction: `Direction::ToServer` for
+    /// messages from the client and `Direction::ToClient` for messages from the
+    /// server. Both share this proxy's hooks and request tracking (requires the
+    /// `tower` feature).
+    #[cfg(feature = "tower")]
+    pub fn service(&self, direction: Direction) -> ProxyService<S> {
+        ProxyService {
+            state: Arc::clone(&self.state),
+            handle: self.handle(),
+            direction,
         }
     }
 
@@ -36,227 +808,1887 @@ impl Proxy {
         CR: AsyncReadExt + Unpin + Send + 'static,
         CW: AsyncWriteExt + Unpin + Send + 'static,
     {
-        let hooks = Arc::new(Mutex::new(self.hooks));
-        let pending_requests = Arc::new(Mutex::new(self.pending_requests));
-
-        let (client_sender, mut client_receiver) = mpsc::unbounded_channel::<Message>();
-        let (server_sender, mut server_receiver) = mpsc::unbounded_channel::<Message>();
-
-        let server_message_sender = server_sender.clone();
-        let client_message_sender = client_sender.clone();
-        let hooks_client = Arc::clone(&hooks);
-        let pending_requests_client = Arc::clone(&pending_requests);
-        let server_to_client_task = tokio::spawn(async move {
-            forward_to_client(
-                hooks_client,
-                pending_requests_client,
-                server_reader,
-                server_message_sender,
-                client_message_sender,
-            )
+        let Proxy {
+            state,
+            write_coalesce_max,
+            idle_timeout,
+            half_close_grace,
+            eof_grace,
+            outbound,
+            receivers,
+        } = self;
+
+        start_hooks(&state).await;
+
+        let handle = state.handle(outbound.clone());
+        let mut tasks = JoinSet::new();
+
+        let outbound_client = outbound.clone();
+        let state_client = Arc::clone(&state);
+        let server_reader_task =
+            tasks
+                .spawn(async move {
+                    forward_to_client(state_client, server_reader, outbound_client).await
+                })
+                .id();
+
+        let state_server = Arc::clone(&state);
+        let outbound_server = outbound.clone();
+        let client_reader_task =
+            tasks
+                .spawn(async move {
+                    forward_to_server(state_server, client_reader, outbound_server).await
+                })
+                .id();
+
+        let mut writers = HashSet::new();
+        match receivers {
+            OutboundReceivers::Split { client, server } => {
+                let server_writer = tasks.spawn(write_to_peer(
+                    Arc::clone(&state),
+                    Direction::ToServer,
+                    server_writer,
+                    PeerQueue::new(server),
+                    outbound.clone(),
+                    write_coalesce_max,
+                ));
+                writers.insert(server_writer.id());
+
+                let client_writer = tasks.spawn(write_to_peer(
+                    Arc::clone(&state),
+                    Direction::ToClient,
+                    client_writer,
+                    PeerQueue::new(client),
+                    outbound,
+                    write_coalesce_max,
+                ));
+                writers.insert(client_writer.id());
+            }
+            OutboundReceivers::Serialized(receiver) => {
+                let writer = tasks.spawn(write_serialized(
+                    Arc::clone(&state),
+                    server_writer,
+                    client_writer,
+                    receiver,
+                    write_coalesce_max,
+                ));
+                writers.insert(writer.id());
+            }
+        }
+
+        let tasks = ForwardTasks {
+            set: tasks,
+            client_reader: client_reader_task,
+            server_reader: server_reader_task,
+            readers: HashSet::from([client_reader_task, server_reader_task]),
+            writers,
+        };
+        run_tasks(
+            tasks,
+            &state,
+            handle,
+            idle_timeout,
+            half_close_grace,
+            eof_grace,
+        )
+        .await
+    }
+
+    /// Like `forward`, but stops once `shutdown` completes. The cancellation
+    /// token handed to hooks through `HookContext::cancellation` is cancelled
+    /// at that point, and hooks still running get until `eof_grace` to return,
+    /// so hooks awaiting external work can wind down.
+    pub async fn forward_with_shutdown<SR, SW, CR, CW, F>(
+        self,
+        server_reader: SR,
+        server_writer: SW,
+        client_reader: CR,
+        client_writer: CW,
+        shutdown: F,
+    ) -> std::io::Result<()>
+    where
+        SR: AsyncReadExt + Unpin + Send + 'static,
+        SW: AsyncWriteExt + Unpin + Send + 'static,
+        CR: AsyncReadExt + Unpin + Send + 'static,
+        CW: AsyncWriteExt + Unpin + Send + 'static,
+        F: Future<Output = ()>,
+    {
+        let token = self.state.shutdown.clone();
+        let mut forward = std::pin::pin!(self.forward(
+            server_reader,
+            server_writer,
+            client_reader,
+            client_writer
+        ));
+
+        select! {
+            result = &mut forward => return result,
+            _ = shutdown => token.cancel(),
+        }
+
+        forward.await
+    }
+
+    /// Like `forward`, over Unix domain sockets: connects to the server
+    /// listening at `server_path`, then binds `client_path` and forwards for
+    /// the first client to connect. The socket file at `client_path` is
+    /// removed once that client is accepted, so later clients are refused
+    /// rather than left waiting.
+    #[cfg(unix)]
+    pub async fn forward_unix(
+        self,
+        server_path: impl AsRef<Path>,
+        client_path: impl AsRef<Path>,
+    ) -> std::io::Result<()> {
+        let (server_reader, server_writer) = connect_unix(server_path).await?;
+        let client_path = client_path.as_ref();
+        let listener = bind_unix(client_path)?;
+        let accepted = listener.accept().await;
+        drop(listener);
+        let _ = std::fs::remove_file(client_path);
+
+        let (client_reader, client_writer) = accepted?.0.into_split();
+        self.forward(server_reader, server_writer, client_reader, client_writer)
             .await
-        });
+    }
 
-        let hooks_server = Arc::clone(&hooks);
-        let pending_requests_server = Arc::clone(&pending_requests);
-        let client_to_server_task = tokio::spawn(async move {
-            forward_to_server(
-                hooks_server,
-                pending_requests_server,
-                client_reader,
-                server_sender,
-                client_sender,
+    /// Forwards between this process's stdin and stdout, as the client, and a
+    /// server spawned from `command`, which is what an editor expects of a
+    /// proxy it launches in place of the server. Every batch written to
+    /// stdout is flushed. The server's stderr is inherited unless `command`
+    /// says otherwise. If the client disconnects without sending `exit`, e.g.
+    /// because the editor was killed, the server is sent the `shutdown`
+    /// request and `exit` notification it would have sent, waiting up to
+    /// `eof_grace` for the `shutdown` response. Once forwarding stops the
+    /// server is given `eof_grace` to exit on its own before it is killed.
+    /// Exit the process when this returns: a read from stdin still blocked in
+    /// the background keeps the runtime from shutting down.
+    pub async fn forward_stdio_to_child(
+        self,
+        command: impl Into<tokio::process::Command>,
+    ) -> std::io::Result<()> {
+        let mut command = command.into();
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true);
+        let mut child = command.spawn()?;
+        let (Some(server_writer), Some(server_reader)) = (child.stdin.take(), child.stdout.take())
+        else {
+            unreachable!("stdin and stdout were piped");
+        };
+
+        let clock = Arc::clone(&self.state.clock);
+        let grace = self.eof_grace;
+        let _ = self.state.child_shutdown_timeout.set(grace);
+        let result = self
+            .forward(
+                server_reader,
+                server_writer,
+                tokio::io::stdin(),
+                tokio::io::stdout(),
             )
-            .await
-        });
+            .await;
 
-        let write_to_server = tokio::spawn(async move {
-            let mut writer = server_writer;
-            while let Some(msg) = server_receiver.recv().await {
-                if write_message(&mut writer, &msg.to_value()).await.is_err() {
-                    break;
+        if clock::timeout(&*clock, grace, child.wait()).await.is_none() {
+            let _ = child.kill().await;
+        }
+        result
+    }
+
+    /// Like `forward`, but every request and notification the client sends,
+    /// after hooks have run, is also sent to `mirror`. Only the primary
+    /// server's responses reach the client. A mirror that fails or closes is
+    /// reported on stderr and the session carries on without it.
+    pub async fn forward_mirrored<SR, SW, CR, CW, MR, MW>(
+        self,
+        server_reader: SR,
+        server_writer: SW,
+        client_reader: CR,
+        client_writer: CW,
+        mirror: Mirror<MR, MW>,
+    ) -> std::io::Result<()>
+    where
+        SR: AsyncReadExt + Unpin + Send + 'static,
+        SW: AsyncWriteExt + Unpin + Send + 'static,
+        CR: AsyncReadExt + Unpin + Send + 'static,
+        CW: AsyncWriteExt + Unpin + Send + 'static,
+        MR: AsyncReadExt + Unpin + Send + 'static,
+        MW: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        let (mirror_reader, mirror_writer, tap, receiver) = mirror.into_parts();
+        let tap = Arc::new(tap);
+        let _ = self.state.mirror.set(Arc::clone(&tap));
+
+        let state = Arc::clone(&self.state);
+        tokio::spawn(async move {
+            let options = state.read_options.clone();
+            select! {
+                result = run_mirror(tap, mirror_reader, mirror_writer, receiver, options) => {
+                    if let Err(e) = result {
+                        state.log(format_args!("Mirror server failed: {}", e));
+                    }
                 }
+                _ = state.shutdown.cancelled() => {}
             }
-            Ok::<(), std::io::Error>(())
         });
 
-        let write_to_client = tokio::spawn(async move {
-            let mut writer = client_writer;
-            while let Some(msg) = client_receiver.recv().await {
-                if write_message(&mut writer, &msg.to_value()).await.is_err() {
-                    break;
-                }
-            }
-            Ok::<(), std::io::Error>(())
-        });
+        self.forward(server_reader, server_writer, client_reader, client_writer)
+            .await
+    }
+
+    /// Like `forward`, but the server connection is opened by `connect` and
+    /// re-opened with exponential backoff whenever it drops, unless the client
+    /// has already sent `exit`. After reconnecting, the client's original
+    /// `initialize` request is replayed, followed by `initialized`, before any
+    /// queued messages are delivered. Not available with `serialized_writes`.
+    pub async fn forward_supervised<C, Fut, SR, SW, CR, CW>(
+        self,
+        connect: C,
+        policy: ReconnectPolicy,
+        client_reader: CR,
+        client_writer: CW,
+    ) -> std::io::Result<()>
+    where
+        C: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = std::io::Result<(SR, SW)>> + Send + 'static,
+        SR: AsyncReadExt + Unpin + Send + 'static,
+        SW: AsyncWriteExt + Unpin + Send + 'static,
+        CR: AsyncReadExt + Unpin + Send + 'static,
+        CW: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        let Proxy {
+            state,
+            write_coalesce_max,
+            idle_timeout,
+            half_close_grace,
+            eof_grace,
+            outbound,
+            receivers,
+        } = self;
+
+        let OutboundReceivers::Split { client, server } = receivers else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Supervised forwarding is not available with serialized writes",
+            ));
+        };
+
+        start_hooks(&state).await;
+
+        let handle = state.handle(outbound.clone());
+        let mut tasks = JoinSet::new();
+
+        let server_task = tasks
+            .spawn(supervise_server(
+                Arc::clone(&state),
+                connect,
+                policy,
+                server,
+                outbound.clone(),
+                write_coalesce_max,
+            ))
+            .id();
+
+        let state_server = Arc::clone(&state);
+        let outbound_server = outbound.clone();
+        let client_reader_task =
+            tasks
+                .spawn(async move {
+                    forward_to_server(state_server, client_reader, outbound_server).await
+                })
+                .id();
+
+        let client_writer = tasks.spawn(write_to_peer(
+            Arc::clone(&state),
+            Direction::ToClient,
+            client_writer,
+            PeerQueue::new(client),
+            outbound,
+            write_coalesce_max,
+        ));
+
+        let tasks = ForwardTasks {
+            set: tasks,
+            client_reader: client_reader_task,
+            server_reader: server_task,
+            readers: HashSet::from([client_reader_task, server_task]),
+            writers: HashSet::from([client_writer.id()]),
+        };
+        run_tasks(
+            tasks,
+            &state,
+            handle,
+            idle_timeout,
+            half_close_grace,
+            eof_grace,
+        )
+        .await
+    }
+}
+
+/// The tasks of a forwarding session, and which of them read and write.
+struct ForwardTasks {
+    set: JoinSet<std::io::Result<()>>,
+    client_reader: task::Id,
+    server_reader: task::Id,
+    /// The readers and writers that are still running.
+    readers: HashSet<task::Id>,
+    writers: HashSet<task::Id>,
+}
+
+impl ForwardTasks {
+    async fn join_next(&mut self) -> Option<Result<(task::Id, std::io::Result<()>), JoinError>> {
+        let finished = self.set.join_next_with_id().await?;
+        let id = match &finished {
+            Ok((id, _)) => *id,
+            Err(e) => e.id(),
+        };
+        self.readers.remove(&id);
+        self.writers.remove(&id);
+        Some(finished)
+    }
+}
+
+async fn run_tasks<S: Send + Sync + 'static>(
+    mut tasks: ForwardTasks,
+    state: &Arc<ProxyState<S>>,
+    handle: ProxyHandle,
+    idle_timeout: Option<Duration>,
+    half_close_grace: Duration,
+    eof_grace: Duration,
+) -> std::io::Result<()> {
+    if state.pairs.is_active() {
+        let state = Arc::clone(state);
+        tasks
+            .set
+            .spawn(async move { state.pairs.expire_forever().await });
+    }
+
+    if state.telemetry.is_some() {
+        tasks
+            .set
+            .spawn(emit_telemetry(Arc::clone(state), handle.clone()));
+    }
+
+    if state.health_check.is_some() {
+        tasks.set.spawn(check_health(Arc::clone(state), handle));
+    }
+
+    // Cancels the token hooks may be watching even if this future is dropped.
+    let _cancel_on_exit = state.shutdown.clone().drop_guard();
+
+    let result = select! {
+        Some(finished) = tasks.join_next() => match finished {
+            // The client closed its side; give the server a chance to deliver
+            // responses that are still in flight, then let the writers finish.
+            Ok((id, Ok(()))) if id == tasks.client_reader => {
+                match drain_server(&mut tasks, state, half_close_grace).await {
+                    Ok(()) => drain_writers(&mut tasks, state, eof_grace).await,
+                    Err(e) => Err(e),
+                }
+            }
+            Ok((id, Ok(()))) if id == tasks.server_reader => {
+                drain_writers(&mut tasks, state, eof_grace).await
+            }
+            Ok((_, result)) => result,
+            Err(e) => Err(e.into()),
+        },
+        _ = wait_until_idle(state, idle_timeout) => {
+            Ok(())
+        },
+        _ = state.shutdown.cancelled() => {
+            Ok(())
+        }
+    };
+
+    if result.is_err() {
+        state.dump_recent();
+    }
+
+    // Stop forwarding before hooks release their resources; once the tasks
+    // are gone, messages injected through a handle are refused. Hooks that are
+    // still running get until `eof_grace` to notice the cancelled token and
+    // return before their reader is dropped.
+    state.shutdown.cancel();
+    finish_readers(&mut tasks, state, eof_grace).await;
+    tasks.set.shutdown().await;
+    for hook in unique_hooks(state) {
+        if let Err(e) = CatchPanic(hook.on_shutdown()).await {
+            state.log(format_args!("Error in on_shutdown: {}", e));
+        }
+    }
+
+    result
+}
+
+/// Probes the server with the configured health check until forwarding stops.
+async fn check_health<S: Send + Sync + 'static>(
+    state: Arc<ProxyState<S>>,
+    handle: ProxyHandle,
+) -> std::io::Result<()> {
+    let Some(check) = &state.health_check else {
+        return Ok(());
+    };
+
+    loop {
+        state.clock.sleep(check.period()).await;
+
+        let healthy = handle
+            .send_request(Direction::ToServer, check.method(), None, check.deadline())
+            .await
+            .is_ok();
+
+        if state.liveness.set_healthy(healthy) {
+            check.notify(if healthy {
+                HealthEvent::Healthy
+            } else {
+                HealthEvent::Unhealthy
+            });
+        }
+    }
+}
+
+/// Sends the client the stats of each telemetry interval until forwarding
+/// stops.
+async fn emit_telemetry<S: Send + Sync + 'static>(
+    state: Arc<ProxyState<S>>,
+    handle: ProxyHandle,
+) -> std::io::Result<()> {
+    let (Some(telemetry), Some(stats)) = (&state.telemetry, &state.stats) else {
+        return Ok(());
+    };
+
+    loop {
+        state.clock.sleep(telemetry.period()).await;
+
+        let params = telemetry.params(&stats.take());
+        let _ = handle.inject(
+            Direction::ToClient,
+            Message::notification("telemetry/event", Some(params)),
+        );
+    }
+}
+
+async fn start_hooks<S: Send + Sync + 'static>(state: &ProxyState<S>) {
+    for hook in unique_hooks(state) {
+        if let Err(e) = CatchPanic(hook.on_start()).await {
+            state.log(format_args!("Error in on_start: {}", e));
+        }
+    }
+}
+
+/// Registered hooks, each once even if it handles several methods.
+fn unique_hooks<S: Send + Sync + 'static>(state: &ProxyState<S>) -> Vec<Arc<dyn Hook<S>>> {
+    let mut hooks: Vec<Arc<dyn Hook<S>>> = Vec::new();
+    for hook in state.hooks.hooks() {
+        if !hooks.iter().any(|seen| Arc::ptr_eq(seen, hook)) {
+            hooks.push(Arc::clone(hook));
+        }
+    }
+    hooks
+}
+
+async fn supervise_server<S: Send + Sync + 'static, C, Fut, SR, SW>(
+    state: Arc<ProxyState<S>>,
+    mut connect: C,
+    policy: ReconnectPolicy,
+    receiver: UnboundedReceiver<Outgoing>,
+    outbound: Outbound,
+    coalesce_max: usize,
+) -> std::io::Result<()>
+where
+    C: FnMut() -> Fut,
+    Fut: Future<Output = std::io::Result<(SR, SW)>>,
+    SR: AsyncReadExt + Unpin,
+    SW: AsyncWriteExt + Unpin,
+{
+    let (mut server_reader, mut server_writer) = connect().await?;
+    let mut queue = PeerQueue::new(receiver);
+    let mut reconnected = false;
+
+    loop {
+        let mut reader = BufReader::new(server_reader);
+
+        if reconnected {
+            replay_handshake(&state, &mut reader, &mut server_writer, &outbound).await?;
+        }
+
+        // The queue outlives each connection, so messages queued while the
+        // server was away are delivered once it is back.
+        let result = select! {
+            result = forward_to_client(Arc::clone(&state), reader, outbound.clone()) => result,
+            result = write_to_peer(
+                Arc::clone(&state),
+                Direction::ToServer,
+                server_writer,
+                &mut queue,
+                outbound.clone(),
+                coalesce_max,
+            ) => result,
+        };
+
+        if state.lifecycle.has_exited()
+            || state.draining.is_cancelled()
+            || state.shutdown.is_cancelled()
+        {
+            return result;
+        }
+
+        // Requests the old server never answered will not be answered now.
+        state.pending_requests.forget(Direction::ToServer);
+
+        policy.notify(ReconnectEvent::Disconnected);
+        (server_reader, server_writer) = reconnect(&mut connect, &policy, &*state.clock).await?;
+        reconnected = true;
+    }
+}
+
+async fn reconnect<C, Fut, SR, SW>(
+    connect: &mut C,
+    policy: &ReconnectPolicy,
+    clock: &dyn Clock,
+) -> std::io::Result<(SR, SW)>
+where
+    C: FnMut() -> Fut,
+    Fut: Future<Output = std::io::Result<(SR, SW)>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        if policy.gives_up_after(attempt) {
+            policy.notify(ReconnectEvent::GaveUp {
+                attempts: attempt - 1,
+            });
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "Gave up reconnecting to the server",
+            ));
+        }
+
+        let delay = policy.delay(attempt);
+        policy.notify(ReconnectEvent::Reconnecting { attempt, delay });
+        clock.sleep(delay).await;
+
+        if let Ok(connection) = connect().await {
+            policy.notify(ReconnectEvent::Reconnected { attempt });
+            return Ok(connection);
+        }
+        attempt += 1;
+    }
+}
+
+async fn replay_handshake<S: Send + Sync + 'static, R, W>(
+    state: &ProxyState<S>,
+    reader: &mut R,
+    writer: &mut W,
+    outbound: &Outbound,
+) -> std::io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let Some(params) = state.initialize_params.lock().await.clone() else {
+        return Ok(());
+    };
+
+    let id = next_injected_id(&state.next_request_id);
+    write_message(
+        writer,
+        &Message::request(id.clone(), "initialize", Some(params)).to_value(),
+    )
+    .await?;
+
+    // The client already has its `initialize` response, so the replayed one is
+    // consumed here; anything else the server sends meanwhile goes through.
+    let read_options = state.read_options_for(Direction::ToServer);
+    loop {
+        let frame = read_frame(reader, &read_options).await?;
+        match Message::from_value(frame.content) {
+            Ok(Message::Response(response)) if response.id == id => break,
+            Ok(message) => {
+                let _ = outbound.send(Direction::ToClient, message);
+            }
+            Err(_) => {}
+        }
+    }
+
+    write_message(
+        writer,
+        &Message::notification("initialized", Some(serde_json::json!({}))).to_value(),
+    )
+    .await
+}
+
+async fn drain_server<S: Send + Sync + 'static>(
+    tasks: &mut ForwardTasks,
+    state: &ProxyState<S>,
+    grace: Duration,
+) -> std::io::Result<()> {
+    let drain = async {
+        while let Some(finished) = tasks.join_next().await {
+            let (id, result) = finished?;
+            if id == tasks.server_reader || result.is_err() {
+                return result;
+            }
+        }
+        Ok(())
+    };
+
+    clock::timeout(&*state.clock, grace, drain)
+        .await
+        .unwrap_or(Ok(()))
+}
+
+/// After a reader reached EOF, lets the writers deliver what is already
+/// queued, for at most `grace`, instead of dropping it with the tasks.
+async fn drain_writers<S: Send + Sync + 'static>(
+    tasks: &mut ForwardTasks,
+    state: &ProxyState<S>,
+    grace: Duration,
+) -> std::io::Result<()> {
+    state.draining.cancel();
+    let drain = async {
+        while !tasks.writers.is_empty()
+            && let Some(finished) = tasks.join_next().await
+        {
+            let (_, result) = finished?;
+            result?;
+        }
+        Ok(())
+    };
+
+    clock::timeout(&*state.clock, grace, drain)
+        .await
+        .unwrap_or(Ok(()))
+}
+
+/// Waits, for at most `grace`, for the readers to stop after the shutdown
+/// token was cancelled.
+async fn finish_readers<S: Send + Sync + 'static>(
+    tasks: &mut ForwardTasks,
+    state: &ProxyState<S>,
+    grace: Duration,
+) {
+    let finish = async { while !tasks.readers.is_empty() && tasks.join_next().await.is_some() {} };
+    clock::timeout(&*state.clock, grace, finish).await;
+}
+
+async fn wait_until_idle<S: Send + Sync + 'static>(
+    state: &ProxyState<S>,
+    idle_timeout: Option<Duration>,
+) {
+    let Some(idle_timeout) = idle_timeout else {
+        return std::future::pending().await;
+    };
+
+    while clock::timeout(&*state.clock, idle_timeout, state.activity.notified())
+        .await
+        .is_some()
+    {}
+}
+
+async fn process_message<S: Send + Sync + 'static>(
+    state: &ProxyState<S>,
+    mut message: Message,
+    context: &HookContext<S>,
+) -> Result<Dispatch, HookError> {
+    let reply_to = context.to_origin();
+
+    if let Some(stats) = &state.stats {
+        stats.record_message();
+    }
+    if let Some(recent) = &state.recent {
+        recent.record(context.to_peer(), &message);
+    }
+
+    if let Some(violation) = state.check_protocol(context.to_peer(), &message) {
+        let generated_messages = match &message {
+            Message::Request(request) => {
+                let code = match violation {
+                    ProtocolViolation::BeforeInitialize { .. } => ErrorCode::ServerNotInitialized,
+                    _ => ErrorCode::InvalidRequest,
+                };
+                vec![(
+                    reply_to,
+                    Message::error_response(request.id.clone(), code, &violation.to_string()),
+                )]
+            }
+            _ => Vec::new(),
+        };
+        return Ok(Dispatch::Processed(ProcessedMessage::Ignore {
+            generated_messages,
+        }));
+    }
+
+    // A message trimmed to its limit no longer matches the bytes it was read
+    // as, so it is forwarded re-serialized.
+    let mut trimmed = false;
+    if let Some(limit) = message
+        .get_method()
+        .and_then(|method| state.params_limits.get(method))
+    {
+        match limit.enforce(&mut message) {
+            Ok(was_trimmed) => trimmed = was_trimmed,
+            Err(received) => {
+                let reason = format!(
+                    "{} is {} bytes, over the limit of {}",
+                    message.get_method().unwrap_or_default(),
+                    received,
+                    limit.max_bytes()
+                );
+                let generated_messages = match &message {
+                    Message::Request(request) => vec![(
+                        reply_to,
+                        Message::error_response(request.id.clone(), REQUEST_FAILED, &reason),
+                    )],
+                    _ => {
+                        state.log(format_args!("Dropping notification: {}", reason));
+                        Vec::new()
+                    }
+                };
+                return Ok(Dispatch::Processed(ProcessedMessage::Ignore {
+                    generated_messages,
+                }));
+            }
+        }
+    }
+
+    if let Some(method) = message.get_method() {
+        let params = match &message {
+            Message::Request(request) => request.params.as_ref(),
+            Message::Notification(notification) => notification.params.as_ref(),
+            Message::Response(_) => None,
+        };
+
+        let rejection = if state
+            .allowlist
+            .as_ref()
+            .is_some_and(|allowlist| !allowlist.contains(method))
+        {
+            Some((METHOD_NOT_FOUND, format!("Method not allowed: {}", method)))
+        } else if state.is_unknown_dollar_method(method) {
+            Some((METHOD_NOT_FOUND, format!("Method not found: {}", method)))
+        } else {
+            state
+                .check_params(method, params)
+                .map(|error| (INVALID_PARAMS, error))
+        };
+
+        if let Some((code, reason)) = rejection {
+            let generated_messages = match &message {
+                Message::Request(request) => {
+                    vec![(
+                        reply_to,
+                        Message::error_response(request.id.clone(), code, &reason),
+                    )]
+                }
+                // Filtered methods are dropped quietly; a notification that
+                // fails validation is worth knowing about.
+                _ if code == INVALID_PARAMS => {
+                    state.log(format_args!("Dropping notification: {}", reason));
+                    Vec::new()
+                }
+                _ => Vec::new(),
+            };
+            return Ok(Dispatch::Processed(ProcessedMessage::Ignore {
+                generated_messages,
+            }));
+        }
+
+        if reply_to == Direction::ToClient
+            && let Message::Request(request) = &message
+            && let Some(duplicate) = state
+                .dedup
+                .as_ref()
+                .and_then(|dedup| dedup.check(request, state.clock.now()))
+        {
+            let generated_messages = match duplicate {
+                Duplicate::Answered(response) => vec![(reply_to, Message::Response(response))],
+                Duplicate::Pending => Vec::new(),
+            };
+            return Ok(Dispatch::Processed(ProcessedMessage::Ignore {
+                generated_messages,
+            }));
+        }
+
+        if reply_to == Direction::ToClient
+            && let Some(bucket) = state.rate_limits.get(method)
+            && !bucket.try_acquire(state.clock.now())
+        {
+            let generated_messages = match &message {
+                Message::Request(request) if bucket.rejects_requests() => vec![(
+                    reply_to,
+                    Message::error_response(
+                        request.id.clone(),
+                        REQUEST_FAILED,
+                        &format!("Rate limit exceeded for {}", method),
+                    ),
+                )],
+                _ => Vec::new(),
+            };
+            return Ok(Dispatch::Processed(ProcessedMessage::Ignore {
+                generated_messages,
+            }));
+        }
+    }
+
+    match message {
+        Message::Request(request) => {
+            if let Some(max_pending) = state.max_pending_requests
+                && state.pending_requests.len() >= max_pending
+            {
+                return Ok(Dispatch::Processed(ProcessedMessage::Ignore {
+                    generated_messages: vec![(
+                        reply_to,
+                        Message::error_response(
+                            request.id,
+                            INTERNAL_ERROR,
+                            "Too many pending requests",
+                        ),
+                    )],
+                }));
+            }
+
+            let destination = reply_to.opposite();
+            let dispatch = match state.hooks.get(&request.method, destination) {
+                Some(hook) => {
+                    run_hook(state, hook, Message::Request(request), context, trimmed).await?
+                }
+                None if trimmed => {
+                    Dispatch::Processed(ProcessedMessage::Forward(Message::Request(request)))
+                }
+                None => Dispatch::Unchanged(Message::Request(request)),
+            };
+
+            // Every forwarded request is tracked, whether or not it had a
+            // hook, under the method it was forwarded with: the response's
+            // hook is resolved from that when the response arrives.
+            if let Some(Message::Request(forwarded)) = dispatch.get_message() {
+                state.pending_requests.insert(
+                    destination,
+                    forwarded.id.clone(),
+                    forwarded.method.clone(),
+                );
+                state.pairs.record_request(destination, forwarded).await;
+
+                // A hook that answered the request itself and still forwarded
+                // it has already given the sender its one response.
+                if dispatch
+                    .get_generated_messages()
+                    .iter()
+                    .any(|(direction, message)| {
+                        *direction == reply_to
+                            && matches!(message, Message::Response(response) if response.id == forwarded.id)
+                    })
+                {
+                    state
+                        .pending_requests
+                        .answer_locally(destination, forwarded.id.clone());
+                }
+            }
+
+            Ok(dispatch)
+        }
+        Message::Notification(mut notification) => {
+            let normalized = trimmed
+                || reply_to == Direction::ToClient
+                    && state
+                        .documents
+                        .as_ref()
+                        .is_some_and(|documents| documents.normalize(&mut notification));
+
+            match state.hooks.get(&notification.method, reply_to.opposite()) {
+                Some(hook) => {
+                    run_hook(
+                        state,
+                        hook,
+                        Message::Notification(notification),
+                        context,
+                        normalized,
+                    )
+                    .await
+                }
+                None if normalized => Ok(Dispatch::Processed(ProcessedMessage::Forward(
+                    Message::Notification(notification),
+                ))),
+                None => Ok(Dispatch::Unchanged(Message::Notification(notification))),
+            }
+        }
+        Message::Response(response) => {
+            state.pairs.resolve(reply_to, &response).await;
+
+            let key = (reply_to, response.id.clone());
+            if let Some(waiter) = state.response_waiters.lock().await.remove(&key) {
+                let _ = waiter.send(response);
+                return Ok(Dispatch::Processed(ProcessedMessage::Ignore {
+                    generated_messages: Vec::new(),
+                }));
+            }
+
+            if reply_to == Direction::ToServer
+                && let Some(mirror) = state.mirror.get()
+            {
+                mirror.observe_primary(&response);
+            }
+
+            let pending = state.pending_requests.remove(reply_to, &response.id);
+
+            if let Some(stats) = &state.stats {
+                if response.error.is_some() {
+                    stats.record_error();
+                }
+                if let Some(forwarded_at) =
+                    pending.as_ref().and_then(|pending| pending.forwarded_at)
+                {
+                    stats.record_latency(state.clock.now().saturating_duration_since(forwarded_at));
+                }
+            }
+
+            if reply_to == Direction::ToServer
+                && pending
+                    .as_ref()
+                    .is_some_and(|pending| pending.method == "initialize")
+            {
+                *state.position_encoding.lock().unwrap() =
+                    PositionEncoding::from_initialize_result(response.result.as_ref());
+                if let Some(documents) = &state.documents {
+                    documents.observe_capabilities(response.result.as_ref());
+                }
+            }
+
+            if pending
+                .as_ref()
+                .is_some_and(|pending| pending.answered_locally)
+            {
+                state.log(format_args!(
+                    "Dropping the response to request {}, which a hook already answered",
+                    response.id
+                ));
+                return Ok(Dispatch::Processed(ProcessedMessage::Ignore {
+                    generated_messages: Vec::new(),
+                }));
+            }
+
+            if let Some(hook) =
+                pending.and_then(|pending| state.hooks.get(&pending.method, reply_to))
+            {
+                return run_hook(state, hook, Message::Response(response), context, false).await;
+            }
+
+            Ok(Dispatch::Unchanged(Message::Response(response)))
+        }
+    }
+}
+
+async fn run_hook<S: Send + Sync + 'static>(
+    state: &ProxyState<S>,
+    hook: &Arc<dyn Hook<S>>,
+    message: Message,
+    context: &HookContext<S>,
+    dirty: bool,
+) -> Result<Dispatch, HookError> {
+    // Kept so the message can still be forwarded if the hook panics.
+    let original = message.clone();
+    // Whether what the hook returns can be written as the bytes read. `dirty`
+    // says the proxy already changed `message` from them.
+    let verbatim = |message: Option<&Message>| {
+        state.preserve_unmodified_bytes && !dirty && message == Some(&original)
+    };
+
+    let output = match message {
+        Message::Request(request) => CatchPanic(hook.on_request(request, context)).await,
+        Message::Response(response) => CatchPanic(hook.on_response(response, context)).await,
+        Message::Notification(notification) => {
+            CatchPanic(hook.on_notification(notification, context)).await
+        }
+    }
+    .and_then(|output| output);
+
+    match output {
+        Ok(_) if state.observe_only => {
+            let processed = ProcessedMessage::Forward(original.clone());
+            Ok(if verbatim(Some(&original)) {
+                Dispatch::Verbatim(processed)
+            } else {
+                Dispatch::Processed(processed)
+            })
+        }
+        Ok(mut output) => Ok(match output.feed.take() {
+            Some(feed) => {
+                let key = output.feed_key.take();
+                Dispatch::Feeding(output.as_processed(), feed, key)
+            }
+            None => {
+                let processed = output.as_processed();
+                if verbatim(processed.get_message()) {
+                    Dispatch::Verbatim(processed)
+                } else {
+                    Dispatch::Processed(processed)
+                }
+            }
+        }),
+        Err(e) => {
+            state.log(format_args!("Error processing message: {}", e));
+            let panicked = matches!(e, HookError::Panicked(_));
+            if panicked {
+                state.dump_recent();
+            }
+
+            // The report only observes the failure: the policy decides what
+            // becomes of the message either way.
+            let report = state.hook_error_report.map(|message_type| {
+                let report = match message_type {
+                    MessageType::Error => Message::show_message(message_type, &e.to_string()),
+                    _ => Message::log_message(message_type, &e.to_string()),
+                };
+                (Direction::ToClient, report)
+            });
+
+            let processed = match state.hook_error_policy {
+                _ if panicked || state.observe_only => ProcessedMessage::Forward(original),
+                HookErrorPolicy::FailOpen => ProcessedMessage::Forward(original),
+                HookErrorPolicy::FailClosed if report.is_none() => return Err(e),
+                HookErrorPolicy::FailClosed => ProcessedMessage::Ignore {
+                    generated_messages: Vec::new(),
+                },
+                HookErrorPolicy::Error => {
+                    let error = |id| Message::error_response(id, INTERNAL_ERROR, &e.to_string());
+                    match original {
+                        Message::Request(request) => ProcessedMessage::Ignore {
+                            generated_messages: vec![(context.to_origin(), error(request.id))],
+                        },
+                        Message::Response(response) => {
+                            ProcessedMessage::Forward(error(response.id))
+                        }
+                        Message::Notification(_) => ProcessedMessage::Ignore {
+                            generated_messages: Vec::new(),
+                        },
+                    }
+                }
+            };
+
+            Ok(Dispatch::Processed(match (processed, report) {
+                (processed, None) => processed,
+                (ProcessedMessage::Forward(message), Some(report)) => {
+                    ProcessedMessage::WithMessages {
+                        message,
+                        generated_messages: vec![report],
+                        order: GeneratedOrder::AfterMessage,
+                    }
+                }
+                (
+                    ProcessedMessage::Ignore {
+                        mut generated_messages,
+                    },
+                    Some(report),
+                ) => {
+                    generated_messages.push(report);
+                    ProcessedMessage::Ignore { generated_messages }
+                }
+                (
+                    ProcessedMessage::WithMessages {
+                        message,
+                        mut generated_messages,
+                        order,
+                    },
+                    Some(report),
+                ) => {
+                    generated_messages.push(report);
+                    ProcessedMessage::WithMessages {
+                        message,
+                        generated_messages,
+                        order,
+                    }
+                }
+            }))
+        }
+    }
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        ProxyBuilder::new().build()
+    }
+}
+
+async fn write_to_peer<S: Send + Sync + 'static, W, Q>(
+    state: Arc<ProxyState<S>>,
+    peer: Direction,
+    mut writer: W,
+    mut queue: Q,
+    outbound: Outbound,
+    coalesce_max: usize,
+) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+    Q: BorrowMut<PeerQueue>,
+{
+    let queue = queue.borrow_mut();
+    let mut batch = Vec::new();
+    let mut requests = Vec::new();
+    while queue
+        .fill(state.coalesce_key.as_ref(), &state.draining)
+        .await
+    {
+        let mut stop = false;
+        for msg in queue.take(coalesce_max) {
+            stop |= state.stops_after(&msg, peer);
+            requests.push(match &msg.message {
+                Some(Message::Request(request)) => {
+                    Some((request.id.clone(), request.method.clone()))
+                }
+                _ => None,
+            });
+            batch.push(state.outgoing_frame(peer, msg)?);
+        }
+
+        if !write_batch(&state, peer, &mut writer, &batch, &requests, &outbound).await {
+            break;
+        }
+        if stop {
+            state.shutdown.cancel();
+            break;
+        }
+        batch.clear();
+        requests.clear();
+    }
+    Ok(())
+}
+
+/// Writes a batch of frames. When a request fails to write, it is written
+/// again after a delay if its method is idempotent per `retry_idempotent`, and
+/// otherwise answered with an error so the sender is not left waiting. A
+/// failure partway through a frame leaves the stream unusable, so the request
+/// is not retried and it and the rest of the batch's requests are answered
+/// with errors instead. Returns `false` if that or anything else fails, which
+/// ends the writer.
+async fn write_batch<S: Send + Sync + 'static, W>(
+    state: &ProxyState<S>,
+    peer: Direction,
+    writer: &mut W,
+    batch: &[(Body, Vec<(String, String)>)],
+    requests: &[Option<(RequestId, String)>],
+    outbound: &Outbound,
+) -> bool
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let options = state.write_options(peer);
+    let mut start = 0;
+    let mut retries = 0;
+
+    loop {
+        let Err(failure) = write_bodies_counted(writer, &batch[start..], &options).await else {
+            return true;
+        };
+
+        let failed = start + failure.frames;
+        let e = failure.error;
+        if failure.offset > 0 {
+            state.log(format_args!(
+                "Failed to write a frame after {} of its bytes: {}",
+                failure.offset, e
+            ));
+            for (id, method) in requests[failed..].iter().flatten() {
+                fail_request(state, peer, id, method, &e, outbound).await;
+            }
+            return false;
+        }
+
+        let Some(Some((id, method))) = requests.get(failed) else {
+            return false;
+        };
+
+        if failed != start {
+            retries = 0;
+        }
+        start = failed;
+
+        if let Some(retry) = &state.write_retry
+            && retry.methods.contains(method)
+            && retries < retry.max_retries
+        {
+            retries += 1;
+            state.clock.sleep(retry.delay).await;
+            continue;
+        }
+
+        fail_request(state, peer, id, method, &e, outbound).await;
+        start += 1;
+        retries = 0;
+    }
+}
+
+/// Answers a request that could not be written with an error, so whoever sent
+/// it is not left waiting.
+async fn fail_request<S: Send + Sync + 'static>(
+    state: &ProxyState<S>,
+    peer: Direction,
+    id: &RequestId,
+    method: &str,
+    e: &std::io::Error,
+    outbound: &Outbound,
+) {
+    state.log(format_args!("Failed to write {} request: {}", method, e));
+    let error = Message::error_response(
+        id.clone(),
+        INTERNAL_ERROR,
+        &format!("Failed to write request: {}", e),
+    );
+    // A request a hook already answered needs no error as well.
+    let answered_locally = state
+        .pending_requests
+        .remove(peer, id)
+        .is_some_and(|pending| pending.answered_locally);
+    match state
+        .response_waiters
+        .lock()
+        .await
+        .remove(&(peer, id.clone()))
+    {
+        Some(waiter) => {
+            if let Message::Response(response) = error {
+                let _ = waiter.send(response);
+            }
+        }
+        None if answered_locally => {}
+        None => {
+            let _ = outbound.send(peer.opposite(), error);
+        }
+    }
+}
+
+/// The outcome of `process_message`. `Unchanged` means no hook touched the
+/// message, so the bytes it arrived as can be forwarded without serializing it
+/// again. `Feeding` is a processed message whose hook also returned a
+/// `MessageFeed`, to be driven once the message itself is queued, with the key
+/// it is sequenced under if any.
+enum Dispatch {
+    Unchanged(Message),
+    Processed(ProcessedMessage),
+    /// A hook's result whose main message is the one read, untouched, so it
+    /// is written as the bytes read; see `preserve_unmodified_bytes`.
+    Verbatim(ProcessedMessage),
+    Feeding(ProcessedMessage, MessageFeed, Option<String>),
+}
+
+impl Dispatch {
+    fn get_message(&self) -> Option<&Message> {
+        match self {
+            Dispatch::Unchanged(message) => Some(message),
+            Dispatch::Processed(processed)
+            | Dispatch::Verbatim(processed)
+            | Dispatch::Feeding(processed, ..) => processed.get_message(),
+        }
+    }
+
+    fn get_generated_messages(&self) -> &[(Direction, Message)] {
+        match self {
+            Dispatch::Unchanged(_) => &[],
+            Dispatch::Processed(processed)
+            | Dispatch::Verbatim(processed)
+            | Dispatch::Feeding(processed, ..) => processed.get_generated_messages(),
+        }
+    }
+}
+
+/// Runs hooks and request tracking on each message, without reading or writing
+/// any connection. The returned `ProcessedMessage` says what to send where;
+/// sending it is up to the caller. Created with `Proxy::service`.
+#[cfg(feature = "tower")]
+pub struct ProxyService<S = ()> {
+    state: Arc<ProxyState<S>>,
+    handle: ProxyHandle,
+    direction: Direction,
+}
+
+#[cfg(feature = "tower")]
+impl<S> Clone for ProxyService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            state: Arc::clone(&self.state),
+            handle: self.handle.clone(),
+            direction: self.direction,
+        }
+    }
+}
+
+#[cfg(feature = "tower")]
+impl<S: Send + Sync + 'static> tower_service::Service<Message> for ProxyService<S> {
+    type Response = ProcessedMessage;
+    type Error = HookError;
+    type Future = std::pin::Pin<
+        Box<dyn Future<Output = Result<ProcessedMessage, HookError>> + Send + 'static>,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), HookError>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, message: Message) -> Self::Future {
+        let state = Arc::clone(&self.state);
+        let direction = self.direction;
+        let handle = self.handle.clone();
+        let context = HookContext::default()
+            .with_state(Arc::clone(&state.app_state))
+            .with_origin(direction.opposite())
+            .with_cancellation(state.shutdown.clone())
+            .with_trace(state.trace())
+            .with_lifecycle_state(state.lifecycle.state())
+            .with_position_encoding(state.position_encoding())
+            .with_workspace_roots(state.workspace_roots())
+            .with_handle(handle.clone());
+
+        Box::pin(async move {
+            let dispatch = process_message(&state, message, &context).await?;
+            if direction == Direction::ToServer {
+                state.observe_outgoing(dispatch.get_message()).await;
+            }
+            Ok(match dispatch {
+                Dispatch::Unchanged(message) => ProcessedMessage::Forward(message),
+                Dispatch::Processed(processed) | Dispatch::Verbatim(processed) => processed,
+                Dispatch::Feeding(processed, feed, key) => {
+                    drive_feed(&state, feed, key, handle.outbound.clone());
+                    processed
+                }
+            })
+        })
+    }
+}
+
+fn emit<S: Send + Sync + 'static>(
+    state: &ProxyState<S>,
+    dispatch: Dispatch,
+    destination: Direction,
+    raw: Option<Arc<[u8]>>,
+    outbound: &Outbound,
+) -> Result<(), ChannelClosed> {
+    // A message changed by a hook is written without `jsonrpc` if it was
+    // read without it.
+    let strip_jsonrpc = state.jsonrpc_field == JsonRpcField::PreserveOriginal
+        && matches!(dispatch, Dispatch::Processed(_) | Dispatch::Feeding(..))
+        && raw
+            .as_deref()
+            .is_some_and(|raw| !Message::body_has_jsonrpc(raw));
+
+    let (processed, feed, main_body) = match (dispatch, raw) {
+        (Dispatch::Unchanged(message), Some(raw)) => {
+            return outbound.send_raw(destination, message, raw);
+        }
+        (Dispatch::Unchanged(message), None) => (ProcessedMessage::Forward(message), None, None),
+        (Dispatch::Verbatim(processed), raw) => (processed, None, raw),
+        (Dispatch::Processed(processed), _) => (processed, None, None),
+        (Dispatch::Feeding(processed, feed, key), _) => (processed, Some((feed, key)), None),
+    };
+
+    let order = processed.get_order();
+    let (main_message, generated_messages) = processed.into_parts();
+    let main_message = main_message.map(|message| {
+        let body = main_body.or_else(|| {
+            strip_jsonrpc
+                .then(|| serialize(&JsonRpcField::strip(message.to_value())))
+                .and_then(Result::ok)
+                .map(Arc::from)
+        });
+        (destination, message, body)
+    });
+    let generated_messages = generated_messages
+        .into_iter()
+        .map(|(direction, message)| (direction, message, None));
+
+    let outgoing: Vec<_> = match order {
+        GeneratedOrder::AfterMessage => {
+            main_message.into_iter().chain(generated_messages).collect()
+        }
+        GeneratedOrder::BeforeMessage => generated_messages.chain(main_message).collect(),
+    };
+
+    for (direction, message, body) in outgoing {
+        match body {
+            Some(body) => outbound.send_raw(direction, message, body)?,
+            None => outbound.send(direction, message)?,
+        }
+    }
+
+    if let Some((feed, key)) = feed {
+        drive_feed(state, feed, key, outbound.clone());
+    }
+
+    Ok(())
+}
+
+/// Forwards what `feed` yields until the feed ends, the peer is gone, or the
+/// session shuts down. A feed with a key takes its place in that key's queue
+/// now, so it is written in the order the feeds were returned.
+fn drive_feed<S: Send + Sync + 'static>(
+    state: &ProxyState<S>,
+    mut feed: MessageFeed,
+    key: Option<String>,
+    outbound: Outbound,
+) {
+    let shutdown = state.shutdown.clone();
+    let slot = key.map(|key| state.sequencer.reserve(key, outbound.clone()));
+    let send = move |direction, message| match &slot {
+        Some(slot) => slot.send(direction, message).is_ok(),
+        None => outbound.send(direction, message).is_ok(),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let next = select! {
+                _ = shutdown.cancelled() => return,
+                next = std::future::poll_fn(|cx| feed.as_mut().poll_next(cx)) => next,
+            };
+
+            let Some((direction, message)) = next else {
+                return;
+            };
+            if !send(direction, message) {
+                return;
+            }
+        }
+    });
+}
+
+/// Handles a body that is valid JSON but not a valid message: dropped, or
+/// forwarded exactly as received when `pass_through_unparsed` is on.
+fn pass_through<S: Send + Sync + 'static>(
+    state: &ProxyState<S>,
+    error: MessageParseError,
+    destination: Direction,
+    context: &HookContext<S>,
+    outbound: &Outbound,
+) -> Result<(), ChannelClosed> {
+    match context.shared_raw_bytes() {
+        Some(raw) if state.pass_through_unparsed => {
+            state.log(format_args!(
+                "Forwarding unparsed message to {:?}: {}",
+                destination, error
+            ));
+            outbound.send_unparsed(destination, raw)
+        }
+        _ => {
+            state.log(format_args!("Dropping invalid message: {}", error));
+            Ok(())
+        }
+    }
+}
+
+/// Forwards a response from the server as the bytes it arrived as, without
+/// building a `Message` from it, when its request has no hook to run. Returns
+/// `None` if the response has to go through `process_message`.
+async fn forward_unparsed_response<S: Send + Sync + 'static>(
+    state: &ProxyState<S>,
+    id: RequestId,
+    frame: &RawFrame,
+    outbound: &Outbound,
+) -> Option<Result<(), ChannelClosed>> {
+    let key = (Direction::ToServer, id.clone());
+    if state.response_waiters.lock().await.contains_key(&key) {
+        return None;
+    }
+
+    if let Some(method) = state.pending_requests.method(Direction::ToServer, &id)
+        && (method == "initialize" || state.hooks.get(&method, Direction::ToServer).is_some())
+    {
+        return None;
+    }
+    state.pending_requests.remove(Direction::ToServer, &id);
+
+    Some(outbound.send_unparsed(Direction::ToClient, Arc::clone(&frame.body)))
+}
+
+/// What a reader returns once messages for `peer` can no longer be queued
+/// because its writer stopped. After `exit` or once the proxy
+/// is shutting down this is the expected end of the session, not an error.
+fn writer_stopped<S: Send + Sync + 'static>(
+    state: &ProxyState<S>,
+    peer: Direction,
+) -> std::io::Result<()> {
+    if state.shutdown.is_cancelled() || state.lifecycle.has_exited() {
+        return Ok(());
+    }
+
+    let peer = match peer {
+        Direction::ToServer => "server",
+        Direction::ToClient => "client",
+    };
+    Err(std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        format!("Writer to the {} stopped before the session ended", peer),
+    ))
+}
+
+/// Workspace roots from `initialize` params: `workspaceFolders` if the client
+/// sent any, otherwise the deprecated `rootUri` or `rootPath`.
+fn initial_workspace_roots(params: &Value) -> Vec<String> {
+    let folders = folder_uris(params.get("workspaceFolders"));
+    if !folders.is_empty() {
+        return folders;
+    }
 
-        select! {
-            client_to_server = client_to_server_task => {
-                client_to_server?
-            },
-            server_to_client = server_to_client_task => {
-                server_to_client?
-            },
-            write_server = write_to_server => {
-                write_server?
-            },
-            write_client = write_to_client => {
-                write_client?
-            }
-        }
+    if let Some(root_uri) = params.get("rootUri").and_then(Value::as_str) {
+        return vec![root_uri.to_owned()];
     }
+
+    params
+        .get("rootPath")
+        .and_then(Value::as_str)
+        .map(|path| {
+            let path = path.replace('\\', "/");
+            vec![format!("file:///{}", path.trim_start_matches('/'))]
+        })
+        .unwrap_or_default()
 }
 
-async fn process_message(
-    hooks: &Mutex<HashMap<String, Arc<dyn Hook>>>,
-    pending_requests: &Mutex<HashMap<i64, String>>,
-    message: Message,
-) -> Result<ProcessedMessage, HookError> {
-    match message {
-        Message::Request(request) => match hooks.lock().await.get(&request.method) {
-            Some(hook) => {
-                pending_requests
-                    .lock()
-                    .await
-                    .insert(request.id, request.method.clone());
+fn folder_uris(folders: Option<&Value>) -> Vec<String> {
+    folders
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|folder| folder.get("uri").and_then(Value::as_str))
+        .map(str::to_owned)
+        .collect()
+}
 
-                Ok(hook.on_request(request).await?.as_processed())
-            }
-            None => Ok(ProcessedMessage::Forward(Message::Request(request))),
-        },
-        Message::Notification(notification) => match hooks.lock().await.get(&notification.method) {
-            Some(hook) => Ok(hook.on_notification(notification).await?.as_processed()),
-            None => Ok(ProcessedMessage::Forward(Message::Notification(
-                notification,
-            ))),
-        },
-        Message::Response(response) => {
-            let method = { pending_requests.lock().await.remove(&response.id) };
+async fn write_serialized<S: Send + Sync + 'static, SW, CW>(
+    state: Arc<ProxyState<S>>,
+    mut server_writer: SW,
+    mut client_writer: CW,
+    mut receiver: UnboundedReceiver<(Direction, Outgoing)>,
+    coalesce_max: usize,
+) -> std::io::Result<()>
+where
+    SW: AsyncWriteExt + Unpin,
+    CW: AsyncWriteExt + Unpin,
+{
+    let mut batch = Vec::new();
+    // The messages in `batch`, kept for the trace and `subscribe_writes` only
+    // while either wants them. Raw bodies forwarded unparsed are numbered but
+    // not reported.
+    let mut written = Vec::new();
+    // Numbers every message written, to either peer, in write order.
+    let mut sequence = 0u64;
+    let mut next = recv_until_drained(&mut receiver, &state.draining).await;
 
-            if let Some(method) = method
-                && let Some(hook) = hooks.lock().await.get(&method)
-            {
-                return Ok(hook.on_response(response).await?.as_processed());
+    while let Some((direction, msg)) = next.take() {
+        let mut stop = state.stops_after(&msg, direction);
+        let tracked = state.traces_verbosely() || state.writes.is_active();
+        if tracked {
+            written.push(msg.message.clone());
+        }
+        batch.push(state.outgoing_frame(direction, msg)?);
+
+        // Batch consecutive messages for the same peer; a message for the
+        // other peer ends the batch and starts the next one.
+        while batch.len() < coalesce_max {
+            match receiver.try_recv() {
+                Ok((next_direction, msg)) if next_direction == direction => {
+                    stop |= state.stops_after(&msg, direction);
+                    if tracked {
+                        written.push(msg.message.clone());
+                    }
+                    batch.push(state.outgoing_frame(direction, msg)?);
+                }
+                Ok(other) => {
+                    next = Some(other);
+                    break;
+                }
+                Err(_) => break,
             }
+        }
 
-            Ok(ProcessedMessage::Forward(Message::Response(response)))
+        let options = state.write_options(direction);
+        let result = match direction {
+            Direction::ToServer => write_bodies(&mut server_writer, &batch, &options).await,
+            Direction::ToClient => write_bodies(&mut client_writer, &batch, &options).await,
+        };
+        if result.is_err() {
+            break;
         }
-    }
-}
+        let first = sequence + 1;
+        sequence += batch.len() as u64;
+        for (sequence, message) in (first..).zip(written.drain(..)) {
+            if let Some(message) = message {
+                state.log_trace(Some(sequence), direction, &message);
+                state.writes.publish(sequence, direction, &message);
+            }
+        }
+        if stop {
+            state.shutdown.cancel();
+            break;
+        }
+        batch.clear();
 
-impl Default for Proxy {
-    fn default() -> Self {
-        Self::new(HashMap::new())
+        if next.is_none() {
+            next = recv_until_drained(&mut receiver, &state.draining).await;
+        }
     }
+    Ok(())
 }
 
-async fn forward_to_server<R>(
-    hooks: Arc<Mutex<HashMap<String, Arc<dyn Hook>>>>,
-    pending_requests: Arc<Mutex<HashMap<i64, String>>>,
-    mut client_reader: R,
-    server_message_sender: UnboundedSender<Message>,
-    client_message_sender: UnboundedSender<Message>,
+async fn forward_to_server<S: Send + Sync + 'static, R>(
+    state: Arc<ProxyState<S>>,
+    client_reader: R,
+    outbound: Outbound,
 ) -> std::io::Result<()>
 where
     R: AsyncReadExt + Unpin,
 {
+    let mut client_reader = BufReader::new(client_reader);
+    let read_options = state.read_options_for(Direction::ToClient);
+    let handle = state.handle(outbound.clone());
+
     loop {
-        let message = match read_message(&mut client_reader).await {
-            Ok(msg) => Message::from_value(msg),
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+        let read = select! {
+            read = read_frame(&mut client_reader, &read_options) => read,
+            _ = state.shutdown.cancelled() => return Ok(()),
+        };
+        let (message, context) = match read {
+            Ok(frame) => {
+                state.activity.notify_one();
+                state.observe_frame(Direction::ToClient, &frame.headers);
+                (
+                    Message::from_value(frame.content),
+                    HookContext::default()
+                        .with_state(Arc::clone(&state.app_state))
+                        .with_origin(Direction::ToClient)
+                        .with_raw_bytes(frame.body)
+                        .with_headers(frame.headers)
+                        .with_cancellation(state.shutdown.clone())
+                        .with_trace(state.trace())
+                        .with_lifecycle_state(state.lifecycle.state())
+                        .with_position_encoding(state.position_encoding())
+                        .with_workspace_roots(state.workspace_roots())
+                        .with_handle(handle.clone()),
+                )
+            }
+            Err(TransportError::Eof) => {
+                break;
+            }
+            Err(e @ TransportError::Truncated { .. }) => {
+                state.log(format_args!("Reading from the client: {}", e));
+                state.dump_recent();
                 break;
             }
-            Err(e) => return Err(e),
+            // The body was read in full, so the next frame can still be found.
+            Err(
+                e @ (TransportError::TooDeep { .. }
+                | TransportError::UnsupportedCharset(_)
+                | TransportError::UnsupportedEncoding(_)),
+            ) => {
+                state.log(format_args!("Skipping a message from the client: {}", e));
+                continue;
+            }
+            Err(e) => return Err(e.into()),
         };
 
-        if let Ok(message) = message {
-            match process_message(&hooks, &pending_requests, message).await {
-                Ok(processed) => {
-                    let (main_message, generated_messages) = processed.into_parts();
-
-                    if let Some(main_message) = main_message
-                        && server_message_sender.send(main_message).is_err()
-                    {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::BrokenPipe,
-                            "Message channel closed",
-                        ));
-                    }
+        state.pause.wait_while_paused().await;
+        if let Some(gate) = &state.init_gate {
+            gate.admit(&message).await;
+        }
 
-                    for (direction, message) in generated_messages {
-                        let result = match direction {
-                            Direction::ToClient => client_message_sender.send(message),
-                            Direction::ToServer => server_message_sender.send(message),
-                        };
-
-                        if result.is_err() {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::BrokenPipe,
-                                "Notification channel closed",
-                            ));
-                        }
-                    }
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                if let Err(ChannelClosed(peer)) =
+                    pass_through(&state, e, Direction::ToServer, &context, &outbound)
+                {
+                    return writer_stopped(&state, peer);
+                }
+                continue;
+            }
+        };
+
+        match process_message(&state, message, &context).await {
+            Ok(dispatch) => {
+                state.observe_outgoing(dispatch.get_message()).await;
+                state.trace_message(Direction::ToServer, &dispatch);
+                if let Some((mirror, message)) = state.mirror.get().zip(dispatch.get_message()) {
+                    mirror.tee(message);
+                }
+                let gate = state
+                    .init_gate
+                    .as_ref()
+                    .filter(|gate| gate.is_answered_by(&dispatch, Direction::ToServer));
+                if let Err(ChannelClosed(peer)) = emit(
+                    &state,
+                    dispatch,
+                    Direction::ToServer,
+                    context.shared_raw_bytes(),
+                    &outbound,
+                ) {
+                    return writer_stopped(&state, peer);
                 }
-                Err(e) => {
-                    eprintln!("Error processing message: {}", e);
+                if let Some(gate) = gate {
+                    gate.open();
                 }
             }
+            Err(e) => {
+                state.log(format_args!("Error processing message: {}", e));
+            }
         }
     }
 
+    if state.cancel_pending_on_eof {
+        match handle.cancel_pending_requests() {
+            Ok(0) => {}
+            Ok(cancelled) => state.log(format_args!(
+                "The client disconnected, cancelling {} pending requests",
+                cancelled
+            )),
+            Err(e) => state.log(format_args!("Cancelling pending requests: {}", e)),
+        }
+    }
+    if let Some(&timeout) = state.child_shutdown_timeout.get() {
+        shut_down_child(&state, &handle, timeout).await;
+    }
     Ok(())
 }
 
-async fn forward_to_client<R>(
-    hooks: Arc<Mutex<HashMap<String, Arc<dyn Hook>>>>,
-    pending_requests: Arc<Mutex<HashMap<i64, String>>>,
-    mut server_reader: R,
-    server_message_sender: UnboundedSender<Message>,
-    client_message_sender: UnboundedSender<Message>,
+/// Finishes the lifecycle for a client that disconnected without `exit`, so
+/// the server the proxy spawned can tear down cleanly instead of being killed:
+/// `shutdown` unless the client already sent it or never initialized, then
+/// `exit`. The client's lifecycle state and exit code are left as it left
+/// them.
+async fn shut_down_child<S: Send + Sync + 'static>(
+    state: &ProxyState<S>,
+    handle: &ProxyHandle,
+    timeout: Duration,
+) {
+    if state.lifecycle.has_exited() {
+        return;
+    }
+
+    state.log(format_args!(
+        "The client disconnected without exit, shutting the server down"
+    ));
+    if matches!(
+        state.lifecycle.state(),
+        LifecycleState::Initializing | LifecycleState::Initialized
+    ) && let Err(e) = handle
+        .send_request(Direction::ToServer, "shutdown", None, timeout)
+        .await
+    {
+        state.log(format_args!("The server did not answer shutdown: {}", e));
+    }
+    let _ = handle.inject(Direction::ToServer, Message::notification("exit", None));
+}
+
+async fn forward_to_client<S: Send + Sync + 'static, R>(
+    state: Arc<ProxyState<S>>,
+    server_reader: R,
+    outbound: Outbound,
 ) -> std::io::Result<()>
 where
     R: AsyncReadExt + Unpin,
 {
+    let mut server_reader = BufReader::new(server_reader);
+    let read_options = state.read_options_for(Direction::ToServer);
+    let handle = state.handle(outbound.clone());
+
     loop {
-        let message = match read_message(&mut server_reader).await {
-            Ok(msg) => Message::from_value(msg),
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+        let read = select! {
+            read = read_raw_frame(&mut server_reader, &read_options) => read,
+            _ = state.shutdown.cancelled() => return Ok(()),
+        };
+        let frame = match read {
+            Ok(frame) => {
+                state.activity.notify_one();
+                state.liveness.touch_server(state.clock.now());
+                state.observe_frame(Direction::ToServer, &frame.headers);
+                state.pause.wait_while_paused().await;
+                if state.streams_unhooked_responses()
+                    && let Some(id) = Message::peek_response_id(&frame.body)
+                    && let Some(sent) =
+                        forward_unparsed_response(&state, id, &frame, &outbound).await
+                {
+                    if let Err(ChannelClosed(peer)) = sent {
+                        return writer_stopped(&state, peer);
+                    }
+                    continue;
+                }
+                frame.parse()
+            }
+            Err(e) => Err(e),
+        };
+
+        let (message, context) = match frame {
+            Ok(frame) => (
+                Message::from_value(frame.content),
+                HookContext::default()
+                    .with_state(Arc::clone(&state.app_state))
+                    .with_origin(Direction::ToServer)
+                    .with_raw_bytes(frame.body)
+                    .with_headers(frame.headers)
+                    .with_cancellation(state.shutdown.clone())
+                    .with_trace(state.trace())
+                    .with_lifecycle_state(state.lifecycle.state())
+                    .with_position_encoding(state.position_encoding())
+                    .with_workspace_roots(state.workspace_roots())
+                    .with_handle(handle.clone()),
+            ),
+            Err(TransportError::Eof) => {
+                break;
+            }
+            Err(e @ TransportError::Truncated { .. }) => {
+                state.log(format_args!("Reading from the server: {}", e));
+                state.dump_recent();
                 break;
             }
-            Err(e) => return Err(e),
+            // The body was read in full, so the next frame can still be found.
+            Err(
+                e @ (TransportError::TooDeep { .. }
+                | TransportError::UnsupportedCharset(_)
+                | TransportError::UnsupportedEncoding(_)),
+            ) => {
+                state.log(format_args!("Skipping a message from the server: {}", e));
+                continue;
+            }
+            Err(e) => return Err(e.into()),
         };
 
-        if let Ok(message) = message {
-            match process_message(&hooks, &pending_requests, message).await {
-                Ok(processed) => {
-                    let (main_message, generated_messages) = processed.into_parts();
-
-                    if let Some(main_message) = main_message
-                        && client_message_sender.send(main_message).is_err()
-                    {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::BrokenPipe,
-                            "Message channel closed",
-                        ));
-                    }
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                if let Err(ChannelClosed(peer)) =
+                    pass_through(&state, e, Direction::ToClient, &context, &outbound)
+                {
+                    return writer_stopped(&state, peer);
+                }
+                continue;
+            }
+        };
 
-                    for (direction, message) in generated_messages {
-                        let result = match direction {
-                            Direction::ToClient => client_message_sender.send(message),
-                            Direction::ToServer => server_message_sender.send(message),
-                        };
-
-                        if result.is_err() {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::BrokenPipe,
-                                "Notification channel closed",
-                            ));
-                        }
-                    }
+        match process_message(&state, message, &context).await {
+            Ok(dispatch) => {
+                if let Some(dedup) = &state.dedup {
+                    dedup.observe_response(dispatch.get_message());
                 }
-                Err(e) => {
-                    eprintln!("Error processing message: {}", e);
+                state.trace_message(Direction::ToClient, &dispatch);
+                let gate = state
+                    .init_gate
+                    .as_ref()
+                    .filter(|gate| gate.is_answered_by(&dispatch, Direction::ToClient));
+                if let Err(ChannelClosed(peer)) = emit(
+                    &state,
+                    dispatch,
+                    Direction::ToClient,
+                    context.shared_raw_bytes(),
+                    &outbound,
+                ) {
+                    return writer_stopped(&state, peer);
                 }
+                if let Some(gate) = gate {
+                    gate.open();
+                }
+            }
+            Err(e) => {
+                state.log(format_args!("Error processing message: {}", e));
             }
         }
     }
@@ -264,24 +2696,692 @@ where
     Ok(())
 }
 
-pub struct ProxyBuilder {
-    hooks: HashMap<String, Arc<dyn Hook>>,
+#[derive(Debug)]
+pub enum BuildError {
+    UnknownMethods(Vec<String>),
+    #[cfg(feature = "schema")]
+    InvalidSchema {
+        method: String,
+        message: String,
+    },
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::UnknownMethods(methods) => {
+                write!(
+                    f,
+                    "Hooks registered for unknown methods: {}",
+                    methods.join(", ")
+                )
+            }
+            #[cfg(feature = "schema")]
+            BuildError::InvalidSchema { method, message } => {
+                write!(f, "Invalid schema for {}: {}", method, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+pub struct ProxyBuilder<S = ()> {
+    state: S,
+    hooks: HookRegistry<S>,
+    known_methods: HashSet<String>,
+    allowlist: Option<HashSet<String>>,
+    filter_unknown_dollar_methods: bool,
+    observe_only: bool,
+    pass_through_unparsed: bool,
+    preserve_unmodified_bytes: bool,
+    cancel_pending_on_eof: bool,
+    normalize_document_sync: bool,
+    hook_error_report: Option<MessageType>,
+    hook_error_policy: HookErrorPolicy,
+    read_options: ReadOptions,
+    outgoing_headers: OutgoingHeaders,
+    jsonrpc_field: JsonRpcField,
+    coalesce_key: Option<CoalesceKeyFn>,
+    stop_after: Option<StopFn>,
+    write_retry: Option<WriteRetry>,
+    dedup: Option<RequestDedup>,
+    hold_until_initialized: bool,
+    trace_to_stderr: bool,
+    keep_recent: Option<usize>,
+    record_filter: Option<RecordFilter>,
+    dump_recent_on_error: bool,
+    protocol_check: Option<ProtocolCheck>,
+    redactor: Option<Redactor>,
+    serialized_writes: bool,
+    write_coalesce_max: usize,
+    idle_timeout: Option<Duration>,
+    half_close_grace: Duration,
+    eof_grace: Duration,
+    pair_timeout: Duration,
+    max_pending_requests: Option<usize>,
+    correlator: Arc<dyn Correlator>,
+    clock: Arc<dyn Clock>,
+    rate_limits: HashMap<String, RateLimit>,
+    params_limits: HashMap<String, ParamsLimit>,
+    health_check: Option<HealthCheck>,
+    telemetry: Option<Telemetry>,
+    #[cfg(feature = "schema")]
+    schemas: HashMap<String, jsonschema::Validator>,
+    #[cfg(feature = "compression")]
+    compression: Compression,
 }
 
 impl ProxyBuilder {
     pub fn new() -> Self {
+        Self::with_state(())
+    }
+}
+
+impl<S: Send + Sync + 'static> ProxyBuilder<S> {
+    /// Starts a builder for a proxy whose hooks share `state`, e.g. their
+    /// configuration, caches or clients for external services. Hooks
+    /// implementing `Hook<S>` read it through `HookContext::state`, typed,
+    /// without downcasting. `new()` is `with_state(())`.
+    pub fn with_state(state: S) -> Self {
         Self {
-            hooks: HashMap::new(),
+            state,
+            hooks: HookRegistry::default(),
+            known_methods: HashSet::new(),
+            allowlist: None,
+            filter_unknown_dollar_methods: false,
+            observe_only: false,
+            pass_through_unparsed: false,
+            preserve_unmodified_bytes: false,
+            cancel_pending_on_eof: false,
+            normalize_document_sync: false,
+            hook_error_report: None,
+            hook_error_policy: HookErrorPolicy::default(),
+            read_options: ReadOptions::default(),
+            outgoing_headers: OutgoingHeaders::default(),
+            jsonrpc_field: JsonRpcField::default(),
+            coalesce_key: None,
+            stop_after: None,
+            write_retry: None,
+            dedup: None,
+            hold_until_initialized: false,
+            trace_to_stderr: false,
+            keep_recent: None,
+            record_filter: None,
+            dump_recent_on_error: false,
+            protocol_check: None,
+            redactor: None,
+            serialized_writes: false,
+            write_coalesce_max: DEFAULT_WRITE_COALESCE_MAX,
+            idle_timeout: None,
+            half_close_grace: DEFAULT_HALF_CLOSE_GRACE,
+            eof_grace: DEFAULT_EOF_GRACE,
+            pair_timeout: DEFAULT_PAIR_TIMEOUT,
+            max_pending_requests: None,
+            correlator: Arc::new(HashMapCorrelator::new()),
+            clock: Arc::new(TokioClock),
+            rate_limits: HashMap::new(),
+            params_limits: HashMap::new(),
+            health_check: None,
+            telemetry: None,
+            #[cfg(feature = "schema")]
+            schemas: HashMap::new(),
+            #[cfg(feature = "compression")]
+            compression: Compression::default(),
+        }
+    }
+
+    pub fn with_hook(mut self, method: &str, hook: Arc<dyn Hook<S>>) -> Self {
+        self.hooks.insert(method, None, hook);
+        self
+    }
+
+    pub fn with_hooks(mut self, methods: &[&str], hook: Arc<dyn Hook<S>>) -> Self {
+        for method in methods {
+            self.hooks.insert(method, None, Arc::clone(&hook));
+        }
+        self
+    }
+
+    /// Registers a hook for every method that has no hook of its own,
+    /// including responses to requests for such methods. Without one, those
+    /// messages are forwarded unchanged; with one, they are forwarded as the
+    /// hook returns them. Responses whose request the proxy did not see are
+    /// not passed to it.
+    pub fn with_default_hook(mut self, hook: Arc<dyn Hook<S>>) -> Self {
+        self.hooks.set_default(hook);
+        self
+    }
+
+    /// Like `with_hook`, but the hook only sees traffic for `method` heading
+    /// in `direction`: `ToServer` for what the client sends, `ToClient` for
+    /// what the server sends. Responses are matched by the direction of the
+    /// request they answer. Useful for methods used both ways, such as
+    /// `$/cancelRequest`. Takes precedence over a hook for both directions.
+    pub fn with_hook_for(
+        mut self,
+        direction: Direction,
+        method: &str,
+        hook: Arc<dyn Hook<S>>,
+    ) -> Self {
+        self.hooks.insert(method, Some(direction), hook);
+        self
+    }
+
+    /// Rewrites requests for `method` with a closure, for one-off transforms
+    /// that do not warrant a full `Hook`. The closure runs after any hook
+    /// already registered for `method`, and transforms run in the order they
+    /// were added. Registering a hook with `with_hook` afterwards replaces them.
+    pub fn map_request<F>(mut self, method: &str, map: F) -> Self
+    where
+        F: Fn(Request) -> Request + Send + Sync + 'static,
+    {
+        self.hooks.wrap(method, None, |inner| {
+            Arc::new(MapHook::requests(inner, map))
+        });
+        self
+    }
+
+    /// Same as `map_request`, for responses to requests for `method`. For
+    /// example, capping completion lists after a hook has ranked them:
+    ///
+    /// ```
+    /// # use async_trait::async_trait;
+    /// # use lsp_proxy::testing::TestClient;
+    /// # use lsp_proxy::transport::duplex;
+    /// # use lsp_proxy::{Hook, HookContext, HookOutput, HookResult, Message, ProxyBuilder, Response};
+    /// # use serde_json::json;
+    /// # use std::sync::Arc;
+    /// # /// Puts the last items first.
+    /// # struct Rank;
+    /// # #[async_trait]
+    /// # impl Hook for Rank {
+    /// #     async fn on_response(&self, mut response: Response, _: &HookContext) -> HookResult {
+    /// #         if let Some(items) = response.result.as_mut().and_then(|r| r["items"].as_array_mut()) {
+    /// #             items.reverse();
+    /// #         }
+    /// #         Ok(HookOutput::new(Message::Response(response)))
+    /// #     }
+    /// # }
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let proxy = ProxyBuilder::new()
+    ///     .with_hook("textDocument/completion", Arc::new(Rank))
+    ///     .map_response("textDocument/completion", |mut response| {
+    ///         let items = response
+    ///             .result
+    ///             .as_mut()
+    ///             .and_then(|result| result.get_mut("items"))
+    ///             .and_then(|items| items.as_array_mut());
+    ///         if let Some(items) = items {
+    ///             items.truncate(50);
+    ///         }
+    ///         response
+    ///     })
+    ///     .build();
+    /// # let io = duplex();
+    /// # tokio::spawn(proxy.forward(
+    /// #     io.proxy_server.reader,
+    /// #     io.proxy_server.writer,
+    /// #     io.proxy_client.reader,
+    /// #     io.proxy_client.writer,
+    /// # ));
+    /// # let mut client = TestClient::from_endpoint(io.client);
+    /// # let mut server = TestClient::from_endpoint(io.server);
+    /// # client.send(&Message::request(1, "textDocument/completion", None)).await.unwrap();
+    /// # server.recv().await.unwrap();
+    /// # let items: Vec<_> = (0..200).map(|i| json!({ "label": format!("item{}", i) })).collect();
+    /// # let result = Some(json!({ "isIncomplete": false, "items": items }));
+    /// # let response = Response { id: 1.into(), result, error: None };
+    /// # server.send(&Message::Response(response)).await.unwrap();
+    /// # let Message::Response(response) = client.recv().await.unwrap() else { panic!() };
+    /// # let items = response.result.unwrap()["items"].as_array().unwrap().clone();
+    /// # assert_eq!(items.len(), 50);
+    /// # assert_eq!(items[0]["label"], "item199");
+    /// # }
+    /// ```
+    pub fn map_response<F>(mut self, method: &str, map: F) -> Self
+    where
+        F: Fn(Response) -> Response + Send + Sync + 'static,
+    {
+        self.hooks.wrap(method, None, |inner| {
+            Arc::new(MapHook::responses(inner, map))
+        });
+        self
+    }
+
+    /// Forwards only requests and notifications whose method is in `methods`.
+    /// Other requests are answered with a `MethodNotFound` error and other
+    /// notifications are dropped; responses always pass. Remember to include
+    /// lifecycle methods such as `initialize`, `initialized`, `shutdown` and
+    /// `exit`.
+    pub fn allowlist(mut self, methods: &[&str]) -> Self {
+        self.allowlist = Some(methods.iter().map(|method| (*method).to_owned()).collect());
+        self
+    }
+
+    /// Handles unknown implementation-defined `$/` methods the way the spec asks
+    /// a receiver to, instead of forwarding them: notifications are dropped and
+    /// requests are answered with a `MethodNotFound` error. A `$/` method is
+    /// unknown if it is not a standard LSP method, has no hook and was not
+    /// whitelisted with `with_known_methods`. Applies in both directions.
+    pub fn filter_unknown_dollar_methods(mut self, enabled: bool) -> Self {
+        self.filter_unknown_dollar_methods = enabled;
+        self
+    }
+
+    /// Keeps a copy of every document the client opens and, once the server's
+    /// `initialize` result says it only accepts full-text sync, rewrites
+    /// incremental `textDocument/didChange` notifications into a single
+    /// full-text change before hooks see them. Ranges are applied in the
+    /// server's `positionEncoding` (UTF-16 by default). Sync kinds registered
+    /// dynamically later are not followed, and a change that cannot be applied
+    /// stops the rewriting for that document.
+    pub fn normalize_document_sync(mut self, enabled: bool) -> Self {
+        self.normalize_document_sync = enabled;
+        self
+    }
+
+    /// Makes the proxy fully transparent: hooks are still invoked, but the
+    /// message they return is discarded and the original is always forwarded.
+    /// Generated messages are suppressed as well, so hooks can only observe.
+    pub fn observe_only(mut self, observe_only: bool) -> Self {
+        self.observe_only = observe_only;
+        self
+    }
+
+    /// Forwards bodies that are valid JSON but not a valid request, response or
+    /// notification byte-for-byte to the other peer instead of dropping them,
+    /// for JSON-RPC extensions the proxy does not understand. They bypass hooks
+    /// and every other check, and each one is reported on stderr.
+    pub fn pass_through_unparsed(mut self, enabled: bool) -> Self {
+        self.pass_through_unparsed = enabled;
+        self
+    }
+
+    /// Forwards a message whose hook returned it unchanged as the bytes it was
+    /// read as, the way messages without a hook are forwarded, instead of
+    /// serializing it again, which can reorder keys or reformat numbers. Costs
+    /// a comparison of the hook's result with the message read. Messages the
+    /// proxy itself rewrote before the hook ran, through `limit_params` or
+    /// `normalize_document_sync`, are always serialized again.
+    pub fn preserve_unmodified_bytes(mut self, enabled: bool) -> Self {
+        self.preserve_unmodified_bytes = enabled;
+        self
+    }
+
+    /// Reports hook failures to the client instead of only printing them to
+    /// stderr. The error is sent as `window/showMessage` for
+    /// `MessageType::Error`, or as `window/logMessage` with the given severity
+    /// otherwise. What happens to the message itself is still decided by
+    /// `on_hook_error`.
+    pub fn surface_hook_errors(mut self, message_type: MessageType) -> Self {
+        self.hook_error_report = Some(message_type);
+        self
+    }
+
+    /// What to do with a message whose hook returns an error: forward it
+    /// anyway, drop it (the default) or answer it with an error. Overridden by
+    /// `observe_only`, which always forwards.
+    pub fn on_hook_error(mut self, policy: HookErrorPolicy) -> Self {
+        self.hook_error_policy = policy;
+        self
+    }
+
+    /// Rejects incoming messages whose `Content-Length` exceeds `bytes`, in
+    /// place of the 64 MiB default.
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.read_options.max_content_length = Some(bytes);
+        self
+    }
+
+    /// Rejects incoming messages whose arrays and objects nest deeper than
+    /// `depth`, before parsing them. Such messages are logged and dropped, and
+    /// the session carries on. `serde_json` already stops at 128 levels; a
+    /// lower limit protects hooks that walk params recursively.
+    pub fn max_json_depth(mut self, depth: usize) -> Self {
+        self.read_options.max_json_depth = Some(depth);
+        self
+    }
+
+    /// Adds the headers returned by `headers` to every frame written to `peer`,
+    /// after `Content-Length`, e.g. an `X-Request-Id` for a downstream logging
+    /// proxy. Strict LSP clients reject unknown headers, so only enable this for
+    /// peers that tolerate them. Headers that would break the framing are
+    /// dropped. Incoming headers are available to hooks via
+    /// `HookContext::headers`.
+    pub fn with_outgoing_headers<F>(mut self, peer: Direction, headers: F) -> Self
+    where
+        F: Fn(&Message) -> Vec<(String, String)> + Send + Sync + 'static,
+    {
+        let headers: HeaderFn = Arc::new(headers);
+        match peer {
+            Direction::ToClient => self.outgoing_headers.client = Some(headers),
+            Direction::ToServer => self.outgoing_headers.server = Some(headers),
         }
+        self
+    }
+
+    /// Controls the `"jsonrpc": "2.0"` field of outgoing messages. By default
+    /// every message the proxy serializes carries it, while frames forwarded
+    /// unchanged are written as they were read; see `JsonRpcField`.
+    pub fn jsonrpc_field(mut self, policy: JsonRpcField) -> Self {
+        self.jsonrpc_field = policy;
+        self
+    }
+
+    /// Routes writes to both peers through a single task, so all outgoing
+    /// messages are written in exactly the order they were queued. Useful for
+    /// debugging and replay; the cost is that a slow peer also delays writes to
+    /// the other one, so the default keeps an independent writer per peer.
+    /// Each message written gets a sequence number, shown in the
+    /// `trace_to_stderr` output and published by `subscribe_writes`.
+    pub fn serialized_writes(mut self, serialized_writes: bool) -> Self {
+        self.serialized_writes = serialized_writes;
+        self
+    }
+
+    /// Maximum number of queued messages written to a peer before flushing.
+    /// Messages already waiting in the channel are written back to back and
+    /// flushed once, which saves syscalls under bursts of traffic. A value of
+    /// `1` flushes after every message.
+    pub fn write_coalesce_max(mut self, write_coalesce_max: usize) -> Self {
+        self.write_coalesce_max = write_coalesce_max.max(1);
+        self
+    }
+
+    /// Lets a slow peer skip notifications that were superseded before they
+    /// could be written: `publishDiagnostics` for the same document and
+    /// `$/progress` reports for the same token. Only the latest queued one is
+    /// kept, in the position it was queued. Has no effect with
+    /// `serialized_writes`.
+    pub fn coalesce_superseded(mut self, enabled: bool) -> Self {
+        self.coalesce_key = enabled.then(|| Arc::new(superseded_key) as CoalesceKeyFn);
+        self
+    }
+
+    /// Like `coalesce_superseded`, with a custom key. A queued notification is
+    /// dropped when a later one with the same method gets the same key from
+    /// `key`; notifications for which it returns `None` are always written.
+    /// Requests and responses are never coalesced.
+    pub fn coalesce_by<F>(mut self, key: F) -> Self
+    where
+        F: Fn(&Message) -> Option<String> + Send + Sync + 'static,
+    {
+        self.coalesce_key = Some(Arc::new(key));
+        self
+    }
+
+    /// Stops forwarding once a message for which `predicate` returns `true`
+    /// has been written to the peer it was heading to, e.g. the response to
+    /// `shutdown` or a custom `$/done`. The predicate sees messages as they are
+    /// forwarded, after hooks, along with the direction they travel. Shutdown
+    /// then proceeds as with `Proxy::forward_with_shutdown`.
+    pub fn stop_after<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Message, Direction) -> bool + Send + Sync + 'static,
+    {
+        self.stop_after = Some(Box::new(predicate));
+        self
+    }
+
+    /// Logs every forwarded message to stderr while the client has tracing set
+    /// to `verbose`, through `initialize` or `$/setTrace`, so the proxy's own
+    /// output follows the trace level negotiated on the wire. The trace
+    /// notifications themselves are forwarded unchanged.
+    pub fn trace_to_stderr(mut self, enabled: bool) -> Self {
+        self.trace_to_stderr = enabled;
+        self
+    }
+
+    /// Keeps the last `capacity` messages read in each direction, as received
+    /// and before hooks run, for `ProxyHandle::recent`.
+    pub fn keep_recent(mut self, capacity: usize) -> Self {
+        self.keep_recent = Some(capacity);
+        self
+    }
+
+    /// Keeps only the messages `filter` accepts in the `keep_recent` buffers,
+    /// e.g. one method's traffic while debugging it. A response is kept along
+    /// with the request it answers, matched by id, so filtering on the method
+    /// captures both sides of each exchange.
+    pub fn record_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Message) -> bool + Send + Sync + 'static,
+    {
+        self.record_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Logs the messages kept by `keep_recent` to stderr when a hook panics or
+    /// forwarding fails, through the redactor if one is set.
+    pub fn dump_recent_on_error(mut self, enabled: bool) -> Self {
+        self.dump_recent_on_error = enabled;
+        self
+    }
+
+    /// Checks the traffic against the LSP message ordering rules and reports
+    /// violations such as duplicate responses or requests before
+    /// `initialize`; see `ProtocolCheck`.
+    pub fn check_protocol(mut self, check: ProtocolCheck) -> Self {
+        self.protocol_check = Some(check);
+        self
+    }
+
+    /// Redacts the paths configured on `redactor` in every copy of a message
+    /// the proxy logs or publishes: `trace_to_stderr` output, the pairs from
+    /// `subscribe_pairs` and the messages from `subscribe_writes`. Forwarded
+    /// messages are left intact.
+    pub fn redact_logs(mut self, redactor: Redactor) -> Self {
+        self.redactor = Some(redactor);
+        self
+    }
+
+    /// Retries writing requests for the idempotent `methods` up to
+    /// `max_retries` times, `delay` apart, when writing them to a peer fails,
+    /// instead of giving up on the first error. Requests that still cannot be
+    /// written, and requests for any other method, are answered with an
+    /// `InternalError` right away. A frame that was partly written before the
+    /// failure cannot be sent again safely: the stream is given up instead and
+    /// the requests still waiting to be written are answered with errors. Has
+    /// no effect with `serialized_writes`.
+    pub fn retry_idempotent(mut self, methods: &[&str], max_retries: u32, delay: Duration) -> Self {
+        self.write_retry = Some(WriteRetry {
+            methods: methods.iter().map(|method| (*method).to_owned()).collect(),
+            max_retries,
+            delay,
+        });
+        self
+    }
+
+    /// Skips client requests for the idempotent `methods` that repeat one seen
+    /// within `window`: same id, method and params. A repeat of a request still
+    /// in flight is dropped, since the one response answers that id; a repeat
+    /// of an answered request gets the response the client was sent.
+    pub fn dedup_requests(mut self, methods: &[&str], window: Duration) -> Self {
+        self.dedup = Some(RequestDedup::new(
+            methods.iter().map(|method| (*method).to_owned()).collect(),
+            window,
+        ));
+        self
+    }
+
+    /// Holds messages the client sends after `initialize` until the response
+    /// to it has been queued for the client, then processes them in order,
+    /// for servers that fail on requests arriving before the handshake
+    /// completes. `exit` is never held. Disabled by default.
+    pub fn hold_until_initialized(mut self, enabled: bool) -> Self {
+        self.hold_until_initialized = enabled;
+        self
+    }
+
+    /// Whitelists custom (non-standard) methods for `build_validated` and
+    /// `filter_unknown_dollar_methods`.
+    pub fn with_known_methods(mut self, methods: &[&str]) -> Self {
+        self.known_methods
+            .extend(methods.iter().map(|method| (*method).to_owned()));
+        self
+    }
+
+    /// Stops forwarding once no message has been read from either side for
+    /// `idle_timeout`, so a proxy orphaned by a crashed editor does not linger.
+    /// Disabled by default, since a quiet session is usually a healthy one.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// How long to keep delivering server messages after the client has closed
+    /// its side of the connection. Forwarding stops earlier if the server
+    /// closes too. `Duration::ZERO` stops immediately on client EOF.
+    pub fn half_close_grace(mut self, grace: Duration) -> Self {
+        self.half_close_grace = grace;
+        self
+    }
+
+    /// How long the writers may take to deliver messages that are already
+    /// queued once forwarding winds down after either side reached EOF,
+    /// following `half_close_grace` on client EOF. Also bounds how long hooks
+    /// still running when forwarding stops may take to return. Defaults to 1s.
+    pub fn eof_grace(mut self, grace: Duration) -> Self {
+        self.eof_grace = grace;
+        self
+    }
+
+    /// Sends the server `$/cancelRequest` for every request the client left
+    /// unanswered when it disconnects, so the server can stop working on them.
+    /// Off by default, since not every server handles cancellation.
+    pub fn cancel_pending_on_eof(mut self, enabled: bool) -> Self {
+        self.cancel_pending_on_eof = enabled;
+        self
+    }
+
+    /// Caps the number of requests awaiting a response, across both
+    /// directions. Once the cap is reached, further requests are answered with
+    /// an `InternalError` until responses bring the count back down, which
+    /// bounds memory when a peer stops answering. Unlimited by default.
+    pub fn max_pending_requests(mut self, max_pending: usize) -> Self {
+        self.max_pending_requests = Some(max_pending);
+        self
+    }
+
+    /// Replaces how responses are matched to the requests the proxy
+    /// forwarded. The default, `HashMapCorrelator`, keys them by direction
+    /// and raw id.
+    pub fn correlator(mut self, correlator: Arc<dyn Correlator>) -> Self {
+        self.correlator = correlator;
+        self
+    }
+
+    /// Replaces the time source behind every timeout, delay and window the
+    /// proxy applies, e.g. with a `TestClock` so tests can step past a
+    /// `send_request` timeout or a rate limit window without sleeping.
+    /// Defaults to `TokioClock`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Limits how often the client may send `method`, with a token bucket that
+    /// is checked after method filtering and schema validation and before any
+    /// hook runs. Over the limit, notifications are dropped and requests are
+    /// answered with a `RequestFailed` error, or dropped if the limit says so.
+    /// Messages from the server are not limited.
+    pub fn rate_limit(mut self, method: &str, limit: RateLimit) -> Self {
+        self.rate_limits.insert(method.to_owned(), limit);
+        self
+    }
+
+    /// Caps the size of `method`'s messages in both directions, checked
+    /// before any other filter so an oversized message is not parsed further.
+    /// Requests over the limit are answered with a `RequestFailed` error and
+    /// notifications are dropped and logged, unless the limit's `truncate`
+    /// fields can be trimmed to fit. Complements `max_message_size`, which
+    /// stops any frame from being read in full.
+    pub fn limit_params(mut self, method: &str, limit: ParamsLimit) -> Self {
+        self.params_limits.insert(method.to_owned(), limit);
+        self
+    }
+
+    /// Periodically probes the server with `check` while forwarding. The result
+    /// is available from `ProxyHandle::is_healthy`. Without it, only the passive
+    /// `ProxyHandle::since_last_server_message` signal is tracked.
+    pub fn health_check(mut self, check: HealthCheck) -> Self {
+        self.health_check = Some(check);
+        self
     }
 
-    pub fn with_hook(mut self, method: &str, hook: Arc<dyn Hook>) -> Self {
-        self.hooks.insert(method.to_owned(), hook);
+    /// Periodically sends the client `telemetry/event` notifications with
+    /// message, error and latency stats, as configured by `telemetry`.
+    pub fn telemetry(mut self, telemetry: Telemetry) -> Self {
+        self.telemetry = Some(telemetry);
         self
     }
 
-    pub fn build(self) -> Proxy {
-        Proxy::new(self.hooks)
+    /// How long a request may stay unanswered before `subscribe_pairs` reports
+    /// it with no response. Defaults to 30 seconds.
+    pub fn pair_timeout(mut self, timeout: Duration) -> Self {
+        self.pair_timeout = timeout;
+        self
+    }
+
+    /// Validates the `params` of requests and notifications for `method`
+    /// against a JSON schema before forwarding, in both directions. Requests
+    /// that do not conform are answered with an `InvalidParams` error and
+    /// non-conforming notifications are dropped. Missing params are validated
+    /// as `null`. Methods without a schema are forwarded as usual.
+    #[cfg(feature = "schema")]
+    pub fn with_schema(mut self, method: &str, schema: &Value) -> Result<Self, BuildError> {
+        let validator =
+            jsonschema::validator_for(schema).map_err(|e| BuildError::InvalidSchema {
+                method: method.to_owned(),
+                message: e.to_string(),
+            })?;
+        self.schemas.insert(method.to_owned(), validator);
+        Ok(self)
+    }
+
+    /// Enables gzip compression on the link to the server. The proxy advertises
+    /// `Accept-Encoding: gzip` on every frame it sends and compresses bodies of
+    /// at least `min_size` bytes once the server has advertised the same, so a
+    /// peer that does not understand compression never receives it. Gzip
+    /// bodies from the server are only decoded with this set, and
+    /// `max_message_size` applies to their decoded length. Only useful
+    /// when the server side is another compression-aware proxy, e.g. over TCP.
+    #[cfg(feature = "compression")]
+    pub fn compress_server_link(mut self, min_size: usize) -> Self {
+        self.compression.server_min_size = Some(min_size);
+        self
+    }
+
+    /// Same as `compress_server_link`, for the link to the client.
+    #[cfg(feature = "compression")]
+    pub fn compress_client_link(mut self, min_size: usize) -> Self {
+        self.compression.client_min_size = Some(min_size);
+        self
+    }
+
+    pub fn build(self) -> Proxy<S> {
+        Proxy::new(self)
+    }
+
+    /// Like `build`, but fails if a hook is registered for a method that is
+    /// neither a standard LSP method nor whitelisted via `with_known_methods`.
+    /// Catches typos such as `textDocment/hover` that would never match.
+    pub fn build_validated(self) -> Result<Proxy<S>, BuildError> {
+        let mut unknown: Vec<String> = self
+            .hooks
+            .methods()
+            .filter(|method| !is_standard_method(method) && !self.known_methods.contains(*method))
+            .cloned()
+            .collect();
+
+        if !unknown.is_empty() {
+            unknown.sort();
+            return Err(BuildError::UnknownMethods(unknown));
+        }
+
+        Ok(self.build())
     }
 }
 
@@ -0,0 +1,70 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A token bucket for one method: bursts of up to `capacity` messages pass,
+/// then one more every `refill_every`. Excess notifications are dropped;
+/// excess requests are answered with a `RequestFailed` error unless
+/// `reject_requests(false)` drops them too.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    capacity: u32,
+    refill_every: Duration,
+    reject_requests: bool,
+}
+
+impl RateLimit {
+    pub fn new(capacity: u32, refill_every: Duration) -> Self {
+        Self {
+            capacity,
+            refill_every,
+            reject_requests: true,
+        }
+    }
+
+    /// Whether excess requests get an error response (the default) or are
+    /// dropped. A dropped request is never answered, so only turn this off for
+    /// clients that give up on their own.
+    pub fn reject_requests(mut self, reject: bool) -> Self {
+        self.reject_requests = reject;
+        self
+    }
+}
+
+/// The shared state of a `RateLimit`: the tokens left and when they were
+/// last topped up.
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit, now: Instant) -> Self {
+        let tokens = f64::from(limit.capacity);
+        Self {
+            limit,
+            state: Mutex::new((tokens, now)),
+        }
+    }
+
+    /// Takes a token if one is left after refilling for the time elapsed
+    /// until `now`.
+    pub(crate) fn try_acquire(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, refilled_at) = &mut *state;
+        let refill = now.saturating_duration_since(*refilled_at).as_secs_f64()
+            / self.limit.refill_every.as_secs_f64();
+        *tokens = (*tokens + refill).min(f64::from(self.limit.capacity));
+        *refilled_at = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn rejects_requests(&self) -> bool {
+        self.limit.reject_requests
+    }
+}
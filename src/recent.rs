@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::{Direction, Message, RequestId};
+
+pub(crate) type RecordFilter = Box<dyn Fn(&Message) -> bool + Send + Sync>;
+
+/// The last messages read from each peer, oldest first, for working out what
+/// led up to a failure.
+pub(crate) struct RecentMessages {
+    capacity: usize,
+    to_server: Mutex<VecDeque<Message>>,
+    to_client: Mutex<VecDeque<Message>>,
+    filter: Option<RecordFilter>,
+    /// Requests the filter kept, by the direction they travelled and id,
+    /// until their response is read.
+    kept_requests: Mutex<HashSet<(Direction, RequestId)>>,
+}
+
+impl RecentMessages {
+    pub(crate) fn new(capacity: usize, filter: Option<RecordFilter>) -> Self {
+        Self {
+            capacity,
+            to_server: Mutex::new(VecDeque::with_capacity(capacity)),
+            to_client: Mutex::new(VecDeque::with_capacity(capacity)),
+            filter,
+            kept_requests: Mutex::default(),
+        }
+    }
+
+    fn buffer(&self, direction: Direction) -> &Mutex<VecDeque<Message>> {
+        match direction {
+            Direction::ToServer => &self.to_server,
+            Direction::ToClient => &self.to_client,
+        }
+    }
+
+    /// Remembers a message travelling in `direction`, forgetting the oldest
+    /// one once `capacity` are held. The message is cloned before the lock is
+    /// taken, so readers are held up only for the push.
+    pub(crate) fn record(&self, direction: Direction, message: &Message) {
+        if self.capacity == 0 || !self.keeps(direction, message) {
+            return;
+        }
+
+        let message = message.clone();
+        let mut buffer = self.buffer(direction).lock().unwrap();
+        if buffer.len() == self.capacity {
+            buffer.pop_front();
+        }
+        buffer.push_back(message);
+    }
+
+    /// Whether the filter, if any, lets `message` in. A response is also let
+    /// in when the request it answers was, even though it has no method of
+    /// its own to filter on.
+    fn keeps(&self, direction: Direction, message: &Message) -> bool {
+        let Some(filter) = &self.filter else {
+            return true;
+        };
+
+        let mut kept_requests = self.kept_requests.lock().unwrap();
+        match message {
+            Message::Request(request) => {
+                let keep = filter(message);
+                if keep {
+                    kept_requests.insert((direction, request.id.clone()));
+                }
+                keep
+            }
+            Message::Response(response) => {
+                kept_requests.remove(&(direction.opposite(), response.id.clone()))
+                    || filter(message)
+            }
+            Message::Notification(_) => filter(message),
+        }
+    }
+
+    pub(crate) fn snapshot(&self, direction: Direction) -> Vec<Message> {
+        self.buffer(direction)
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
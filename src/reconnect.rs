@@ -0,0 +1,81 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReconnectEvent {
+    Disconnected,
+    Reconnecting { attempt: u32, delay: Duration },
+    Reconnected { attempt: u32 },
+    GaveUp { attempts: u32 },
+}
+
+type Observer = Arc<dyn Fn(&ReconnectEvent) + Send + Sync>;
+
+/// Controls how `Proxy::forward_supervised` re-establishes a lost server
+/// connection: the delay starts at `initial_delay` and doubles after every
+/// failed attempt, up to `max_delay`.
+#[derive(Clone)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+    observer: Option<Observer>,
+}
+
+impl ReconnectPolicy {
+    pub fn new() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            max_attempts: Some(10),
+            observer: None,
+        }
+    }
+
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Attempts per disconnect before giving up; `None` retries forever.
+    pub fn max_attempts(mut self, max_attempts: Option<u32>) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn on_event<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(&ReconnectEvent) + Send + Sync + 'static,
+    {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+
+    pub(crate) fn gives_up_after(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt > max)
+    }
+
+    pub(crate) fn notify(&self, event: ReconnectEvent) {
+        if let Some(observer) = &self.observer {
+            observer(&event);
+        }
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
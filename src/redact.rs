@@ -0,0 +1,70 @@
+use serde_json::Value;
+
+use crate::{Message, Request, Response};
+
+const REDACTED: &str = "<redacted>";
+
+/// Replaces sensitive values with `"<redacted>"` in copies of messages made
+/// for logs and recordings, so captures can be shared. Paths are JSON pointers
+/// into the message, starting at `params`, `result` or `error`, e.g.
+/// `/params/initializationOptions/token`. Forwarded messages are never
+/// touched.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    paths: Vec<String>,
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a path to redact. Paths that do not exist in a message are
+    /// skipped.
+    pub fn path(mut self, pointer: &str) -> Self {
+        self.paths.push(pointer.to_owned());
+        self
+    }
+
+    /// Returns a copy of `message` with every configured path redacted.
+    pub fn redact(&self, message: &Message) -> Message {
+        match message {
+            Message::Request(request) => Message::Request(self.redact_request(request)),
+            Message::Response(response) => Message::Response(self.redact_response(response)),
+            Message::Notification(notification) => {
+                let mut notification = notification.clone();
+                self.redact_field("params", notification.params.as_mut());
+                Message::Notification(notification)
+            }
+        }
+    }
+
+    pub(crate) fn redact_request(&self, request: &Request) -> Request {
+        let mut request = request.clone();
+        self.redact_field("params", request.params.as_mut());
+        request
+    }
+
+    pub(crate) fn redact_response(&self, response: &Response) -> Response {
+        let mut response = response.clone();
+        self.redact_field("result", response.result.as_mut());
+        self.redact_field("error", response.error.as_mut());
+        response
+    }
+
+    fn redact_field(&self, field: &str, value: Option<&mut Value>) {
+        let Some(value) = value else {
+            return;
+        };
+        for path in &self.paths {
+            if let Some(pointer) = path
+                .strip_prefix('/')
+                .and_then(|path| path.strip_prefix(field))
+                && (pointer.is_empty() || pointer.starts_with('/'))
+                && let Some(target) = value.pointer_mut(pointer)
+            {
+                *target = Value::from(REDACTED);
+            }
+        }
+    }
+}
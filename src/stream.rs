@@ -0,0 +1,230 @@
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::Message;
+use crate::transport::{
+    Frame, RawFrame, ReadOptions, TransportError, WriteOptions, check_header_size, content_length,
+    decode_frame, encode_frame, parse_header_line, serialize,
+};
+
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Buffered frames are written out before accepting more once they exceed this.
+const WRITE_HIGH_WATER: usize = 64 * 1024;
+
+/// Reads messages from a reader as a `Stream`. The stream owns its read
+/// buffer, so bytes read ahead of one frame are kept for the next. Ends when
+/// the reader reaches EOF between frames.
+pub struct MessageStream<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    options: ReadOptions,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> MessageStream<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_options(reader, ReadOptions::default())
+    }
+
+    pub fn with_options(reader: R, options: ReadOptions) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+            options,
+            eof: false,
+        }
+    }
+
+    /// Returns the reader. Bytes already buffered are lost.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Takes a complete frame off the front of the buffer, or returns how many
+    /// bytes are needed before one can be complete. Empty keep-alive frames
+    /// are skipped.
+    fn parse(&mut self) -> Result<Result<Frame, usize>, TransportError> {
+        loop {
+            let head = match self.parse_head() {
+                Ok(Some(head)) => head,
+                Ok(None) => return Ok(Err(self.buffer.len() + 1)),
+                Err(e) => {
+                    // Without valid headers the rest cannot be framed reliably.
+                    self.buffer.clear();
+                    return Err(e);
+                }
+            };
+
+            if self.buffer.len() < head.frame_len {
+                return Ok(Err(head.frame_len));
+            }
+
+            let content = self.buffer[head.body_start..head.frame_len].to_vec();
+            self.buffer.drain(..head.frame_len);
+            if !content.is_empty() {
+                return decode_frame(head.headers, content, &self.options)
+                    .and_then(RawFrame::parse)
+                    .map(Ok);
+            }
+        }
+    }
+
+    /// Parses the headers at the front of the buffer, or returns `None` if
+    /// they are not complete yet.
+    fn parse_head(&self) -> Result<Option<FrameHead>, TransportError> {
+        let mut headers = Vec::new();
+        let mut position = 0;
+
+        loop {
+            let Some(end) = self.buffer[position..].iter().position(|b| *b == b'\n') else {
+                check_header_size(self.buffer.len(), headers.len(), &self.options)?;
+                return Ok(None);
+            };
+            let line = &self.buffer[position..position + end + 1];
+            position += end + 1;
+            check_header_size(position, headers.len(), &self.options)?;
+
+            match parse_header_line(line)? {
+                Some(header) => headers.push(header),
+                None => break,
+            }
+        }
+
+        let frame_len = position + content_length(&headers, &self.options)?;
+        Ok(Some(FrameHead {
+            headers,
+            body_start: position,
+            frame_len,
+        }))
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for MessageStream<R> {
+    type Item = io::Result<Message>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            let needed = match this.parse() {
+                Ok(Ok(frame)) => {
+                    let message = Message::from_value(frame.content)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+                    return Poll::Ready(Some(message));
+                }
+                Ok(Err(needed)) => needed,
+                Err(e) => return Poll::Ready(Some(Err(e.into()))),
+            };
+
+            if this.eof {
+                if this.buffer.is_empty() {
+                    return Poll::Ready(None);
+                }
+                let error = match this.parse_head() {
+                    Ok(Some(head)) => TransportError::Truncated {
+                        expected: Some(head.frame_len - head.body_start),
+                        received: this.buffer.len() - head.body_start,
+                    },
+                    _ => TransportError::Truncated {
+                        expected: None,
+                        received: this.buffer.len(),
+                    },
+                };
+                this.buffer.clear();
+                return Poll::Ready(Some(Err(error.into())));
+            }
+
+            let start = this.buffer.len();
+            this.buffer
+                .resize(start + (needed - start).max(READ_CHUNK), 0);
+            let mut read_buf = ReadBuf::new(&mut this.buffer[start..]);
+            let result = Pin::new(&mut this.reader).poll_read(cx, &mut read_buf);
+            let read = read_buf.filled().len();
+            this.buffer.truncate(start + read);
+
+            ready!(result)?;
+            this.eof = read == 0;
+        }
+    }
+}
+
+struct FrameHead {
+    headers: Vec<(String, String)>,
+    body_start: usize,
+    frame_len: usize,
+}
+
+/// Writes messages to a writer as a `Sink`. Frames are buffered and written
+/// when the buffer fills up or the sink is flushed, so feeding several messages
+/// before flushing writes them together.
+pub struct MessageSink<W> {
+    writer: W,
+    buffer: Vec<u8>,
+    options: WriteOptions,
+}
+
+impl<W: AsyncWrite + Unpin> MessageSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self::with_options(writer, WriteOptions::default())
+    }
+
+    pub fn with_options(writer: W, options: WriteOptions) -> Self {
+        Self {
+            writer,
+            buffer: Vec::new(),
+            options,
+        }
+    }
+
+    /// Returns the writer. Frames not flushed yet are lost.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.buffer.is_empty() {
+            let written = ready!(Pin::new(&mut self.writer).poll_write(cx, &self.buffer))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.buffer.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Sink<Message> for MessageSink<W> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.buffer.len() < WRITE_HIGH_WATER {
+            return Poll::Ready(Ok(()));
+        }
+        this.poll_write_buffer(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> io::Result<()> {
+        let this = self.get_mut();
+        let content = serialize(&message.to_value())?;
+        encode_frame(&mut this.buffer, &content, &[], &this.options)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffer(cx))?;
+        Pin::new(&mut this.writer).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffer(cx))?;
+        ready!(Pin::new(&mut this.writer).poll_flush(cx))?;
+        Pin::new(&mut this.writer).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,111 @@
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+type PayloadFn = Arc<dyn Fn(&ProxyStats) -> Value + Send + Sync>;
+
+/// What the proxy saw during one telemetry interval.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyStats {
+    /// Messages received from either peer.
+    pub messages: u64,
+    /// Responses from either peer that carried an error, not counting answers
+    /// to requests the proxy sent itself.
+    pub errors: u64,
+    /// 95th percentile time between forwarding a request and receiving its
+    /// response, or `None` if no request was answered.
+    pub p95_latency: Option<Duration>,
+}
+
+/// Periodically sends the client a `telemetry/event` notification with the
+/// `ProxyStats` of the last `interval`. By default the params are
+/// `{"messages": .., "errors": .., "p95LatencyMs": ..}`; `payload` replaces
+/// them.
+#[derive(Clone)]
+pub struct Telemetry {
+    interval: Duration,
+    payload: Option<PayloadFn>,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            payload: None,
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Builds the notification params from the stats of an interval.
+    pub fn payload<F>(mut self, payload: F) -> Self
+    where
+        F: Fn(&ProxyStats) -> Value + Send + Sync + 'static,
+    {
+        self.payload = Some(Arc::new(payload));
+        self
+    }
+
+    pub(crate) fn period(&self) -> Duration {
+        self.interval
+    }
+
+    pub(crate) fn params(&self, stats: &ProxyStats) -> Value {
+        match &self.payload {
+            Some(payload) => payload(stats),
+            None => json!({
+                "messages": stats.messages,
+                "errors": stats.errors,
+                "p95LatencyMs": stats
+                    .p95_latency
+                    .map(|latency| latency.as_secs_f64() * 1000.0),
+            }),
+        }
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counters behind `ProxyStats`, reset each time they are taken.
+#[derive(Default)]
+pub(crate) struct StatsCollector {
+    messages: AtomicU64,
+    errors: AtomicU64,
+    latencies: Mutex<Vec<Duration>>,
+}
+
+impl StatsCollector {
+    pub(crate) fn record_message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_latency(&self, latency: Duration) {
+        self.latencies.lock().unwrap().push(latency);
+    }
+
+    /// Returns the stats gathered since the last call and starts over.
+    pub(crate) fn take(&self) -> ProxyStats {
+        let mut latencies = std::mem::take(&mut *self.latencies.lock().unwrap());
+        latencies.sort_unstable();
+        let p95_index = (latencies.len() * 95).div_ceil(100).saturating_sub(1);
+
+        ProxyStats {
+            messages: self.messages.swap(0, Ordering::Relaxed),
+            errors: self.errors.swap(0, Ordering::Relaxed),
+            p95_latency: latencies.get(p95_index).copied(),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+use crate::transport::{
+    DuplexReader, DuplexWriter, Endpoint, ReadOptions, read_frame, write_message,
+};
+use crate::{Message, Response};
+
+/// A minimal LSP peer for driving a proxy in tests. It can play either the
+/// client or the server: it sends framed messages and reads the ones the proxy
+/// writes back, keeping messages it was not waiting for until `recv` is called.
+pub struct TestClient<R = DuplexReader, W = DuplexWriter> {
+    reader: BufReader<R>,
+    writer: W,
+    next_id: i64,
+    backlog: VecDeque<Message>,
+}
+
+impl TestClient {
+    pub fn from_endpoint(endpoint: Endpoint) -> Self {
+        Self::new(endpoint.reader, endpoint.writer)
+    }
+}
+
+impl<R, W> TestClient<R, W>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer,
+            next_id: 1,
+            backlog: VecDeque::new(),
+        }
+    }
+
+    pub async fn send(&mut self, message: &Message) -> io::Result<()> {
+        write_message(&mut self.writer, &message.to_value()).await
+    }
+
+    /// Writes `bytes` as they are, e.g. a hand-made or malformed frame.
+    pub async fn send_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes).await?;
+        self.writer.flush().await
+    }
+
+    pub async fn recv(&mut self) -> io::Result<Message> {
+        match self.backlog.pop_front() {
+            Some(message) => Ok(message),
+            None => self.read().await,
+        }
+    }
+
+    /// Sends a request with the next free id and waits for its response.
+    pub async fn request(&mut self, method: &str, params: Option<Value>) -> io::Result<Response> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(&Message::request(id, method, params)).await?;
+
+        loop {
+            match self.read().await? {
+                Message::Response(response) if response.id == id => return Ok(response),
+                message => self.backlog.push_back(message),
+            }
+        }
+    }
+
+    async fn read(&mut self) -> io::Result<Message> {
+        let frame = read_frame(&mut self.reader, &ReadOptions::default()).await?;
+        Message::from_value(frame.content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
@@ -1,58 +1,995 @@
 use serde_json::Value;
+use std::borrow::Cow;
+use std::fmt::Display;
 use std::io;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf,
+};
+#[cfg(unix)]
+use tokio::net::{
+    UnixListener, UnixStream,
+    unix::{OwnedReadHalf, OwnedWriteHalf},
+};
 
-pub async fn read_message<R: AsyncReadExt + Unpin>(reader: &mut R) -> io::Result<Value> {
-    let mut buffer = BufReader::new(reader);
+#[derive(Debug)]
+pub enum TransportError {
+    Eof,
+    /// The stream closed part way through a frame, after `received` bytes of
+    /// it. `expected` is the body length when the headers were complete.
+    Truncated {
+        expected: Option<usize>,
+        received: usize,
+    },
+    MissingContentLength,
+    InvalidContentLength(String),
+    /// The body is longer than `ReadOptions::max_content_length`. For a
+    /// compressed body, `length` is how much of it was decoded before giving
+    /// up.
+    TooLarge {
+        length: usize,
+        limit: usize,
+    },
+    TooDeep {
+        limit: usize,
+    },
+    /// The header section is longer than `ReadOptions::max_header_bytes`.
+    HeadersTooLarge {
+        limit: usize,
+    },
+    /// There are more headers than `ReadOptions::max_header_lines`.
+    TooManyHeaders {
+        limit: usize,
+    },
+    InvalidUtf8Header,
+    InvalidHeader(String),
+    UnsupportedEncoding(String),
+    UnsupportedCharset(String),
+    InvalidJson(serde_json::Error),
+    Io(io::Error),
+}
+
+impl Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportError::Eof => write!(f, "Stream closed"),
+            TransportError::Truncated {
+                expected: Some(expected),
+                received,
+            } => write!(
+                f,
+                "Stream closed after {} of {} body bytes",
+                received, expected
+            ),
+            TransportError::Truncated {
+                expected: None,
+                received,
+            } => write!(
+                f,
+                "Stream closed after {} bytes of unfinished headers",
+                received
+            ),
+            TransportError::MissingContentLength => write!(f, "Missing Content-Length header"),
+            TransportError::InvalidContentLength(value) => {
+                write!(f, "Invalid Content-Length: {}", value)
+            }
+            TransportError::TooLarge { length, limit } => write!(
+                f,
+                "Message length {} exceeds the limit of {} bytes",
+                length, limit
+            ),
+            TransportError::TooDeep { limit } => {
+                write!(f, "JSON nesting exceeds the limit of {} levels", limit)
+            }
+            TransportError::HeadersTooLarge { limit } => {
+                write!(f, "Headers exceed the limit of {} bytes", limit)
+            }
+            TransportError::TooManyHeaders { limit } => {
+                write!(f, "Headers exceed the limit of {} lines", limit)
+            }
+            TransportError::InvalidUtf8Header => write!(f, "Header is not valid UTF-8"),
+            TransportError::InvalidHeader(msg) => write!(f, "Invalid header: {}", msg),
+            TransportError::UnsupportedEncoding(encoding) => {
+                write!(f, "Unsupported Content-Encoding: {}", encoding)
+            }
+            TransportError::UnsupportedCharset(charset) => {
+                write!(f, "Unsupported charset: {}", charset)
+            }
+            TransportError::InvalidJson(e) => write!(f, "Invalid JSON: {}", e),
+            TransportError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransportError::InvalidJson(e) => Some(e),
+            TransportError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+impl From<TransportError> for io::Error {
+    fn from(e: TransportError) -> Self {
+        match e {
+            TransportError::Io(e) => e,
+            TransportError::Eof | TransportError::Truncated { .. } => {
+                io::Error::new(io::ErrorKind::UnexpectedEof, e.to_string())
+            }
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+/// Limits and decoding settings for reading frames. The default caps bodies
+/// at 64 MiB and the header section at 8 KiB or 32 headers, so a peer cannot
+/// make the reader allocate without bound; `None` lifts a limit.
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    pub max_content_length: Option<usize>,
+    /// Rejects frames whose header section, line endings included, is longer
+    /// than this, before reading any further.
+    pub max_header_bytes: Option<usize>,
+    /// Rejects frames with more headers than this.
+    pub max_header_lines: Option<usize>,
+    /// Rejects bodies whose arrays and objects nest deeper than this, before
+    /// they are parsed. `serde_json` itself stops at 128 levels, so only
+    /// lower limits have an effect.
+    pub max_json_depth: Option<usize>,
+    /// Whether gzip-encoded bodies are decoded, which should only be the case
+    /// once this side has advertised `Accept-Encoding: gzip` to the peer.
+    /// Otherwise they are rejected with `UnsupportedEncoding`. Decoded bodies
+    /// are held to `max_content_length` too.
+    #[cfg(feature = "compression")]
+    pub accept_gzip: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            max_content_length: Some(64 * 1024 * 1024),
+            max_header_bytes: Some(8 * 1024),
+            max_header_lines: Some(32),
+            max_json_depth: None,
+            #[cfg(feature = "compression")]
+            accept_gzip: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    #[cfg(feature = "compression")]
+    pub accept_gzip: bool,
+    #[cfg(feature = "compression")]
+    pub gzip_min_size: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub headers: Vec<(String, String)>,
+    pub body: Arc<[u8]>,
+    pub content: Value,
+}
+
+impl Frame {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+/// A frame whose body has been decoded but not parsed, so the proxy can
+/// forward it as is when nothing needs to look inside.
+pub(crate) struct RawFrame {
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Arc<[u8]>,
+}
+
+impl RawFrame {
+    pub(crate) fn parse(self) -> Result<Frame, TransportError> {
+        let content = serde_json::from_slice(&self.body).map_err(TransportError::InvalidJson)?;
+        Ok(Frame {
+            headers: self.headers,
+            body: self.body,
+            content,
+        })
+    }
+}
+
+pub(crate) fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Reads one message from a buffered reader, e.g. a `BufReader` created once
+/// for the connection. Bytes read past the end of the message stay in its
+/// buffer for the next call.
+pub async fn read_message<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<Value, TransportError> {
+    read_message_with_options(reader, &ReadOptions::default()).await
+}
+
+pub async fn read_message_with_options<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    options: &ReadOptions,
+) -> Result<Value, TransportError> {
+    Ok(read_frame(reader, options).await?.content)
+}
+
+/// Reads one frame from a buffered reader, keeping any bytes that were read
+/// ahead in `buffer` for the next frame. Frames with `Content-Length: 0`,
+/// which some tools send as keep-alives, are skipped. A stream that closes
+/// between frames gives `Eof`, one that closes inside a frame gives
+/// `Truncated`.
+pub async fn read_frame<R: AsyncBufRead + Unpin>(
+    buffer: &mut R,
+    options: &ReadOptions,
+) -> Result<Frame, TransportError> {
+    read_raw_frame(buffer, options).await?.parse()
+}
+
+/// Like `read_frame`, but leaves the body unparsed.
+pub(crate) async fn read_raw_frame<R: AsyncBufRead + Unpin>(
+    buffer: &mut R,
+    options: &ReadOptions,
+) -> Result<RawFrame, TransportError> {
+    loop {
+        let headers = read_headers(buffer, options).await?;
+        let content_length = content_length(&headers, options)?;
+        if content_length == 0 {
+            continue;
+        }
+
+        let mut content_buf = vec![0u8; content_length];
+        let mut received = 0;
+        while received < content_length {
+            match buffer.read(&mut content_buf[received..]).await? {
+                0 => {
+                    return Err(TransportError::Truncated {
+                        expected: Some(content_length),
+                        received,
+                    });
+                }
+                read => received += read,
+            }
+        }
+
+        return decode_frame(headers, content_buf, options);
+    }
+}
+
+async fn read_headers<R: AsyncBufRead + Unpin>(
+    buffer: &mut R,
+    options: &ReadOptions,
+) -> Result<Vec<(String, String)>, TransportError> {
     let mut header_buf = Vec::new();
-    let mut content_length: Option<usize> = None;
+    let mut headers = Vec::new();
+    let mut received = 0;
 
-    let bytes_len = buffer.read_until(b'\n', &mut header_buf).await?;
-    if bytes_len == 0 {
-        return Err(io::Error::new(
-            io::ErrorKind::UnexpectedEof,
-            "Unexpected EOF while reading headers",
-        ));
+    loop {
+        header_buf.clear();
+        // One byte past the limit at most, so a line that never ends cannot
+        // grow the buffer.
+        let allowed = options
+            .max_header_bytes
+            .map_or(u64::MAX, |limit| (limit - received) as u64 + 1);
+        let bytes_len = (&mut *buffer)
+            .take(allowed)
+            .read_until(b'\n', &mut header_buf)
+            .await?;
+        received += bytes_len;
+        check_header_size(received, headers.len(), options)?;
+        if bytes_len == 0 || !header_buf.ends_with(b"\n") {
+            if received == 0 {
+                return Err(TransportError::Eof);
+            }
+            return Err(TransportError::Truncated {
+                expected: None,
+                received,
+            });
+        }
+
+        match parse_header_line(&header_buf)? {
+            Some(header) => headers.push(header),
+            None => return Ok(headers),
+        }
     }
+}
 
-    let header = String::from_utf8_lossy(&header_buf);
-    if let Some(value) = header.strip_prefix("Content-Length: ") {
-        content_length = value.trim().parse().ok();
+/// Fails once the headers read so far, `bytes` long and `lines` in number,
+/// are over the limits in `options`.
+pub(crate) fn check_header_size(
+    bytes: usize,
+    lines: usize,
+    options: &ReadOptions,
+) -> Result<(), TransportError> {
+    if let Some(limit) = options.max_header_bytes
+        && bytes > limit
+    {
+        return Err(TransportError::HeadersTooLarge { limit });
+    }
+    if let Some(limit) = options.max_header_lines
+        && lines > limit
+    {
+        return Err(TransportError::TooManyHeaders { limit });
     }
+    Ok(())
+}
 
-    let content_length = content_length.ok_or_else(|| {
-        io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header")
-    })?;
+/// Parses one header line including its `\r\n`. Returns `None` for the empty
+/// line that ends the header section. Some servers end lines with a bare `\n`,
+/// which is accepted as well.
+pub(crate) fn parse_header_line(line: &[u8]) -> Result<Option<(String, String)>, TransportError> {
+    let line = line
+        .strip_suffix(b"\n")
+        .ok_or_else(|| TransportError::InvalidHeader("line is not terminated by \\n".to_owned()))?;
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
 
-    let bytes_len = buffer.read_exact(&mut [0u8; 2]).await?;
-    if bytes_len == 0 {
-        return Err(io::Error::new(
-            io::ErrorKind::UnexpectedEof,
-            "Unexpected EOF while reading headers",
-        ));
+    if line.is_empty() {
+        return Ok(None);
     }
 
-    let mut content_buf = vec![0u8; content_length];
-    buffer.read_exact(&mut content_buf).await?;
+    let header = std::str::from_utf8(line).map_err(|_| TransportError::InvalidUtf8Header)?;
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| TransportError::InvalidHeader(header.to_owned()))?;
 
-    serde_json::from_slice(&content_buf)
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid JSON: {}", e)))
+    Ok(Some((name.trim().to_owned(), value.trim().to_owned())))
+}
+
+pub(crate) fn content_length(
+    headers: &[(String, String)],
+    options: &ReadOptions,
+) -> Result<usize, TransportError> {
+    let value = headers
+        .iter()
+        .rev()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .map(|(_, value)| value)
+        .ok_or(TransportError::MissingContentLength)?;
+    let content_length = value
+        .parse()
+        .map_err(|_| TransportError::InvalidContentLength(value.clone()))?;
+
+    if let Some(limit) = options.max_content_length
+        && content_length > limit
+    {
+        return Err(TransportError::TooLarge {
+            length: content_length,
+            limit,
+        });
+    }
+
+    Ok(content_length)
+}
+
+/// Checks the charset of a body that has been read in full, undoes any
+/// `Content-Encoding` and enforces the nesting limit, leaving the body ready
+/// to parse.
+pub(crate) fn decode_frame(
+    headers: Vec<(String, String)>,
+    content_buf: Vec<u8>,
+    options: &ReadOptions,
+) -> Result<RawFrame, TransportError> {
+    let header = |name: &str| find_header(&headers, name);
+
+    if let Some(content_type) = header("Content-Type") {
+        check_charset(content_type)?;
+    }
+
+    let body: Arc<[u8]> = match header("Content-Encoding") {
+        None => content_buf,
+        Some(encoding) if encoding.eq_ignore_ascii_case("identity") => content_buf,
+        #[cfg(feature = "compression")]
+        Some(encoding) if options.accept_gzip && encoding.eq_ignore_ascii_case("gzip") => {
+            gunzip(&content_buf, options.max_content_length)?
+        }
+        Some(encoding) => return Err(TransportError::UnsupportedEncoding(encoding.to_owned())),
+    }
+    .into();
+
+    if let Some(limit) = options.max_json_depth
+        && json_depth_exceeds(&body, limit)
+    {
+        return Err(TransportError::TooDeep { limit });
+    }
+
+    Ok(RawFrame { headers, body })
+}
+
+/// Whether arrays and objects in `body` nest deeper than `limit`, counting
+/// brackets outside of strings. Malformed JSON is left for the parser to
+/// report.
+fn json_depth_exceeds(body: &[u8], limit: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for &byte in body {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > limit {
+                    return true;
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+// Bodies are always written as UTF-8 without a `Content-Type` header, which is
+// the LSP default, so any accepted input charset is re-emitted in normalized form.
+fn check_charset(content_type: &str) -> Result<(), TransportError> {
+    let charset = content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches('"'));
+
+    match charset {
+        None => Ok(()),
+        Some(charset) if charset.eq_ignore_ascii_case("utf-8") => Ok(()),
+        Some(charset) if charset.eq_ignore_ascii_case("utf8") => Ok(()),
+        Some(charset) => Err(TransportError::UnsupportedCharset(charset.to_owned())),
+    }
+}
+
+pub type DuplexReader = ReadHalf<DuplexStream>;
+pub type DuplexWriter = WriteHalf<DuplexStream>;
+
+pub struct Endpoint {
+    pub reader: DuplexReader,
+    pub writer: DuplexWriter,
+}
+
+/// In-memory connections for running a proxy without OS pipes or sockets.
+/// `client` and `server` are the ends used by a test client and a fake server;
+/// `proxy_client` and `proxy_server` are the matching ends passed to
+/// `Proxy::forward`.
+pub struct Duplex {
+    pub client: Endpoint,
+    pub server: Endpoint,
+    pub proxy_client: Endpoint,
+    pub proxy_server: Endpoint,
+}
+
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+pub fn duplex() -> Duplex {
+    let (client, proxy_client) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+    let (server, proxy_server) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+
+    let endpoint = |stream: DuplexStream| {
+        let (reader, writer) = tokio::io::split(stream);
+        Endpoint { reader, writer }
+    };
+
+    Duplex {
+        client: endpoint(client),
+        server: endpoint(server),
+        proxy_client: endpoint(proxy_client),
+        proxy_server: endpoint(proxy_server),
+    }
+}
+
+/// Connects to the Unix domain socket at `path` and returns its read and
+/// write halves, ready for `Proxy::forward`.
+#[cfg(unix)]
+pub async fn connect_unix(path: impl AsRef<Path>) -> io::Result<(OwnedReadHalf, OwnedWriteHalf)> {
+    Ok(UnixStream::connect(path).await?.into_split())
+}
+
+/// Binds a Unix domain socket at `path`. A socket file left behind by an
+/// earlier run is removed first; one that something is still listening on is
+/// not, and neither is any other kind of file.
+#[cfg(unix)]
+pub fn bind_unix(path: impl AsRef<Path>) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let path = path.as_ref();
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+        && std::os::unix::net::UnixStream::connect(path).is_err()
+    {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
 }
 
 pub async fn write_message<W: AsyncWriteExt + Unpin>(
     writer: &mut W,
     message: &Value,
 ) -> io::Result<()> {
-    let content = serde_json::to_string(message).map_err(|e| {
+    write_messages(writer, std::slice::from_ref(message)).await
+}
+
+pub async fn write_messages<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    messages: &[Value],
+) -> io::Result<()> {
+    write_messages_with_options(writer, messages, &WriteOptions::default()).await
+}
+
+pub async fn write_messages_with_options<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    messages: &[Value],
+    options: &WriteOptions,
+) -> io::Result<()> {
+    for message in messages {
+        write_frame(writer, message, &[], options).await?;
+    }
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Like `write_messages_with_options`, but each message carries extra headers
+/// written after `Content-Length`. Many LSP clients only accept
+/// `Content-Length` and `Content-Type`, so only send extra headers to peers
+/// known to tolerate them. Headers that would break framing, such as
+/// `Content-Length` itself or values containing line breaks, are rejected with
+/// `InvalidInput`.
+pub async fn write_messages_with_headers<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    messages: &[(Value, Vec<(String, String)>)],
+    options: &WriteOptions,
+) -> io::Result<()> {
+    for (message, headers) in messages {
+        write_frame(writer, message, headers, options).await?;
+    }
+    writer.flush().await?;
+
+    Ok(())
+}
+
+pub(crate) fn is_valid_extra_header(name: &str, value: &str) -> bool {
+    !name.is_empty()
+        && !name.contains(':')
+        && !name.eq_ignore_ascii_case("Content-Length")
+        && !name.eq_ignore_ascii_case("Content-Encoding")
+        && !name.contains(['\r', '\n'])
+        && !value.contains(['\r', '\n'])
+}
+
+async fn write_frame<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    message: &Value,
+    headers: &[(String, String)],
+    options: &WriteOptions,
+) -> io::Result<()> {
+    let content = serialize(message)?;
+    write_body(writer, &content, headers, options).await
+}
+
+pub(crate) fn serialize(message: &Value) -> io::Result<Vec<u8>> {
+    serde_json::to_vec(message).map_err(|e| {
         io::Error::new(
             io::ErrorKind::InvalidData,
             format!("Failed to serialize JSON: {}", e),
         )
-    })?;
+    })
+}
 
-    let message = format!("Content-Length: {}\r\n\r\n{}", content.len(), content);
-    writer.write_all(message.as_bytes()).await?;
-    writer.flush().await?;
+/// Writes already serialized bodies as frames and flushes once, like
+/// `write_messages_with_headers` without the serialization step.
+pub(crate) async fn write_bodies<W, B>(
+    writer: &mut W,
+    bodies: &[(B, Vec<(String, String)>)],
+    options: &WriteOptions,
+) -> io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+    B: AsRef<[u8]>,
+{
+    write_bodies_counted(writer, bodies, options)
+        .await
+        .map_err(|failure| failure.error)
+}
+
+/// Where `write_bodies_counted` stopped.
+pub(crate) struct WriteFailure {
+    /// The frames written in full before the one that failed, or all of them
+    /// if only the flush failed.
+    pub(crate) frames: usize,
+    /// The bytes of the failed frame that were written before the error. When
+    /// not zero the stream is left partway through a frame.
+    pub(crate) offset: usize,
+    pub(crate) error: io::Error,
+}
 
+/// Like `write_bodies`, but a failure also reports how far the writing got.
+pub(crate) async fn write_bodies_counted<W, B>(
+    writer: &mut W,
+    bodies: &[(B, Vec<(String, String)>)],
+    options: &WriteOptions,
+) -> Result<(), WriteFailure>
+where
+    W: AsyncWriteExt + Unpin,
+    B: AsRef<[u8]>,
+{
+    for (frames, (body, headers)) in bodies.iter().enumerate() {
+        let fail = |offset, error| WriteFailure {
+            frames,
+            offset,
+            error,
+        };
+        let mut frame = Vec::new();
+        encode_frame(&mut frame, body.as_ref(), headers, options).map_err(|e| fail(0, e))?;
+
+        // `write_all` would not say how much of the frame it wrote.
+        let mut offset = 0;
+        while offset < frame.len() {
+            match writer.write(&frame[offset..]).await {
+                Ok(0) => return Err(fail(offset, io::ErrorKind::WriteZero.into())),
+                Ok(written) => offset += written,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(fail(offset, e)),
+            }
+        }
+    }
+    writer.flush().await.map_err(|error| WriteFailure {
+        frames: bodies.len(),
+        offset: 0,
+        error,
+    })
+}
+
+async fn write_body<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    content: &[u8],
+    headers: &[(String, String)],
+    options: &WriteOptions,
+) -> io::Result<()> {
+    // Header and body go out in one write rather than two.
+    let mut frame = Vec::new();
+    encode_frame(&mut frame, content, headers, options)?;
+    writer.write_all(&frame).await
+}
+
+/// Appends `content` to `frame` as a complete frame, headers included.
+pub(crate) fn encode_frame(
+    frame: &mut Vec<u8>,
+    content: &[u8],
+    headers: &[(String, String)],
+    options: &WriteOptions,
+) -> io::Result<()> {
+    let (content, mut extra_headers) = encode(content, options)?;
+    for (name, value) in headers {
+        if !is_valid_extra_header(name, value) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid header: {}", name),
+            ));
+        }
+        extra_headers.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    let header = format!("Content-Length: {}\r\n{}\r\n", content.len(), extra_headers);
+    frame.reserve(header.len() + content.len());
+    frame.extend_from_slice(header.as_bytes());
+    frame.extend_from_slice(&content);
     Ok(())
 }
+
+#[cfg(not(feature = "compression"))]
+fn encode<'a>(content: &'a [u8], _options: &WriteOptions) -> io::Result<(Cow<'a, [u8]>, String)> {
+    Ok((Cow::Borrowed(content), String::new()))
+}
+
+#[cfg(feature = "compression")]
+fn encode<'a>(content: &'a [u8], options: &WriteOptions) -> io::Result<(Cow<'a, [u8]>, String)> {
+    use flate2::{Compression, write::GzEncoder};
+    use std::io::Write;
+
+    let mut headers = String::new();
+    if options.accept_gzip {
+        headers.push_str("Accept-Encoding: gzip\r\n");
+    }
+
+    match options.gzip_min_size {
+        Some(min_size) if content.len() >= min_size => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content)?;
+            headers.push_str("Content-Encoding: gzip\r\n");
+            Ok((Cow::Owned(encoder.finish()?), headers))
+        }
+        _ => Ok((Cow::Borrowed(content), headers)),
+    }
+}
+
+/// Decodes a gzip body, giving up as soon as it grows past `limit` so a small
+/// frame cannot expand into an unbounded allocation.
+#[cfg(feature = "compression")]
+fn gunzip(content: &[u8], limit: Option<usize>) -> Result<Vec<u8>, TransportError> {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let mut decoded = Vec::new();
+    GzDecoder::new(content)
+        .take(limit.map_or(u64::MAX, |limit| limit as u64 + 1))
+        .read_to_end(&mut decoded)?;
+
+    match limit {
+        Some(limit) if decoded.len() > limit => Err(TransportError::TooLarge {
+            length: decoded.len(),
+            limit,
+        }),
+        _ => Ok(decoded),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use tokio::io::BufReader;
+
+    async fn read(bytes: &[u8], options: &ReadOptions) -> Result<Frame, TransportError> {
+        read_frame(&mut BufReader::new(bytes), options).await
+    }
+
+    #[cfg(feature = "compression")]
+    async fn gzip_frame(body: &Value) -> Vec<u8> {
+        let options = WriteOptions {
+            accept_gzip: false,
+            gzip_min_size: Some(0),
+        };
+        let mut frame = Vec::new();
+        write_messages_with_options(&mut frame, std::slice::from_ref(body), &options)
+            .await
+            .unwrap();
+        frame
+    }
+
+    fn frame(headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut frame = format!("Content-Length: {}\r\n{}\r\n", body.len(), headers).into_bytes();
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    #[tokio::test]
+    async fn body_resembling_headers_is_read_as_body() {
+        let options = ReadOptions::default();
+        let mut bytes = frame("", b"\r\n{\"jsonrpc\":\"2.0\",\"method\":\"first\"}");
+        bytes.extend(frame(
+            "Content-Type: application/vscode-jsonrpc; charset=utf-8\r\n",
+            b"Content-Type: text/plain\r\n\r\n{}",
+        ));
+        bytes.extend(frame("", b"{\"jsonrpc\":\"2.0\",\"method\":\"third\"}"));
+        let mut reader = BufReader::new(bytes.as_slice());
+
+        let first = read_frame(&mut reader, &options).await.unwrap();
+        assert_eq!(first.content["method"], "first");
+        assert!(matches!(
+            read_frame(&mut reader, &options).await,
+            Err(TransportError::InvalidJson(_))
+        ));
+        let third = read_frame(&mut reader, &options).await.unwrap();
+        assert_eq!(third.content["method"], "third");
+    }
+
+    #[tokio::test]
+    async fn utf8_charset_aliases_are_accepted() {
+        let body = br#"{"jsonrpc":"2.0","method":"initialized"}"#;
+        for content_type in [
+            "application/vscode-jsonrpc; charset=utf8",
+            "application/vscode-jsonrpc; charset=utf-8",
+            "application/vscode-jsonrpc; charset=\"UTF-8\"",
+        ] {
+            let bytes = frame(&format!("Content-Type: {}\r\n", content_type), body);
+            let read = read(&bytes, &ReadOptions::default()).await.unwrap();
+            assert_eq!(read.content["method"], "initialized", "{}", content_type);
+        }
+
+        let bytes = frame("Content-Type: application/json; charset=utf-16\r\n", body);
+        assert!(matches!(
+            read(&bytes, &ReadOptions::default()).await,
+            Err(TransportError::UnsupportedCharset(charset)) if charset == "utf-16"
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_input_is_rejected_by_default() {
+        let options = ReadOptions::default();
+
+        let huge = b"Content-Length: 1000000000\r\n\r\n{}";
+        assert!(matches!(
+            read(huge, &options).await,
+            Err(TransportError::TooLarge {
+                length: 1_000_000_000,
+                ..
+            })
+        ));
+
+        // A header line that never ends is cut off at the limit rather than
+        // read to the end of the stream.
+        let mut endless = b"X-Padding: ".to_vec();
+        endless.resize(1024 * 1024, b'a');
+        assert!(matches!(
+            read(&endless, &options).await,
+            Err(TransportError::HeadersTooLarge { limit: 8192 })
+        ));
+
+        let many = frame(&"X-Padding: a\r\n".repeat(100), b"{}");
+        assert!(matches!(
+            read(&many, &options).await,
+            Err(TransportError::TooManyHeaders { limit: 32 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn gzip_is_rejected_unless_accepted() {
+        let frame = b"Content-Length: 4\r\nContent-Encoding: gzip\r\n\r\n\x1f\x8b\x08\x00";
+
+        assert!(matches!(
+            read(frame, &ReadOptions::default()).await,
+            Err(TransportError::UnsupportedEncoding(encoding)) if encoding == "gzip"
+        ));
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn accepted_gzip_is_decoded() {
+        let frame =
+            gzip_frame(&serde_json::json!({"jsonrpc": "2.0", "method": "initialized"})).await;
+        let options = ReadOptions {
+            accept_gzip: true,
+            ..ReadOptions::default()
+        };
+        let decoded = read(&frame, &options).await.unwrap();
+        assert_eq!(decoded.content["method"], "initialized");
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn decoded_gzip_is_held_to_the_size_limit() {
+        let frame = gzip_frame(&serde_json::json!({"padding": "0".repeat(1 << 20)})).await;
+        let options = ReadOptions {
+            max_content_length: Some(4096),
+            accept_gzip: true,
+            ..ReadOptions::default()
+        };
+
+        assert!(frame.len() < 4096);
+        assert!(matches!(
+            read(&frame, &options).await,
+            Err(TransportError::TooLarge { limit: 4096, .. })
+        ));
+    }
+
+    /// Reads frames from `bytes` with both parsers until they give up.
+    /// Anything may come back, as long as nothing panics and both stop.
+    fn read_everything(bytes: &[u8], options: &ReadOptions) {
+        use crate::stream::MessageStream;
+        use futures_core::Stream;
+        use std::pin::Pin;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut reader = BufReader::new(bytes);
+            for _ in 0..=bytes.len() {
+                if read_frame(&mut reader, options).await.is_err() {
+                    break;
+                }
+            }
+
+            let mut stream = MessageStream::with_options(bytes, options.clone());
+            for _ in 0..=bytes.len() {
+                let next = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await;
+                if next.is_none() {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn header_line() -> impl Strategy<Value = String> {
+        prop_oneof![
+            (0usize..64).prop_map(|length| format!("Content-Length: {}\r\n", length)),
+            "[ -~]{0,12}".prop_map(|length| format!("Content-Length: {}\n", length)),
+            "(utf-8|utf8|latin1|\"UTF-8\")".prop_map(|charset| format!(
+                "Content-Type: application/json; charset={}\r\n",
+                charset
+            )),
+            "(gzip|identity|br)".prop_map(|encoding| format!("Content-Encoding: {}\r\n", encoding)),
+            "[ -~]{0,24}\r?\n".prop_map(String::from),
+        ]
+    }
+
+    /// Frames that look plausible enough to get past the headers.
+    fn framed() -> impl Strategy<Value = Vec<u8>> {
+        let body = prop_oneof![
+            "[\\[\\]{}\":,0-9a-z ]{0,48}".prop_map(String::into_bytes),
+            proptest::collection::vec(any::<u8>(), 0..48),
+        ];
+        proptest::collection::vec((proptest::collection::vec(header_line(), 0..4), body), 1..4)
+            .prop_map(|frames| {
+                let mut bytes = Vec::new();
+                for (headers, body) in frames {
+                    bytes.extend(headers.concat().into_bytes());
+                    bytes.extend(b"\r\n");
+                    bytes.extend(body);
+                }
+                bytes
+            })
+    }
+
+    fn read_options() -> impl Strategy<Value = ReadOptions> {
+        (
+            proptest::option::of(0usize..64),
+            proptest::option::of(0usize..64),
+            proptest::option::of(0usize..4),
+            proptest::option::of(0usize..8),
+            any::<bool>(),
+        )
+            .prop_map(
+                |(
+                    max_content_length,
+                    max_header_bytes,
+                    max_header_lines,
+                    max_json_depth,
+                    _accept_gzip,
+                )| ReadOptions {
+                    max_content_length,
+                    max_header_bytes,
+                    max_header_lines,
+                    max_json_depth,
+                    #[cfg(feature = "compression")]
+                    accept_gzip: _accept_gzip,
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn random_bytes_never_panic(
+            bytes in proptest::collection::vec(any::<u8>(), 0..256),
+            options in read_options(),
+        ) {
+            read_everything(&bytes, &options);
+        }
+
+        #[test]
+        fn random_frames_never_panic(bytes in framed(), options in read_options()) {
+            read_everything(&bytes, &options);
+        }
+
+        #[test]
+        fn decode_frame_never_panics(
+            headers in proptest::collection::vec(("[ -~]{0,16}", "[ -~]{0,16}"), 0..4),
+            body in proptest::collection::vec(any::<u8>(), 0..64),
+            options in read_options(),
+        ) {
+            let _ = decode_frame(headers, body, &options).map(RawFrame::parse);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use lsp_types::request::{self as lsp, Request as LspRequest};
+use serde_json::Value;
+
+use crate::Request;
+
+macro_rules! typed_requests {
+    ($($variant:ident => $request:ty),* $(,)?) => {
+        /// A request with its params deserialized into the matching `lsp-types`
+        /// struct, so hooks can match on the request kind instead of comparing
+        /// method names. Methods not listed here are kept as `Custom`.
+        // Built per request and matched on right away, so the size of the
+        // largest params struct does not matter.
+        #[allow(clippy::large_enum_variant)]
+        #[derive(Debug, Clone)]
+        pub enum TypedRequest {
+            $($variant(<$request as LspRequest>::Params),)*
+            Custom(String, Value),
+        }
+
+        impl TypedRequest {
+            /// The LSP method this request is for.
+            pub fn method(&self) -> &str {
+                match self {
+                    $(TypedRequest::$variant(_) => <$request as LspRequest>::METHOD,)*
+                    TypedRequest::Custom(method, _) => method,
+                }
+            }
+
+            fn from_request(request: &Request) -> Result<Self, serde_json::Error> {
+                let params = request.params.clone().unwrap_or(Value::Null);
+
+                $(if request.method == <$request as LspRequest>::METHOD {
+                    return serde_json::from_value(params).map(TypedRequest::$variant);
+                })*
+
+                Ok(TypedRequest::Custom(request.method.clone(), params))
+            }
+        }
+    };
+}
+
+typed_requests! {
+    Initialize => lsp::Initialize,
+    Shutdown => lsp::Shutdown,
+    ShowMessageRequest => lsp::ShowMessageRequest,
+    RegisterCapability => lsp::RegisterCapability,
+    UnregisterCapability => lsp::UnregisterCapability,
+    Completion => lsp::Completion,
+    ResolveCompletionItem => lsp::ResolveCompletionItem,
+    Hover => lsp::HoverRequest,
+    SignatureHelp => lsp::SignatureHelpRequest,
+    Declaration => lsp::GotoDeclaration,
+    Definition => lsp::GotoDefinition,
+    References => lsp::References,
+    TypeDefinition => lsp::GotoTypeDefinition,
+    Implementation => lsp::GotoImplementation,
+    DocumentHighlight => lsp::DocumentHighlightRequest,
+    DocumentSymbol => lsp::DocumentSymbolRequest,
+    WorkspaceSymbol => lsp::WorkspaceSymbolRequest,
+    WorkspaceSymbolResolve => lsp::WorkspaceSymbolResolve,
+    ExecuteCommand => lsp::ExecuteCommand,
+    WillSaveWaitUntil => lsp::WillSaveWaitUntil,
+    ApplyWorkspaceEdit => lsp::ApplyWorkspaceEdit,
+    WorkspaceConfiguration => lsp::WorkspaceConfiguration,
+    CodeAction => lsp::CodeActionRequest,
+    CodeActionResolve => lsp::CodeActionResolveRequest,
+    CodeLens => lsp::CodeLensRequest,
+    CodeLensResolve => lsp::CodeLensResolve,
+    DocumentLink => lsp::DocumentLinkRequest,
+    DocumentLinkResolve => lsp::DocumentLinkResolve,
+    Formatting => lsp::Formatting,
+    RangeFormatting => lsp::RangeFormatting,
+    OnTypeFormatting => lsp::OnTypeFormatting,
+    LinkedEditingRange => lsp::LinkedEditingRange,
+    Rename => lsp::Rename,
+    PrepareRename => lsp::PrepareRenameRequest,
+    DocumentColor => lsp::DocumentColor,
+    ColorPresentation => lsp::ColorPresentationRequest,
+    FoldingRange => lsp::FoldingRangeRequest,
+    WorkspaceFolders => lsp::WorkspaceFoldersRequest,
+    WorkDoneProgressCreate => lsp::WorkDoneProgressCreate,
+    SelectionRange => lsp::SelectionRangeRequest,
+    CallHierarchyPrepare => lsp::CallHierarchyPrepare,
+    CallHierarchyIncomingCalls => lsp::CallHierarchyIncomingCalls,
+    CallHierarchyOutgoingCalls => lsp::CallHierarchyOutgoingCalls,
+    SemanticTokensFull => lsp::SemanticTokensFullRequest,
+    SemanticTokensFullDelta => lsp::SemanticTokensFullDeltaRequest,
+    SemanticTokensRange => lsp::SemanticTokensRangeRequest,
+    SemanticTokensRefresh => lsp::SemanticTokensRefresh,
+    CodeLensRefresh => lsp::CodeLensRefresh,
+    WillCreateFiles => lsp::WillCreateFiles,
+    WillRenameFiles => lsp::WillRenameFiles,
+    WillDeleteFiles => lsp::WillDeleteFiles,
+    ShowDocument => lsp::ShowDocument,
+    Moniker => lsp::MonikerRequest,
+    InlayHint => lsp::InlayHintRequest,
+    InlayHintResolve => lsp::InlayHintResolveRequest,
+    InlayHintRefresh => lsp::InlayHintRefreshRequest,
+    InlineValue => lsp::InlineValueRequest,
+    InlineValueRefresh => lsp::InlineValueRefreshRequest,
+    DocumentDiagnostic => lsp::DocumentDiagnosticRequest,
+    WorkspaceDiagnostic => lsp::WorkspaceDiagnosticRequest,
+    WorkspaceDiagnosticRefresh => lsp::WorkspaceDiagnosticRefresh,
+    TypeHierarchyPrepare => lsp::TypeHierarchyPrepare,
+    TypeHierarchySupertypes => lsp::TypeHierarchySupertypes,
+    TypeHierarchySubtypes => lsp::TypeHierarchySubtypes,
+}
+
+impl Request {
+    /// Deserializes the params into the `TypedRequest` variant for this
+    /// method. Fails if a known method carries params that do not match the
+    /// spec; the error converts into `HookError`, so hooks can use `?`.
+    pub fn typed(&self) -> Result<TypedRequest, serde_json::Error> {
+        TypedRequest::from_request(self)
+    }
+}
@@ -0,0 +1,45 @@
+use serde_json::Value;
+
+/// What `merge` does when both sides hold an array.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArrayMerge {
+    /// The patch's array replaces the target's.
+    #[default]
+    Replace,
+    /// The patch's elements are appended to the target's.
+    Concat,
+}
+
+/// Deep-merges `patch` into `target`. Objects are merged key by key,
+/// recursively, so nested siblings the patch does not mention are kept; arrays
+/// are merged as `arrays` says; anything else, `null` included, replaces the
+/// target's value.
+pub fn merge(target: &mut Value, patch: Value, arrays: ArrayMerge) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                match target.get_mut(&key) {
+                    Some(existing) => merge(existing, value, arrays),
+                    None => {
+                        target.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(target), Value::Array(patch)) if arrays == ArrayMerge::Concat => {
+            target.extend(patch);
+        }
+        (target, patch) => *target = patch,
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`. Unlike `std`'s hashers its output is
+/// fixed, so it can be compared across processes and releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
@@ -0,0 +1,170 @@
+use futures_core::Stream;
+use futures_sink::Sink;
+use futures_util::StreamExt;
+use futures_util::stream::{SplitSink, SplitStream};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use crate::transport::{ReadOptions, content_length, parse_header_line};
+
+/// Splits a WebSocket connection, e.g. one accepted with
+/// `tokio_tungstenite::accept_async`, into a reader and a writer that
+/// `Proxy::forward` takes in place of a byte stream. Browser clients such as
+/// Monaco send one JSON message per text frame without a `Content-Length`
+/// header: the reader hands each frame on as a regular LSP frame, and the
+/// writer sends the body of each frame it is given as one text frame. Pings
+/// are answered by the connection, a close frame ends the reader like EOF,
+/// and shutting down the writer sends one. Requires the `websocket` feature.
+pub fn split<S>(websocket: WebSocketStream<S>) -> (WebSocketReader<S>, WebSocketWriter<S>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (sink, stream) = websocket.split();
+    (
+        WebSocketReader {
+            stream,
+            frame: Vec::new(),
+            position: 0,
+            closed: false,
+        },
+        WebSocketWriter {
+            sink,
+            buffer: Vec::new(),
+            queued: VecDeque::new(),
+        },
+    )
+}
+
+/// The reading half from `split`, yielding each text or binary WebSocket
+/// message as a `Content-Length` frame.
+pub struct WebSocketReader<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    /// The frame being read out, and how much of it was.
+    frame: Vec<u8>,
+    position: usize,
+    closed: bool,
+}
+
+impl<S> AsyncRead for WebSocketReader<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.position == this.frame.len() {
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            let body = match ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                Some(Ok(WsMessage::Text(text))) => text.as_bytes().to_vec(),
+                Some(Ok(WsMessage::Binary(data))) => data.to_vec(),
+                None => {
+                    this.closed = true;
+                    continue;
+                }
+                // Pings are answered by the connection itself, and so is a
+                // close frame, after which the stream ends.
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+            };
+
+            this.frame = format!("Content-Length: {}\r\n\r\n", body.len()).into_bytes();
+            this.frame.extend(body);
+            this.position = 0;
+        }
+
+        let len = buf.remaining().min(this.frame.len() - this.position);
+        buf.put_slice(&this.frame[this.position..this.position + len]);
+        this.position += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The writing half from `split`, sending the body of each `Content-Length`
+/// frame written to it as a text message once it is flushed.
+pub struct WebSocketWriter<S> {
+    sink: SplitSink<WebSocketStream<S>, WsMessage>,
+    /// Bytes written that do not make up a whole frame yet.
+    buffer: Vec<u8>,
+    queued: VecDeque<WsMessage>,
+}
+
+impl<S> WebSocketWriter<S> {
+    /// Moves every complete frame at the front of the buffer to the queue.
+    fn queue_frames(&mut self) -> io::Result<()> {
+        loop {
+            let mut headers = Vec::new();
+            let mut position = 0;
+            loop {
+                let Some(end) = self.buffer[position..].iter().position(|b| *b == b'\n') else {
+                    return Ok(());
+                };
+                let line = &self.buffer[position..position + end + 1];
+                position += end + 1;
+                match parse_header_line(line)? {
+                    Some(header) => headers.push(header),
+                    None => break,
+                }
+            }
+
+            let frame_len = position + content_length(&headers, &ReadOptions::default())?;
+            if self.buffer.len() < frame_len {
+                return Ok(());
+            }
+            let body = self.buffer[position..frame_len].to_vec();
+            self.buffer.drain(..frame_len);
+            let text = String::from_utf8(body)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.queued.push_back(WsMessage::text(text));
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocketWriter<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().buffer.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.queue_frames()?;
+
+        while !this.queued.is_empty() {
+            ready!(Pin::new(&mut this.sink).poll_ready(cx)).map_err(io::Error::other)?;
+            if let Some(message) = this.queued.pop_front() {
+                Pin::new(&mut this.sink)
+                    .start_send(message)
+                    .map_err(io::Error::other)?;
+            }
+        }
+        Pin::new(&mut this.sink)
+            .poll_flush(cx)
+            .map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().sink)
+            .poll_close(cx)
+            .map_err(io::Error::other)
+    }
+}
@@ -0,0 +1,58 @@
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::redact::Redactor;
+use crate::{Direction, Message};
+
+const WRITE_CHANNEL_CAPACITY: usize = 1024;
+
+/// A message as the serialized writer wrote it. `sequence` counts the
+/// messages written to either peer from 1, so it gives their total order.
+#[derive(Debug, Clone)]
+pub struct WrittenMessage {
+    pub sequence: u64,
+    pub direction: Direction,
+    pub message: Message,
+}
+
+pub(crate) struct WriteLog {
+    subscribers: std::sync::Mutex<Vec<Sender<WrittenMessage>>>,
+    redactor: Option<Redactor>,
+}
+
+impl WriteLog {
+    pub(crate) fn new(redactor: Option<Redactor>) -> Self {
+        Self {
+            subscribers: std::sync::Mutex::new(Vec::new()),
+            redactor,
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> Receiver<WrittenMessage> {
+        let (sender, receiver) = mpsc::channel(WRITE_CHANNEL_CAPACITY);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        !self.subscribers.lock().unwrap().is_empty()
+    }
+
+    // Like pairs, writes are dropped for subscribers that fall behind rather
+    // than slowing down the writer.
+    pub(crate) fn publish(&self, sequence: u64, direction: Direction, message: &Message) {
+        let written = WrittenMessage {
+            sequence,
+            direction,
+            message: match &self.redactor {
+                Some(redactor) => redactor.redact(message),
+                None => message.clone(),
+            },
+        };
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            !matches!(
+                subscriber.try_send(written.clone()),
+                Err(mpsc::error::TrySendError::Closed(_))
+            )
+        });
+    }
+}
@@ -0,0 +1,85 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lsp_proxy::{BuildError, Direction, Hook, HookDescriptor, HookKind, ProxyBuilder, RateLimit};
+
+struct Noop;
+
+#[async_trait]
+impl Hook for Noop {}
+
+#[test]
+fn build_validated_rejects_unknown_methods() {
+    let result = ProxyBuilder::new()
+        .with_hook("textDocment/hover", Arc::new(Noop))
+        .with_hook("textDocument/definition", Arc::new(Noop))
+        .with_hook("myserver/reload", Arc::new(Noop))
+        .build_validated();
+
+    let Err(BuildError::UnknownMethods(methods)) = result else {
+        panic!("expected the misspelled and custom methods to be rejected");
+    };
+    assert_eq!(methods, ["myserver/reload", "textDocment/hover"]);
+}
+
+#[test]
+fn build_validated_accepts_known_methods() {
+    let result = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(Noop))
+        .with_hook("myserver/reload", Arc::new(Noop))
+        .with_known_methods(&["myserver/reload"])
+        .build_validated();
+
+    assert!(result.is_ok());
+}
+
+/// A hook that names itself in `describe_dispatch`.
+struct Named(&'static str);
+
+#[async_trait]
+impl Hook for Named {
+    fn name(&self) -> &str {
+        self.0
+    }
+}
+
+#[test]
+fn describe_dispatch_lists_steps_in_the_order_they_run() {
+    let proxy = ProxyBuilder::new()
+        .rate_limit(
+            "textDocument/hover",
+            RateLimit::new(10, Duration::from_secs(1)),
+        )
+        .with_hook("textDocument/hover", Arc::new(Named("audit")))
+        .map_request("textDocument/hover", |request| request)
+        .map_response("textDocument/hover", |response| response)
+        .with_hook_for(
+            Direction::ToClient,
+            "textDocument/hover",
+            Arc::new(Named("server-side")),
+        )
+        .with_default_hook(Arc::new(Named("fallback")))
+        .build();
+    let step = |name: &str, direction, kind| HookDescriptor {
+        name: name.to_owned(),
+        direction,
+        kind,
+    };
+
+    assert_eq!(
+        proxy.describe_dispatch("textDocument/hover"),
+        [
+            step("rate_limit", Some(Direction::ToServer), HookKind::Builtin),
+            // The hook scoped to server messages takes their direction over.
+            step("audit", Some(Direction::ToServer), HookKind::Method),
+            step("map_request", Some(Direction::ToServer), HookKind::Method),
+            step("map_response", Some(Direction::ToServer), HookKind::Method),
+            step("server-side", Some(Direction::ToClient), HookKind::Method),
+        ]
+    );
+    assert_eq!(
+        proxy.describe_dispatch("textDocument/definition"),
+        [step("fallback", None, HookKind::Default)]
+    );
+}
@@ -0,0 +1,275 @@
+mod common;
+
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lsp_proxy::builtins::{ConfigurationHook, DiagnosticsHook, UriRemapHook};
+use lsp_proxy::{Message, ProxyBuilder, Response};
+
+use common::{assert_silent, recv, start};
+
+#[tokio::test]
+async fn definition_round_trip_remaps_uris_both_ways() {
+    let remap = UriRemapHook::new().with_mapping("file:///home/me/project", "file:///workspace");
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/definition", Arc::new(remap))
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::request(
+            1,
+            "textDocument/definition",
+            Some(json!({
+                "textDocument": { "uri": "file:///home/me/project/src/main.rs" },
+                "position": { "line": 3, "character": 7 }
+            })),
+        ))
+        .await
+        .unwrap();
+
+    let Message::Request(request) = recv(&mut session.server).await else {
+        panic!("expected the definition request");
+    };
+    assert_eq!(
+        request.params.unwrap()["textDocument"]["uri"],
+        "file:///workspace/src/main.rs"
+    );
+
+    let range = json!({
+        "start": { "line": 0, "character": 4 },
+        "end": { "line": 0, "character": 8 }
+    });
+    session
+        .server
+        .send(&Message::Response(Response {
+            id: 1.into(),
+            result: Some(json!([
+                { "uri": "file:///workspace/src/lib.rs", "range": range },
+                { "uri": "file:///usr/lib/rust/core.rs", "range": range }
+            ])),
+            error: None,
+        }))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        recv(&mut session.client).await,
+        Message::Response(Response {
+            id: 1.into(),
+            result: Some(json!([
+                { "uri": "file:///home/me/project/src/lib.rs", "range": range },
+                { "uri": "file:///usr/lib/rust/core.rs", "range": range }
+            ])),
+            error: None,
+        })
+    );
+}
+
+#[tokio::test]
+async fn incremental_edits_become_full_text_for_a_full_sync_server() {
+    let proxy = ProxyBuilder::new().normalize_document_sync(true).build();
+    let mut session = start(proxy);
+    let uri = "file:///a.rs";
+
+    session
+        .client
+        .send(&Message::request(
+            1,
+            "initialize",
+            Some(json!({ "processId": null, "rootUri": null, "capabilities": {} })),
+        ))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    session
+        .server
+        .send(&Message::Response(Response {
+            id: 1.into(),
+            result: Some(json!({ "capabilities": { "textDocumentSync": 1 } })),
+            error: None,
+        }))
+        .await
+        .unwrap();
+    recv(&mut session.client).await;
+
+    let did_open = Message::notification(
+        "textDocument/didOpen",
+        Some(json!({
+            "textDocument": {
+                "uri": uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "// 😀 a\nfn main() {\n    let x = 1;\n}\n"
+            }
+        })),
+    );
+    session.client.send(&did_open).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, did_open);
+
+    let range = |line, start, end| {
+        json!({
+            "start": { "line": line, "character": start },
+            "end": { "line": line, "character": end }
+        })
+    };
+    session
+        .client
+        .send(&Message::notification(
+            "textDocument/didChange",
+            Some(json!({
+                "textDocument": { "uri": uri, "version": 2 },
+                "contentChanges": [
+                    // Past the emoji, which is two UTF-16 code units.
+                    { "range": range(0, 6, 7), "text": "b" },
+                    { "range": range(2, 8, 9), "text": "value" }
+                ]
+            })),
+        ))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        recv(&mut session.server).await,
+        Message::notification(
+            "textDocument/didChange",
+            Some(json!({
+                "textDocument": { "uri": uri, "version": 2 },
+                "contentChanges": [
+                    { "text": "// 😀 b\nfn main() {\n    let value = 1;\n}\n" }
+                ]
+            })),
+        )
+    );
+}
+
+fn configuration_request(sections: &[&str]) -> Message {
+    let items: Vec<_> = sections
+        .iter()
+        .map(|section| json!({ "section": section }))
+        .collect();
+    Message::request(
+        1,
+        "workspace/configuration",
+        Some(json!({ "items": items })),
+    )
+}
+
+fn settings(section: Option<&str>) -> Option<serde_json::Value> {
+    match section {
+        Some("rust-analyzer") => Some(json!({ "checkOnSave": false })),
+        Some("editor") => Some(json!({ "tabSize": 4 })),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn configuration_requests_are_answered_for_each_section() {
+    let proxy = ProxyBuilder::new()
+        .with_hook(
+            "workspace/configuration",
+            Arc::new(ConfigurationHook::new(settings)),
+        )
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .server
+        .send(&configuration_request(&["rust-analyzer", "editor"]))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        recv(&mut session.server).await,
+        Message::Response(Response {
+            id: 1.into(),
+            result: Some(json!([{ "checkOnSave": false }, { "tabSize": 4 }])),
+            error: None,
+        })
+    );
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn sections_without_settings_are_asked_of_the_client() {
+    let proxy = ProxyBuilder::new()
+        .with_hook(
+            "workspace/configuration",
+            Arc::new(ConfigurationHook::new(settings)),
+        )
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .server
+        .send(&configuration_request(&["files", "editor"]))
+        .await
+        .unwrap();
+
+    let Message::Request(asked) = recv(&mut session.client).await else {
+        panic!("expected the open section to be asked of the client");
+    };
+    assert_eq!(
+        asked.params,
+        Some(json!({ "items": [{ "section": "files" }] }))
+    );
+    session
+        .client
+        .send(&Message::Response(Response {
+            id: asked.id,
+            result: Some(json!([{ "watcherExclude": [] }])),
+            error: None,
+        }))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        recv(&mut session.server).await,
+        Message::Response(Response {
+            id: 1.into(),
+            result: Some(json!([{ "watcherExclude": [] }, { "tabSize": 4 }])),
+            error: None,
+        })
+    );
+}
+
+fn publish(diagnostics: serde_json::Value) -> Message {
+    Message::notification(
+        "textDocument/publishDiagnostics",
+        Some(json!({ "uri": "file:///a.rs", "diagnostics": diagnostics })),
+    )
+}
+
+#[tokio::test]
+async fn diagnostics_with_a_suppressed_code_are_dropped() {
+    let suppress = DiagnosticsHook::new(|_uri, diagnostics| {
+        diagnostics.retain(|diagnostic| diagnostic["code"] != "unused_variables")
+    });
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/publishDiagnostics", Arc::new(suppress))
+        .build();
+    let mut session = start(proxy);
+    let range = json!({
+        "start": { "line": 0, "character": 0 },
+        "end": { "line": 0, "character": 1 }
+    });
+    let unused = json!({ "range": range, "code": "unused_variables", "message": "unused" });
+    let mismatch = json!({ "range": range, "code": "E0308", "message": "mismatched types" });
+
+    session
+        .server
+        .send(&publish(json!([unused, mismatch])))
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut session.client).await, publish(json!([mismatch])));
+
+    // Suppressing every diagnostic still tells the client to clear them.
+    session
+        .server
+        .send(&publish(json!([unused])))
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut session.client).await, publish(json!([])));
+}
@@ -0,0 +1,113 @@
+#![allow(dead_code)]
+
+use serde_json::Value;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::task::JoinHandle;
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::{DuplexReader, DuplexWriter, Frame, ReadOptions, duplex, read_frame};
+use lsp_proxy::{Message, Proxy, Response};
+
+/// Long enough for anything the proxy is going to write to arrive.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A proxy forwarding between a test client and a fake server.
+pub struct Session {
+    pub client: TestClient,
+    pub server: TestClient,
+    pub forward: JoinHandle<io::Result<()>>,
+}
+
+pub fn start<S: Send + Sync + 'static>(proxy: Proxy<S>) -> Session {
+    let duplex = duplex();
+    let forward = tokio::spawn(proxy.forward(
+        duplex.proxy_server.reader,
+        duplex.proxy_server.writer,
+        duplex.proxy_client.reader,
+        duplex.proxy_client.writer,
+    ));
+
+    Session {
+        client: TestClient::from_endpoint(duplex.client),
+        server: TestClient::from_endpoint(duplex.server),
+        forward,
+    }
+}
+
+/// A proxy whose server end is a plain stream, to read and write the exact
+/// bytes of each frame.
+pub struct RawSession {
+    pub client: TestClient,
+    pub server: BufReader<DuplexReader>,
+    pub server_writer: DuplexWriter,
+    pub forward: JoinHandle<io::Result<()>>,
+}
+
+pub fn start_raw<S: Send + Sync + 'static>(proxy: Proxy<S>) -> RawSession {
+    let io = duplex();
+    let forward = tokio::spawn(proxy.forward(
+        io.proxy_server.reader,
+        io.proxy_server.writer,
+        io.proxy_client.reader,
+        io.proxy_client.writer,
+    ));
+
+    RawSession {
+        client: TestClient::from_endpoint(io.client),
+        server: BufReader::new(io.server.reader),
+        server_writer: io.server.writer,
+        forward,
+    }
+}
+
+/// The next frame written to the server, read with `options`.
+pub async fn recv_frame_with(session: &mut RawSession, options: &ReadOptions) -> Frame {
+    tokio::time::timeout(TIMEOUT, read_frame(&mut session.server, options))
+        .await
+        .expect("timed out waiting for a frame")
+        .expect("failed to read a frame")
+}
+
+/// The next frame written to the server.
+pub async fn recv_frame(session: &mut RawSession) -> Frame {
+    recv_frame_with(session, &ReadOptions::default()).await
+}
+
+/// The body of the next frame written to the server.
+pub async fn recv_body(session: &mut RawSession) -> Vec<u8> {
+    recv_frame(session).await.body.to_vec()
+}
+
+/// The next message `peer` receives, failing the test if none arrives.
+pub async fn recv<R, W>(peer: &mut TestClient<R, W>) -> Message
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    tokio::time::timeout(TIMEOUT, peer.recv())
+        .await
+        .expect("timed out waiting for a message")
+        .expect("failed to read a message")
+}
+
+/// Fails the test if `peer` receives anything within `wait`.
+pub async fn assert_silent<R, W>(peer: &mut TestClient<R, W>, wait: Duration)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if let Ok(message) = tokio::time::timeout(wait, peer.recv()).await {
+        panic!("expected nothing, received {:?}", message);
+    }
+}
+
+/// A successful response to `id` with a `null` result.
+pub fn reply(id: i64) -> Message {
+    Message::Response(Response {
+        id: id.into(),
+        result: Some(Value::Null),
+        error: None,
+    })
+}
@@ -0,0 +1,68 @@
+#![cfg(feature = "compression")]
+
+mod common;
+
+use serde_json::json;
+
+use lsp_proxy::transport::{ReadOptions, WriteOptions, write_message, write_messages_with_options};
+use lsp_proxy::{Message, ProxyBuilder, Response};
+
+use common::{recv, recv_frame_with, start_raw};
+
+const GZIP: WriteOptions = WriteOptions {
+    accept_gzip: true,
+    gzip_min_size: Some(0),
+};
+
+fn large_request(id: i64) -> Message {
+    Message::request(
+        id,
+        "workspace/symbol",
+        Some(json!({"query": "x".repeat(256)})),
+    )
+}
+
+#[tokio::test]
+async fn gzip_is_used_once_both_ends_advertise_it() {
+    let mut session = start_raw(ProxyBuilder::new().compress_server_link(64).build());
+    let accepting = ReadOptions {
+        accept_gzip: true,
+        ..ReadOptions::default()
+    };
+
+    session.client.send(&large_request(1)).await.unwrap();
+    let frame = recv_frame_with(&mut session, &accepting).await;
+    assert_eq!(frame.header("Accept-Encoding"), Some("gzip"));
+    assert_eq!(frame.header("Content-Encoding"), None);
+
+    let response = Message::Response(Response {
+        id: 1.into(),
+        result: Some(json!(["y".repeat(256)])),
+        error: None,
+    });
+    write_messages_with_options(&mut session.server_writer, &[response.to_value()], &GZIP)
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut session.client).await, response);
+
+    session.client.send(&large_request(2)).await.unwrap();
+    let frame = recv_frame_with(&mut session, &accepting).await;
+    assert_eq!(frame.header("Content-Encoding"), Some("gzip"));
+    assert_eq!(frame.content, large_request(2).to_value());
+}
+
+#[tokio::test]
+async fn gzip_that_was_not_negotiated_is_dropped() {
+    let mut session = start_raw(ProxyBuilder::new().build());
+
+    let compressed = Message::notification("window/logMessage", Some(json!({"message": "gz"})));
+    write_messages_with_options(&mut session.server_writer, &[compressed.to_value()], &GZIP)
+        .await
+        .unwrap();
+    let plain = Message::notification("window/logMessage", Some(json!({"message": "plain"})));
+    write_message(&mut session.server_writer, &plain.to_value())
+        .await
+        .unwrap();
+
+    assert_eq!(recv(&mut session.client).await, plain);
+}
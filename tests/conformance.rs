@@ -0,0 +1,66 @@
+mod common;
+
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lsp_proxy::{Direction, Message, ProtocolCheck, ProtocolViolation, ProxyBuilder};
+
+use common::{Session, assert_silent, recv, reply, start};
+
+/// Runs the `initialize` handshake, then has the server answer a hover
+/// request twice.
+async fn answer_twice(session: &mut Session) {
+    session
+        .client
+        .send(&Message::request(
+            1,
+            "initialize",
+            Some(json!({ "capabilities": {} })),
+        ))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    session.server.send(&reply(1)).await.unwrap();
+    recv(&mut session.client).await;
+
+    let hover = Message::request(2, "textDocument/hover", None);
+    session.client.send(&hover).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, hover);
+    session.server.send(&reply(2)).await.unwrap();
+    session.server.send(&reply(2)).await.unwrap();
+}
+
+#[tokio::test]
+async fn a_second_response_for_an_id_is_reported_and_still_forwarded() {
+    let violations = Arc::new(Mutex::new(Vec::new()));
+    let check = ProtocolCheck::new().on_violation({
+        let violations = violations.clone();
+        move |violation| violations.lock().unwrap().push(violation.clone())
+    });
+    let mut session = start(ProxyBuilder::new().check_protocol(check).build());
+
+    answer_twice(&mut session).await;
+    assert_eq!(recv(&mut session.client).await, reply(2));
+    assert_eq!(recv(&mut session.client).await, reply(2));
+
+    assert_eq!(
+        *violations.lock().unwrap(),
+        [ProtocolViolation::DuplicateResponse {
+            peer: Direction::ToClient,
+            id: 2.into()
+        }]
+    );
+}
+
+#[tokio::test]
+async fn a_second_response_is_dropped_when_violations_are_dropped() {
+    let check = ProtocolCheck::new()
+        .on_violation(|_| {})
+        .drop_violations(true);
+    let mut session = start(ProxyBuilder::new().check_protocol(check).build());
+
+    answer_twice(&mut session).await;
+    assert_eq!(recv(&mut session.client).await, reply(2));
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use serde_json::json;
+use std::sync::Arc;
+
+use lsp_proxy::{Correlator, Direction, HashMapCorrelator, Message, ProxyBuilder, Response};
+
+use common::{recv, start};
+
+#[test]
+fn the_default_correlator_resolves_each_request_once() {
+    let correlator = HashMapCorrelator::new();
+    correlator.register(
+        Direction::ToServer,
+        1.into(),
+        "textDocument/hover".to_owned(),
+    );
+    correlator.register(
+        Direction::ToClient,
+        1.into(),
+        "workspace/configuration".to_owned(),
+    );
+    assert_eq!(correlator.pending(), 2);
+
+    assert_eq!(
+        correlator.method(Direction::ToServer, &1.into()).as_deref(),
+        Some("textDocument/hover")
+    );
+    assert_eq!(
+        correlator
+            .resolve(Direction::ToServer, &1.into())
+            .as_deref(),
+        Some("textDocument/hover")
+    );
+    assert_eq!(correlator.resolve(Direction::ToServer, &1.into()), None);
+    assert_eq!(correlator.pending_ids(Direction::ToClient), [1]);
+
+    correlator.forget(Direction::ToClient);
+    assert_eq!(correlator.pending(), 0);
+}
+
+#[tokio::test]
+async fn forwarded_requests_are_registered_until_their_response() {
+    let correlator = Arc::new(HashMapCorrelator::new());
+    let proxy = ProxyBuilder::new().correlator(correlator.clone()).build();
+    let mut session = start(proxy);
+
+    let request = Message::request(7, "textDocument/hover", None);
+    session.client.send(&request).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, request);
+    assert_eq!(
+        correlator.method(Direction::ToServer, &7.into()).as_deref(),
+        Some("textDocument/hover")
+    );
+
+    let response = Message::Response(Response {
+        id: 7.into(),
+        result: Some(json!(null)),
+        error: None,
+    });
+    session.server.send(&response).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, response);
+    assert_eq!(correlator.pending(), 0);
+}
@@ -0,0 +1,227 @@
+mod common;
+
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lsp_proxy::message::{METHOD_NOT_FOUND, REQUEST_FAILED};
+use lsp_proxy::{Message, ParamsLimit, ProxyBuilder, RateLimit, Response, TestClock};
+
+use common::{assert_silent, recv, start};
+
+#[tokio::test]
+async fn disallowed_requests_are_answered_and_never_reach_the_server() {
+    let proxy = ProxyBuilder::new()
+        .allowlist(&["initialize", "textDocument/hover"])
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::request(1, "workspace/executeCommand", None))
+        .await
+        .unwrap();
+    session
+        .client
+        .send(&Message::notification("textDocument/didSave", None))
+        .await
+        .unwrap();
+    let allowed = Message::request(2, "textDocument/hover", None);
+    session.client.send(&allowed).await.unwrap();
+
+    let Message::Response(rejected) = recv(&mut session.client).await else {
+        panic!("expected the disallowed request to be answered");
+    };
+    assert_eq!(rejected.id, 1);
+    assert_eq!(rejected.error.unwrap()["code"], METHOD_NOT_FOUND);
+
+    assert_eq!(recv(&mut session.server).await, allowed);
+    assert_silent(&mut session.server, Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn unknown_dollar_methods_are_handled_like_the_spec_asks() {
+    let proxy = ProxyBuilder::new()
+        .filter_unknown_dollar_methods(true)
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::request(1, "$/custom", None))
+        .await
+        .unwrap();
+    session
+        .client
+        .send(&Message::notification("$/customProgress", None))
+        .await
+        .unwrap();
+    let set_trace = Message::notification("$/setTrace", Some(json!({ "value": "verbose" })));
+    session.client.send(&set_trace).await.unwrap();
+
+    let Message::Response(rejected) = recv(&mut session.client).await else {
+        panic!("expected the unknown request to be answered");
+    };
+    assert_eq!(rejected.id, 1);
+    assert_eq!(rejected.error.unwrap()["code"], METHOD_NOT_FOUND);
+
+    assert_eq!(recv(&mut session.server).await, set_trace);
+    assert_silent(&mut session.server, Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn unknown_dollar_methods_are_forwarded_by_default() {
+    let mut session = start(ProxyBuilder::new().build());
+
+    let custom = Message::request(1, "$/custom", None);
+    session.client.send(&custom).await.unwrap();
+
+    assert_eq!(recv(&mut session.server).await, custom);
+}
+
+#[tokio::test]
+async fn bursts_past_the_rate_limit_are_rejected_until_it_recovers() {
+    let clock = TestClock::new();
+    let proxy = ProxyBuilder::new()
+        .clock(Arc::new(clock.clone()))
+        .rate_limit(
+            "textDocument/completion",
+            RateLimit::new(2, Duration::from_secs(1)),
+        )
+        .rate_limit(
+            "textDocument/didChange",
+            RateLimit::new(1, Duration::from_secs(1)),
+        )
+        .build();
+    let mut session = start(proxy);
+    let completion = |id| Message::request(id, "textDocument/completion", None);
+    let did_change = Message::notification("textDocument/didChange", None);
+
+    for id in 1..=3 {
+        session.client.send(&completion(id)).await.unwrap();
+    }
+    session.client.send(&did_change).await.unwrap();
+    session.client.send(&did_change).await.unwrap();
+
+    let Message::Response(rejected) = recv(&mut session.client).await else {
+        panic!("expected the excess request to be answered");
+    };
+    assert_eq!(rejected.id, 3);
+    assert_eq!(rejected.error.unwrap()["code"], REQUEST_FAILED);
+    assert_eq!(recv(&mut session.server).await, completion(1));
+    assert_eq!(recv(&mut session.server).await, completion(2));
+    assert_eq!(recv(&mut session.server).await, did_change);
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+
+    clock.advance(Duration::from_secs(1));
+    session.client.send(&completion(4)).await.unwrap();
+    session.client.send(&did_change).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, completion(4));
+    assert_eq!(recv(&mut session.server).await, did_change);
+}
+
+#[tokio::test]
+async fn duplicate_requests_within_the_window_are_forwarded_once() {
+    let clock = TestClock::new();
+    let proxy = ProxyBuilder::new()
+        .clock(Arc::new(clock.clone()))
+        .dedup_requests(&["textDocument/hover"], Duration::from_secs(1))
+        .build();
+    let mut session = start(proxy);
+    let hover = Message::request(1, "textDocument/hover", Some(json!({ "line": 3 })));
+    let definition = Message::request(2, "textDocument/definition", None);
+
+    session.client.send(&hover).await.unwrap();
+    session.client.send(&hover).await.unwrap();
+    session.client.send(&definition).await.unwrap();
+    session.client.send(&definition).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, hover);
+    assert_eq!(recv(&mut session.server).await, definition);
+    assert_eq!(recv(&mut session.server).await, definition);
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+
+    let response = Message::Response(Response {
+        id: 1.into(),
+        result: Some(json!({ "contents": "docs" })),
+        error: None,
+    });
+    session.server.send(&response).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, response);
+
+    // An answered repeat gets the same response without reaching the server.
+    session.client.send(&hover).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, response);
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+
+    clock.advance(Duration::from_secs(2));
+    session.client.send(&hover).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, hover);
+}
+
+fn did_open(text: &str) -> Message {
+    Message::notification(
+        "textDocument/didOpen",
+        Some(json!({
+            "textDocument": { "uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": text }
+        })),
+    )
+}
+
+#[tokio::test]
+async fn oversized_messages_are_rejected_or_dropped() {
+    let proxy = ProxyBuilder::new()
+        .limit_params("textDocument/didOpen", ParamsLimit::new(256))
+        .limit_params("workspace/executeCommand", ParamsLimit::new(256))
+        .build();
+    let mut session = start(proxy);
+    let large = "x".repeat(1024);
+
+    session.client.send(&did_open(&large)).await.unwrap();
+    session
+        .client
+        .send(&Message::request(
+            1,
+            "workspace/executeCommand",
+            Some(json!({ "command": "paste", "arguments": [large] })),
+        ))
+        .await
+        .unwrap();
+    let small = did_open("fn main() {}");
+    session.client.send(&small).await.unwrap();
+
+    let Message::Response(rejected) = recv(&mut session.client).await else {
+        panic!("expected the oversized request to be answered");
+    };
+    assert_eq!(rejected.id, 1);
+    assert_eq!(rejected.error.unwrap()["code"], REQUEST_FAILED);
+    assert_eq!(recv(&mut session.server).await, small);
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn configured_fields_are_truncated_to_fit() {
+    let proxy = ProxyBuilder::new()
+        .limit_params(
+            "textDocument/didOpen",
+            ParamsLimit::new(256).truncate("/textDocument/text"),
+        )
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&did_open(&"x".repeat(1024)))
+        .await
+        .unwrap();
+
+    let forwarded = recv(&mut session.server).await;
+    assert!(forwarded.byte_len() <= 256, "{}", forwarded.byte_len());
+    let Message::Notification(notification) = forwarded else {
+        panic!("expected the didOpen notification");
+    };
+    let text = notification.params.unwrap()["textDocument"]["text"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    assert!(!text.is_empty() && text.len() < 1024, "{}", text.len());
+}
@@ -0,0 +1,463 @@
+mod common;
+
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader};
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::{ReadOptions, TransportError, duplex, read_frame};
+use lsp_proxy::{
+    Direction, Hook, HookContext, HookOutput, HookResult, JsonRpcField, LogFormat, Message,
+    Notification, ProxyBuilder, Response,
+};
+
+use common::{TIMEOUT, assert_silent, recv, recv_body, recv_frame, start, start_raw};
+
+fn frame(body: &str) -> Vec<u8> {
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+}
+
+#[tokio::test]
+async fn messages_nested_beyond_the_limit_are_dropped() {
+    let mut session = start(ProxyBuilder::new().max_json_depth(4).build());
+
+    let nested = format!(
+        r#"{{"jsonrpc":"2.0","id":1,"method":"deep","params":{}{}}}"#,
+        "[".repeat(10),
+        "]".repeat(10)
+    );
+    session.client.send_bytes(&frame(&nested)).await.unwrap();
+    let normal = Message::request(2, "textDocument/hover", Some(serde_json::json!({"a": [1]})));
+    session.client.send(&normal).await.unwrap();
+
+    assert_eq!(recv(&mut session.server).await, normal);
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+    assert!(!session.forward.is_finished());
+}
+
+/// Records the raw bytes it is given and sets `params.changed` when asked to.
+#[derive(Default)]
+struct RawRecorder {
+    seen: Mutex<Vec<Vec<u8>>>,
+}
+
+#[async_trait]
+impl Hook for RawRecorder {
+    async fn on_notification(
+        &self,
+        mut notification: Notification,
+        context: &HookContext,
+    ) -> HookResult {
+        self.seen
+            .lock()
+            .unwrap()
+            .push(context.raw_bytes().unwrap().to_vec());
+        if let Some(params) = notification.params.as_mut()
+            && params["mutate"] == true
+        {
+            params["changed"] = true.into();
+        }
+        Ok(HookOutput::new(Message::Notification(notification)))
+    }
+}
+
+#[tokio::test]
+async fn raw_bytes_are_forwarded_only_while_the_message_is_unchanged() {
+    let recorder = Arc::new(RawRecorder::default());
+    let proxy = ProxyBuilder::new()
+        .with_hook("custom/note", recorder.clone())
+        .preserve_unmodified_bytes(true)
+        .build();
+    let mut session = start_raw(proxy);
+
+    let kept = r#"{"params": {"z": 1.50, "a": 2, "mutate": false}, "method": "custom/note", "jsonrpc": "2.0"}"#;
+    let mutated =
+        r#"{"params": {"z": 1.50, "mutate": true}, "method": "custom/note", "jsonrpc": "2.0"}"#;
+    session.client.send_bytes(&frame(kept)).await.unwrap();
+    session.client.send_bytes(&frame(mutated)).await.unwrap();
+
+    assert_eq!(recv_body(&mut session).await, kept.as_bytes());
+    let rewritten = recv_body(&mut session).await;
+    assert_ne!(rewritten, mutated.as_bytes());
+    let rewritten: serde_json::Value = serde_json::from_slice(&rewritten).unwrap();
+    assert_eq!(rewritten["params"]["changed"], true);
+
+    assert_eq!(
+        *recorder.seen.lock().unwrap(),
+        [kept.as_bytes(), mutated.as_bytes()]
+    );
+}
+
+/// Copies the `X-Trace` header a response arrived with into its result.
+struct TraceHeader;
+
+#[async_trait]
+impl Hook for TraceHeader {
+    async fn on_response(&self, mut response: Response, context: &HookContext) -> HookResult {
+        response.result = Some(serde_json::json!({ "trace": context.header("x-trace") }));
+        Ok(HookOutput::new(Message::Response(response)))
+    }
+}
+
+#[tokio::test]
+async fn custom_headers_are_written_and_read() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(TraceHeader))
+        .with_outgoing_headers(Direction::ToServer, |message| {
+            let id = message
+                .get_id()
+                .map(|id| id.to_string())
+                .unwrap_or_default();
+            vec![("X-Request-Id".to_owned(), id)]
+        })
+        .build();
+    let mut session = start_raw(proxy);
+
+    session
+        .client
+        .send(&Message::request(7, "textDocument/hover", None))
+        .await
+        .unwrap();
+    let request = recv_frame(&mut session).await;
+    assert_eq!(request.header("x-request-id"), Some("7"));
+    assert_eq!(request.content["id"], 7);
+
+    let body = r#"{"jsonrpc":"2.0","id":7,"result":null}"#;
+    let response = format!(
+        "Content-Length: {}\r\nX-Trace: abc\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    session
+        .server_writer
+        .write_all(response.as_bytes())
+        .await
+        .unwrap();
+
+    let Message::Response(response) = recv(&mut session.client).await else {
+        panic!("expected the hover response");
+    };
+    assert_eq!(response.result, Some(serde_json::json!({ "trace": "abc" })));
+}
+
+#[tokio::test]
+async fn wire_output_is_compact_while_log_output_is_pretty() {
+    let mut session = start_raw(ProxyBuilder::new().build());
+    let message = Message::request(
+        1,
+        "textDocument/hover",
+        Some(serde_json::json!({
+            "textDocument": { "uri": "file:///a.rs" },
+            "position": { "line": 3, "character": 7 }
+        })),
+    );
+    session.client.send(&message).await.unwrap();
+
+    let body = recv_body(&mut session).await;
+    let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body, serde_json::to_vec(&value).unwrap());
+    assert_eq!(Message::from_value(value).unwrap(), message);
+
+    let pretty = message.to_log_string(LogFormat::Pretty);
+    assert!(pretty.contains("\n  \"method\": \"textDocument/hover\""));
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&pretty).unwrap(),
+        message.to_value()
+    );
+    let keys = ["\"id\"", "\"jsonrpc\"", "\"method\"", "\"params\""];
+    let offsets = keys.map(|key| pretty.find(key).unwrap());
+    assert!(offsets.is_sorted(), "keys are not sorted in {pretty}");
+}
+
+#[tokio::test]
+async fn zero_length_frames_are_skipped_as_keep_alives() {
+    let mut session = start(ProxyBuilder::new().build());
+    let first = Message::notification("textDocument/didSave", None);
+    let second = Message::request(1, "textDocument/hover", None);
+
+    let mut bytes = frame(&first.to_value().to_string());
+    bytes.extend_from_slice(b"Content-Length: 0\r\n\r\n");
+    bytes.extend(frame(&second.to_value().to_string()));
+    session.client.send_bytes(&bytes).await.unwrap();
+
+    assert_eq!(recv(&mut session.server).await, first);
+    assert_eq!(recv(&mut session.server).await, second);
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+    assert!(!session.forward.is_finished());
+}
+
+#[tokio::test]
+async fn ids_beyond_i64_round_trip_unchanged() {
+    // The hook makes the proxy parse and serialize the request again rather
+    // than forwarding the bytes it read.
+    let proxy = ProxyBuilder::new()
+        .map_request("textDocument/hover", |request| request)
+        .build();
+    let mut session = start_raw(proxy);
+
+    let id = "9999999999999999999";
+    let request = format!(r#"{{"jsonrpc":"2.0","id":{id},"method":"textDocument/hover"}}"#);
+    session.client.send_bytes(&frame(&request)).await.unwrap();
+    let body = String::from_utf8(recv_body(&mut session).await).unwrap();
+    assert!(body.contains(&format!(r#""id":{id}"#)), "{body}");
+
+    let response = format!(r#"{{"jsonrpc":"2.0","id":{id},"result":null}}"#);
+    session
+        .server_writer
+        .write_all(&frame(&response))
+        .await
+        .unwrap();
+    let Message::Response(response) = recv(&mut session.client).await else {
+        panic!("expected the response");
+    };
+    assert_eq!(serde_json::to_string(&response.id).unwrap(), id);
+}
+
+#[tokio::test]
+async fn non_conforming_json_is_passed_through_only_when_enabled() {
+    let exotic = r#"{"jsonrpc":"2.0", "method":"x/ext", "result":{"b":1,"a":2}}"#;
+    let did_save = Message::notification("textDocument/didSave", None);
+
+    let mut session = start_raw(ProxyBuilder::new().pass_through_unparsed(true).build());
+    session.client.send_bytes(&frame(exotic)).await.unwrap();
+    session.client.send(&did_save).await.unwrap();
+    assert_eq!(recv_body(&mut session).await, exotic.as_bytes());
+    let body = recv_body(&mut session).await;
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+        did_save.to_value()
+    );
+
+    let mut session = start_raw(ProxyBuilder::new().build());
+    session.client.send_bytes(&frame(exotic)).await.unwrap();
+    session.client.send(&did_save).await.unwrap();
+    let body = recv_body(&mut session).await;
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+        did_save.to_value()
+    );
+}
+
+#[tokio::test]
+async fn null_results_keep_their_field_on_the_wire() {
+    // The hook makes the proxy serialize the response again.
+    let proxy = ProxyBuilder::new()
+        .map_response("shutdown", |response| response)
+        .build();
+    let mut session = start_raw(proxy);
+
+    session
+        .client
+        .send(&Message::request(1, "shutdown", None))
+        .await
+        .unwrap();
+    recv_body(&mut session).await;
+    session
+        .server_writer
+        .write_all(&frame(r#"{"jsonrpc":"2.0","id":1,"result":null}"#))
+        .await
+        .unwrap();
+
+    let Message::Response(response) = recv(&mut session.client).await else {
+        panic!("expected the shutdown response");
+    };
+    assert_eq!(response.result, Some(serde_json::Value::Null));
+    assert!(
+        Message::Response(response)
+            .to_log_string(LogFormat::Compact)
+            .contains(r#""result":null"#)
+    );
+}
+
+#[tokio::test]
+async fn headers_ending_in_a_bare_lf_are_read_like_crlf_ones() {
+    let body = r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#;
+    let crlf = format!(
+        "Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc\r\n\r\n{body}",
+        body.len()
+    );
+    let lf = format!(
+        "Content-Length: {}\nContent-Type: application/vscode-jsonrpc\n\n{body}",
+        body.len()
+    );
+
+    for bytes in [crlf, lf] {
+        // Two frames back to back, so the blank line must end exactly where
+        // the body starts.
+        let stream = bytes.repeat(2);
+        let mut reader = BufReader::new(stream.as_bytes());
+        for _ in 0..2 {
+            let frame = read_frame(&mut reader, &ReadOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(&*frame.body, body.as_bytes());
+            assert_eq!(
+                frame.header("Content-Type"),
+                Some("application/vscode-jsonrpc")
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn lf_only_frames_from_the_server_reach_the_client() {
+    let mut session = start_raw(ProxyBuilder::new().build());
+    let notification = Message::notification("window/logMessage", None);
+    let body = notification.to_value().to_string();
+
+    session
+        .server_writer
+        .write_all(format!("Content-Length: {}\n\n{body}", body.len()).as_bytes())
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut session.client).await, notification);
+}
+
+#[tokio::test]
+async fn streams_closing_inside_a_frame_are_truncated_rather_than_eof() {
+    let read = async |bytes: &[u8]| {
+        read_frame(&mut BufReader::new(bytes), &ReadOptions::default())
+            .await
+            .unwrap_err()
+    };
+
+    assert!(matches!(read(b"").await, TransportError::Eof));
+    assert!(matches!(
+        read(b"Content-Length: 10\r\n\r\n").await,
+        TransportError::Truncated {
+            expected: Some(10),
+            received: 0
+        }
+    ));
+    assert!(matches!(
+        read(b"Content-Length: 10\r\n\r\n{\"a\"").await,
+        TransportError::Truncated {
+            expected: Some(10),
+            received: 4
+        }
+    ));
+    assert!(matches!(
+        read(b"Content-Len").await,
+        TransportError::Truncated {
+            expected: None,
+            received: 11
+        }
+    ));
+}
+
+#[tokio::test]
+async fn a_server_dying_after_a_header_ends_the_session_cleanly() {
+    let io = duplex();
+    let forward = tokio::spawn(ProxyBuilder::new().build().forward(
+        io.proxy_server.reader,
+        io.proxy_server.writer,
+        io.proxy_client.reader,
+        io.proxy_client.writer,
+    ));
+    let mut server_writer = io.server.writer;
+    let _server_reader = io.server.reader;
+    let _client = TestClient::from_endpoint(io.client);
+
+    server_writer
+        .write_all(b"Content-Length: 52\r\n\r\n")
+        .await
+        .unwrap();
+    server_writer.shutdown().await.unwrap();
+    drop(server_writer);
+
+    let result = tokio::time::timeout(TIMEOUT, forward)
+        .await
+        .expect("the proxy kept running after the server closed")
+        .unwrap();
+    assert!(result.is_ok(), "{result:?}");
+}
+
+/// Whether the hover requests, one read without `jsonrpc` and one with it,
+/// reach the server with the field under `policy`.
+async fn jsonrpc_fields_written(policy: JsonRpcField) -> [bool; 2] {
+    // The hook makes the proxy serialize the requests rather than forwarding
+    // the bytes it read.
+    let proxy = ProxyBuilder::new()
+        .jsonrpc_field(policy)
+        .map_request("textDocument/hover", |request| request)
+        .build();
+    let mut session = start_raw(proxy);
+
+    let mut written = [false; 2];
+    for (id, body) in [
+        r#"{"id":1,"method":"textDocument/hover"}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover"}"#,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        session.client.send_bytes(&frame(body)).await.unwrap();
+        let frame = recv_frame(&mut session).await;
+        assert_eq!(frame.content["id"], id + 1);
+        written[id] = frame.content.get("jsonrpc").is_some();
+    }
+    written
+}
+
+#[tokio::test]
+async fn each_jsonrpc_field_policy_writes_the_expected_envelope() {
+    assert_eq!(
+        jsonrpc_fields_written(JsonRpcField::Always).await,
+        [true, true]
+    );
+    assert_eq!(
+        jsonrpc_fields_written(JsonRpcField::PreserveOriginal).await,
+        [false, true]
+    );
+    assert_eq!(
+        jsonrpc_fields_written(JsonRpcField::Never).await,
+        [false, false]
+    );
+}
+
+#[tokio::test]
+async fn unhooked_and_unchanged_messages_are_forwarded_byte_identical() {
+    let proxy = ProxyBuilder::new()
+        .map_request("textDocument/hover", |request| request)
+        .preserve_unmodified_bytes(true)
+        .build();
+    let mut session = start_raw(proxy);
+
+    let unhooked =
+        r#"{ "params" : {"z":1.50,"a":1e3,"s":"é"}, "method":"custom/note" ,"jsonrpc":"2.0"}"#;
+    let hooked = r#"{"params":{"position":{"line":1.0}},"id":7,"method":"textDocument/hover","jsonrpc":"2.0"}"#;
+    for body in [unhooked, hooked] {
+        session.client.send_bytes(&frame(body)).await.unwrap();
+        assert_eq!(
+            String::from_utf8(recv_body(&mut session).await).unwrap(),
+            body
+        );
+    }
+}
+
+#[tokio::test]
+async fn unchanged_frames_keep_their_envelope_unless_it_is_stripped() {
+    let body = r#"{"method":"custom/note","params":{}}"#;
+
+    let mut session = start_raw(
+        ProxyBuilder::new()
+            .jsonrpc_field(JsonRpcField::Always)
+            .build(),
+    );
+    session.client.send_bytes(&frame(body)).await.unwrap();
+    assert_eq!(recv_body(&mut session).await, body.as_bytes());
+
+    let with_field = r#"{"jsonrpc":"2.0","method":"custom/note","params":{}}"#;
+    let mut session = start_raw(
+        ProxyBuilder::new()
+            .jsonrpc_field(JsonRpcField::Never)
+            .build(),
+    );
+    session.client.send_bytes(&frame(with_field)).await.unwrap();
+    let frame = recv_frame(&mut session).await;
+    assert_eq!(
+        frame.content,
+        serde_json::json!({"method": "custom/note", "params": {}})
+    );
+}
@@ -0,0 +1,397 @@
+mod common;
+
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+use lsp_proxy::message::{INTERNAL_ERROR, METHOD_NOT_FOUND};
+use lsp_proxy::{
+    Direction, HealthCheck, HealthEvent, Message, MessageType, ProxyBuilder, RequestError,
+    Response, TestClock,
+};
+
+use common::{TIMEOUT, assert_silent, recv, reply, start};
+
+#[tokio::test]
+async fn pending_count_is_capped_and_drops_as_responses_arrive() {
+    let proxy = ProxyBuilder::new().max_pending_requests(2).build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+
+    for id in 1..=3 {
+        session
+            .client
+            .send(&Message::request(id, "textDocument/hover", None))
+            .await
+            .unwrap();
+    }
+
+    let Message::Response(rejected) = recv(&mut session.client).await else {
+        panic!("expected the third request to be answered by the proxy");
+    };
+    assert_eq!(rejected.id, 3);
+    assert_eq!(rejected.error.unwrap()["code"], INTERNAL_ERROR);
+    assert_eq!(handle.pending_count(), 2);
+
+    for id in 1..=2 {
+        assert_eq!(recv(&mut session.server).await.get_id(), Some(&id.into()));
+    }
+
+    session.server.send(&reply(1)).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, reply(1));
+    assert_eq!(handle.pending_count(), 1);
+
+    session.server.send(&reply(2)).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, reply(2));
+    assert_eq!(handle.pending_count(), 0);
+}
+
+#[tokio::test]
+async fn injected_requests_resolve_to_their_response_or_time_out() {
+    let proxy = ProxyBuilder::new().build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+
+    let timed_out = tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            handle
+                .send_request(
+                    Direction::ToClient,
+                    "workspace/configuration",
+                    Some(json!({ "items": [] })),
+                    Duration::from_millis(50),
+                )
+                .await
+        }
+    });
+    let request = recv(&mut session.client).await;
+    assert_eq!(request.get_method(), Some("workspace/configuration"));
+    assert!(matches!(
+        timed_out.await.unwrap(),
+        Err(RequestError::Timeout)
+    ));
+
+    let answered = tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            handle
+                .send_request(
+                    Direction::ToServer,
+                    "workspace/executeCommand",
+                    None,
+                    Duration::from_secs(5),
+                )
+                .await
+        }
+    });
+    let request = recv(&mut session.server).await;
+    let id = request.get_id().unwrap().clone();
+    let response = Response {
+        id: id.clone(),
+        result: Some(json!("done")),
+        error: None,
+    };
+    session
+        .server
+        .send(&Message::Response(response))
+        .await
+        .unwrap();
+
+    let response = answered.await.unwrap().unwrap();
+    assert_eq!(response.id, id);
+    assert_eq!(response.result, Some(json!("done")));
+    assert_silent(&mut session.client, Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn injected_ids_do_not_capture_responses_to_the_peers_own_requests() {
+    let proxy = ProxyBuilder::new().build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+
+    // Negative ids the peers picked themselves, one in each direction.
+    let to_server = Message::request(-1, "textDocument/hover", None);
+    session.client.send(&to_server).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, to_server);
+    let to_client = Message::request(-1, "workspace/configuration", None);
+    session.server.send(&to_client).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, to_client);
+
+    let injected = tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            handle
+                .send_request(
+                    Direction::ToClient,
+                    "window/workDoneProgress/create",
+                    None,
+                    TIMEOUT,
+                )
+                .await
+        }
+    });
+    let request = recv(&mut session.client).await;
+    assert_eq!(request.get_method(), Some("window/workDoneProgress/create"));
+    // -1 is still pending towards the client, so the injected id skips it.
+    assert_eq!(request.get_id().unwrap(), &(-2));
+
+    // The server's answer to the client's -1 still reaches the client.
+    session.server.send(&reply(-1)).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, reply(-1));
+    session.client.send(&reply(-1)).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, reply(-1));
+
+    session.client.send(&reply(-2)).await.unwrap();
+    assert_eq!(injected.await.unwrap().unwrap().id, -2);
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn time_since_the_last_server_message_tracks_server_traffic() {
+    let proxy = ProxyBuilder::new().build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+    assert_eq!(handle.since_last_server_message(), None);
+
+    // Client traffic says nothing about the server.
+    session
+        .client
+        .send(&Message::notification("textDocument/didSave", None))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    assert_eq!(handle.since_last_server_message(), None);
+
+    let log = Message::notification("window/logMessage", None);
+    session.server.send(&log).await.unwrap();
+    recv(&mut session.client).await;
+    let fresh = handle.since_last_server_message().unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let stale = handle.since_last_server_message().unwrap();
+    assert!(stale >= fresh + Duration::from_millis(100));
+
+    session.server.send(&log).await.unwrap();
+    recv(&mut session.client).await;
+    assert!(handle.since_last_server_message().unwrap() < stale);
+}
+
+#[tokio::test]
+async fn unanswered_health_checks_mark_the_server_unhealthy_until_it_answers() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let check = HealthCheck::new("$/ping")
+        .interval(Duration::from_millis(20))
+        .timeout(Duration::from_millis(50))
+        .on_event({
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event.clone())
+        });
+    let proxy = ProxyBuilder::new().health_check(check).build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+    assert!(handle.is_healthy());
+
+    // The first probe goes unanswered.
+    assert_eq!(recv(&mut session.server).await.get_method(), Some("$/ping"));
+    let probe = recv(&mut session.server).await;
+    assert!(!handle.is_healthy());
+    assert_eq!(*events.lock().unwrap(), [HealthEvent::Unhealthy]);
+
+    session
+        .server
+        .send(&Message::error_response(
+            probe.get_id().unwrap().clone(),
+            METHOD_NOT_FOUND,
+            "no ping here",
+        ))
+        .await
+        .unwrap();
+    tokio::time::timeout(TIMEOUT, async {
+        while !handle.is_healthy() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the server stayed unhealthy after answering");
+    assert_eq!(
+        *events.lock().unwrap(),
+        [HealthEvent::Unhealthy, HealthEvent::Healthy]
+    );
+}
+
+#[tokio::test]
+async fn notifications_can_be_injected_mid_session() {
+    let proxy = ProxyBuilder::new().build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+
+    let did_save = Message::notification("textDocument/didSave", None);
+    session.client.send(&did_save).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, did_save);
+
+    let show = Message::notification(
+        "window/showMessage",
+        Some(json!({ "type": 3, "message": "index rebuilt" })),
+    );
+    handle.inject(Direction::ToClient, show.clone()).unwrap();
+    assert_eq!(recv(&mut session.client).await, show);
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+
+    let common::Session {
+        client,
+        server,
+        forward,
+    } = session;
+    drop((client, server));
+    tokio::time::timeout(TIMEOUT, forward)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        handle.inject(Direction::ToClient, show),
+        Err(RequestError::ChannelClosed)
+    ));
+}
+
+#[tokio::test]
+async fn messages_sent_while_paused_are_held_and_then_delivered_in_order() {
+    let proxy = ProxyBuilder::new().build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+    let did_save = |n: i64| Message::notification("textDocument/didSave", Some(json!({ "n": n })));
+
+    handle.pause();
+    assert!(handle.is_paused());
+    for n in 1..=3 {
+        session.client.send(&did_save(n)).await.unwrap();
+    }
+    let log = Message::notification("window/logMessage", None);
+    session.server.send(&log).await.unwrap();
+    assert_silent(&mut session.server, Duration::from_millis(100)).await;
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+
+    handle.resume();
+    assert!(!handle.is_paused());
+    for n in 1..=3 {
+        assert_eq!(recv(&mut session.server).await, did_save(n));
+    }
+    assert_eq!(recv(&mut session.client).await, log);
+}
+
+#[tokio::test]
+async fn lines_from_a_child_stderr_reach_the_client_as_log_messages() {
+    let proxy = ProxyBuilder::new().build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+
+    let mut child = tokio::process::Command::new("sh")
+        .args([
+            "-c",
+            "echo 'panicked at src/main.rs' >&2; printf 'no newline' >&2",
+        ])
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stderr = child.stderr.take().unwrap();
+    handle
+        .forward_stderr(stderr, MessageType::Error)
+        .await
+        .unwrap();
+    child.wait().await.unwrap();
+
+    assert_eq!(
+        recv(&mut session.client).await,
+        Message::log_message(MessageType::Error, "panicked at src/main.rs")
+    );
+    assert_eq!(
+        recv(&mut session.client).await,
+        Message::log_message(MessageType::Error, "no newline")
+    );
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn stderr_split_across_reads_is_sent_line_by_line() {
+    let proxy = ProxyBuilder::new().build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+    let (mut writer, stderr) = tokio::io::duplex(64);
+
+    let forwarding =
+        tokio::spawn(async move { handle.forward_stderr(stderr, MessageType::Warning).await });
+    writer.write_all("first ha".as_bytes()).await.unwrap();
+    writer
+        .write_all("lf\r\n\nsécond\n".as_bytes())
+        .await
+        .unwrap();
+    writer.write_all(b"bad \xff byte\n").await.unwrap();
+    drop(writer);
+    forwarding.await.unwrap().unwrap();
+
+    for text in ["first half", "sécond", "bad \u{fffd} byte"] {
+        assert_eq!(
+            recv(&mut session.client).await,
+            Message::log_message(MessageType::Warning, text)
+        );
+    }
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn advancing_a_test_clock_times_out_a_pending_request_without_sleeping() {
+    let clock = TestClock::new();
+    let proxy = ProxyBuilder::new().clock(Arc::new(clock.clone())).build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+
+    let pending = tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            handle
+                .send_request(
+                    Direction::ToServer,
+                    "custom/status",
+                    None,
+                    Duration::from_secs(3600),
+                )
+                .await
+        }
+    });
+    assert_eq!(
+        recv(&mut session.server).await.get_method(),
+        Some("custom/status")
+    );
+
+    clock.advance(Duration::from_secs(3599));
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+    assert!(!pending.is_finished());
+
+    clock.advance(Duration::from_secs(1));
+    let result = tokio::time::timeout(TIMEOUT, pending)
+        .await
+        .expect("the request did not time out on the test clock")
+        .unwrap();
+    assert!(matches!(result, Err(RequestError::Timeout)));
+}
+
+#[tokio::test]
+async fn only_pending_client_requests_can_be_cancelled() {
+    let proxy = ProxyBuilder::new().build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+
+    let request = Message::request(1, "textDocument/hover", None);
+    session.client.send(&request).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, request);
+
+    assert!(matches!(handle.cancel_request(&1.into()), Ok(true)));
+    assert_eq!(
+        recv(&mut session.server).await,
+        Message::notification("$/cancelRequest", Some(json!({ "id": 1 })))
+    );
+    assert!(matches!(handle.cancel_request(&9.into()), Ok(false)));
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+}
@@ -0,0 +1,108 @@
+#![cfg(feature = "test-util")]
+
+use async_trait::async_trait;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lsp_proxy::{
+    Direction, Hook, HookContext, HookError, HookOutput, HookResult, Message, MessageType,
+    ProxyBuilder, Request, Response, TestHarness,
+};
+
+/// Upper-cases hover contents and logs that it did.
+struct Shout;
+
+#[async_trait]
+impl Hook for Shout {
+    async fn on_response(&self, mut response: Response, _context: &HookContext) -> HookResult {
+        if let Some(contents) = response
+            .result
+            .as_mut()
+            .map(|result| &mut result["contents"])
+        {
+            *contents = json!(contents.as_str().unwrap_or_default().to_uppercase());
+        }
+        Ok(HookOutput::new(Message::Response(response)).with_message(
+            Direction::ToClient,
+            Message::log_message(MessageType::Log, "shouted"),
+        ))
+    }
+}
+
+#[tokio::test]
+async fn snapshot_of_a_transforming_hook() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(Shout))
+        .build();
+
+    let transcript = TestHarness::new(proxy)
+        .server_replies("textDocument/hover", json!({ "contents": "docs" }))
+        .client_sends(Message::request(1, "textDocument/hover", None))
+        .server_sends(Message::notification("window/logMessage", None))
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        transcript.snapshot(),
+        "\
+client <- {\"id\":1,\"jsonrpc\":\"2.0\",\"result\":{\"contents\":\"DOCS\"}}
+client <- {\"jsonrpc\":\"2.0\",\"method\":\"window/logMessage\",\"params\":{\"message\":\"shouted\",\"type\":4}}
+client <- {\"jsonrpc\":\"2.0\",\"method\":\"window/logMessage\"}
+server <- {\"id\":1,\"jsonrpc\":\"2.0\",\"method\":\"textDocument/hover\"}
+"
+    );
+}
+
+/// Asks the server for the scope at the cursor before forwarding a request.
+struct Enrich;
+
+#[async_trait]
+impl Hook for Enrich {
+    async fn on_request(&self, mut request: Request, context: &HookContext) -> HookResult {
+        let handle = context.handle().expect("running in the proxy");
+        let scope = handle
+            .send_request(
+                context.to_peer(),
+                "custom/scope",
+                None,
+                Duration::from_secs(1),
+            )
+            .await
+            .map_err(|e| HookError::ProcessingFailed(e.to_string()))?;
+        request.params = scope.result;
+        Ok(HookOutput::new(Message::Request(request)))
+    }
+}
+
+#[tokio::test]
+async fn injected_requests_are_answered_by_the_scripted_server() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/completion", Arc::new(Enrich))
+        .build();
+
+    let transcript = TestHarness::new(proxy)
+        .server_replies("custom/scope", json!({ "scope": "fn main" }))
+        .client_sends(Message::request(1, "textDocument/completion", None))
+        .run()
+        .await
+        .unwrap();
+
+    let methods: Vec<_> = transcript
+        .to_server
+        .iter()
+        .map(|message| message.get_method().unwrap())
+        .collect();
+    assert_eq!(methods, ["custom/scope", "textDocument/completion"]);
+    assert_eq!(
+        transcript.to_server[1],
+        Message::request(
+            1,
+            "textDocument/completion",
+            Some(json!({ "scope": "fn main" }))
+        )
+    );
+    // The answer to the injected request is consumed by the proxy.
+    assert!(transcript.to_client.is_empty());
+}
@@ -0,0 +1,958 @@
+mod common;
+
+use async_trait::async_trait;
+use futures_util::stream;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use lsp_proxy::message::INTERNAL_ERROR;
+use lsp_proxy::{
+    ConnectionId, Direction, GeneratedOrder, Hook, HookContext, HookError, HookErrorPolicy,
+    HookOutput, HookResult, Message, MessageType, Notification, ProxyBuilder, Request, Response,
+    TraceValue,
+};
+
+use common::{assert_silent, recv, start};
+
+/// Rewrites every notification and tells the client about it.
+struct Rewrite;
+
+#[async_trait]
+impl Hook for Rewrite {
+    async fn on_notification(
+        &self,
+        mut notification: Notification,
+        _context: &HookContext,
+    ) -> HookResult {
+        notification.params = Some(json!({ "rewritten": true }));
+        Ok(
+            HookOutput::new(Message::Notification(notification)).with_message(
+                Direction::ToClient,
+                Message::notification("proxy/rewrote", None),
+            ),
+        )
+    }
+}
+
+#[tokio::test]
+async fn observe_only_forwards_the_original() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/didSave", Arc::new(Rewrite))
+        .observe_only(true)
+        .build();
+    let mut session = start(proxy);
+
+    let did_save = Message::notification(
+        "textDocument/didSave",
+        Some(json!({ "textDocument": { "uri": "file:///a.rs" } })),
+    );
+    session.client.send(&did_save).await.unwrap();
+
+    assert_eq!(recv(&mut session.server).await, did_save);
+    assert_silent(&mut session.client, Duration::from_millis(100)).await;
+}
+
+/// Queues two messages for each peer around the notification it handles.
+struct Surround(GeneratedOrder);
+
+#[async_trait]
+impl Hook for Surround {
+    async fn on_notification(
+        &self,
+        notification: Notification,
+        _context: &HookContext,
+    ) -> HookResult {
+        Ok(HookOutput::new(Message::Notification(notification))
+            .with_message(
+                Direction::ToServer,
+                Message::notification("server/first", None),
+            )
+            .with_message(
+                Direction::ToClient,
+                Message::notification("client/first", None),
+            )
+            .with_message(
+                Direction::ToServer,
+                Message::notification("server/second", None),
+            )
+            .with_message(
+                Direction::ToClient,
+                Message::notification("client/second", None),
+            )
+            .with_order(self.0))
+    }
+}
+
+async fn surround_order(order: GeneratedOrder) -> (Vec<String>, Vec<String>) {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/didSave", Arc::new(Surround(order)))
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::notification("textDocument/didSave", None))
+        .await
+        .unwrap();
+
+    let mut server = Vec::new();
+    for _ in 0..3 {
+        let message = recv(&mut session.server).await;
+        server.push(message.get_method().unwrap().to_owned());
+    }
+    let mut client = Vec::new();
+    for _ in 0..2 {
+        let message = recv(&mut session.client).await;
+        client.push(message.get_method().unwrap().to_owned());
+    }
+    (server, client)
+}
+
+#[tokio::test]
+async fn generated_messages_follow_the_requested_order_on_each_stream() {
+    let (server, client) = surround_order(GeneratedOrder::AfterMessage).await;
+    assert_eq!(
+        server,
+        ["textDocument/didSave", "server/first", "server/second"]
+    );
+    assert_eq!(client, ["client/first", "client/second"]);
+
+    let (server, client) = surround_order(GeneratedOrder::BeforeMessage).await;
+    assert_eq!(
+        server,
+        ["server/first", "server/second", "textDocument/didSave"]
+    );
+    assert_eq!(client, ["client/first", "client/second"]);
+}
+
+/// Splits a batched notification into one notification per item.
+struct Split;
+
+#[async_trait]
+impl Hook for Split {
+    async fn on_notification(
+        &self,
+        notification: Notification,
+        _context: &HookContext,
+    ) -> HookResult {
+        let items = notification.params.unwrap()["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| Message::notification("myserver/item", Some(item.clone())))
+            .collect();
+        Ok(HookOutput::replace_with(items, Direction::ToServer))
+    }
+}
+
+#[tokio::test]
+async fn a_notification_is_replaced_by_its_parts_in_order() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("myserver/batch", Arc::new(Split))
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::notification(
+            "myserver/batch",
+            Some(json!({ "items": [1, 2, 3] })),
+        ))
+        .await
+        .unwrap();
+
+    for item in 1..=3 {
+        assert_eq!(
+            recv(&mut session.server).await,
+            Message::notification("myserver/item", Some(json!(item)))
+        );
+    }
+    assert_silent(&mut session.server, Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn test_client_requests_see_the_transformed_response() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(Tag))
+        .build();
+    let mut session = start(proxy);
+
+    let server = tokio::spawn(async move {
+        let request = recv(&mut session.server).await;
+        let response = Response {
+            id: request.get_id().unwrap().clone(),
+            result: Some(json!({ "contents": "docs" })),
+            error: None,
+        };
+        session
+            .server
+            .send(&Message::Response(response))
+            .await
+            .unwrap();
+    });
+
+    let response = session
+        .client
+        .request(
+            "textDocument/hover",
+            Some(json!({ "position": { "line": 0, "character": 0 } })),
+        )
+        .await
+        .unwrap();
+    server.await.unwrap();
+    assert_eq!(response.result, Some(json!({ "tagged": true })));
+}
+
+/// Answers every ping to whoever sent it and swallows the ping.
+struct Pong;
+
+#[async_trait]
+impl Hook for Pong {
+    async fn on_notification(
+        &self,
+        _notification: Notification,
+        context: &HookContext,
+    ) -> HookResult {
+        Ok(HookOutput::empty().with_message(
+            context.to_origin(),
+            Message::notification("custom/pong", None),
+        ))
+    }
+}
+
+#[tokio::test]
+async fn to_origin_replies_to_the_sender_on_both_paths() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("custom/ping", Arc::new(Pong))
+        .build();
+    let mut session = start(proxy);
+    let ping = Message::notification("custom/ping", None);
+    let pong = Message::notification("custom/pong", None);
+
+    session.client.send(&ping).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, pong);
+
+    session.server.send(&ping).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, pong);
+
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+}
+
+/// Fails on every request and notification.
+struct Failing;
+
+#[async_trait]
+impl Hook for Failing {
+    async fn on_request(&self, _request: Request, _context: &HookContext) -> HookResult {
+        Err(HookError::ProcessingFailed("boom".to_owned()))
+    }
+
+    async fn on_notification(
+        &self,
+        _notification: Notification,
+        _context: &HookContext,
+    ) -> HookResult {
+        Err(HookError::ProcessingFailed("boom".to_owned()))
+    }
+}
+
+#[tokio::test]
+async fn surfaced_hook_errors_reach_the_client_under_fail_open() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/didSave", Arc::new(Failing))
+        .surface_hook_errors(MessageType::Warning)
+        .on_hook_error(HookErrorPolicy::FailOpen)
+        .build();
+    let mut session = start(proxy);
+
+    let did_save = Message::notification(
+        "textDocument/didSave",
+        Some(json!({ "textDocument": { "uri": "file:///a.rs" } })),
+    );
+    session.client.send(&did_save).await.unwrap();
+
+    assert_eq!(recv(&mut session.server).await, did_save);
+    let Message::Notification(log) = recv(&mut session.client).await else {
+        panic!("expected a notification");
+    };
+    assert_eq!(log.method, "window/logMessage");
+    let params = log.params.unwrap();
+    assert_eq!(params["type"], MessageType::Warning as u8);
+    assert!(params["message"].as_str().unwrap().contains("boom"));
+}
+
+/// Sends a custom method to the server under its standard name.
+struct Redirect;
+
+#[async_trait]
+impl Hook for Redirect {
+    async fn on_request(&self, mut request: Request, _context: &HookContext) -> HookResult {
+        request.method = "textDocument/hover".to_owned();
+        Ok(HookOutput::new(Message::Request(request)))
+    }
+}
+
+/// Marks the responses it sees.
+struct Tag;
+
+#[async_trait]
+impl Hook for Tag {
+    async fn on_response(&self, mut response: Response, _context: &HookContext) -> HookResult {
+        response.result = Some(json!({ "tagged": true }));
+        Ok(HookOutput::new(Message::Response(response)))
+    }
+}
+
+#[tokio::test]
+async fn responses_go_to_the_hook_of_the_rewritten_method() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("myserver/hover", Arc::new(Redirect))
+        .with_hook("textDocument/hover", Arc::new(Tag))
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::request(1, "myserver/hover", None))
+        .await
+        .unwrap();
+    let request = recv(&mut session.server).await;
+    assert_eq!(request.get_method(), Some("textDocument/hover"));
+
+    let response = Response {
+        id: 1.into(),
+        result: Some(json!({ "contents": "docs" })),
+        error: None,
+    };
+    session
+        .server
+        .send(&Message::Response(response))
+        .await
+        .unwrap();
+
+    let Message::Response(response) = recv(&mut session.client).await else {
+        panic!("expected a response");
+    };
+    assert_eq!(response.result, Some(json!({ "tagged": true })));
+}
+
+#[tokio::test]
+async fn string_ids_are_matched_to_their_responses() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(Tag))
+        .build();
+    let mut session = start(proxy);
+
+    let request = Message::request("1", "textDocument/hover", None);
+    session.client.send(&request).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, request);
+
+    // The numeric id 1 is a different request, so its response is not
+    // tagged.
+    let stray = Response {
+        id: 1.into(),
+        result: Some(Value::Null),
+        error: None,
+    };
+    let response = Response {
+        id: "1".into(),
+        result: Some(json!({ "contents": "docs" })),
+        error: None,
+    };
+    for response in [stray, response] {
+        session
+            .server
+            .send(&Message::Response(response))
+            .await
+            .unwrap();
+    }
+
+    let Message::Response(stray) = recv(&mut session.client).await else {
+        panic!("expected the stray response");
+    };
+    assert_eq!(stray.result, Some(Value::Null));
+    let Message::Response(response) = recv(&mut session.client).await else {
+        panic!("expected a response");
+    };
+    assert_eq!(response.id.as_str(), Some("1"));
+    assert_eq!(response.result, Some(json!({ "tagged": true })));
+}
+
+/// A buggy hook that panics on every request.
+struct Panics;
+
+#[async_trait]
+impl Hook for Panics {
+    async fn on_request(&self, _request: Request, _context: &HookContext) -> HookResult {
+        panic!("boom");
+    }
+}
+
+#[tokio::test]
+async fn a_panicking_hook_does_not_take_the_proxy_down() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(Panics))
+        .surface_hook_errors(MessageType::Warning)
+        .build();
+    let mut session = start(proxy);
+
+    for id in 1..=2 {
+        let request = Message::request(id, "textDocument/hover", Some(json!({ "id": id })));
+        session.client.send(&request).await.unwrap();
+        assert_eq!(recv(&mut session.server).await, request);
+
+        let Message::Notification(report) = recv(&mut session.client).await else {
+            panic!("expected the panic to be reported");
+        };
+        assert_eq!(report.method, "window/logMessage");
+        assert_eq!(report.params.unwrap()["message"], "Hook panicked: boom");
+    }
+    assert!(!session.forward.is_finished());
+}
+
+#[tokio::test]
+async fn direction_scoped_hooks_ignore_the_other_direction() {
+    let proxy = ProxyBuilder::new()
+        .with_hook_for(Direction::ToServer, "telemetry/event", Arc::new(Rewrite))
+        .build();
+    let mut session = start(proxy);
+    let event = Message::notification("telemetry/event", Some(json!({ "n": 1 })));
+
+    session.client.send(&event).await.unwrap();
+    assert_eq!(
+        recv(&mut session.server).await,
+        Message::notification("telemetry/event", Some(json!({ "rewritten": true })))
+    );
+    assert_eq!(
+        recv(&mut session.client).await.get_method(),
+        Some("proxy/rewrote")
+    );
+
+    session.server.send(&event).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, event);
+    assert_silent(&mut session.client, Duration::from_millis(100)).await;
+}
+
+/// Reports the trace level each request sees.
+struct TraceProbe(mpsc::UnboundedSender<TraceValue>);
+
+#[async_trait]
+impl Hook for TraceProbe {
+    async fn on_request(&self, request: Request, context: &HookContext) -> HookResult {
+        self.0.send(context.trace()).unwrap();
+        Ok(HookOutput::new(Message::Request(request)))
+    }
+}
+
+#[tokio::test]
+async fn set_trace_updates_the_trace_level_hooks_see() {
+    let (traces, mut seen) = mpsc::unbounded_channel();
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(TraceProbe(traces)))
+        .build();
+    let mut session = start(proxy);
+    let hover = |id| Message::request(id, "textDocument/hover", None);
+
+    session.client.send(&hover(1)).await.unwrap();
+    recv(&mut session.server).await;
+    assert_eq!(seen.recv().await, Some(TraceValue::Off));
+
+    let set_trace = Message::notification("$/setTrace", Some(json!({ "value": "verbose" })));
+    session.client.send(&set_trace).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, set_trace);
+    let log_trace = Message::notification(
+        "$/logTrace",
+        Some(json!({ "message": "hover", "verbose": "took 3ms" })),
+    );
+    session.server.send(&log_trace).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, log_trace);
+
+    session.client.send(&hover(2)).await.unwrap();
+    recv(&mut session.server).await;
+    assert_eq!(seen.recv().await, Some(TraceValue::Verbose));
+}
+
+/// Reports the workspace roots each request sees.
+struct RootsProbe(mpsc::UnboundedSender<Vec<String>>);
+
+#[async_trait]
+impl Hook for RootsProbe {
+    async fn on_request(&self, request: Request, context: &HookContext) -> HookResult {
+        self.0.send(context.workspace_roots().to_vec()).unwrap();
+        Ok(HookOutput::new(Message::Request(request)))
+    }
+}
+
+#[tokio::test]
+async fn workspace_roots_follow_initialize_and_folder_changes() {
+    let (roots, mut seen) = mpsc::unbounded_channel();
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(RootsProbe(roots)))
+        .build();
+    let mut session = start(proxy);
+    let folder = |uri: &str| json!({ "uri": uri, "name": uri });
+
+    session
+        .client
+        .send(&Message::request(
+            1,
+            "initialize",
+            Some(json!({
+                "processId": null,
+                "rootUri": "file:///ignored",
+                "capabilities": {},
+                "workspaceFolders": [folder("file:///a"), folder("file:///b")]
+            })),
+        ))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    session
+        .client
+        .send(&Message::request(2, "textDocument/hover", None))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    assert_eq!(seen.recv().await.unwrap(), ["file:///a", "file:///b"]);
+
+    session
+        .client
+        .send(&Message::notification(
+            "workspace/didChangeWorkspaceFolders",
+            Some(json!({
+                "event": { "added": [folder("file:///c")], "removed": [folder("file:///a")] }
+            })),
+        ))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    session
+        .client
+        .send(&Message::request(3, "textDocument/hover", None))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    assert_eq!(seen.recv().await.unwrap(), ["file:///b", "file:///c"]);
+}
+
+#[tokio::test]
+async fn workspace_roots_fall_back_to_the_root_uri() {
+    let (roots, mut seen) = mpsc::unbounded_channel();
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(RootsProbe(roots)))
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::request(
+            1,
+            "initialize",
+            Some(json!({ "processId": null, "rootUri": "file:///root", "capabilities": {} })),
+        ))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    session
+        .client
+        .send(&Message::request(2, "textDocument/hover", None))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    assert_eq!(seen.recv().await.unwrap(), ["file:///root"]);
+}
+
+/// Asks the server for the scope at the cursor before forwarding a completion
+/// request, and adds the answer to its params.
+struct Enrich;
+
+#[async_trait]
+impl Hook for Enrich {
+    async fn on_request(&self, mut request: Request, context: &HookContext) -> HookResult {
+        let handle = context.handle().expect("running in the proxy");
+        let scope = handle
+            .send_request(
+                context.to_peer(),
+                "custom/scope",
+                request.params.clone(),
+                Duration::from_secs(1),
+            )
+            .await
+            .map_err(|e| HookError::ProcessingFailed(e.to_string()))?;
+        if let Some(params) = request.params.as_mut() {
+            params["scope"] = scope.result.unwrap_or_default();
+        }
+        Ok(HookOutput::new(Message::Request(request)))
+    }
+}
+
+#[tokio::test]
+async fn hooks_can_await_a_server_query_before_forwarding() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/completion", Arc::new(Enrich))
+        .build();
+    let mut session = start(proxy);
+    let position = json!({ "line": 1, "character": 4 });
+
+    session
+        .client
+        .send(&Message::request(
+            1,
+            "textDocument/completion",
+            Some(json!({ "position": position })),
+        ))
+        .await
+        .unwrap();
+
+    let Message::Request(query) = recv(&mut session.server).await else {
+        panic!("expected the hook's query first");
+    };
+    assert_eq!(query.method, "custom/scope");
+    assert_eq!(query.params, Some(json!({ "position": position })));
+    session
+        .server
+        .send(&Message::Response(Response {
+            id: query.id,
+            result: Some(json!("fn main")),
+            error: None,
+        }))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        recv(&mut session.server).await,
+        Message::request(
+            1,
+            "textDocument/completion",
+            Some(json!({ "position": position, "scope": "fn main" })),
+        )
+    );
+    // The query's response was consumed by the proxy.
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+}
+
+/// Forwards everything unchanged, through the trait's default methods.
+struct Passthrough;
+
+#[async_trait]
+impl Hook for Passthrough {}
+
+#[tokio::test]
+async fn the_default_hook_only_sees_methods_without_a_hook() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/didSave", Arc::new(Passthrough))
+        .with_default_hook(Arc::new(Rewrite))
+        .build();
+    let mut session = start(proxy);
+
+    let did_save = Message::notification("textDocument/didSave", Some(json!({ "a": 1 })));
+    session.client.send(&did_save).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, did_save);
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+
+    session
+        .client
+        .send(&Message::notification(
+            "textDocument/didClose",
+            Some(json!({ "a": 1 })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        recv(&mut session.server).await,
+        Message::notification("textDocument/didClose", Some(json!({ "rewritten": true })))
+    );
+    assert_eq!(
+        recv(&mut session.client).await.get_method(),
+        Some("proxy/rewrote")
+    );
+}
+
+/// Reports progress on a long-running request three times, 30ms apart.
+struct Progress;
+
+#[async_trait]
+impl Hook for Progress {
+    async fn on_request(&self, request: Request, _context: &HookContext) -> HookResult {
+        let reports = stream::unfold(1, |step| async move {
+            if step > 3 {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            let report = Message::notification(
+                "$/progress",
+                Some(
+                    json!({ "token": "op", "value": { "kind": "report", "percentage": step * 33 } }),
+                ),
+            );
+            Some(((Direction::ToClient, report), step + 1))
+        });
+        Ok(HookOutput::new(Message::Request(request)).with_feed(reports))
+    }
+}
+
+#[tokio::test]
+async fn feeds_forward_messages_as_they_arrive() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("custom/longOperation", Arc::new(Progress))
+        .build();
+    let mut session = start(proxy);
+
+    let request = Message::request(1, "custom/longOperation", None);
+    session.client.send(&request).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, request);
+
+    let mut arrived = Vec::new();
+    for percentage in [33, 66, 99] {
+        let Message::Notification(report) = recv(&mut session.client).await else {
+            panic!("expected a progress report");
+        };
+        assert_eq!(report.params.unwrap()["value"]["percentage"], percentage);
+        arrived.push(Instant::now());
+    }
+    // Each report is forwarded when it is ready, not all at the end.
+    for pair in arrived.windows(2) {
+        assert!(pair[1] - pair[0] >= Duration::from_millis(20));
+    }
+}
+
+/// Reports the connection each request belongs to.
+struct ConnectionProbe(mpsc::UnboundedSender<Option<ConnectionId>>);
+
+#[async_trait]
+impl Hook for ConnectionProbe {
+    async fn on_request(&self, request: Request, context: &HookContext) -> HookResult {
+        self.0.send(context.connection_id()).unwrap();
+        Ok(HookOutput::new(Message::Request(request)))
+    }
+}
+
+#[tokio::test]
+async fn concurrent_proxies_have_distinct_connection_ids() {
+    let (ids, mut seen) = mpsc::unbounded_channel();
+    let build = || {
+        ProxyBuilder::new()
+            .with_hook("textDocument/hover", Arc::new(ConnectionProbe(ids.clone())))
+            .build()
+    };
+    let (first, second) = (build(), build());
+    let expected = [
+        first.handle().connection_id(),
+        second.handle().connection_id(),
+    ];
+    assert_ne!(expected[0], expected[1]);
+    // Logs are tagged with the id's display form.
+    assert_eq!(
+        expected[0].to_string(),
+        format!("connection {}", expected[0].as_u64())
+    );
+
+    let mut sessions = [start(first), start(second)];
+    for (session, expected) in sessions.iter_mut().zip(expected) {
+        session
+            .client
+            .send(&Message::request(1, "textDocument/hover", None))
+            .await
+            .unwrap();
+        recv(&mut session.server).await;
+        assert_eq!(seen.recv().await, Some(Some(expected)));
+    }
+}
+
+/// Starts a proxy whose `Failing` hook covers hover and didSave, sends one of
+/// each, and returns the session once both have been handled.
+async fn failing_session(policy: HookErrorPolicy) -> common::Session {
+    let proxy = ProxyBuilder::new()
+        .with_hooks(
+            &["textDocument/hover", "textDocument/didSave"],
+            Arc::new(Failing),
+        )
+        .on_hook_error(policy)
+        .build();
+    let mut session = start(proxy);
+    session
+        .client
+        .send(&Message::request(1, "textDocument/hover", None))
+        .await
+        .unwrap();
+    session
+        .client
+        .send(&Message::notification("textDocument/didSave", None))
+        .await
+        .unwrap();
+    session
+}
+
+#[tokio::test]
+async fn fail_open_forwards_the_original_message() {
+    let mut session = failing_session(HookErrorPolicy::FailOpen).await;
+
+    assert_eq!(
+        recv(&mut session.server).await,
+        Message::request(1, "textDocument/hover", None)
+    );
+    assert_eq!(
+        recv(&mut session.server).await,
+        Message::notification("textDocument/didSave", None)
+    );
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn fail_closed_drops_the_message() {
+    let mut session = failing_session(HookErrorPolicy::FailClosed).await;
+
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+    assert!(!session.forward.is_finished());
+}
+
+#[tokio::test]
+async fn fail_closed_drops_the_message_even_when_errors_are_surfaced() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/didSave", Arc::new(Failing))
+        .surface_hook_errors(MessageType::Warning)
+        .on_hook_error(HookErrorPolicy::FailClosed)
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::notification("textDocument/didSave", None))
+        .await
+        .unwrap();
+
+    let Message::Notification(log) = recv(&mut session.client).await else {
+        panic!("expected the failure to be reported");
+    };
+    assert_eq!(log.method, "window/logMessage");
+    assert!(
+        log.params.unwrap()["message"]
+            .as_str()
+            .unwrap()
+            .contains("boom")
+    );
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn the_error_policy_answers_requests_with_the_hook_error() {
+    let mut session = failing_session(HookErrorPolicy::Error).await;
+
+    let Message::Response(answer) = recv(&mut session.client).await else {
+        panic!("expected the failed request to be answered");
+    };
+    assert_eq!(answer.id, 1);
+    let error = answer.error.unwrap();
+    assert_eq!(error["code"], INTERNAL_ERROR);
+    assert!(error["message"].as_str().unwrap().contains("boom"));
+    // The notification cannot be answered, so it is only dropped.
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+    assert_silent(&mut session.client, Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn requests_are_tracked_without_a_hook_and_response_only_hooks_still_run() {
+    // `Tag` only handles responses, so the hover request is forwarded
+    // untouched; its hook is looked up again when the response arrives.
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(Tag))
+        .build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+
+    let definition = Message::request(2, "textDocument/definition", None);
+    session.client.send(&definition).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, definition);
+    let request = Message::request(1, "textDocument/hover", None);
+    session.client.send(&request).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, request);
+    assert_eq!(handle.pending_count(), 2);
+
+    session
+        .server
+        .send(&Message::Response(Response {
+            id: 1.into(),
+            result: Some(json!({ "contents": "docs" })),
+            error: None,
+        }))
+        .await
+        .unwrap();
+    assert_eq!(
+        recv(&mut session.client).await,
+        Message::Response(Response {
+            id: 1.into(),
+            result: Some(json!({ "tagged": true })),
+            error: None,
+        })
+    );
+    assert_eq!(handle.pending_count(), 1);
+
+    let definition = Message::Response(Response {
+        id: 2.into(),
+        result: Some(json!(null)),
+        error: None,
+    });
+    session.server.send(&definition).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, definition);
+    assert_eq!(handle.pending_count(), 0);
+}
+
+/// Forwards the request and answers it straight away from a cache.
+struct AnswerAndForward;
+
+#[async_trait]
+impl Hook for AnswerAndForward {
+    async fn on_request(&self, request: Request, context: &HookContext) -> HookResult {
+        let cached = Message::Response(Response {
+            id: request.id.clone(),
+            result: Some(json!({ "contents": "cached" })),
+            error: None,
+        });
+        Ok(HookOutput::new(Message::Request(request)).with_message(context.to_origin(), cached))
+    }
+}
+
+#[tokio::test]
+async fn a_request_a_hook_answers_and_forwards_gets_one_response() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(AnswerAndForward))
+        .build();
+    let mut session = start(proxy);
+    let request = Message::request(1, "textDocument/hover", None);
+
+    session.client.send(&request).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, request);
+    let cached = Message::Response(Response {
+        id: 1.into(),
+        result: Some(json!({ "contents": "cached" })),
+        error: None,
+    });
+    assert_eq!(recv(&mut session.client).await, cached);
+
+    session
+        .server
+        .send(&Message::Response(Response {
+            id: 1.into(),
+            result: Some(json!({ "contents": "fresh" })),
+            error: None,
+        }))
+        .await
+        .unwrap();
+    assert_silent(&mut session.client, Duration::from_millis(100)).await;
+
+    // Later requests reusing the id are answered by the server as usual.
+    let request = Message::request(1, "textDocument/definition", None);
+    session.client.send(&request).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, request);
+    let response = Message::Response(Response {
+        id: 1.into(),
+        result: Some(json!(null)),
+        error: None,
+    });
+    session.server.send(&response).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, response);
+}
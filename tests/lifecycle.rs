@@ -0,0 +1,583 @@
+mod common;
+
+use async_trait::async_trait;
+use serde_json::json;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::{DuplexWriter, duplex, write_message};
+use lsp_proxy::{
+    Direction, Hook, HookContext, HookOutput, HookResult, LifecycleState, Message, Notification,
+    ProxyBuilder, Request, Response,
+};
+
+use common::{TIMEOUT, assert_silent, recv, start};
+
+#[tokio::test]
+async fn idle_sessions_stop_after_the_idle_timeout() {
+    let proxy = ProxyBuilder::new()
+        .idle_timeout(Duration::from_millis(200))
+        .build();
+    let mut session = start(proxy);
+
+    // Traffic keeps pushing the deadline back.
+    for _ in 0..5 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        session
+            .client
+            .send(&Message::notification("$/ping", None))
+            .await
+            .unwrap();
+        recv(&mut session.server).await;
+    }
+    assert!(!session.forward.is_finished());
+
+    tokio::time::timeout(TIMEOUT, session.forward)
+        .await
+        .expect("the idle proxy kept running")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn responses_still_reach_a_client_that_closed_its_side() {
+    let io = duplex();
+    let forward = tokio::spawn(ProxyBuilder::new().build().forward(
+        io.proxy_server.reader,
+        io.proxy_server.writer,
+        io.proxy_client.reader,
+        io.proxy_client.writer,
+    ));
+    let mut server = TestClient::from_endpoint(io.server);
+    let mut client_writer = io.client.writer;
+    let mut client = TestClient::new(io.client.reader, tokio::io::sink());
+
+    let request = Message::request(1, "textDocument/hover", None);
+    write_message(&mut client_writer, &request.to_value())
+        .await
+        .unwrap();
+    client_writer.shutdown().await.unwrap();
+    drop(client_writer);
+
+    assert_eq!(recv(&mut server).await, request);
+    let response = Message::Response(Response {
+        id: 1.into(),
+        result: Some(json!({ "contents": "docs" })),
+        error: None,
+    });
+    server.send(&response).await.unwrap();
+
+    assert_eq!(recv(&mut client).await, response);
+    tokio::time::timeout(TIMEOUT, forward)
+        .await
+        .expect("the proxy kept running after the client left")
+        .unwrap()
+        .unwrap();
+}
+
+/// Waits for slow external work, reporting whether it started and whether it
+/// was abandoned on shutdown.
+struct SlowLookup {
+    events: mpsc::UnboundedSender<&'static str>,
+}
+
+#[async_trait]
+impl Hook for SlowLookup {
+    async fn on_request(&self, request: Request, context: &HookContext) -> HookResult {
+        self.events.send("started").unwrap();
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(60)) => {}
+            _ = context.cancellation().cancelled() => {
+                self.events.send("cancelled").unwrap();
+            }
+        }
+        Ok(HookOutput::new(Message::Request(request)))
+    }
+}
+
+#[tokio::test]
+async fn hooks_see_the_cancellation_on_shutdown() {
+    let (events, mut seen) = mpsc::unbounded_channel();
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(SlowLookup { events }))
+        .build();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let io = duplex();
+    let forward = tokio::spawn(proxy.forward_with_shutdown(
+        io.proxy_server.reader,
+        io.proxy_server.writer,
+        io.proxy_client.reader,
+        io.proxy_client.writer,
+        async move {
+            let _ = stopped.await;
+        },
+    ));
+    let mut client = TestClient::from_endpoint(io.client);
+
+    client
+        .send(&Message::request(1, "textDocument/hover", None))
+        .await
+        .unwrap();
+    assert_eq!(seen.recv().await, Some("started"));
+
+    stop.send(()).unwrap();
+    let cancelled = tokio::time::timeout(TIMEOUT, seen.recv()).await;
+    assert_eq!(cancelled, Ok(Some("cancelled")));
+    tokio::time::timeout(TIMEOUT, forward)
+        .await
+        .expect("the proxy kept running after shutdown")
+        .unwrap()
+        .unwrap();
+}
+
+/// Counts how often it is started and shut down.
+#[derive(Default)]
+struct Resource {
+    starts: AtomicUsize,
+    shutdowns: AtomicUsize,
+}
+
+#[async_trait]
+impl Hook for Resource {
+    async fn on_start(&self) {
+        self.starts.fetch_add(1, Ordering::SeqCst);
+    }
+
+    async fn on_shutdown(&self) {
+        self.shutdowns.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[tokio::test]
+async fn hooks_are_started_and_shut_down_once() {
+    let resource = Arc::new(Resource::default());
+    let proxy = ProxyBuilder::new()
+        .with_hooks(
+            &["textDocument/hover", "textDocument/definition"],
+            resource.clone(),
+        )
+        .build();
+    let session = start(proxy);
+
+    let common::Session {
+        client,
+        server,
+        forward,
+    } = session;
+    drop(client);
+    drop(server);
+    tokio::time::timeout(TIMEOUT, forward)
+        .await
+        .expect("the proxy kept running after both sides left")
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(resource.starts.load(Ordering::SeqCst), 1);
+    assert_eq!(resource.shutdowns.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn forwarding_stops_after_the_shutdown_response() {
+    // The harness sends `shutdown` with a known id and stops once it is
+    // answered.
+    let proxy = ProxyBuilder::new()
+        .stop_after(|message, direction| {
+            direction == Direction::ToClient
+                && matches!(message, Message::Response(response) if response.id == 7)
+        })
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::request(7, "shutdown", None))
+        .await
+        .unwrap();
+    assert_eq!(
+        recv(&mut session.server).await.get_method(),
+        Some("shutdown")
+    );
+    assert!(!session.forward.is_finished());
+
+    let response = Message::Response(Response {
+        id: 7.into(),
+        result: Some(json!(null)),
+        error: None,
+    });
+    session.server.send(&response).await.unwrap();
+
+    assert_eq!(recv(&mut session.client).await, response);
+    tokio::time::timeout(TIMEOUT, session.forward)
+        .await
+        .expect("the proxy kept running after the shutdown response")
+        .unwrap()
+        .unwrap();
+}
+
+/// Sends `methods` from the client in order, `initialize` and `shutdown` as
+/// requests and the rest as notifications, and returns the exit code the
+/// handle reports afterwards.
+async fn exit_code_after(methods: &[&str]) -> Option<i32> {
+    let proxy = ProxyBuilder::new().build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+
+    for (id, method) in (1..).zip(methods) {
+        let message = match *method {
+            "initialize" | "shutdown" => Message::request(id, method, None),
+            _ => Message::notification(method, None),
+        };
+        assert_eq!(handle.exit_code(), None);
+        session.client.send(&message).await.unwrap();
+        assert_eq!(recv(&mut session.server).await, message);
+    }
+    handle.exit_code()
+}
+
+#[tokio::test]
+async fn exit_after_shutdown_is_clean_and_exit_alone_is_a_violation() {
+    assert_eq!(
+        exit_code_after(&["initialize", "initialized", "shutdown", "exit"]).await,
+        Some(0)
+    );
+    assert_eq!(
+        exit_code_after(&["initialize", "initialized", "exit"]).await,
+        Some(1)
+    );
+}
+
+/// Passes writes through until `gone` is set, then fails them all, like a
+/// server process that has exited.
+struct Exiting {
+    inner: DuplexWriter,
+    gone: Arc<AtomicBool>,
+}
+
+impl AsyncWrite for Exiting {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.gone.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn a_server_gone_after_exit_is_not_an_error() {
+    let io = duplex();
+    let gone = Arc::new(AtomicBool::new(false));
+    let forward = tokio::spawn(ProxyBuilder::new().build().forward(
+        io.proxy_server.reader,
+        Exiting {
+            inner: io.proxy_server.writer,
+            gone: gone.clone(),
+        },
+        io.proxy_client.reader,
+        io.proxy_client.writer,
+    ));
+    let mut client = TestClient::from_endpoint(io.client);
+    let mut server = TestClient::from_endpoint(io.server);
+
+    let exit = Message::notification("exit", None);
+    client.send(&exit).await.unwrap();
+    assert_eq!(recv(&mut server).await, exit);
+    gone.store(true, Ordering::SeqCst);
+    for _ in 0..3 {
+        client
+            .send(&Message::notification("textDocument/didSave", None))
+            .await
+            .unwrap();
+    }
+
+    let result = tokio::time::timeout(TIMEOUT, forward)
+        .await
+        .expect("the proxy kept running without a server")
+        .unwrap();
+    assert!(result.is_ok(), "{result:?}");
+}
+
+#[tokio::test]
+async fn shutting_down_mid_traffic_is_not_an_error() {
+    let (stop, stopped) = oneshot::channel::<()>();
+    let io = duplex();
+    let forward = tokio::spawn(ProxyBuilder::new().build().forward_with_shutdown(
+        io.proxy_server.reader,
+        io.proxy_server.writer,
+        io.proxy_client.reader,
+        io.proxy_client.writer,
+        async move {
+            let _ = stopped.await;
+        },
+    ));
+    let mut client = TestClient::from_endpoint(io.client);
+    let mut server = TestClient::from_endpoint(io.server);
+
+    let did_save = Message::notification("textDocument/didSave", None);
+    client.send(&did_save).await.unwrap();
+    assert_eq!(recv(&mut server).await, did_save);
+    stop.send(()).unwrap();
+    // The client keeps talking while the proxy winds down.
+    while !forward.is_finished() {
+        if client.send(&did_save).await.is_err() {
+            break;
+        }
+        tokio::task::yield_now().await;
+    }
+
+    let result = tokio::time::timeout(TIMEOUT, forward)
+        .await
+        .expect("the proxy kept running after shutdown")
+        .unwrap();
+    assert!(result.is_ok(), "{result:?}");
+}
+
+#[tokio::test]
+async fn messages_after_initialize_wait_for_its_response() {
+    let proxy = ProxyBuilder::new().hold_until_initialized(true).build();
+    let mut session = start(proxy);
+
+    let initialize = Message::request(
+        1,
+        "initialize",
+        Some(json!({ "processId": null, "rootUri": null, "capabilities": {} })),
+    );
+    let did_open = Message::notification(
+        "textDocument/didOpen",
+        Some(json!({
+            "textDocument": { "uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": "" }
+        })),
+    );
+    session.client.send(&initialize).await.unwrap();
+    session.client.send(&did_open).await.unwrap();
+
+    assert_eq!(recv(&mut session.server).await, initialize);
+    assert_silent(&mut session.server, Duration::from_millis(100)).await;
+
+    let response = Message::Response(Response {
+        id: 1.into(),
+        result: Some(json!({ "capabilities": {} })),
+        error: None,
+    });
+    session.server.send(&response).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, response);
+    assert_eq!(recv(&mut session.server).await, did_open);
+}
+
+/// Has the client send a request and close its side while the large response
+/// is still queued behind a pipe it has not read from, then returns what the
+/// client reads once it starts reading a little later.
+async fn response_after_client_eof(eof_grace: Duration) -> Option<Message> {
+    let io = duplex();
+    let (client_end, proxy_end) = tokio::io::duplex(1024);
+    let (proxy_reader, proxy_writer) = tokio::io::split(proxy_end);
+    let proxy = ProxyBuilder::new()
+        .half_close_grace(Duration::ZERO)
+        .eof_grace(eof_grace)
+        .build();
+    let forward = tokio::spawn(proxy.forward(
+        io.proxy_server.reader,
+        io.proxy_server.writer,
+        proxy_reader,
+        proxy_writer,
+    ));
+    let (client_reader, mut client_writer) = tokio::io::split(client_end);
+    let mut client = TestClient::new(client_reader, tokio::io::sink());
+    let mut server = TestClient::from_endpoint(io.server);
+
+    let request = Message::request(1, "textDocument/hover", None);
+    write_message(&mut client_writer, &request.to_value())
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut server).await, request);
+    server.send(&large_response()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    client_writer.shutdown().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let reading = tokio::spawn(async move { client.recv().await.ok() });
+    tokio::time::timeout(TIMEOUT, forward)
+        .await
+        .expect("the proxy kept running after the client left")
+        .unwrap()
+        .unwrap();
+    tokio::time::timeout(TIMEOUT, reading)
+        .await
+        .expect("the client kept waiting")
+        .unwrap()
+}
+
+fn large_response() -> Message {
+    Message::Response(Response {
+        id: 1.into(),
+        result: Some(json!({ "contents": "x".repeat(8192) })),
+        error: None,
+    })
+}
+
+#[tokio::test]
+async fn a_response_queued_before_client_eof_is_still_written() {
+    assert_eq!(
+        response_after_client_eof(Duration::from_secs(1)).await,
+        Some(large_response())
+    );
+    // Without the grace period the writer is stopped part way through.
+    assert_eq!(response_after_client_eof(Duration::ZERO).await, None);
+}
+
+/// Reports the lifecycle state each client message was read in.
+struct StateProbe {
+    states: mpsc::UnboundedSender<(String, LifecycleState)>,
+}
+
+#[async_trait]
+impl Hook for StateProbe {
+    async fn on_request(&self, request: Request, context: &HookContext) -> HookResult {
+        let state = context.lifecycle_state();
+        self.states.send((request.method.clone(), state)).unwrap();
+        Ok(HookOutput::new(Message::Request(request)))
+    }
+
+    async fn on_notification(
+        &self,
+        notification: Notification,
+        context: &HookContext,
+    ) -> HookResult {
+        let state = context.lifecycle_state();
+        self.states
+            .send((notification.method.clone(), state))
+            .unwrap();
+        Ok(HookOutput::new(Message::Notification(notification)))
+    }
+}
+
+#[tokio::test]
+async fn the_lifecycle_state_advances_through_a_normal_session() {
+    let (states, mut seen) = mpsc::unbounded_channel();
+    let proxy = ProxyBuilder::new()
+        .with_default_hook(Arc::new(StateProbe { states }))
+        .build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+    assert_eq!(handle.lifecycle_state(), LifecycleState::Uninitialized);
+
+    let steps = [
+        (
+            "initialize",
+            LifecycleState::Uninitialized,
+            LifecycleState::Initializing,
+        ),
+        (
+            "initialized",
+            LifecycleState::Initializing,
+            LifecycleState::Initialized,
+        ),
+        (
+            "textDocument/didSave",
+            LifecycleState::Initialized,
+            LifecycleState::Initialized,
+        ),
+        (
+            "shutdown",
+            LifecycleState::Initialized,
+            LifecycleState::ShuttingDown,
+        ),
+        ("exit", LifecycleState::ShuttingDown, LifecycleState::Exited),
+    ];
+    for (id, (method, read_in, after)) in (1..).zip(steps) {
+        let message = match method {
+            "initialize" | "shutdown" => Message::request(id, method, None),
+            _ => Message::notification(method, None),
+        };
+        session.client.send(&message).await.unwrap();
+        assert_eq!(recv(&mut session.server).await, message);
+        assert_eq!(seen.recv().await, Some((method.to_owned(), read_in)));
+        assert_eq!(handle.lifecycle_state(), after, "after {method}");
+    }
+}
+
+#[tokio::test]
+async fn skipped_lifecycle_steps_are_caught_up_on() {
+    let proxy = ProxyBuilder::new().build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+
+    // A proxy attached mid-session sees `shutdown` first.
+    let shutdown = Message::request(1, "shutdown", None);
+    session.client.send(&shutdown).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, shutdown);
+    assert_eq!(handle.lifecycle_state(), LifecycleState::ShuttingDown);
+
+    // A step arriving out of order does not move the state back.
+    let initialized = Message::notification("initialized", None);
+    session.client.send(&initialized).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, initialized);
+    assert_eq!(handle.lifecycle_state(), LifecycleState::ShuttingDown);
+}
+
+#[tokio::test]
+async fn client_eof_cancels_its_pending_requests() {
+    let io = duplex();
+    let proxy = ProxyBuilder::new().cancel_pending_on_eof(true).build();
+    tokio::spawn(proxy.forward(
+        io.proxy_server.reader,
+        io.proxy_server.writer,
+        io.proxy_client.reader,
+        io.proxy_client.writer,
+    ));
+    let mut server = TestClient::from_endpoint(io.server);
+    let mut client_writer = io.client.writer;
+    let _client_reader = io.client.reader;
+
+    let requests = [
+        Message::request(1, "textDocument/hover", None),
+        Message::request(2, "textDocument/completion", None),
+        Message::request(3, "textDocument/definition", None),
+    ];
+    for request in &requests {
+        write_message(&mut client_writer, &request.to_value())
+            .await
+            .unwrap();
+        assert_eq!(&recv(&mut server).await, request);
+    }
+    server
+        .send(&Message::Response(Response {
+            id: 2.into(),
+            result: Some(json!(null)),
+            error: None,
+        }))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    client_writer.shutdown().await.unwrap();
+
+    let mut cancelled = Vec::new();
+    for _ in 0..2 {
+        let Message::Notification(cancel) = recv(&mut server).await else {
+            panic!("expected a cancellation");
+        };
+        assert_eq!(cancel.method, "$/cancelRequest");
+        cancelled.push(cancel.params.unwrap()["id"].as_i64().unwrap());
+    }
+    cancelled.sort();
+    assert_eq!(cancelled, [1, 3]);
+    assert_silent(&mut server, Duration::from_millis(50)).await;
+}
@@ -0,0 +1,170 @@
+use serde_json::json;
+
+use lsp_proxy::message::METHOD_NOT_FOUND;
+use lsp_proxy::transport::write_message;
+use lsp_proxy::{ErrorCode, Message, MessageParseError, Response, ResponseError};
+
+#[test]
+fn each_malformed_shape_has_its_own_error() {
+    let cases = [
+        (json!([1, 2]), MessageParseError::NotAnObject),
+        (
+            json!({ "id": true, "method": "initialize" }),
+            MessageParseError::InvalidId,
+        ),
+        (
+            json!({ "id": 1, "method": 7 }),
+            MessageParseError::InvalidMethod,
+        ),
+        (
+            json!({ "id": 1, "method": "initialize", "result": null }),
+            MessageParseError::MethodWithResult,
+        ),
+        (
+            json!({ "method": "exit", "error": { "code": -1, "message": "" } }),
+            MessageParseError::MethodWithResult,
+        ),
+        (json!({ "result": 1 }), MessageParseError::ResponseWithoutId),
+        (json!({ "id": 1 }), MessageParseError::IdWithoutMethod),
+        (json!({ "jsonrpc": "2.0" }), MessageParseError::Empty),
+    ];
+
+    for (value, expected) in cases {
+        assert_eq!(Message::from_value(value.clone()), Err(expected), "{value}");
+    }
+}
+
+#[test]
+fn parse_errors_describe_the_inconsistency() {
+    let error = Message::from_value(json!({ "id": 1, "method": "a", "result": 1 })).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Message has both `method` and `result` or `error`"
+    );
+    let error = Message::from_value(json!({ "result": 1 })).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Response has `result` or `error` but no `id`"
+    );
+}
+
+#[tokio::test]
+async fn byte_len_matches_the_bytes_write_message_emits() {
+    let large: Vec<_> = (0..2000)
+        .map(|i| json!({ "label": format!("item{i} \"ünïcode\" \n\t"), "kind": 3 }))
+        .collect();
+    let messages = [
+        Message::request(1, "shutdown", None),
+        Message::request(
+            2,
+            "textDocument/completion",
+            Some(json!({ "items": large })),
+        ),
+        Message::notification("window/logMessage", Some(json!({ "message": "🦀 ok" }))),
+        Message::Response(Response {
+            id: 3.into(),
+            result: Some(json!(null)),
+            error: None,
+        }),
+        Message::error_response(4, METHOD_NOT_FOUND, "no such method"),
+    ];
+
+    for message in messages {
+        let mut written = Vec::new();
+        write_message(&mut written, &message.to_value())
+            .await
+            .unwrap();
+        let separator = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let body = &written[separator + 4..];
+
+        assert_eq!(message.byte_len(), body.len(), "{message:?}");
+        let header = format!("Content-Length: {}\r\n", body.len());
+        assert!(written.starts_with(header.as_bytes()));
+    }
+}
+
+#[test]
+fn null_results_are_kept_apart_from_absent_ones() {
+    let shutdown = json!({ "jsonrpc": "2.0", "id": 1, "result": null });
+    let message = Message::from_value(shutdown.clone()).unwrap();
+    assert_eq!(message.to_value(), shutdown);
+    assert!(
+        serde_json::to_string(&message.to_value())
+            .unwrap()
+            .contains(r#""result":null"#)
+    );
+
+    let failed = json!({ "jsonrpc": "2.0", "id": 1, "error": { "code": -32601, "message": "" } });
+    let message = Message::from_value(failed.clone()).unwrap();
+    assert_eq!(message.to_value(), failed);
+    assert!(message.to_value().get("result").is_none());
+}
+
+#[test]
+fn methods_with_control_characters_or_whitespace_are_rejected() {
+    for method in [
+        "textDocument/hover\n[INFO] forged",
+        "",
+        "a b",
+        "tab\there",
+        "nul\0",
+    ] {
+        assert_eq!(
+            Message::from_value(json!({ "method": method })),
+            Err(MessageParseError::MalformedMethod(method.to_owned()))
+        );
+    }
+    for method in [
+        "textDocument/hover",
+        "$/cancelRequest",
+        "rust-analyzer/expandMacro",
+    ] {
+        let message = Message::from_value(json!({ "method": method })).unwrap();
+        assert_eq!(message.get_method(), Some(method));
+    }
+}
+
+#[test]
+fn request_cancelled_errors_carry_the_lsp_code() {
+    let error = ResponseError::new(
+        ErrorCode::RequestCancelled,
+        "cancelled by the client",
+        Some(json!({ "retry": false })),
+    );
+    let Message::Response(response) = Message::response_error(4, error.clone()) else {
+        panic!("expected a response");
+    };
+
+    assert_eq!(response.id, 4);
+    assert_eq!(
+        response.error,
+        Some(json!({
+            "code": -32800,
+            "message": "cancelled by the client",
+            "data": { "retry": false }
+        }))
+    );
+    assert_eq!(
+        ResponseError::from_value(&response.error.unwrap()),
+        Some(error)
+    );
+    assert_eq!(ErrorCode::from(-32800), ErrorCode::RequestCancelled);
+    assert_eq!(ErrorCode::from(-1), ErrorCode::Custom(-1));
+}
+
+#[test]
+fn messages_differing_only_in_key_order_hash_equally() {
+    let parse = |text: &str| Message::from_value(serde_json::from_str(text).unwrap()).unwrap();
+    let first = parse(
+        r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.rs"},"position":{"line":1,"character":2}}}"#,
+    );
+    let reordered = parse(
+        r#"{"params":{"position":{"character":2,"line":1},"textDocument":{"uri":"file:///a.rs"}},"method":"textDocument/hover","id":1,"jsonrpc":"2.0"}"#,
+    );
+    let other = parse(
+        r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.rs"},"position":{"line":1,"character":3}}}"#,
+    );
+
+    assert_eq!(first.content_hash(), reordered.content_hash());
+    assert_ne!(first.content_hash(), other.content_hash());
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lsp_proxy::message::REQUEST_FAILED;
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::duplex;
+use lsp_proxy::{Message, Mirror, ProxyBuilder, Response};
+
+use common::{TIMEOUT, assert_silent, recv};
+
+fn reply(id: i64, result: &str) -> Message {
+    Message::Response(Response {
+        id: id.into(),
+        result: Some(json!(result)),
+        error: None,
+    })
+}
+
+#[tokio::test]
+async fn client_traffic_reaches_both_servers_and_only_the_primary_answers() {
+    let io = duplex();
+    let mirror_io = duplex();
+    let compared = Arc::new(Mutex::new(Vec::new()));
+    let mirror =
+        Mirror::new(mirror_io.proxy_server.reader, mirror_io.proxy_server.writer).compare({
+            let compared = compared.clone();
+            move |request, primary, mirror| {
+                compared.lock().unwrap().push((
+                    request.method.clone(),
+                    primary.result.clone(),
+                    mirror.result.clone(),
+                ))
+            }
+        });
+    tokio::spawn(ProxyBuilder::new().build().forward_mirrored(
+        io.proxy_server.reader,
+        io.proxy_server.writer,
+        io.proxy_client.reader,
+        io.proxy_client.writer,
+        mirror,
+    ));
+    let mut client = TestClient::from_endpoint(io.client);
+    let mut primary = TestClient::from_endpoint(io.server);
+    let mut mirror = TestClient::from_endpoint(mirror_io.server);
+
+    let did_save = Message::notification("textDocument/didSave", None);
+    let hover = Message::request(1, "textDocument/hover", None);
+    client.send(&did_save).await.unwrap();
+    client.send(&hover).await.unwrap();
+    for server in [&mut primary, &mut mirror] {
+        assert_eq!(recv(server).await, did_save);
+        assert_eq!(recv(server).await, hover);
+    }
+
+    mirror.send(&reply(1, "mirror")).await.unwrap();
+    primary.send(&reply(1, "primary")).await.unwrap();
+    assert_eq!(recv(&mut client).await, reply(1, "primary"));
+    assert_silent(&mut client, Duration::from_millis(50)).await;
+
+    tokio::time::timeout(TIMEOUT, async {
+        while compared.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the responses were never compared");
+    assert_eq!(
+        *compared.lock().unwrap(),
+        [(
+            "textDocument/hover".to_owned(),
+            Some(json!("primary")),
+            Some(json!("mirror"))
+        )]
+    );
+
+    // Requests from the mirror never reach the client.
+    mirror
+        .send(&Message::request(7, "workspace/configuration", None))
+        .await
+        .unwrap();
+    let Message::Response(refused) = recv(&mut mirror).await else {
+        panic!("expected the mirror's request to be refused");
+    };
+    assert_eq!(refused.id, 7);
+    assert_eq!(refused.error.unwrap()["code"], REQUEST_FAILED);
+    assert_silent(&mut client, Duration::from_millis(50)).await;
+}
@@ -0,0 +1,216 @@
+mod common;
+
+use serde_json::{Value, json};
+use std::time::Duration;
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::duplex;
+use lsp_proxy::{Message, Multiplexer, RequestId, Response};
+
+use common::{TIMEOUT, assert_silent, recv};
+
+struct Clients {
+    multiplexer: Multiplexer,
+    first: TestClient,
+    second: TestClient,
+    server: TestClient,
+}
+
+fn start() -> Clients {
+    let server = duplex();
+    let multiplexer = Multiplexer::new(server.proxy_server.reader, server.proxy_server.writer);
+    let attach = || {
+        let client = duplex();
+        multiplexer.attach(client.proxy_client.reader, client.proxy_client.writer);
+        TestClient::from_endpoint(client.client)
+    };
+    let (first, second) = (attach(), attach());
+
+    Clients {
+        multiplexer,
+        first,
+        second,
+        server: TestClient::from_endpoint(server.server),
+    }
+}
+
+fn response(id: i64, result: Value) -> Value {
+    Message::Response(Response {
+        id: id.into(),
+        result: Some(result),
+        error: None,
+    })
+    .to_value()
+}
+
+fn text_document(method: &str, params: Value) -> Message {
+    Message::notification(method, Some(params))
+}
+
+/// The text a `didOpen` or `didChange` gives the server.
+fn text_of(message: &Message) -> (&str, i64, &str) {
+    let Message::Notification(notification) = message else {
+        panic!("expected a notification, got {:?}", message);
+    };
+    let params = notification.params.as_ref().unwrap();
+    let text = params
+        .pointer("/textDocument/text")
+        .or_else(|| params.pointer("/contentChanges/0/text"))
+        .and_then(Value::as_str)
+        .unwrap();
+    let version = params["textDocument"]["version"].as_i64().unwrap();
+    (&notification.method, version, text)
+}
+
+#[tokio::test]
+async fn responses_reach_the_client_that_asked_without_id_collisions() {
+    let mut clients = start();
+
+    clients
+        .first
+        .send(&Message::request(1, "textDocument/hover", None))
+        .await
+        .unwrap();
+    let first = recv(&mut clients.server).await;
+    clients
+        .second
+        .send(&Message::request(1, "textDocument/hover", None))
+        .await
+        .unwrap();
+    let second = recv(&mut clients.server).await;
+
+    let (first_id, second_id) = (
+        first.get_id().unwrap().clone(),
+        second.get_id().unwrap().clone(),
+    );
+    assert_ne!(first_id, second_id);
+    assert_eq!(clients.multiplexer.pending_count(), 2);
+
+    let reply = |id: RequestId, result: Value| {
+        Message::Response(Response {
+            id,
+            result: Some(result),
+            error: None,
+        })
+    };
+    clients
+        .server
+        .send(&reply(second_id, json!("second")))
+        .await
+        .unwrap();
+    clients
+        .server
+        .send(&reply(first_id, json!("first")))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        recv(&mut clients.second).await.to_value(),
+        response(1, json!("second"))
+    );
+    assert_eq!(
+        recv(&mut clients.first).await.to_value(),
+        response(1, json!("first"))
+    );
+    assert_eq!(clients.multiplexer.pending_count(), 0);
+}
+
+#[tokio::test]
+async fn each_client_keeps_its_own_copy_of_a_shared_document() {
+    let mut clients = start();
+    let uri = "file:///shared.rs";
+    let open = |text: &str| {
+        text_document(
+            "textDocument/didOpen",
+            json!({"textDocument": {"uri": uri, "languageId": "rust", "version": 1, "text": text}}),
+        )
+    };
+
+    clients.first.send(&open("first")).await.unwrap();
+    assert_eq!(
+        text_of(&recv(&mut clients.server).await),
+        ("textDocument/didOpen", 1, "first")
+    );
+
+    // The server already has it open, so it only gets the second text.
+    clients.second.send(&open("second")).await.unwrap();
+    assert_eq!(
+        text_of(&recv(&mut clients.server).await),
+        ("textDocument/didChange", 2, "second")
+    );
+
+    // A ranged edit of the first client's copy is sent as its whole text.
+    let edit = text_document(
+        "textDocument/didChange",
+        json!({
+            "textDocument": {"uri": uri, "version": 2},
+            "contentChanges": [{
+                "range": {"start": {"line": 0, "character": 5}, "end": {"line": 0, "character": 5}},
+                "text": "!",
+            }],
+        }),
+    );
+    clients.first.send(&edit).await.unwrap();
+    assert_eq!(
+        text_of(&recv(&mut clients.server).await),
+        ("textDocument/didChange", 3, "first!")
+    );
+
+    // Closing it in the first client leaves the server with the second's.
+    let close = text_document(
+        "textDocument/didClose",
+        json!({"textDocument": {"uri": uri}}),
+    );
+    clients.first.send(&close).await.unwrap();
+    assert_eq!(
+        text_of(&recv(&mut clients.server).await),
+        ("textDocument/didChange", 4, "second")
+    );
+
+    clients.second.send(&close).await.unwrap();
+    assert_eq!(recv(&mut clients.server).await, close);
+}
+
+#[tokio::test]
+async fn initialize_completes_when_the_client_that_sent_it_disconnects() {
+    let Clients {
+        multiplexer,
+        mut first,
+        mut second,
+        mut server,
+    } = start();
+
+    first
+        .send(&Message::request(1, "initialize", Some(json!({}))))
+        .await
+        .unwrap();
+    let initialize = recv(&mut server).await;
+    drop(first);
+    tokio::time::timeout(TIMEOUT, async {
+        while multiplexer.pending_count() > 0 {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("the first client was never detached");
+
+    second
+        .send(&Message::request(7, "initialize", Some(json!({}))))
+        .await
+        .unwrap();
+    let capabilities = json!({ "capabilities": {} });
+    server
+        .send(&Message::Response(Response {
+            id: initialize.get_id().unwrap().clone(),
+            result: Some(capabilities.clone()),
+            error: None,
+        }))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        recv(&mut second).await.to_value(),
+        response(7, capabilities)
+    );
+    assert_silent(&mut server, Duration::from_millis(50)).await;
+}
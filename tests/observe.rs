@@ -0,0 +1,199 @@
+mod common;
+
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lsp_proxy::message::REQUEST_FAILED;
+use lsp_proxy::{Direction, Message, ProxyBuilder, Redactor, Response, Telemetry, TestClock};
+
+use common::{TIMEOUT, recv, reply, start};
+
+#[tokio::test]
+async fn requests_are_paired_with_their_responses() {
+    let proxy = ProxyBuilder::new()
+        .pair_timeout(Duration::from_millis(100))
+        .build();
+    let mut pairs = proxy.subscribe_pairs();
+    let mut session = start(proxy);
+    let mut next_pair = async || {
+        tokio::time::timeout(TIMEOUT, pairs.recv())
+            .await
+            .expect("timed out waiting for a pair")
+            .unwrap()
+    };
+
+    session
+        .client
+        .send(&Message::request(1, "textDocument/hover", None))
+        .await
+        .unwrap();
+    session
+        .client
+        .send(&Message::notification("textDocument/didSave", None))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    recv(&mut session.server).await;
+    session.server.send(&reply(1)).await.unwrap();
+    recv(&mut session.client).await;
+
+    let pair = next_pair().await;
+    assert_eq!(pair.request.method, "textDocument/hover");
+    assert_eq!(pair.direction, Direction::ToServer);
+    assert_eq!(pair.response.unwrap().result, Some(json!(null)));
+
+    session
+        .server
+        .send(&Message::request(2, "workspace/configuration", None))
+        .await
+        .unwrap();
+    recv(&mut session.client).await;
+    session.client.send(&reply(2)).await.unwrap();
+    recv(&mut session.server).await;
+
+    let pair = next_pair().await;
+    assert_eq!(pair.request.method, "workspace/configuration");
+    assert_eq!(pair.direction, Direction::ToClient);
+    assert!(pair.response.is_some());
+
+    session
+        .client
+        .send(&Message::request(3, "textDocument/definition", None))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+
+    let pair = next_pair().await;
+    assert_eq!(pair.request.method, "textDocument/definition");
+    assert!(pair.response.is_none());
+    assert!(pair.latency >= Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn recorded_pairs_are_redacted_while_forwarded_messages_are_intact() {
+    let redactor = Redactor::new()
+        .path("/params/initializationOptions/token")
+        .path("/result/serverInfo/licenseKey");
+    let proxy = ProxyBuilder::new().redact_logs(redactor).build();
+    let mut pairs = proxy.subscribe_pairs();
+    let mut session = start(proxy);
+
+    let initialize = Message::request(
+        1,
+        "initialize",
+        Some(
+            json!({ "capabilities": {}, "initializationOptions": { "token": "s3cret", "mode": "fast" } }),
+        ),
+    );
+    session.client.send(&initialize).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, initialize);
+    let response = Message::Response(Response {
+        id: 1.into(),
+        result: Some(
+            json!({ "capabilities": {}, "serverInfo": { "name": "ls", "licenseKey": "k3y" } }),
+        ),
+        error: None,
+    });
+    session.server.send(&response).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, response);
+
+    let pair = tokio::time::timeout(TIMEOUT, pairs.recv())
+        .await
+        .expect("timed out waiting for a pair")
+        .unwrap();
+    assert_eq!(
+        pair.request.params.unwrap()["initializationOptions"],
+        json!({ "token": "<redacted>", "mode": "fast" })
+    );
+    assert_eq!(
+        pair.response.unwrap().result.unwrap()["serverInfo"],
+        json!({ "name": "ls", "licenseKey": "<redacted>" })
+    );
+}
+
+#[tokio::test]
+async fn telemetry_events_report_each_interval_alongside_normal_traffic() {
+    let clock = TestClock::new();
+    let proxy = ProxyBuilder::new()
+        .clock(Arc::new(clock.clone()))
+        .telemetry(Telemetry::new().interval(Duration::from_secs(10)))
+        .build();
+    let mut session = start(proxy);
+
+    let request = Message::request(1, "textDocument/hover", None);
+    session.client.send(&request).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, request);
+    let failed = Message::error_response(1, REQUEST_FAILED, "no hover");
+    session.server.send(&failed).await.unwrap();
+    assert_eq!(recv(&mut session.client).await, failed);
+
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(
+        recv(&mut session.client).await,
+        Message::notification(
+            "telemetry/event",
+            Some(json!({ "messages": 2, "errors": 1, "p95LatencyMs": 0.0 }))
+        )
+    );
+
+    // Traffic is unaffected, and each interval starts from zero.
+    let did_save = Message::notification("textDocument/didSave", None);
+    session.client.send(&did_save).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, did_save);
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(
+        recv(&mut session.client).await,
+        Message::notification(
+            "telemetry/event",
+            Some(json!({ "messages": 1, "errors": 0, "p95LatencyMs": null }))
+        )
+    );
+}
+
+#[tokio::test]
+async fn only_the_last_messages_of_each_direction_are_kept_in_order() {
+    let proxy = ProxyBuilder::new().keep_recent(3).build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+    let did_save = |n: i64| Message::notification("textDocument/didSave", Some(json!({ "n": n })));
+
+    for n in 1..=5 {
+        session.client.send(&did_save(n)).await.unwrap();
+        recv(&mut session.server).await;
+    }
+    let log = Message::notification("window/logMessage", None);
+    session.server.send(&log).await.unwrap();
+    recv(&mut session.client).await;
+
+    assert_eq!(
+        handle.recent(Direction::ToServer),
+        [did_save(3), did_save(4), did_save(5)]
+    );
+    assert_eq!(handle.recent(Direction::ToClient), [log]);
+}
+
+#[tokio::test]
+async fn a_record_filter_keeps_one_method_and_its_responses() {
+    let proxy = ProxyBuilder::new()
+        .keep_recent(16)
+        .record_filter(|message| message.get_method() == Some("textDocument/completion"))
+        .build();
+    let handle = proxy.handle();
+    let mut session = start(proxy);
+
+    let completion = Message::request(1, "textDocument/completion", None);
+    let hover = Message::request(2, "textDocument/hover", None);
+    let did_save = Message::notification("textDocument/didSave", None);
+    for message in [&completion, &hover, &did_save] {
+        session.client.send(message).await.unwrap();
+        recv(&mut session.server).await;
+    }
+    for id in [2, 1] {
+        session.server.send(&reply(id)).await.unwrap();
+        recv(&mut session.client).await;
+    }
+
+    assert_eq!(handle.recent(Direction::ToServer), [completion]);
+    assert_eq!(handle.recent(Direction::ToClient), [reply(1)]);
+}
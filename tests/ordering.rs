@@ -0,0 +1,287 @@
+mod common;
+
+use async_trait::async_trait;
+use futures_util::stream;
+use serde_json::json;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::duplex;
+use lsp_proxy::{
+    Direction, Hook, HookContext, HookOutput, HookResult, Message, Notification, ProxyBuilder,
+};
+
+use common::{TIMEOUT, assert_silent, recv, start};
+
+/// Asks the server to analyze each change it forwards.
+struct AnalyzeOnChange;
+
+#[async_trait]
+impl Hook for AnalyzeOnChange {
+    async fn on_notification(
+        &self,
+        notification: Notification,
+        _context: &HookContext,
+    ) -> HookResult {
+        let version = notification.params.as_ref().unwrap()["textDocument"]["version"]
+            .as_i64()
+            .unwrap();
+        let analyze = Message::request(
+            100 + version,
+            "custom/analyze",
+            Some(json!({"version": version})),
+        );
+        Ok(HookOutput::new(Message::Notification(notification))
+            .with_message(Direction::ToServer, analyze))
+    }
+}
+
+/// Like `AnalyzeOnChange`, but through a feed that takes longer for earlier
+/// versions, so the requests are ready in the reverse order of the changes.
+struct AnalyzeLater;
+
+#[async_trait]
+impl Hook for AnalyzeLater {
+    async fn on_notification(
+        &self,
+        notification: Notification,
+        _context: &HookContext,
+    ) -> HookResult {
+        let document = notification.params.as_ref().unwrap()["textDocument"].clone();
+        let uri = document["uri"].as_str().unwrap().to_owned();
+        let version = document["version"].as_i64().unwrap();
+
+        let analyze = stream::once(async move {
+            tokio::time::sleep(Duration::from_millis(100 / version as u64)).await;
+            (
+                Direction::ToServer,
+                Message::request(
+                    100 + version,
+                    "custom/analyze",
+                    Some(json!({"version": version})),
+                ),
+            )
+        });
+        Ok(HookOutput::new(Message::Notification(notification)).with_ordered_feed(uri, analyze))
+    }
+}
+
+fn did_change(version: i64) -> Message {
+    Message::notification(
+        "textDocument/didChange",
+        Some(json!({
+            "textDocument": {"uri": "file:///a.rs", "version": version},
+            "contentChanges": [{"text": version.to_string()}],
+        })),
+    )
+}
+
+#[tokio::test]
+async fn requests_derived_from_changes_follow_their_change() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/didChange", Arc::new(AnalyzeOnChange))
+        .build();
+    let mut session = start(proxy);
+
+    for version in 1..=3 {
+        session.client.send(&did_change(version)).await.unwrap();
+    }
+
+    for version in 1..=3 {
+        assert_eq!(recv(&mut session.server).await, did_change(version));
+        let analyze = recv(&mut session.server).await;
+        assert_eq!(analyze.get_id(), Some(&(100 + version).into()));
+    }
+}
+
+#[tokio::test]
+async fn ordered_feeds_keep_the_order_of_their_changes() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/didChange", Arc::new(AnalyzeLater))
+        .build();
+    let mut session = start(proxy);
+
+    for version in 1..=3 {
+        session.client.send(&did_change(version)).await.unwrap();
+    }
+
+    let mut received = Vec::new();
+    for _ in 0..6 {
+        received.push(recv(&mut session.server).await);
+    }
+    let analyzed: Vec<_> = received
+        .iter()
+        .filter(|message| message.get_method() == Some("custom/analyze"))
+        .map(|message| message.get_id().unwrap().as_i64().unwrap() - 100)
+        .collect();
+    assert_eq!(analyzed, [1, 2, 3]);
+
+    for version in 1..=3 {
+        let change = received.iter().position(|m| *m == did_change(version));
+        let analyze = received
+            .iter()
+            .position(|m| m.get_id() == Some(&(100 + version).into()));
+        assert!(change < analyze);
+    }
+}
+
+/// A writer that logs the method of each frame written to it, shared with the
+/// writer of the other peer to see the order of writes across both.
+struct Tap {
+    peer: &'static str,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl AsyncWrite for Tap {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let text = String::from_utf8_lossy(buf);
+        let (_, body) = text.split_once("\r\n\r\n").expect("one frame per write");
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        self.log.lock().unwrap().push(format!(
+            "{} {}",
+            self.peer,
+            body["method"].as_str().unwrap()
+        ));
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Sends messages to both peers along with each notification.
+struct Interleave;
+
+#[async_trait]
+impl Hook for Interleave {
+    async fn on_notification(
+        &self,
+        notification: Notification,
+        _context: &HookContext,
+    ) -> HookResult {
+        Ok(HookOutput::new(Message::Notification(notification))
+            .with_message(Direction::ToClient, Message::notification("a", None))
+            .with_message(Direction::ToServer, Message::notification("b", None))
+            .with_message(Direction::ToClient, Message::notification("c", None)))
+    }
+}
+
+#[tokio::test]
+async fn serialized_writes_keep_one_order_across_both_peers() {
+    let io = duplex();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let tap = |peer| Tap {
+        peer,
+        log: log.clone(),
+    };
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/didSave", Arc::new(Interleave))
+        .serialized_writes(true)
+        .build();
+    let mut writes = proxy.subscribe_writes();
+    tokio::spawn(proxy.forward(
+        io.proxy_server.reader,
+        tap("server"),
+        io.proxy_client.reader,
+        tap("client"),
+    ));
+    let mut client = TestClient::from_endpoint(io.client);
+
+    for _ in 0..10 {
+        client
+            .send(&Message::notification("textDocument/didSave", None))
+            .await
+            .unwrap();
+    }
+
+    tokio::time::timeout(TIMEOUT, async {
+        while log.lock().unwrap().len() < 40 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("timed out waiting for the writes");
+
+    let expected: Vec<_> = [
+        "server textDocument/didSave",
+        "client a",
+        "server b",
+        "client c",
+    ]
+    .repeat(10);
+    assert_eq!(*log.lock().unwrap(), expected);
+
+    // The writer numbers the same order.
+    for (sequence, expected) in (1..).zip(expected) {
+        let written = writes.recv().await.unwrap();
+        let peer = match written.direction {
+            Direction::ToServer => "server",
+            Direction::ToClient => "client",
+        };
+        assert_eq!(written.sequence, sequence);
+        assert_eq!(
+            format!("{} {}", peer, written.message.get_method().unwrap()),
+            expected
+        );
+    }
+}
+
+fn diagnostics(uri: &str, version: i64) -> Message {
+    Message::notification(
+        "textDocument/publishDiagnostics",
+        Some(json!({ "uri": uri, "version": version, "diagnostics": [] })),
+    )
+}
+
+#[tokio::test]
+async fn diagnostics_for_a_slow_client_collapse_to_the_latest() {
+    let io = duplex();
+    // A small pipe the client does not read from yet, so the proxy's writer
+    // blocks while the server keeps publishing.
+    let (client_end, proxy_end) = tokio::io::duplex(1024);
+    let (proxy_reader, proxy_writer) = tokio::io::split(proxy_end);
+    let proxy = ProxyBuilder::new().coalesce_superseded(true).build();
+    tokio::spawn(proxy.forward(
+        io.proxy_server.reader,
+        io.proxy_server.writer,
+        proxy_reader,
+        proxy_writer,
+    ));
+    let (client_reader, client_writer) = tokio::io::split(client_end);
+    let mut client = TestClient::new(client_reader, client_writer);
+    let mut server = TestClient::from_endpoint(io.server);
+
+    let log = Message::notification(
+        "window/logMessage",
+        Some(json!({ "type": 3, "message": "x".repeat(4096) })),
+    );
+    server.send(&log).await.unwrap();
+    for version in 1..20 {
+        server
+            .send(&diagnostics("file:///a.rs", version))
+            .await
+            .unwrap();
+    }
+    server.send(&diagnostics("file:///b.rs", 1)).await.unwrap();
+    server.send(&diagnostics("file:///a.rs", 20)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(recv(&mut client).await, log);
+    assert_eq!(recv(&mut client).await, diagnostics("file:///b.rs", 1));
+    assert_eq!(recv(&mut client).await, diagnostics("file:///a.rs", 20));
+    assert_silent(&mut client, Duration::from_millis(100)).await;
+}
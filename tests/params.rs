@@ -0,0 +1,125 @@
+mod common;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lsp_proxy::{Hook, HookContext, HookOutput, HookResult, Message, ProxyBuilder, Request};
+
+use common::{recv, start};
+
+/// Counts how often a `Position` is deserialized.
+static DESERIALIZED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Deserialize)]
+struct RawPosition {
+    line: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(from = "RawPosition")]
+struct Position {
+    line: u32,
+}
+
+impl From<RawPosition> for Position {
+    fn from(raw: RawPosition) -> Self {
+        DESERIALIZED.fetch_add(1, Ordering::SeqCst);
+        Position { line: raw.line }
+    }
+}
+
+/// Reads the typed params and tags the request with the line it saw.
+struct Lookup;
+
+#[async_trait]
+impl Hook for Lookup {
+    async fn on_request(&self, mut request: Request, context: &HookContext) -> HookResult {
+        let line = context.params(&request.params).as_typed::<Position>()?.line;
+        request.params.as_mut().unwrap()["seen"] = json!(line);
+        Ok(HookOutput::new(Message::Request(request)))
+    }
+}
+
+/// Reads the typed params, optionally moves the position down a line, and
+/// hands the request on to `Lookup`.
+struct Chain {
+    shift: bool,
+}
+
+#[async_trait]
+impl Hook for Chain {
+    async fn on_request(&self, mut request: Request, context: &HookContext) -> HookResult {
+        let line = context.params(&request.params).as_typed::<Position>()?.line;
+        if self.shift {
+            request.params = Some(json!({ "line": line + 1 }));
+        }
+        Lookup.on_request(request, context).await
+    }
+}
+
+#[tokio::test]
+async fn chained_hooks_deserialize_the_same_params_once() {
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", Arc::new(Chain { shift: false }))
+        .with_hook("textDocument/definition", Arc::new(Chain { shift: true }))
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::request(
+            1,
+            "textDocument/hover",
+            Some(json!({ "line": 3 })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        recv(&mut session.server).await,
+        Message::request(
+            1,
+            "textDocument/hover",
+            Some(json!({ "line": 3, "seen": 3 }))
+        )
+    );
+    assert_eq!(DESERIALIZED.load(Ordering::SeqCst), 1);
+
+    // Changing the params makes the next hook deserialize them again.
+    session
+        .client
+        .send(&Message::request(
+            2,
+            "textDocument/definition",
+            Some(json!({ "line": 3 })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        recv(&mut session.server).await,
+        Message::request(
+            2,
+            "textDocument/definition",
+            Some(json!({ "line": 4, "seen": 4 }))
+        )
+    );
+    assert_eq!(DESERIALIZED.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn fields_are_read_by_pointer() {
+    let context = HookContext::default();
+    let params = Some(json!({
+        "textDocument": { "uri": "file:///a.rs", "version": 2 },
+        "context": { "includeDeclaration": true }
+    }));
+    let params = context.params(&params);
+
+    assert_eq!(params.get_str("/textDocument/uri"), Some("file:///a.rs"));
+    assert_eq!(params.get_i64("/textDocument/version"), Some(2));
+    assert_eq!(params.get_bool("/context/includeDeclaration"), Some(true));
+    assert_eq!(params.get_str("/textDocument/version"), None);
+    assert_eq!(params.get("/missing"), None);
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use serde_json::json;
+
+use lsp_proxy::position::{offset, position};
+use lsp_proxy::{Message, PositionEncoding, ProxyBuilder, Response};
+
+use common::{recv, start};
+
+#[test]
+fn utf8_positions_count_bytes() {
+    let text = "é😀x\nab";
+
+    // `é` is two bytes and the emoji four, so `x` starts at byte 6.
+    assert_eq!(offset(text, 0, 6, PositionEncoding::Utf8), 6);
+    assert_eq!(position(text, 6, PositionEncoding::Utf8), (0, 6));
+    // The same character in the other encodings.
+    assert_eq!(offset(text, 0, 3, PositionEncoding::Utf16), 6);
+    assert_eq!(offset(text, 0, 2, PositionEncoding::Utf32), 6);
+    assert_eq!(position(text, 6, PositionEncoding::Utf16), (0, 3));
+    assert_eq!(position(text, 6, PositionEncoding::Utf32), (0, 2));
+
+    assert_eq!(offset(text, 1, 1, PositionEncoding::Utf8), 9);
+    assert_eq!(position(text, 9, PositionEncoding::Utf8), (1, 1));
+}
+
+#[test]
+fn the_encoding_defaults_to_utf16_unless_the_server_negotiates_one() {
+    let result = |capabilities| json!({ "capabilities": capabilities });
+
+    assert_eq!(
+        PositionEncoding::from_initialize_result(Some(&result(
+            json!({ "positionEncoding": "utf-8" })
+        ))),
+        PositionEncoding::Utf8
+    );
+    assert_eq!(
+        PositionEncoding::from_initialize_result(Some(&result(json!({})))),
+        PositionEncoding::Utf16
+    );
+    assert_eq!(
+        PositionEncoding::from_initialize_result(Some(&result(
+            json!({ "positionEncoding": "utf-7" })
+        ))),
+        PositionEncoding::Utf16
+    );
+    assert_eq!(
+        PositionEncoding::from_initialize_result(None),
+        PositionEncoding::Utf16
+    );
+}
+
+#[tokio::test]
+async fn edits_are_applied_at_byte_offsets_once_utf8_is_negotiated() {
+    let proxy = ProxyBuilder::new().normalize_document_sync(true).build();
+    let mut session = start(proxy);
+    let uri = "file:///a.rs";
+
+    session
+        .client
+        .send(&Message::request(
+            1,
+            "initialize",
+            Some(json!({ "processId": null, "rootUri": null, "capabilities": {} })),
+        ))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    session
+        .server
+        .send(&Message::Response(Response {
+            id: 1.into(),
+            result: Some(json!({
+                "capabilities": { "textDocumentSync": 1, "positionEncoding": "utf-8" }
+            })),
+            error: None,
+        }))
+        .await
+        .unwrap();
+    recv(&mut session.client).await;
+
+    session
+        .client
+        .send(&Message::notification(
+            "textDocument/didOpen",
+            Some(json!({
+                "textDocument": { "uri": uri, "languageId": "rust", "version": 1, "text": "// 😀 a\n" }
+            })),
+        ))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+
+    // `a` is at byte 8: three for `// `, four for the emoji, one for the space.
+    session
+        .client
+        .send(&Message::notification(
+            "textDocument/didChange",
+            Some(json!({
+                "textDocument": { "uri": uri, "version": 2 },
+                "contentChanges": [{
+                    "range": {
+                        "start": { "line": 0, "character": 8 },
+                        "end": { "line": 0, "character": 9 }
+                    },
+                    "text": "b"
+                }]
+            })),
+        ))
+        .await
+        .unwrap();
+
+    let Message::Notification(did_change) = recv(&mut session.server).await else {
+        panic!("expected the didChange notification");
+    };
+    assert_eq!(
+        did_change.params.unwrap()["contentChanges"],
+        json!([{ "text": "// 😀 b\n" }])
+    );
+}
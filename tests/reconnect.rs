@@ -0,0 +1,108 @@
+mod common;
+
+use serde_json::json;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::duplex;
+use lsp_proxy::{Message, ProxyBuilder, ReconnectEvent, ReconnectPolicy, Response};
+
+use common::recv;
+
+#[tokio::test]
+async fn lost_servers_are_reconnected_with_backoff() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let policy = ReconnectPolicy::new()
+        .initial_delay(Duration::from_millis(10))
+        .max_delay(Duration::from_millis(25))
+        .on_event({
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event.clone())
+        });
+
+    // The first connection works, the next two attempts fail, the third works.
+    let (servers, mut connected) = mpsc::unbounded_channel();
+    let calls = Arc::new(AtomicU32::new(0));
+    let connect = move || {
+        let call = calls.fetch_add(1, Ordering::SeqCst);
+        let servers = servers.clone();
+        async move {
+            if call == 1 || call == 2 {
+                return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+            }
+            let io = duplex();
+            servers.send(TestClient::from_endpoint(io.server)).unwrap();
+            Ok((io.proxy_server.reader, io.proxy_server.writer))
+        }
+    };
+
+    let io = duplex();
+    tokio::spawn(ProxyBuilder::new().build().forward_supervised(
+        connect,
+        policy,
+        io.proxy_client.reader,
+        io.proxy_client.writer,
+    ));
+    let mut client = TestClient::from_endpoint(io.client);
+
+    let params = json!({ "processId": null, "rootUri": null, "capabilities": {} });
+    client
+        .send(&Message::request(1, "initialize", Some(params.clone())))
+        .await
+        .unwrap();
+    let mut server = connected.recv().await.unwrap();
+    recv(&mut server).await;
+    let response = Message::Response(Response {
+        id: 1.into(),
+        result: Some(json!({ "capabilities": {} })),
+        error: None,
+    });
+    server.send(&response).await.unwrap();
+    assert_eq!(recv(&mut client).await, response);
+    drop(server);
+
+    let mut server = connected.recv().await.unwrap();
+    let Message::Request(replayed) = recv(&mut server).await else {
+        panic!("expected the initialize request to be replayed");
+    };
+    assert_eq!(replayed.method, "initialize");
+    assert_eq!(replayed.params, Some(params));
+    server
+        .send(&Message::Response(Response {
+            id: replayed.id,
+            result: Some(json!({ "capabilities": {} })),
+            error: None,
+        }))
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut server).await.get_method(), Some("initialized"));
+
+    let did_save = Message::notification("textDocument/didSave", None);
+    client.send(&did_save).await.unwrap();
+    assert_eq!(recv(&mut server).await, did_save);
+
+    let delay = Duration::from_millis;
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            ReconnectEvent::Disconnected,
+            ReconnectEvent::Reconnecting {
+                attempt: 1,
+                delay: delay(10)
+            },
+            ReconnectEvent::Reconnecting {
+                attempt: 2,
+                delay: delay(20)
+            },
+            ReconnectEvent::Reconnecting {
+                attempt: 3,
+                delay: delay(25)
+            },
+            ReconnectEvent::Reconnected { attempt: 3 },
+        ]
+    );
+}
@@ -0,0 +1,147 @@
+mod common;
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+
+use lsp_proxy::message::INTERNAL_ERROR;
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::{DuplexWriter, duplex};
+use lsp_proxy::{Message, ProxyBuilder};
+
+use common::{assert_silent, recv};
+
+/// Fails the next `failures` writes whole, as a momentarily full socket
+/// might, and passes everything else through. With `tear` set, the next write
+/// instead gets only half its bytes through and the one after it fails.
+struct Flaky {
+    inner: DuplexWriter,
+    failures: Arc<AtomicU32>,
+    tear: Arc<AtomicBool>,
+}
+
+impl AsyncWrite for Flaky {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.tear.swap(false, Ordering::SeqCst) {
+            self.failures.store(1, Ordering::SeqCst);
+            let half = buf.len() / 2;
+            return Pin::new(&mut self.inner).poll_write(cx, &buf[..half]);
+        }
+        let failures = &self.failures;
+        if failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return Poll::Ready(Err(io::Error::other("transient")));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+struct FlakySession {
+    client: TestClient,
+    server: TestClient,
+    failures: Arc<AtomicU32>,
+    tear: Arc<AtomicBool>,
+}
+
+/// A proxy that retries hover requests, writing to the server through `Flaky`.
+fn start_flaky() -> FlakySession {
+    let io = duplex();
+    let failures = Arc::new(AtomicU32::new(0));
+    let tear = Arc::new(AtomicBool::new(false));
+    let proxy = ProxyBuilder::new()
+        .retry_idempotent(&["textDocument/hover"], 2, Duration::from_millis(10))
+        .build();
+    tokio::spawn(proxy.forward(
+        io.proxy_server.reader,
+        Flaky {
+            inner: io.proxy_server.writer,
+            failures: failures.clone(),
+            tear: tear.clone(),
+        },
+        io.proxy_client.reader,
+        io.proxy_client.writer,
+    ));
+
+    FlakySession {
+        client: TestClient::from_endpoint(io.client),
+        server: TestClient::from_endpoint(io.server),
+        failures,
+        tear,
+    }
+}
+
+#[tokio::test]
+async fn only_idempotent_requests_are_retried_after_a_failed_write() {
+    let FlakySession {
+        mut client,
+        mut server,
+        failures,
+        ..
+    } = start_flaky();
+
+    failures.store(1, Ordering::SeqCst);
+    let hover = Message::request(1, "textDocument/hover", None);
+    client.send(&hover).await.unwrap();
+    assert_eq!(recv(&mut server).await, hover);
+    assert_silent(&mut client, Duration::from_millis(50)).await;
+
+    failures.store(1, Ordering::SeqCst);
+    client
+        .send(&Message::request(2, "textDocument/rename", None))
+        .await
+        .unwrap();
+    let Message::Response(failed) = recv(&mut client).await else {
+        panic!("expected the failed request to be answered");
+    };
+    assert_eq!(failed.id, 2);
+    assert_eq!(failed.error.unwrap()["code"], INTERNAL_ERROR);
+    assert_silent(&mut server, Duration::from_millis(50)).await;
+
+    // The writer keeps going after both failures.
+    let did_save = Message::notification("textDocument/didSave", None);
+    client.send(&did_save).await.unwrap();
+    assert_eq!(recv(&mut server).await, did_save);
+}
+
+#[tokio::test]
+async fn a_write_that_fails_partway_through_a_frame_is_not_retried() {
+    let FlakySession {
+        mut client,
+        mut server,
+        tear,
+        ..
+    } = start_flaky();
+
+    tear.store(true, Ordering::SeqCst);
+    client
+        .send(&Message::request(1, "textDocument/hover", None))
+        .await
+        .unwrap();
+
+    let Message::Response(failed) = recv(&mut client).await else {
+        panic!("expected the torn request to be answered");
+    };
+    assert_eq!(failed.id, 1);
+    assert_eq!(failed.error.unwrap()["code"], INTERNAL_ERROR);
+    // Only the first half of the frame ever reaches the server.
+    let read = tokio::time::timeout(Duration::from_millis(100), server.recv()).await;
+    assert!(!matches!(read, Ok(Ok(_))), "{read:?}");
+}
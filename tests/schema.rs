@@ -0,0 +1,92 @@
+#![cfg(feature = "schema")]
+
+mod common;
+
+use serde_json::json;
+use std::time::Duration;
+
+use lsp_proxy::message::INVALID_PARAMS;
+use lsp_proxy::{Message, ProxyBuilder};
+
+use common::{assert_silent, recv, start};
+
+fn did_change_schema() -> serde_json::Value {
+    json!({
+        "type": "object",
+        "required": ["textDocument", "contentChanges"],
+        "properties": {
+            "textDocument": {
+                "type": "object",
+                "required": ["uri", "version"],
+                "properties": {
+                    "uri": { "type": "string" },
+                    "version": { "type": "integer" }
+                }
+            },
+            "contentChanges": { "type": "array" }
+        }
+    })
+}
+
+#[tokio::test]
+async fn only_conforming_did_change_notifications_are_forwarded() {
+    let proxy = ProxyBuilder::new()
+        .with_schema("textDocument/didChange", &did_change_schema())
+        .unwrap()
+        .build();
+    let mut session = start(proxy);
+
+    let broken = Message::notification(
+        "textDocument/didChange",
+        Some(json!({ "textDocument": { "uri": 42 }, "contentChanges": [] })),
+    );
+    session.client.send(&broken).await.unwrap();
+    let conforming = Message::notification(
+        "textDocument/didChange",
+        Some(json!({
+            "textDocument": { "uri": "file:///a.rs", "version": 2 },
+            "contentChanges": [{ "text": "fn main() {}" }]
+        })),
+    );
+    session.client.send(&conforming).await.unwrap();
+
+    assert_eq!(recv(&mut session.server).await, conforming);
+    assert_silent(&mut session.server, Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn non_conforming_requests_are_answered_with_invalid_params() {
+    let schema = json!({ "type": "object", "required": ["position"] });
+    let proxy = ProxyBuilder::new()
+        .with_schema("textDocument/hover", &schema)
+        .unwrap()
+        .build();
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::request(1, "textDocument/hover", Some(json!({}))))
+        .await
+        .unwrap();
+    let Message::Response(rejected) = recv(&mut session.client).await else {
+        panic!("expected the invalid request to be answered");
+    };
+    assert_eq!(rejected.id, 1);
+    assert_eq!(rejected.error.unwrap()["code"], INVALID_PARAMS);
+
+    // Methods without a schema are forwarded whatever their params.
+    let unchecked = Message::request(2, "textDocument/definition", Some(json!(7)));
+    session.client.send(&unchecked).await.unwrap();
+    assert_eq!(recv(&mut session.server).await, unchecked);
+    assert_silent(&mut session.server, Duration::from_millis(50)).await;
+}
+
+#[test]
+fn invalid_schemas_are_rejected_when_building() {
+    let schema = json!({ "type": "no-such-type" });
+    assert!(
+        ProxyBuilder::new()
+            .with_schema("textDocument/hover", &schema)
+            .is_err()
+    );
+}
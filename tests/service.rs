@@ -0,0 +1,60 @@
+#![cfg(feature = "tower")]
+
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tower::limit::ConcurrencyLimit;
+use tower::{Service, ServiceExt};
+
+use lsp_proxy::processed_message::ProcessedMessage;
+use lsp_proxy::{
+    Direction, Hook, HookContext, HookError, HookOutput, HookResult, Message, ProxyBuilder, Request,
+};
+
+/// Takes a while per request and records the most requests it was ever
+/// handling at once.
+#[derive(Default)]
+struct Slow {
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+#[async_trait]
+impl Hook for Slow {
+    async fn on_request(&self, request: Request, _context: &HookContext) -> HookResult {
+        let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(now, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        Ok(HookOutput::new(Message::Request(request)))
+    }
+}
+
+#[tokio::test]
+async fn concurrency_limit_bounds_in_flight_hooks() {
+    let slow = Arc::new(Slow::default());
+    let proxy = ProxyBuilder::new()
+        .with_hook("textDocument/hover", slow.clone())
+        .build();
+    let service = ConcurrencyLimit::new(proxy.service(Direction::ToServer), 2);
+
+    let calls: Vec<_> = (0..6)
+        .map(|id| {
+            let mut service = service.clone();
+            tokio::spawn(async move {
+                let request = Message::request(id, "textDocument/hover", None);
+                let processed = service.ready().await?.call(request.clone()).await?;
+                assert!(
+                    matches!(processed, ProcessedMessage::Forward(message) if message == request)
+                );
+                Ok::<_, HookError>(())
+            })
+        })
+        .collect();
+    for call in calls {
+        call.await.unwrap().unwrap();
+    }
+
+    assert_eq!(slow.max_in_flight.load(Ordering::SeqCst), 2);
+}
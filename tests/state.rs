@@ -0,0 +1,88 @@
+mod common;
+
+use async_trait::async_trait;
+use serde_json::json;
+use std::sync::Arc;
+
+use lsp_proxy::{Hook, HookContext, HookOutput, HookResult, Message, ProxyBuilder, Response};
+
+use common::{recv, start};
+
+struct Settings {
+    hover_footer: String,
+}
+
+/// Appends the configured footer to every hover.
+struct FooterHook;
+
+#[async_trait]
+impl Hook<Settings> for FooterHook {
+    async fn on_response(
+        &self,
+        mut response: Response,
+        context: &HookContext<Settings>,
+    ) -> HookResult {
+        if let Some(value) = response
+            .result
+            .as_mut()
+            .and_then(|result| result.get_mut("contents"))
+        {
+            let contents = format!(
+                "{}{}",
+                value.as_str().unwrap_or_default(),
+                context.state().hover_footer
+            );
+            *value = json!(contents);
+        }
+        Ok(HookOutput::new(Message::Response(response)))
+    }
+}
+
+fn hover(contents: &str) -> Response {
+    Response {
+        id: 1.into(),
+        result: Some(json!({"contents": contents})),
+        error: None,
+    }
+}
+
+#[tokio::test]
+async fn hooks_read_the_typed_state() {
+    let proxy = ProxyBuilder::with_state(Settings {
+        hover_footer: " (via proxy)".to_owned(),
+    })
+    .with_hook("textDocument/hover", Arc::new(FooterHook))
+    .build();
+    assert_eq!(proxy.state().hover_footer, " (via proxy)");
+    let mut session = start(proxy);
+
+    session
+        .client
+        .send(&Message::request(1, "textDocument/hover", None))
+        .await
+        .unwrap();
+    recv(&mut session.server).await;
+    session
+        .server
+        .send(&Message::Response(hover("fn main()")))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        recv(&mut session.client).await,
+        Message::Response(hover("fn main() (via proxy)"))
+    );
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test]
+async fn hooks_can_be_called_directly_with_a_state() {
+    let context = HookContext::default()
+        .with_origin(lsp_proxy::Direction::ToServer)
+        .with_state(Arc::new(Settings {
+            hover_footer: "!".to_owned(),
+        }));
+
+    let output = FooterHook.on_response(hover("hi"), &context).await.unwrap();
+    assert_eq!(output.message, Some(Message::Response(hover("hi!"))));
+}
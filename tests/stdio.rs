@@ -0,0 +1,155 @@
+//! Runs `Proxy::forward_stdio_to_child` for real, which needs a process of
+//! its own: this binary starts itself again as the proxy, which starts it
+//! once more as a mock server. The libtest harness would write to stdout, so
+//! this test has none.
+
+mod common;
+
+use serde_json::json;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::{read_message, write_message};
+use lsp_proxy::{Message, ProxyBuilder, Response};
+
+use common::{TIMEOUT, recv};
+
+const ROLE: &str = "LSP_PROXY_STDIO_TEST_ROLE";
+const RECEIVED: &str = "server received ";
+
+#[tokio::main]
+async fn main() {
+    match std::env::var(ROLE).as_deref() {
+        Ok("proxy") => run_proxy().await,
+        Ok("server") => run_server().await,
+        _ => {
+            round_trip_through_a_child_server().await;
+            println!("test round_trip_through_a_child_server ... ok");
+            a_client_gone_without_exit_has_the_server_shut_down().await;
+            println!("test a_client_gone_without_exit_has_the_server_shut_down ... ok");
+        }
+    }
+}
+
+fn this_binary(role: &str) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(std::env::current_exe().unwrap());
+    command.env(ROLE, role);
+    command
+}
+
+async fn run_proxy() {
+    let result = ProxyBuilder::new()
+        .build()
+        .forward_stdio_to_child(this_binary("server"))
+        .await;
+    std::process::exit(if result.is_ok() { 0 } else { 1 });
+}
+
+/// Answers every request with its method, and leaves on `exit`. Each method
+/// read is reported on stderr, which the proxy passes on.
+async fn run_server() {
+    let mut stdin = BufReader::new(tokio::io::stdin());
+    let mut stdout = tokio::io::stdout();
+    while let Ok(value) = read_message(&mut stdin).await {
+        let message = Message::from_value(value).unwrap();
+        if let Some(method) = message.get_method() {
+            eprintln!("{RECEIVED}{method}");
+        }
+        match message {
+            Message::Request(request) => {
+                let response = Message::Response(Response {
+                    id: request.id,
+                    result: Some(json!({ "echo": request.method })),
+                    error: None,
+                });
+                write_message(&mut stdout, &response.to_value())
+                    .await
+                    .unwrap();
+            }
+            Message::Notification(notification) if notification.method == "exit" => break,
+            _ => {}
+        }
+    }
+}
+
+fn spawn_proxy() -> tokio::process::Child {
+    this_binary("proxy")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap()
+}
+
+fn echo(id: i64, method: &str) -> Message {
+    Message::Response(Response {
+        id: id.into(),
+        result: Some(json!({ "echo": method })),
+        error: None,
+    })
+}
+
+async fn round_trip_through_a_child_server() {
+    let mut proxy = spawn_proxy();
+    let mut client = TestClient::new(proxy.stdout.take().unwrap(), proxy.stdin.take().unwrap());
+
+    let initialize = Message::request(1, "initialize", Some(json!({ "capabilities": {} })));
+    client.send(&initialize).await.unwrap();
+    assert_eq!(recv(&mut client).await, echo(1, "initialize"));
+    let initialized = Message::notification("initialized", None);
+    client.send(&initialized).await.unwrap();
+
+    client
+        .send(&Message::request(2, "shutdown", None))
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut client).await, echo(2, "shutdown"));
+    client
+        .send(&Message::notification("exit", None))
+        .await
+        .unwrap();
+
+    let status = tokio::time::timeout(TIMEOUT, proxy.wait())
+        .await
+        .expect("the proxy kept running after exit")
+        .unwrap();
+    assert!(status.success(), "{status}");
+}
+
+async fn a_client_gone_without_exit_has_the_server_shut_down() {
+    let mut proxy = spawn_proxy();
+    let mut stdin = proxy.stdin.take().unwrap();
+    let mut client = TestClient::new(proxy.stdout.take().unwrap(), tokio::io::sink());
+    let stderr = proxy.stderr.take().unwrap();
+
+    let initialize = Message::request(1, "initialize", Some(json!({ "capabilities": {} })));
+    write_message(&mut stdin, &initialize.to_value())
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut client).await, echo(1, "initialize"));
+    let initialized = Message::notification("initialized", None);
+    write_message(&mut stdin, &initialized.to_value())
+        .await
+        .unwrap();
+    // The editor is killed: stdin closes without `shutdown` or `exit`.
+    drop(stdin);
+
+    let status = tokio::time::timeout(TIMEOUT, proxy.wait())
+        .await
+        .expect("the proxy kept running after the client left")
+        .unwrap();
+    assert!(status.success(), "{status}");
+
+    let mut lines = BufReader::new(stderr).lines();
+    let mut received = Vec::new();
+    while let Some(line) = lines.next_line().await.unwrap() {
+        if let Some(method) = line.strip_prefix(RECEIVED) {
+            received.push(method.to_owned());
+        }
+    }
+    assert_eq!(received, ["initialize", "initialized", "shutdown", "exit"]);
+    // The client was not sent the synthetic shutdown's response.
+    assert!(client.recv().await.is_err());
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio::io::BufReader;
+
+use lsp_proxy::transport::{duplex, read_message, write_messages};
+use lsp_proxy::{Message, MessageSink, MessageStream, ProxyBuilder, Response};
+
+use common::TIMEOUT;
+
+#[tokio::test]
+async fn read_message_keeps_bytes_read_ahead() {
+    let (mut writer, reader) = tokio::io::duplex(1024);
+    let messages = [
+        Message::notification("initialized", Some(json!({}))).to_value(),
+        Message::notification("exit", None).to_value(),
+    ];
+    write_messages(&mut writer, &messages).await.unwrap();
+    drop(writer);
+
+    let mut reader = BufReader::new(reader);
+    assert_eq!(read_message(&mut reader).await.unwrap(), messages[0]);
+    assert_eq!(read_message(&mut reader).await.unwrap(), messages[1]);
+}
+
+#[tokio::test]
+async fn a_full_session_runs_through_message_stream_and_sink() {
+    let duplex = duplex();
+    let forward = tokio::spawn(ProxyBuilder::new().build().forward(
+        duplex.proxy_server.reader,
+        duplex.proxy_server.writer,
+        duplex.proxy_client.reader,
+        duplex.proxy_client.writer,
+    ));
+    let mut client_sink = MessageSink::new(duplex.client.writer);
+    let mut client_stream = MessageStream::new(duplex.client.reader);
+    let mut server_sink = MessageSink::new(duplex.server.writer);
+    let mut server_stream = MessageStream::new(duplex.server.reader);
+
+    let response = |id: i64, result| {
+        Message::Response(Response {
+            id: id.into(),
+            result: Some(result),
+            error: None,
+        })
+    };
+    let client_messages = [
+        Message::request(1, "initialize", Some(json!({"capabilities": {}}))),
+        Message::notification("initialized", Some(json!({}))),
+        Message::request(2, "shutdown", None),
+        Message::notification("exit", None),
+    ];
+    let server_replies = [
+        Some(response(1, json!({"capabilities": {}}))),
+        None,
+        Some(response(2, json!(null))),
+        None,
+    ];
+
+    for (message, reply) in client_messages.into_iter().zip(server_replies) {
+        client_sink.send(message.clone()).await.unwrap();
+        assert_eq!(server_stream.next().await.unwrap().unwrap(), message);
+
+        if let Some(reply) = reply {
+            server_sink.send(reply.clone()).await.unwrap();
+            assert_eq!(client_stream.next().await.unwrap().unwrap(), reply);
+        }
+    }
+
+    client_sink.close().await.unwrap();
+    server_sink.close().await.unwrap();
+    tokio::time::timeout(TIMEOUT, forward)
+        .await
+        .expect("the proxy did not stop")
+        .unwrap()
+        .unwrap();
+    assert!(client_stream.next().await.is_none());
+    assert!(server_stream.next().await.is_none());
+}
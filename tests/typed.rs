@@ -0,0 +1,52 @@
+#![cfg(feature = "lsp-types")]
+
+use serde_json::json;
+
+use lsp_proxy::{Message, Request, TypedRequest};
+
+fn request(method: &str, params: serde_json::Value) -> Request {
+    let Message::Request(request) = Message::request(1, method, Some(params)) else {
+        unreachable!();
+    };
+    request
+}
+
+#[test]
+fn hover_requests_carry_their_typed_position() {
+    let hover = request(
+        "textDocument/hover",
+        json!({
+            "textDocument": { "uri": "file:///a.rs" },
+            "position": { "line": 3, "character": 7 }
+        }),
+    );
+
+    let TypedRequest::Hover(params) = hover.typed().unwrap() else {
+        panic!("expected a hover request");
+    };
+    let position = params.text_document_position_params.position;
+    assert_eq!((position.line, position.character), (3, 7));
+    assert_eq!(
+        params
+            .text_document_position_params
+            .text_document
+            .uri
+            .as_str(),
+        "file:///a.rs"
+    );
+}
+
+#[test]
+fn unknown_methods_are_custom_and_bad_params_fail() {
+    let custom = request("rust-analyzer/expandMacro", json!({ "a": 1 }));
+    let typed = custom.typed().unwrap();
+    assert_eq!(typed.method(), "rust-analyzer/expandMacro");
+    assert!(matches!(
+        typed,
+        TypedRequest::Custom(method, params)
+            if method == "rust-analyzer/expandMacro" && params == json!({ "a": 1 })
+    ));
+
+    let broken = request("textDocument/hover", json!({ "position": "nowhere" }));
+    assert!(broken.typed().is_err());
+}
@@ -0,0 +1,74 @@
+#![cfg(unix)]
+
+mod common;
+
+use serde_json::json;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::{bind_unix, connect_unix};
+use lsp_proxy::{Message, ProxyBuilder, Response};
+
+use common::{TIMEOUT, recv};
+
+/// A fresh directory for this test's socket files.
+fn socket_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lsp-proxy-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn a_round_trip_over_unix_sockets() {
+    let dir = socket_dir("round-trip");
+    let server_path = dir.join("server.sock");
+    let client_path = dir.join("client.sock");
+    // A socket file left behind by an earlier run.
+    drop(std::os::unix::net::UnixListener::bind(&client_path).unwrap());
+
+    let server_listener = bind_unix(&server_path).unwrap();
+    let forward = tokio::spawn(
+        ProxyBuilder::new()
+            .build()
+            .forward_unix(server_path, client_path.clone()),
+    );
+    let (server_stream, _) = server_listener.accept().await.unwrap();
+    let (reader, writer) = server_stream.into_split();
+    let mut server = TestClient::new(reader, writer);
+
+    let (reader, writer) = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            match connect_unix(&client_path).await {
+                Ok(halves) => break halves,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .expect("the proxy never listened for the client");
+    let mut client = TestClient::new(reader, writer);
+
+    let request = Message::request(1, "textDocument/hover", None);
+    client.send(&request).await.unwrap();
+    assert_eq!(recv(&mut server).await, request);
+    let response = Message::Response(Response {
+        id: 1.into(),
+        result: Some(json!({ "contents": "docs" })),
+        error: None,
+    });
+    server.send(&response).await.unwrap();
+    assert_eq!(recv(&mut client).await, response);
+
+    // The client socket is gone once its one client is accepted.
+    assert!(!client_path.exists());
+    drop(client);
+    drop(server);
+    tokio::time::timeout(TIMEOUT, forward)
+        .await
+        .expect("the proxy kept running after both sides left")
+        .unwrap()
+        .unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
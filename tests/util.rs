@@ -0,0 +1,53 @@
+use serde_json::json;
+
+use lsp_proxy::Request;
+use lsp_proxy::util::{ArrayMerge, merge};
+
+#[test]
+fn nested_objects_are_merged_key_by_key() {
+    let mut target = json!({ "a": { "b": 1 } });
+    merge(&mut target, json!({ "a": { "c": 2 } }), ArrayMerge::Replace);
+    assert_eq!(target, json!({ "a": { "b": 1, "c": 2 } }));
+}
+
+#[test]
+fn arrays_are_replaced_or_concatenated_and_scalars_replaced() {
+    let target = json!({ "list": [1, 2], "name": "old", "keep": true });
+    let patch = json!({ "list": [3], "name": null });
+
+    let mut replaced = target.clone();
+    merge(&mut replaced, patch.clone(), ArrayMerge::Replace);
+    assert_eq!(replaced, json!({ "list": [3], "name": null, "keep": true }));
+
+    let mut concatenated = target;
+    merge(&mut concatenated, patch, ArrayMerge::Concat);
+    assert_eq!(
+        concatenated,
+        json!({ "list": [1, 2, 3], "name": null, "keep": true })
+    );
+}
+
+#[test]
+fn merge_params_adds_fields_without_clobbering_siblings() {
+    let mut request = Request {
+        id: 1.into(),
+        method: "textDocument/references".to_owned(),
+        params: Some(json!({ "context": { "includeDeclaration": true } })),
+    };
+    request.merge_params(json!({ "workDoneToken": "t", "context": { "extra": 1 } }));
+    assert_eq!(
+        request.params,
+        Some(json!({
+            "context": { "includeDeclaration": true, "extra": 1 },
+            "workDoneToken": "t"
+        }))
+    );
+
+    let mut request = Request {
+        id: 2.into(),
+        method: "shutdown".to_owned(),
+        params: None,
+    };
+    request.merge_params(json!({ "workDoneToken": "t" }));
+    assert_eq!(request.params, Some(json!({ "workDoneToken": "t" })));
+}
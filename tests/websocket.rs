@@ -0,0 +1,77 @@
+#![cfg(feature = "websocket")]
+
+mod common;
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{accept_async, client_async};
+
+use lsp_proxy::testing::TestClient;
+use lsp_proxy::transport::duplex;
+use lsp_proxy::{Message, ProxyBuilder, Response, websocket};
+
+use common::{TIMEOUT, assert_silent, recv};
+
+#[tokio::test]
+async fn a_browser_client_talks_to_the_server_one_frame_per_message() {
+    let io = duplex();
+    let (browser_end, proxy_end) = tokio::io::duplex(4096);
+    let (accepted, connected) = tokio::join!(
+        accept_async(proxy_end),
+        client_async("ws://localhost/lsp", browser_end)
+    );
+    let (client_reader, client_writer) = websocket::split(accepted.unwrap());
+    let forward = tokio::spawn(ProxyBuilder::new().build().forward(
+        io.proxy_server.reader,
+        io.proxy_server.writer,
+        client_reader,
+        client_writer,
+    ));
+    let mut browser = connected.unwrap().0;
+    let mut server = TestClient::from_endpoint(io.server);
+
+    let request = Message::request(1, "textDocument/hover", None);
+    browser
+        .send(WsMessage::text(request.to_value().to_string()))
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut server).await, request);
+
+    // Pings are answered by the connection and never reach the server.
+    browser.send(WsMessage::Ping("alive".into())).await.unwrap();
+    let pong = tokio::time::timeout(TIMEOUT, browser.next())
+        .await
+        .expect("timed out waiting for the pong")
+        .unwrap()
+        .unwrap();
+    assert_eq!(pong, WsMessage::Pong("alive".into()));
+    assert_silent(&mut server, Duration::from_millis(50)).await;
+
+    let response = Message::Response(Response {
+        id: 1.into(),
+        result: Some(json!({ "contents": "docs" })),
+        error: None,
+    });
+    server.send(&response).await.unwrap();
+    let frame = tokio::time::timeout(TIMEOUT, browser.next())
+        .await
+        .expect("timed out waiting for the response")
+        .unwrap()
+        .unwrap();
+    let WsMessage::Text(text) = frame else {
+        panic!("expected a text frame, got {frame:?}");
+    };
+    let value = serde_json::from_str(text.as_str()).unwrap();
+    assert_eq!(Message::from_value(value).unwrap(), response);
+
+    // Closing the connection ends the session like EOF.
+    browser.close(None).await.unwrap();
+    drop(server);
+    tokio::time::timeout(TIMEOUT, forward)
+        .await
+        .expect("the proxy kept running after the close frame")
+        .unwrap()
+        .unwrap();
+}